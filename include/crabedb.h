@@ -0,0 +1,34 @@
+/* C API of the CrabeDB storage engine, built with the `ffi` feature (src/crabedb/ffi.rs). */
+#ifndef CRABEDB_H
+#define CRABEDB_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CRABEDB_OK 0
+#define CRABEDB_NOT_FOUND 1
+#define CRABEDB_INVALID_ARGUMENT -1
+#define CRABEDB_IO_ERROR -2
+#define CRABEDB_CORRUPTION -3
+#define CRABEDB_READ_ONLY -4
+#define CRABEDB_ERROR -5
+#define CRABEDB_PANIC -6
+
+typedef struct crabedb crabedb;
+
+int crabedb_open(const char *path, crabedb **db);
+int crabedb_get(const crabedb *db, const uint8_t *key, size_t key_len, uint8_t **value, size_t *value_len);
+void crabedb_free_value(uint8_t *value, size_t value_len);
+int crabedb_set(const crabedb *db, const uint8_t *key, size_t key_len, const uint8_t *value, size_t value_len, uint64_t *seq);
+int crabedb_remove(const crabedb *db, const uint8_t *key, size_t key_len, uint64_t *seq);
+int crabedb_close(crabedb *db);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,515 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::process;
+use std::time::{Duration, UNIX_EPOCH};
+
+use clap::{Arg, App, ArgMatches, SubCommand};
+
+extern crate crabedb;
+use crabedb::client::ring::HashRing;
+use crabedb::storage::bitcask::import_bitcask;
+use crabedb::storage::encryption::{EnvKeyProvider, Keyring, KmsKeyProvider};
+use crabedb::storage::merge::merge_stores;
+use crabedb::storage::options::{StorageOptions, SyncOptions};
+use crabedb::storage::pitr::{restore, RestoreTarget};
+use crabedb::storage::renumber::renumber;
+use crabedb::storage::split::split_store;
+use crabedb::storage::util::human_readable_byte_count;
+use crabedb::storage::verify::{verify, verify_files};
+
+fn data_files_usage(datadir: &str) -> io::Result<(usize, u64)> {
+    let mut count = 0;
+    let mut size = 0;
+    for file in fs::read_dir(datadir)? {
+        let file = file?;
+        if file.file_name().to_string_lossy().ends_with(".crabe.sst") {
+            count += 1;
+            size += file.metadata()?.len();
+        }
+    }
+    Ok((count, size))
+}
+
+// The keyring of the --keys and --key-provider arguments of a subcommand, if any.
+fn keyring(matches: &ArgMatches) -> Result<Option<Keyring>, Box<dyn std::error::Error>> {
+    let keyring = match (matches.value_of("key-provider").unwrap_or("file"), matches.value_of("keys")) {
+        ("file", Some(path)) => Keyring::load(path)?,
+        ("file", None) => return Ok(None),
+        ("env", _) => Keyring::with_provider(EnvKeyProvider::default()),
+        ("kms", Some(path)) => Keyring::with_provider(KmsKeyProvider::from_env(path, None)?),
+        (provider, _) => return Err(format!("invalid key provider {:?}, or --keys missing", provider).into()),
+    };
+    Ok(Some(keyring))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let matches = App::new("
+    .d8888b.                  888               8888888b.  888888b.
+    d88P  Y88b                 888               888  'Y88b 888  '88b
+    888    888                 888               888    888 888  .88P
+    888        888d888 8888b.  88888b.   .d88b.  888    888 8888888K.
+    888        888P'      '88b 888 '88b d8P  Y8b 888    888 888  'Y88b
+    888    888 888    .d888888 888  888 88888888 888    888 888    888
+    Y88b  d88P 888    888  888 888 d88P Y8b.     888  .d88P 888   d88P
+     'Y8888P'  888    'Y888888 88888P'   'Y8888  8888888P'  8888888P'
+    \n\n
+    ")
+    .version("0.1.0")
+    .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+    .about("Offline administration tool for CrabeDB stores")
+    .subcommand(
+        SubCommand::with_name("verify")
+            .about("Check every record and compaction file of a store which is not in use.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("files")
+                .long("files")
+                .help("Only check the length and hash of the sealed data files against the manifest, without decoding their records.")
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("check-consistency")
+            .about("Check every index entry of a store which is not in use against the record it points to.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory.")
+                .required(true)
+                .index(1)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("compact")
+            .about("Merge all the data files of a store which is not in use into fresh ones.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory.")
+                .required(true)
+                .index(1)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("export")
+            .about("Dump the live key/value pairs of a store which is not in use into a portable archive.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("archive")
+                .help("Path of the archive to create.")
+                .required(true)
+                .index(2)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("import")
+            .about("Load the key/value pairs of a portable archive into a store which is not in use.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory, created if needed.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("archive")
+                .help("Path of the archive to load.")
+                .required(true)
+                .index(2)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("import-bitcask")
+            .about("Rewrite the records of a Bitcask data directory into a store which is not in use.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("bitcaskdir")
+                .help("Path of the Bitcask data directory.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory, created if needed.")
+                .required(true)
+                .index(2)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("merge")
+            .about("Merge two stores which are not in use into a new compacted store, keeping the latest version of each key.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir-a")
+                .help("Path of the first store directory.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("datadir-b")
+                .help("Path of the second store directory, whose version of a key wins a tie.")
+                .required(true)
+                .index(2)
+            )
+            .arg(Arg::with_name("out")
+                .long("out")
+                .help("Path of the new store directory, which must not exist or be empty.")
+                .takes_value(true)
+                .required(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("split")
+            .about("Partition the live records of a store which is not in use into new stores, with the hash ring of the sharded client.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("shards")
+                .long("shards")
+                .help("Number of new stores.")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("out")
+                .long("out")
+                .help("Path of the new store directories, in which {} is replaced by the index of the shard, from 0. They must not exist or be empty.")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("nodes")
+                .long("nodes")
+                .help("Comma-separated addresses (<ip>:<port>) of the servers which will serve the shards, in order, so that each key lands in the shard of the node the sharded client sends it to. (default: shard-0, shard-1, ...)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("restore")
+            .about("Rebuild a store as it was at a point in time from its archive of compacted files, see the archive_dir option.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("archive")
+                .help("Path of the archive directory of the store.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("datadir")
+                .long("datadir")
+                .help("Path of the store directory, holding the files which weren't compacted yet. It may be in use.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("seq")
+                .long("seq")
+                .help("Restore every write up to this sequence number.")
+                .takes_value(true)
+                .conflicts_with("time")
+                .required_unless("time")
+            )
+            .arg(Arg::with_name("time")
+                .long("time")
+                .help("Restore every write made until this UTC time, as 'YYYY-MM-DD HH:MM:SS'.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("out")
+                .long("out")
+                .help("Path of the new store directory, which must not exist or be empty.")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("keys")
+                .long("keys")
+                .help("Path of the key file of an encrypted store, whose current key also encrypts the new store.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("key-provider")
+                .long("key-provider")
+                .help("Where the keys come from: 'file' (--keys), 'env' (the CRABEDB_ENCRYPTION_KEY_<id> variables) or 'kms' (keys of --keys wrapped by AWS KMS). (default: file)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("renumber")
+            .about("Copy a store which is not in use into a new store whose data files are numbered from 1, once its file ids run out.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory. Its offloaded data files must be fetched first.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("out")
+                .long("out")
+                .help("Path of the new store directory, which must not exist or be empty.")
+                .takes_value(true)
+                .required(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("rewrap")
+            .about("Re-encrypt the sealed data files of a store which is not in use with the current key of its key file, see the encryption option.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("datadir")
+                .help("Path of the store directory.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("keys")
+                .long("keys")
+                .help("Path of the key file, holding the current key and those of the files to re-encrypt.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("key-provider")
+                .long("key-provider")
+                .help("Where the keys come from: 'file' (--keys), 'env' (the CRABEDB_ENCRYPTION_KEY_<id> variables) or 'kms' (keys of --keys wrapped by AWS KMS). (default: file)")
+                .takes_value(true)
+            )
+    )
+    .get_matches();
+
+    match matches.subcommand() {
+        ("verify", Some(verify_subcommand)) => {
+            let datadir = verify_subcommand.value_of("datadir").unwrap();
+            let files_only = verify_subcommand.is_present("files");
+            let report = if files_only {
+                verify_files(datadir)?
+            } else {
+                verify(datadir)?
+            };
+
+            for corruption in &report.corruptions {
+                println!("{}", corruption);
+            }
+            if files_only {
+                println!(
+                    "Verified {} sealed data files, skipped {} unsealed ones: {} corruption(s) found.",
+                    report.files,
+                    report.unsealed_files,
+                    report.corruptions.len()
+                );
+            } else {
+                println!(
+                    "Verified {} data files, {} records: {} corruption(s) found.",
+                    report.files,
+                    report.records,
+                    report.corruptions.len()
+                );
+            }
+
+            if !report.is_ok() {
+                process::exit(1);
+            }
+        },
+        ("check-consistency", Some(check_subcommand)) => {
+            let datadir = check_subcommand.value_of("datadir").unwrap();
+            let db = StorageOptions::default()
+                .create(false)
+                .compaction(false)
+                .read_only(true)
+                .load(datadir)?;
+            let report = db.check_consistency()?;
+
+            for inconsistency in &report.inconsistencies {
+                println!("{}", inconsistency);
+            }
+            println!(
+                "Checked {} index entries: {} inconsistency(ies) found.",
+                report.entries,
+                report.inconsistencies.len()
+            );
+
+            if !report.is_ok() {
+                process::exit(1);
+            }
+        },
+        ("compact", Some(compact_subcommand)) => {
+            let datadir = compact_subcommand.value_of("datadir").unwrap();
+            let (files_before, size_before) = data_files_usage(datadir)?;
+
+            let db = StorageOptions::default()
+                .create(false)
+                .sync(SyncOptions::Never)
+                .compaction(false)
+                .load(datadir)?;
+            db.full_compaction()?;
+            drop(db);
+
+            let (files_after, size_after) = data_files_usage(datadir)?;
+            println!(
+                "Compacted {} data files ({}) into {} data files ({}).",
+                files_before,
+                human_readable_byte_count(size_before as usize, true),
+                files_after,
+                human_readable_byte_count(size_after as usize, true)
+            );
+        },
+        ("export", Some(export_subcommand)) => {
+            let datadir = export_subcommand.value_of("datadir").unwrap();
+            let archive = export_subcommand.value_of("archive").unwrap();
+
+            let db = StorageOptions::default()
+                .create(false)
+                .sync(SyncOptions::Never)
+                .compaction(false)
+                .load(datadir)?;
+            let count = db.export(BufWriter::new(File::create(archive)?))?;
+            println!("Exported {} keys into {}.", count, archive);
+        },
+        ("import", Some(import_subcommand)) => {
+            let datadir = import_subcommand.value_of("datadir").unwrap();
+            let archive = import_subcommand.value_of("archive").unwrap();
+
+            let db = StorageOptions::default()
+                .sync(SyncOptions::Never)
+                .compaction(false)
+                .load(datadir)?;
+            let count = db.import(BufReader::new(File::open(archive)?))?;
+            println!("Imported {} keys from {}.", count, archive);
+        },
+        ("import-bitcask", Some(import_subcommand)) => {
+            let bitcaskdir = import_subcommand.value_of("bitcaskdir").unwrap();
+            let datadir = import_subcommand.value_of("datadir").unwrap();
+
+            let db = StorageOptions::default()
+                .sync(SyncOptions::Never)
+                .compaction(false)
+                .load(datadir)?;
+            let import = import_bitcask(&db, bitcaskdir)?;
+            drop(db);
+
+            // Replaying the history leaves superseded records behind, reopen the store so
+            // that no file is active anymore and the whole import can be merged.
+            StorageOptions::default()
+                .create(false)
+                .sync(SyncOptions::Never)
+                .compaction(false)
+                .load(datadir)?
+                .full_compaction()?;
+            println!(
+                "Imported {} records ({} tombstones) from {} Bitcask data files.",
+                import.records,
+                import.tombstones,
+                import.files
+            );
+        },
+        ("merge", Some(merge_subcommand)) => {
+            let datadir_a = merge_subcommand.value_of("datadir-a").unwrap();
+            let datadir_b = merge_subcommand.value_of("datadir-b").unwrap();
+            let out = merge_subcommand.value_of("out").unwrap();
+
+            let report = merge_stores(datadir_a, datadir_b, out)?;
+            println!(
+                "Merged {} and {} into {}: {} keys written, {} found in both stores, {} removed, {} tombstones dropped.",
+                datadir_a,
+                datadir_b,
+                out,
+                report.records,
+                report.duplicates,
+                report.removed,
+                report.tombstones
+            );
+        },
+        ("split", Some(split_subcommand)) => {
+            let datadir = split_subcommand.value_of("datadir").unwrap();
+            let shards = split_subcommand.value_of("shards").unwrap().parse::<usize>()?;
+            let pattern = split_subcommand.value_of("out").unwrap();
+            if shards == 0 || !pattern.contains("{}") {
+                return Err("at least one shard is needed, and the output pattern must contain {}".into());
+            }
+
+            let nodes: Vec<String> = match split_subcommand.value_of("nodes") {
+                Some(n) => n.split(',').filter(|addr| !addr.is_empty()).map(String::from).collect(),
+                None => (0..shards).map(|shard| format!("shard-{}", shard)).collect(),
+            };
+            if nodes.len() != shards {
+                return Err(format!("{} nodes for {} shards", nodes.len(), shards).into());
+            }
+            let mut ring = HashRing::default();
+            for node in &nodes {
+                ring.add_node(node);
+            }
+
+            let outs: Vec<String> = (0..shards).map(|shard| pattern.replace("{}", &shard.to_string())).collect();
+            let report = split_store(datadir, &outs, |key| {
+                let node = ring.node(key).unwrap();
+                nodes.iter().position(|n| n == node).unwrap()
+            })?;
+            for ((out, node), records) in outs.iter().zip(&nodes).zip(&report.records) {
+                println!("{} ({}): {} records", out, node, records);
+            }
+        },
+        ("restore", Some(restore_subcommand)) => {
+            let archive = restore_subcommand.value_of("archive").unwrap();
+            let datadir = restore_subcommand.value_of("datadir");
+            let out = restore_subcommand.value_of("out").unwrap();
+            let target = match restore_subcommand.value_of("seq") {
+                Some(seq) => RestoreTarget::Seq(seq.parse::<u64>()?),
+                None => {
+                    let until = restore_subcommand.value_of("time").unwrap();
+                    let tm = time::strptime(until, "%Y-%m-%d %H:%M:%S")
+                        .map_err(|err| format!("invalid time {:?}: {}", until, err))?;
+                    let seconds = tm.to_timespec().sec;
+                    if seconds < 0 {
+                        return Err(format!("invalid time {:?}: before the Unix epoch", until).into());
+                    }
+                    RestoreTarget::Time(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+                }
+            };
+
+            let report = restore(archive, datadir, out, target, keyring(restore_subcommand)?)?;
+            println!(
+                "Restored {} keys up to sequence number {} from {} data files into {}.",
+                report.records,
+                report.last_seq,
+                report.files,
+                out
+            );
+        },
+        ("renumber", Some(renumber_subcommand)) => {
+            let datadir = renumber_subcommand.value_of("datadir").unwrap();
+            let out = renumber_subcommand.value_of("out").unwrap();
+
+            let report = renumber(datadir, out)?;
+            println!(
+                "Renumbered the {} data files of {} into {}, the last id was {}.",
+                report.files,
+                datadir,
+                out,
+                report.last_file_id
+            );
+        },
+        ("rewrap", Some(rewrap_subcommand)) => {
+            let datadir = rewrap_subcommand.value_of("datadir").unwrap();
+            let keyring = keyring(rewrap_subcommand)?.ok_or("the keys are needed, see --keys")?;
+            let key_id = keyring.current_key_id()?;
+
+            let db = StorageOptions::default()
+                .create(false)
+                .sync(SyncOptions::Never)
+                .compaction(false)
+                .encryption(keyring)
+                .load(datadir)?;
+            let files = db.rewrap()?;
+            drop(db);
+
+            println!("Re-encrypted {} data files with key {}.", files.len(), key_id);
+        },
+        _ => {
+            println!("{}", matches.usage());
+        }
+    }
+
+    Ok(())
+}
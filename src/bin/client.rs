@@ -1,11 +1,30 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use futures_util::StreamExt;
 use log::{info, warn};
 use clap::{Arg, App, SubCommand};
-use protobuf::{GetRequest, SetRequest, RemoveRequest};
+use crabedb::client::crabe_client::CrabeClient;
+use crabedb::client::{protobuf, store_interceptor};
+use protobuf::{
+    Consistency, GetRequest, SetRequest, RemoveRequest, PauseCompactionRequest, ResumeCompactionRequest,
+    CompactionStatusRequest, GetStreamRequest, SetStreamRequest, ListKeysRequest, StatsRequest,
+    FileStatsRequest, CheckConsistencyRequest, PromoteRequest, LeaseGrantRequest, LeaseRevokeRequest, LeaseKeepAliveRequest,
+    LeaseInfoRequest, LockRequest, UnlockRequest, TtlRequest, CrdtOp, CrdtType, CrdtUpdateRequest, CrdtGetRequest,
+    BatchOp, BatchOpType, NotLeader, ClusterStatusRequest, AddNodeRequest, RemoveNodeRequest,
+    TransferLeadershipRequest, RebalanceRequest, RoutingTableRequest, MigrateRequest,
+};
+use protobuf::admin_client::AdminClient;
+use protobuf::cluster_client::ClusterClient;
 use protobuf::kvstore_client::KvstoreClient;
-pub mod protobuf {
-    tonic::include_proto!("kvstore");
-}
+use protobuf::lease_client::LeaseClient;
 use regex::Regex;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use serde::{Deserialize, Serialize};
+use tonic::transport::{Channel, Endpoint};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -29,6 +48,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .required(true)
         .index(1)
     )
+    .arg(Arg::with_name("store")
+        .long("store")
+        .help("Name of the store of the server the requests are routed to. (default: the store of its --dump directory)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("connect-timeout")
+        .long("connect-timeout")
+        .help("How long an attempt to connect to the server may take, e.g. 500ms or 5s. (default: 5s)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("request-timeout")
+        .long("request-timeout")
+        .help("How long a request may take, e.g. 500ms or 5s. (default: none)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("retries")
+        .long("retries")
+        .help("Number of times the connection, and the get, set, remove, list-keys and ttl requests, are tried again after a transport error, waiting twice as long each time. (default: 2)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("keepalive-interval")
+        .long("keepalive-interval")
+        .help("Interval between the HTTP/2 pings keeping the connection alive, e.g. 30s. (default: no pings)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("keepalive-timeout")
+        .long("keepalive-timeout")
+        .help("How long to wait for the answer to a ping before closing the connection. (default: 20s)")
+        .takes_value(true)
+    )
     .subcommand(
         SubCommand::with_name("get")
             .about("Get a the value of the given key from the remote server.")
@@ -39,6 +88,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .required(true)
                 .index(1)
             )
+            .arg(Arg::with_name("min-seq")
+                .long("min-seq")
+                .help("Sequence number of a previous write the value must include, e.g. to read it from a standby. (default: none)")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("metadata")
+                .long("metadata")
+                .help("Also print the sequence number, creation time, data file and size of the value.")
+            )
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("File the value is written to as raw bytes, instead of being printed.")
+                .conflicts_with_all(&["metadata", "min-seq", "consistency"])
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("consistency")
+                .long("consistency")
+                .help("Number of nodes of the cluster the request waits for: one, quorum or all. (default: one)")
+                .takes_value(true)
+            )
     )
     .subcommand(
         SubCommand::with_name("set")
@@ -52,9 +122,49 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             )
             .arg(Arg::with_name("value")
                 .help("The value associated with the key you want to set.")
-                .required(true)
+                .required_unless_one(&["value-option", "value-file"])
+                .conflicts_with_all(&["value-option", "value-file"])
                 .index(2)
             )
+            .arg(Arg::with_name("value-option")
+                .long("value")
+                .help("The value, or - to read it as raw bytes from the standard input.")
+                .conflicts_with("value-file")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+            )
+            .arg(Arg::with_name("value-file")
+                .long("value-file")
+                .help("File whose content, as raw bytes, is the value.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("lease")
+                .long("lease")
+                .help("Id of the lease the key is removed with. (default: none)")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("ttl")
+                .long("ttl")
+                .help("Time after which the key is removed, e.g. 500ms, 30s, 5m or 2h, a number alone being seconds. (default: none)")
+                .conflicts_with("lease")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("consistency")
+                .long("consistency")
+                .help("Number of nodes of the cluster the request waits for: one, quorum or all. (default: one)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("ttl")
+            .about("Show the remaining time to live of a key.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("key")
+                .help("The key whose time to live is shown.")
+                .required(true)
+                .index(1)
+            )
     )
     .subcommand(
         SubCommand::with_name("remove")
@@ -66,6 +176,408 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .required(true)
                 .index(1)
             )
+            .arg(Arg::with_name("consistency")
+                .long("consistency")
+                .help("Number of nodes of the cluster the request waits for: one, quorum or all. (default: one)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("list-keys")
+            .about("List the keys of the remote server, one page at a time.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("prefix")
+                .help("Only list the keys starting with this prefix. (default: every key)")
+                .index(1)
+            )
+            .arg(Arg::with_name("cursor")
+                .long("cursor")
+                .help("The cursor printed after the previous page, hex-encoded. (default: first page)")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("limit")
+                .long("limit")
+                .help("Maximum number of keys of the page, 0 lets the server choose. (default: 0)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("scan")
+            .about("Print the keys of the remote server along with their values, ordered by key, as they are received.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("prefix")
+                .help("Only print the keys starting with this prefix. (default: every key)")
+                .index(1)
+            )
+            .arg(Arg::with_name("limit")
+                .long("limit")
+                .help("Maximum number of pairs printed, 0 for all of them. (default: 0)")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .help("Format of the pairs: 'tsv' (a tab between the key and the value) or 'json' (an object per line). (default: tsv)")
+                .possible_values(&["tsv", "json"])
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("watch")
+            .about("Print the changes to the keys starting with a prefix as they happen, until interrupted.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("prefix")
+                .help("Only watch the keys starting with this prefix. (default: every key)")
+                .index(1)
+            )
+            .arg(Arg::with_name("start-seq")
+                .long("start-seq")
+                .help("Replay the changes from this sequence number on, 0 to only watch the changes to come. (default: 0)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("get-stream")
+            .about("Get a large value from the remote server, streamed in chunks.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("key")
+                .help("The key you want to get.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("File the value is written to. (default: standard output)")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("chunk-size")
+                .long("chunk-size")
+                .help("Size in bytes of the chunks, 0 lets the server choose. (default: 0)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("set-stream")
+            .about("Set a large value, read from a file, in the remote server by streaming it in chunks.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("key")
+                .help("The name of the key you want to set.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("file")
+                .help("The file holding the value associated with the key.")
+                .required(true)
+                .index(2)
+            )
+            .arg(Arg::with_name("chunk-size")
+                .long("chunk-size")
+                .help("Size in bytes of the chunks. (default: 1048576) => 1MB")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("pause-compaction")
+            .about("Stop the compaction of the remote server, once the one in progress is finished.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+    )
+    .subcommand(
+        SubCommand::with_name("resume-compaction")
+            .about("Resume the compaction of the remote server.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+    )
+    .subcommand(
+        SubCommand::with_name("compaction-status")
+            .about("Show the progress of the current (or last) compaction of the remote server.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+    )
+    .subcommand(
+        SubCommand::with_name("stats")
+            .about("Show the number of keys, the size of a range of keys and the descriptor cache counters of the remote server.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("start")
+                .help("First key of the range whose size is computed. (default: the whole store)")
+                .index(1)
+            )
+            .arg(Arg::with_name("end")
+                .help("End of the range, excluded. (default: no upper bound)")
+                .index(2)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("file-stats")
+            .about("Show the live and dead entries of every data file of the remote server.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+    )
+    .subcommand(
+        SubCommand::with_name("check-consistency")
+            .about("Check every index entry of the remote server against the record it points to.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+    )
+    .subcommand(
+        SubCommand::with_name("promote")
+            .about("Turn the remote standby server into a primary accepting writes.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+    )
+    .subcommand(
+        SubCommand::with_name("lock")
+            .about("Acquire a named lock, released when the lease expires.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("name")
+                .help("The name of the lock.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("lease")
+                .long("lease")
+                .help("Id of the lease holding the lock.")
+                .required(true)
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("timeout")
+                .long("timeout")
+                .help("How long to wait for the lock, in milliseconds. (default: 0) => a single try")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("token")
+                .long("token")
+                .help("Token of the lock already held by the lease, to acquire it again. (default: none)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("unlock")
+            .about("Release a named lock held by the lease.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("name")
+                .help("The name of the lock.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("lease")
+                .long("lease")
+                .help("Id of the lease holding the lock.")
+                .required(true)
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("token")
+                .long("token")
+                .help("Token returned when the lock was acquired.")
+                .required(true)
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("crdt-update")
+            .about("Update a CRDT value, merged with the versions written concurrently on the other nodes of the cluster.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("key")
+                .help("The key of the value.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("op")
+                .help("assign (a register), increment (a counter), add or remove (an element of a set).")
+                .required(true)
+                .possible_values(&["assign", "increment", "add", "remove"])
+                .index(2)
+            )
+            .arg(Arg::with_name("value")
+                .help("The value assigned, the element added or removed, or the increment. (default: 1 for an increment)")
+                .index(3)
+            )
+            .arg(Arg::with_name("consistency")
+                .long("consistency")
+                .help("Number of nodes of the cluster the request waits for: one, quorum or all. (default: one)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("crdt-get")
+            .about("Get a CRDT value.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("key")
+                .help("The key of the value.")
+                .required(true)
+                .index(1)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("lease-grant")
+            .about("Grant a lease, which removes the keys set with it once it isn't kept alive.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("ttl")
+                .help("Time to live of the lease, in seconds.")
+                .required(true)
+                .index(1)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("lease-revoke")
+            .about("Revoke a lease, removing the keys set with it.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("id")
+                .help("Id of the lease.")
+                .required(true)
+                .index(1)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("lease-keep-alive")
+            .about("Keep a lease alive until interrupted.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("id")
+                .help("Id of the lease.")
+                .required(true)
+                .index(1)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("lease-info")
+            .about("Show the time to live and the keys of a lease.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("id")
+                .help("Id of the lease.")
+                .required(true)
+                .index(1)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("import")
+            .about("Set the key/value pairs of a file of newline-delimited JSON objects ({\"key\": ..., \"value\": ...}) or CSV (a key,value header, then a pair per line).")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("file")
+                .help("The file of pairs, - for the standard input.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("format")
+                .long("format")
+                .help("Format of the file: 'json' or 'csv'. (default: json)")
+                .possible_values(&["json", "csv"])
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("batch-size")
+                .long("batch-size")
+                .help("Number of pairs read from the file and sent before the progress is reported. (default: 1000)")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("concurrency")
+                .long("concurrency")
+                .help("Maximum number of batched requests a batch of pairs is split into, sent at the same time. (default: 8)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("export")
+            .about("Write the key/value pairs of the remote server to a file of newline-delimited JSON objects or CSV, ordered by key.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .arg(Arg::with_name("file")
+                .help("The file written, - for the standard output.")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("prefix")
+                .long("prefix")
+                .help("Only export the keys starting with this prefix. (default: every key)")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("format")
+                .long("format")
+                .help("Format of the file: 'json' or 'csv'. (default: json)")
+                .possible_values(&["json", "csv"])
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("batch-size")
+                .long("batch-size")
+                .help("Number of pairs written between two progress reports. (default: 1000)")
+                .takes_value(true)
+            )
+    )
+    .subcommand(
+        SubCommand::with_name("repl")
+            .about("Run an interactive shell (get, set, del, scan, stats) over a single connection, with a history.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+    )
+    .subcommand(
+        SubCommand::with_name("cluster")
+            .about("Administrate the multi-node deployment of the remote server.")
+            .version("0.1.0")
+            .author("Gabriel Mougard <gabriel.mougard@gmail.com>")
+            .subcommand(
+                SubCommand::with_name("status")
+                    .about("Show the role, term and last sequence number of the server and of the other nodes of its failover group or its peers.")
+            )
+            .subcommand(
+                SubCommand::with_name("add-node")
+                    .about("Add a node to the failover group, through its primary.")
+                    .arg(Arg::with_name("address")
+                        .help("Address (<ip>:<port>) of the node.")
+                        .required(true)
+                        .index(1)
+                    )
+            )
+            .subcommand(
+                SubCommand::with_name("remove-node")
+                    .about("Remove a node from the failover group, through its primary.")
+                    .arg(Arg::with_name("address")
+                        .help("Address (<ip>:<port>) of the node.")
+                        .required(true)
+                        .index(1)
+                    )
+            )
+            .subcommand(
+                SubCommand::with_name("transfer-leadership")
+                    .about("Make another node of the failover group its primary, once it has caught up.")
+                    .arg(Arg::with_name("address")
+                        .help("Address (<ip>:<port>) of the node.")
+                        .required(true)
+                        .index(1)
+                    )
+            )
+            .subcommand(
+                SubCommand::with_name("rebalance")
+                    .about("Move the keys of the remote server and of the nodes of a sharded deployment to the node their ring maps them to.")
+                    .arg(Arg::with_name("nodes")
+                        .long("nodes")
+                        .help("Comma-separated addresses (<ip>:<port>) of the nodes of the ring, as given to the sharded clients.")
+                        .required(true)
+                        .takes_value(true)
+                    )
+            )
+            .subcommand(
+                SubCommand::with_name("migrate")
+                    .about("Move the keys of a sharded deployment to the nodes of a new routing table while they are served, then switch every node to it.")
+                    .arg(Arg::with_name("nodes")
+                        .long("nodes")
+                        .help("Comma-separated addresses (<ip>:<port>) of the nodes of the new routing table, as the nodes know each other.")
+                        .required(true)
+                        .takes_value(true)
+                    )
+            )
     )
     .get_matches();
 
@@ -84,66 +596,1016 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
     };
 
-    let mut tx = KvstoreClient::connect(format!("http://{}", node_addr)).await?;
+    let connect_timeout = match matches.value_of("connect-timeout") {
+        Some(t) => parse_duration(t)?,
+        None => DEFAULT_CONNECT_TIMEOUT,
+    };
+    let retries = match matches.value_of("retries") {
+        Some(r) => {
+            r.parse::<u32>().unwrap_or(DEFAULT_RETRIES)
+        },
+        None => DEFAULT_RETRIES,
+    };
+    let mut endpoint = Endpoint::from_shared(format!("http://{}", node_addr))?;
+    if let Some(timeout) = matches.value_of("request-timeout") {
+        endpoint = endpoint.timeout(parse_duration(timeout)?);
+    }
+    if let Some(interval) = matches.value_of("keepalive-interval") {
+        endpoint = endpoint
+            .http2_keep_alive_interval(parse_duration(interval)?)
+            .keep_alive_while_idle(true);
+    }
+    if let Some(timeout) = matches.value_of("keepalive-timeout") {
+        endpoint = endpoint.keep_alive_timeout(parse_duration(timeout)?);
+    }
+    let channel = connect(&endpoint, connect_timeout, retries).await?;
+    let store = matches.value_of("store").unwrap_or("");
+    let mut tx = KvstoreClient::with_interceptor(channel.clone(), store_interceptor(store)?);
+    let mut admin = AdminClient::with_interceptor(channel.clone(), store_interceptor(store)?);
+    let mut leases = LeaseClient::with_interceptor(channel.clone(), store_interceptor(store)?);
+    let cluster = ClusterClient::new(channel.clone());
+    let client = CrabeClient::with_store(channel, store)?;
     info!("Target node address is: {:?}", node_addr);
 
     match matches.subcommand() {
         ("get", Some(get_subcommand)) => {
-            match get_subcommand.value_of("key") {
-                Some(key) => {
-                    let request = tonic::Request::new(GetRequest {
-                        key: String::from(key),
-                    });
-                    let response = tx.kv_get_call(request).await?;
-                    if response.get_ref().exist {
-                        info!("Retrieved value: {:?} for Key: {:?}", response.get_ref().value, key);
-                    } else {
-                        warn!("Key: {:?} doesn't exist.", key);
+            if let (Some(key), Some(path)) = (get_subcommand.value_of("key"), get_subcommand.value_of("output")) {
+                // The chunks of KvGetStreamCall hold raw bytes, unlike the value of a GetResponse.
+                let request = tonic::Request::new(GetStreamRequest {
+                    key: String::from(key),
+                    chunk_size: 0,
+                });
+                let mut stream = tx.kv_get_stream_call(request).await?.into_inner();
+                let mut output: Option<File> = None;
+                while let Some(chunk) = stream.message().await? {
+                    if !chunk.exist {
+                        break;
                     }
-                },
-                None => {}
+                    if output.is_none() {
+                        output = Some(File::create(path)?);
+                    }
+                    output.as_mut().unwrap().write_all(&chunk.data)?;
+                }
+                match output {
+                    Some(mut output) => {
+                        output.flush()?;
+                        info!("Value of Key: {:?} has been written to {:?}", key, path);
+                    },
+                    None => warn!("Key: {:?} doesn't exist.", key),
+                }
+            } else if let Some(key) = get_subcommand.value_of("key") {
+                let min_seq = match get_subcommand.value_of("min-seq") {
+                    Some(ms) => {
+                        ms.parse::<u64>().unwrap_or(0)
+                    },
+                    None => 0,
+                };
+                let request = GetRequest {
+                    key: String::from(key),
+                    min_seq,
+                    with_metadata: get_subcommand.is_present("metadata"),
+                    consistency: consistency(get_subcommand.value_of("consistency")),
+                };
+                let response = with_retries(retries, &tx, &request, |mut tx, request| async move { tx.kv_get_call(request).await }).await?;
+                if response.get_ref().exist {
+                    info!("Retrieved value: {:?} for Key: {:?}", response.get_ref().value, key);
+                    if let Some(ref metadata) = response.get_ref().metadata {
+                        info!(
+                            "Sequence number: {}, created at: {} ms since epoch, file: {}, size: {} bytes",
+                            metadata.seq,
+                            metadata.timestamp,
+                            metadata.file_id,
+                            metadata.size
+                        );
+                    }
+                } else {
+                    warn!("Key: {:?} doesn't exist.", key);
+                }
             }
         },
         ("set", Some(set_subcommand)) => {
-            match set_subcommand.value_of("key") {
-                Some(key) => {
-                    match set_subcommand.value_of("value") {
-                        Some(value) => {
-                            let request = tonic::Request::new(SetRequest {
-                                key: String::from(key),
-                                value: String::from(value),
-                            });
-                            let response = tx.kv_set_call(request).await?;
-                            if response.get_ref().success {
-                                info!("Key: {:?} has been successfully set with Value: {:?}", key, value);
-                            } else {
-                                warn!("Key: {:?} couldn't be set.", key);
+            if let Some(key) = set_subcommand.value_of("key") {
+                let value = match (set_subcommand.value_of("value-option"), set_subcommand.value_of("value-file")) {
+                    (Some("-"), _) => {
+                        let mut value = Vec::new();
+                        io::stdin().read_to_end(&mut value)?;
+                        value
+                    },
+                    (Some(value), _) => value.as_bytes().to_vec(),
+                    (None, Some(path)) => std::fs::read(path)?,
+                    (None, None) => set_subcommand.value_of("value").unwrap_or_default().as_bytes().to_vec(),
+                };
+                let lease = match set_subcommand.value_of("lease") {
+                    Some(l) => {
+                        l.parse::<u64>().unwrap_or(0)
+                    },
+                    None => 0,
+                };
+                let ttl_ms = match set_subcommand.value_of("ttl") {
+                    Some(ttl) => parse_duration(ttl)?.as_millis() as u64,
+                    None => 0,
+                };
+                let value = match String::from_utf8(value) {
+                    Ok(value) => value,
+                    // A SetRequest only carries text, unlike the chunks of KvSetStreamCall.
+                    Err(err) => {
+                        if lease > 0 || ttl_ms > 0 || set_subcommand.is_present("consistency") {
+                            return Err("a binary value can't be set with --lease, --ttl or --consistency".into());
+                        }
+                        let request = SetStreamRequest {
+                            key: String::from(key),
+                            data: err.into_bytes(),
+                            replica_seq: 0,
+                            total_size: 0,
+                        };
+                        let size = request.data.len();
+                        let response = tx.kv_set_stream_call(tokio_stream::iter(vec![request])).await?;
+                        if response.get_ref().success {
+                            info!("Key: {:?} has been successfully set with a binary value of {} bytes (sequence number: {})", key, size, response.get_ref().seq);
+                        } else {
+                            warn!("Key: {:?} couldn't be set.", key);
+                        }
+                        return Ok(());
+                    },
+                };
+                let request = SetRequest {
+                    key: String::from(key),
+                    value: value.clone(),
+                    lease,
+                    consistency: consistency(set_subcommand.value_of("consistency")),
+                    replica_seq: 0,
+                    ttl_ms,
+                };
+                let response = with_retries(retries, &tx, &request, |mut tx, request| async move { tx.kv_set_call(request).await }).await?;
+                if response.get_ref().success {
+                    info!("Key: {:?} has been successfully set with Value: {:?} (sequence number: {})", key, value, response.get_ref().seq);
+                } else {
+                    warn!("Key: {:?} couldn't be set.", key);
+                }
+            }
+        },
+        ("ttl", Some(ttl_subcommand)) => {
+            if let Some(key) = ttl_subcommand.value_of("key") {
+                let request = TtlRequest { key: String::from(key) };
+                let response = with_retries(retries, &tx, &request, |mut tx, request| async move { tx.kv_ttl_call(request).await }).await?.into_inner();
+                if !response.exist {
+                    warn!("Key: {:?} doesn't exist.", key);
+                } else if response.ttl_ms == 0 {
+                    info!("Key: {:?} has no time to live.", key);
+                } else if response.lease == 0 {
+                    info!(
+                        "Key: {:?} is removed in {}ms (time to live: {}ms)",
+                        key,
+                        response.remaining_ms,
+                        response.ttl_ms
+                    );
+                } else {
+                    info!(
+                        "Key: {:?} is removed in {}ms (time to live: {}ms, lease: {})",
+                        key,
+                        response.remaining_ms,
+                        response.ttl_ms,
+                        response.lease
+                    );
+                }
+            }
+        },
+        ("remove", Some(remove_subcommand)) => {
+            if let Some(key) = remove_subcommand.value_of("key") {
+                let request = RemoveRequest {
+                    key: String::from(key),
+                    consistency: consistency(remove_subcommand.value_of("consistency")),
+                    replica_seq: 0,
+                };
+                let response = with_retries(retries, &tx, &request, |mut tx, request| async move { tx.kv_remove_call(request).await }).await?;
+                if response.get_ref().success {
+                    info!("Key: {:?} has been successfully removed with its value (sequence number: {}).", key, response.get_ref().seq);
+                } else {
+                    warn!("Key: {:?} couldn't be removed.", key);
+                }
+            }
+        },
+        ("list-keys", Some(list_subcommand)) => {
+            let limit = match list_subcommand.value_of("limit") {
+                Some(l) => {
+                    l.parse::<u32>().unwrap_or(0)
+                },
+                None => 0,
+            };
+            // The cursor is the last key of the previous page, which may not be valid UTF-8.
+            let cursor = hex::decode(list_subcommand.value_of("cursor").unwrap_or(""))
+                .map_err(|_| "the cursor isn't hex-encoded")?;
+            let request = ListKeysRequest {
+                prefix: String::from(list_subcommand.value_of("prefix").unwrap_or("")),
+                cursor,
+                limit,
+            };
+            let response = with_retries(retries, &tx, &request, |mut tx, request| async move { tx.kv_list_keys_call(request).await }).await?.into_inner();
+            for key in response.keys {
+                println!("{}", key);
+            }
+            if !response.next_cursor.is_empty() {
+                println!("next cursor: {}", hex::encode(&response.next_cursor));
+            }
+        },
+        ("scan", Some(scan_subcommand)) => {
+            let limit = match scan_subcommand.value_of("limit") {
+                Some(l) => {
+                    l.parse::<u64>().unwrap_or(0)
+                },
+                None => 0,
+            };
+            let json = scan_subcommand.value_of("output") == Some("json");
+            let prefix = scan_subcommand.value_of("prefix").unwrap_or("");
+            let mut stream = client.scan(prefix, limit).await?;
+            let stdout = io::stdout();
+            let mut output = stdout.lock();
+            while let Some(pair) = stream.message().await? {
+                if json {
+                    writeln!(output, "{}", serde_json::json!({ "key": pair.key, "value": pair.value }))?;
+                } else {
+                    writeln!(output, "{}\t{}", tsv_escape(&pair.key), tsv_escape(&pair.value))?;
+                }
+            }
+            output.flush()?;
+        },
+        ("watch", Some(watch_subcommand)) => {
+            let start_seq = match watch_subcommand.value_of("start-seq") {
+                Some(seq) => {
+                    seq.parse::<u64>().unwrap_or(0)
+                },
+                None => 0,
+            };
+            let prefix = watch_subcommand.value_of("prefix").unwrap_or("");
+            let mut stream = client.watch(prefix, start_seq).await?;
+            while let Some(event) = stream.message().await? {
+                if event.range {
+                    // An empty end means no upper bound.
+                    println!("{}\tDELETE RANGE\t{:?}\t{:?}", event.seq, event.key, event.range_end);
+                } else if event.deleted {
+                    println!("{}\tDELETE\t{:?}", event.seq, event.key);
+                } else {
+                    println!("{}\tPUT\t{:?}\t{:?}", event.seq, event.key, event.value);
+                }
+            }
+        },
+        ("get-stream", Some(get_subcommand)) => {
+            if let Some(key) = get_subcommand.value_of("key") {
+                let chunk_size = match get_subcommand.value_of("chunk-size") {
+                    Some(cs) => {
+                        cs.parse::<u32>().unwrap_or(0)
+                    },
+                    None => 0,
+                };
+                let request = tonic::Request::new(GetStreamRequest {
+                    key: String::from(key),
+                    chunk_size,
+                });
+                let mut stream = tx.kv_get_stream_call(request).await?.into_inner();
+                let mut output: Box<dyn Write> = match get_subcommand.value_of("output") {
+                    Some(path) => Box::new(File::create(path)?),
+                    None => Box::new(io::stdout()),
+                };
+                while let Some(chunk) = stream.message().await? {
+                    if !chunk.exist {
+                        warn!("Key: {:?} doesn't exist.", key);
+                        break;
+                    }
+                    output.write_all(&chunk.data)?;
+                }
+                output.flush()?;
+            }
+        },
+        ("set-stream", Some(set_subcommand)) => {
+            if let (Some(key), Some(path)) = (set_subcommand.value_of("key"), set_subcommand.value_of("file")) {
+                let chunk_size = match set_subcommand.value_of("chunk-size") {
+                    Some(cs) => {
+                        cs.parse::<usize>().unwrap_or(1024 * 1024)
+                    },
+                    None => 1024 * 1024,
+                };
+                let mut file = File::open(path)?;
+                let (key, path) = (String::from(key), String::from(path));
+                // The file is read one chunk at a time, as the messages are sent.
+                let messages = async_stream::stream! {
+                    let mut key = Some(key);
+                    loop {
+                        let mut data = vec![0; chunk_size.max(1)];
+                        let n = match file.read(&mut data) {
+                            Ok(n) => n,
+                            Err(err) => {
+                                warn!("Couldn't read {:?}: {}", path, err);
+                                break;
                             }
-                        },
-                        None => {}
+                        };
+                        if n == 0 && key.is_none() {
+                            break;
+                        }
+                        data.truncate(n);
+                        yield SetStreamRequest {
+                            key: key.take().unwrap_or_default(),
+                            data,
+                            replica_seq: 0,
+                            total_size: 0,
+                        };
                     }
-                },
-                None => {}
+                };
+                let response = tx.kv_set_stream_call(messages).await?;
+                let key = set_subcommand.value_of("key").unwrap();
+                if response.get_ref().success {
+                    info!("Key: {:?} has been successfully set with the content of {:?} (sequence number: {})", key, set_subcommand.value_of("file").unwrap(), response.get_ref().seq);
+                } else {
+                    warn!("Key: {:?} couldn't be set.", key);
+                }
             }
         },
-        ("remove", Some(remove_subcommand)) => {
-            match remove_subcommand.value_of("key") {
-                Some(key) => {
-                    let request = tonic::Request::new(RemoveRequest {
-                        key: String::from(key),
+        ("pause-compaction", Some(_)) => {
+            admin.pause_compaction(PauseCompactionRequest {}).await?;
+            info!("Compaction has been paused.");
+        },
+        ("resume-compaction", Some(_)) => {
+            admin.resume_compaction(ResumeCompactionRequest {}).await?;
+            info!("Compaction has been resumed.");
+        },
+        ("compaction-status", Some(_)) => {
+            let status = admin.compaction_status(CompactionStatusRequest {}).await?.into_inner();
+            println!(
+                "paused: {}, running: {}, files: {:?}, records processed: {}, bytes written: {}, \
+                started at: {}, finished at: {}",
+                status.paused,
+                status.running,
+                status.files,
+                status.records_processed,
+                status.bytes_written,
+                status.started_at,
+                status.finished_at
+            );
+        },
+        ("import", Some(import_subcommand)) => {
+            if let Some(path) = import_subcommand.value_of("file") {
+                let batch_size = match import_subcommand.value_of("batch-size") {
+                    Some(bs) => {
+                        bs.parse::<usize>().unwrap_or(1000)
+                    },
+                    None => 1000,
+                };
+                let concurrency = match import_subcommand.value_of("concurrency") {
+                    Some(c) => {
+                        c.parse::<usize>().unwrap_or(8)
+                    },
+                    None => 8,
+                };
+                let input: Box<dyn Read> = match path {
+                    "-" => Box::new(io::stdin()),
+                    path => Box::new(File::open(path)?),
+                };
+                let csv = import_subcommand.value_of("format") == Some("csv");
+                let imported = import(&client, input, csv, batch_size.max(1), concurrency.max(1)).await?;
+                info!("{} pairs have been imported from {:?}", imported, path);
+            }
+        },
+        ("export", Some(export_subcommand)) => {
+            if let Some(path) = export_subcommand.value_of("file") {
+                let batch_size = match export_subcommand.value_of("batch-size") {
+                    Some(bs) => {
+                        bs.parse::<u64>().unwrap_or(1000)
+                    },
+                    None => 1000,
+                };
+                let output: Box<dyn Write> = match path {
+                    "-" => Box::new(io::stdout()),
+                    path => Box::new(File::create(path)?),
+                };
+                let prefix = export_subcommand.value_of("prefix").unwrap_or("");
+                let csv = export_subcommand.value_of("format") == Some("csv");
+                let exported = export(&client, output, prefix, csv, batch_size.max(1)).await?;
+                info!("{} pairs have been exported to {:?}", exported, path);
+            }
+        },
+        ("repl", Some(_)) => {
+            repl(node_addr, &client, &mut admin).await?;
+        },
+        ("cluster", Some(cluster_subcommand)) => {
+            match cluster_subcommand.subcommand() {
+                ("status", Some(_)) => {
+                    let status = cluster.clone().cluster_status(ClusterStatusRequest {}).await?.into_inner();
+                    for node in status.nodes {
+                        if node.reachable {
+                            println!(
+                                "{}\t{}\tterm: {}\tprimary: {}\tlast sequence number: {}\tkeys: {}",
+                                node.address,
+                                node.role,
+                                node.term,
+                                if node.leader.is_empty() { "-" } else { &node.leader },
+                                node.last_seq,
+                                node.keys
+                            );
+                        } else {
+                            println!("{}\tunreachable", node.address);
+                        }
+                    }
+                },
+                ("add-node", Some(add_subcommand)) => {
+                    let request = AddNodeRequest { address: String::from(add_subcommand.value_of("address").unwrap()) };
+                    let response = on_primary(&cluster, &request, |mut cluster, request| async move {
+                        cluster.add_node(request).await
+                    }).await?;
+                    info!("{} added, members of the failover group: {:?}", request.address, response.members);
+                },
+                ("remove-node", Some(remove_subcommand)) => {
+                    let request = RemoveNodeRequest { address: String::from(remove_subcommand.value_of("address").unwrap()) };
+                    let response = on_primary(&cluster, &request, |mut cluster, request| async move {
+                        cluster.remove_node(request).await
+                    }).await?;
+                    info!("{} removed, members of the failover group: {:?}", request.address, response.members);
+                },
+                ("transfer-leadership", Some(transfer_subcommand)) => {
+                    let request = TransferLeadershipRequest { address: String::from(transfer_subcommand.value_of("address").unwrap()) };
+                    let response = on_primary(&cluster, &request, |mut cluster, request| async move {
+                        cluster.transfer_leadership(request).await
+                    }).await?;
+                    info!("{} is the primary of term {}", request.address, response.term);
+                },
+                ("rebalance", Some(rebalance_subcommand)) => {
+                    let nodes: Vec<String> = rebalance_subcommand.value_of("nodes").unwrap()
+                        .split(',')
+                        .filter(|node| !node.is_empty())
+                        .map(String::from)
+                        .collect();
+                    // The remote server is rebalanced too, e.g. when it leaves the ring.
+                    let mut targets = nodes.clone();
+                    if !targets.iter().any(|node| node == node_addr) {
+                        targets.push(node_addr.to_string());
+                    }
+                    for target in targets {
+                        let mut cluster = ClusterClient::connect(format!("http://{}", target)).await?;
+                        let response = cluster.rebalance(RebalanceRequest {
+                            nodes: nodes.clone(),
+                            address: target.clone(),
+                        }).await?.into_inner();
+                        info!(
+                            "{}: {} keys moved, {} already on their node and {} skipped",
+                            target,
+                            response.moved,
+                            response.dropped,
+                            response.skipped
+                        );
+                    }
+                },
+                ("migrate", Some(migrate_subcommand)) => {
+                    let nodes: Vec<String> = migrate_subcommand.value_of("nodes").unwrap()
+                        .split(',')
+                        .filter(|node| !node.is_empty())
+                        .map(String::from)
+                        .collect();
+                    let current = cluster.clone().get_routing_table(RoutingTableRequest {}).await?.into_inner();
+                    let version = current.version + 1;
+                    // The nodes of both tables move their keys at the same time, each switching
+                    // once its keys are moved.
+                    let mut targets = current.nodes.clone();
+                    targets.extend(nodes.iter().cloned());
+                    if !targets.iter().any(|node| node == node_addr) {
+                        targets.push(node_addr.to_string());
+                    }
+                    targets.sort();
+                    targets.dedup();
+                    let migrations = targets.iter().map(|target| {
+                        let request = MigrateRequest { nodes: nodes.clone(), version, current: Some(current.clone()) };
+                        async move {
+                            let mut cluster = ClusterClient::connect(format!("http://{}", target)).await?;
+                            let response = cluster.migrate(request).await?.into_inner();
+                            Ok::<_, Box<dyn std::error::Error>>(response)
+                        }
                     });
-                    let response = tx.kv_remove_call(request).await?;
-                    if response.get_ref().success {
-                        info!("Key: {:?} has been successfully removed with its value.", key);
-                    } else {
-                        warn!("Key: {:?} couldn't be removed.", key);
+                    let mut failed = false;
+                    for (target, result) in targets.iter().zip(join_all(migrations).await) {
+                        match result {
+                            Ok(response) => info!(
+                                "{}: {} records moved, {} keys removed",
+                                target,
+                                response.moved,
+                                response.removed
+                            ),
+                            Err(err) => {
+                                warn!("{}: the migration failed: {}", target, err);
+                                failed = true;
+                            }
+                        }
                     }
+                    if failed {
+                        return Err(format!("the migration to version {} of the routing table failed", version).into());
+                    }
+                    info!("Switched to version {} of the routing table: {:?}", version, nodes);
                 },
-                None => {}
+                _ => {}
+            }
+        },
+        ("stats", Some(stats_subcommand)) => {
+            let stats = admin.stats(StatsRequest {
+                start: String::from(stats_subcommand.value_of("start").unwrap_or("")),
+                end: String::from(stats_subcommand.value_of("end").unwrap_or("")),
+            }).await?.into_inner();
+            println!(
+                "approximate key count: {}, approximate size: {} bytes, descriptor cache hits: {}, \
+                misses: {}, evictions: {}, index memory: {} bytes",
+                stats.approximate_key_count,
+                stats.approximate_size,
+                stats.descriptor_cache_hits,
+                stats.descriptor_cache_misses,
+                stats.descriptor_cache_evictions,
+                stats.index_memory
+            );
+        },
+        ("file-stats", Some(_)) => {
+            let response = admin.file_stats(FileStatsRequest {}).await?.into_inner();
+            for file in response.files {
+                println!(
+                    "file: {}{}{}, entries: {}, dead entries: {}, dead bytes: {}, size: {} bytes, \
+                    fragmentation: {:.2}",
+                    file.file_id,
+                    if file.active { " (active)" } else { "" },
+                    if file.remote { " (remote)" } else { "" },
+                    file.entries,
+                    file.dead_entries,
+                    file.dead_bytes,
+                    file.size,
+                    file.fragmentation
+                );
+            }
+        },
+        ("check-consistency", Some(_)) => {
+            let response = admin.check_consistency(CheckConsistencyRequest {}).await?.into_inner();
+            for inconsistency in &response.inconsistencies {
+                println!("{}", inconsistency);
+            }
+            println!(
+                "Checked {} index entries: {} inconsistency(ies) found.",
+                response.entries,
+                response.inconsistencies.len()
+            );
+        },
+        ("promote", Some(_)) => {
+            let response = admin.promote(PromoteRequest {}).await?.into_inner();
+            info!("Standby has been promoted, next sequence number: {}", response.next_seq);
+        },
+        ("lock", Some(lock_subcommand)) => {
+            if let (Some(name), Some(lease)) = (lock_subcommand.value_of("name"), lock_subcommand.value_of("lease")) {
+                let timeout_ms = match lock_subcommand.value_of("timeout") {
+                    Some(t) => {
+                        t.parse::<u64>().unwrap_or(0)
+                    },
+                    None => 0,
+                };
+                let request = tonic::Request::new(LockRequest {
+                    name: String::from(name),
+                    lease: lease.parse::<u64>()?,
+                    timeout_ms,
+                    token: lock_subcommand.value_of("token").unwrap_or_default().to_string(),
+                    lease_ttl_ms: 0,
+                });
+                let response = tx.kv_lock_call(request).await?.into_inner();
+                if response.acquired {
+                    info!(
+                        "Lock {:?} acquired (key: {:?}, sequence number: {}, token: {})",
+                        name, response.key, response.seq, response.token
+                    );
+                } else {
+                    warn!("Lock {:?} is held by lease {}.", name, response.owner);
+                }
+            }
+        },
+        ("unlock", Some(unlock_subcommand)) => {
+            if let (Some(name), Some(lease)) = (unlock_subcommand.value_of("name"), unlock_subcommand.value_of("lease")) {
+                let request = tonic::Request::new(UnlockRequest {
+                    name: String::from(name),
+                    lease: lease.parse::<u64>()?,
+                    token: unlock_subcommand.value_of("token").unwrap_or_default().to_string(),
+                });
+                let response = tx.kv_unlock_call(request).await?.into_inner();
+                if response.released {
+                    info!("Lock {:?} released (sequence number: {})", name, response.seq);
+                } else {
+                    warn!("Lock {:?} isn't held by lease {} with this token.", name, lease);
+                }
+            }
+        },
+        ("crdt-update", Some(crdt_subcommand)) => {
+            if let (Some(key), Some(op)) = (crdt_subcommand.value_of("key"), crdt_subcommand.value_of("op")) {
+                let value = crdt_subcommand.value_of("value").unwrap_or_default();
+                let (op, increment) = match op {
+                    "assign" => (CrdtOp::Assign, 0),
+                    "increment" => {
+                        (CrdtOp::Increment, if value.is_empty() { 1 } else { value.parse::<u64>()? })
+                    },
+                    "add" => (CrdtOp::Add, 0),
+                    _ => (CrdtOp::Remove, 0),
+                };
+                let request = tonic::Request::new(CrdtUpdateRequest {
+                    key: String::from(key),
+                    op: op as i32,
+                    value: String::from(value),
+                    increment,
+                    consistency: consistency(crdt_subcommand.value_of("consistency")),
+                });
+                let response = tx.kv_crdt_update_call(request).await?.into_inner();
+                info!("Key: {:?} has been updated to {} (sequence number: {})", key, crdt_display(&response), response.seq);
+            }
+        },
+        ("crdt-get", Some(crdt_subcommand)) => {
+            if let Some(key) = crdt_subcommand.value_of("key") {
+                let request = tonic::Request::new(CrdtGetRequest { key: String::from(key) });
+                let response = tx.kv_crdt_get_call(request).await?.into_inner();
+                if response.exist {
+                    info!("Retrieved value: {} for Key: {:?}", crdt_display(&response), key);
+                } else {
+                    warn!("Key: {:?} doesn't exist.", key);
+                }
+            }
+        },
+        ("lease-grant", Some(lease_subcommand)) => {
+            if let Some(ttl) = lease_subcommand.value_of("ttl") {
+                let ttl = ttl.parse::<u64>()?;
+                let response = leases.lease_grant(LeaseGrantRequest { ttl }).await?.into_inner();
+                info!("Granted lease {} with a time to live of {}s", response.id, response.ttl);
+            }
+        },
+        ("lease-revoke", Some(lease_subcommand)) => {
+            if let Some(id) = lease_subcommand.value_of("id") {
+                let id = id.parse::<u64>()?;
+                let response = leases.lease_revoke(LeaseRevokeRequest { id }).await?.into_inner();
+                info!("Lease {} has been revoked (sequence number: {})", id, response.seq);
+            }
+        },
+        ("lease-keep-alive", Some(lease_subcommand)) => {
+            if let Some(id) = lease_subcommand.value_of("id") {
+                let id = id.parse::<u64>()?;
+                let info = leases.lease_info(LeaseInfoRequest { id }).await?.into_inner();
+                if !info.exist {
+                    warn!("Lease {} doesn't exist.", id);
+                    return Ok(());
+                }
+                // Well before the deadline, so that a late keep-alive doesn't lose the lease.
+                let interval = Duration::from_secs((info.ttl / 3).max(1));
+                let requests = async_stream::stream! {
+                    loop {
+                        yield LeaseKeepAliveRequest { id };
+                        tokio::time::sleep(interval).await;
+                    }
+                };
+                let mut responses = leases.lease_keep_alive(requests).await?.into_inner();
+                while let Some(response) = responses.message().await? {
+                    if response.ttl == 0 {
+                        warn!("Lease {} expired.", id);
+                        break;
+                    }
+                    info!("Lease {} kept alive for {}s", id, response.ttl);
+                }
+            }
+        },
+        ("lease-info", Some(lease_subcommand)) => {
+            if let Some(id) = lease_subcommand.value_of("id") {
+                let id = id.parse::<u64>()?;
+                let info = leases.lease_info(LeaseInfoRequest { id }).await?.into_inner();
+                if info.exist {
+                    info!(
+                        "Lease {}: time to live {}s, remaining {}s, keys: {:?}",
+                        id,
+                        info.ttl,
+                        info.remaining,
+                        info.keys
+                    );
+                } else {
+                    warn!("Lease {} doesn't exist.", id);
+                }
             }
         },
         _ => {}
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+fn consistency(level: Option<&str>) -> i32 {
+    match level {
+        Some("quorum") => Consistency::Quorum as i32,
+        Some("all") => Consistency::All as i32,
+        _ => Consistency::One as i32,
+    }
+}
+
+// A line of the files of import and export.
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+enum PairWriter {
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+    Json(io::BufWriter<Box<dyn Write>>),
+}
+
+// The pairs are read one batch at a time, each batch being split into up to `concurrency`
+// `KvBatchCall` requests in flight before the next one is read.
+async fn import(
+    client: &CrabeClient,
+    input: Box<dyn Read>,
+    csv: bool,
+    batch_size: usize,
+    concurrency: usize,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut pairs: Box<dyn Iterator<Item = Result<Pair, Box<dyn std::error::Error>>>> = if csv {
+        Box::new(csv::Reader::from_reader(input).into_deserialize().map(|pair| pair.map_err(Into::into)))
+    } else {
+        Box::new(BufReader::new(input).lines().filter_map(|line| match line {
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => Some(serde_json::from_str(&line).map_err(Into::into)),
+            Err(err) => Some(Err(err.into())),
+        }))
+    };
+
+    let mut imported = 0;
+    loop {
+        let batch = pairs.by_ref().take(batch_size).collect::<Result<Vec<Pair>, _>>()?;
+        if batch.is_empty() {
+            return Ok(imported);
+        }
+        let chunk_size = batch.len().div_ceil(concurrency);
+        let requests = batch.chunks(chunk_size).map(|pairs| {
+            let ops = pairs.iter().map(|pair| BatchOp {
+                r#type: BatchOpType::Set as i32,
+                key: pair.key.clone(),
+                value: pair.value.clone(),
+            }).collect();
+            client.batch(ops)
+        });
+        let mut responses = futures_util::stream::iter(requests).buffer_unordered(concurrency);
+        while let Some(results) = responses.next().await {
+            for result in results? {
+                if !result.success {
+                    return Err(format!("A pair couldn't be set: {}", result.error).into());
+                }
+                imported += 1;
+            }
+        }
+        info!("{} pairs imported", imported);
+    }
+}
+
+async fn export(
+    client: &CrabeClient,
+    output: Box<dyn Write>,
+    prefix: &str,
+    csv: bool,
+    batch_size: u64,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut stream = client.scan(prefix, 0).await?;
+
+    let mut output = if csv {
+        PairWriter::Csv(Box::new(csv::Writer::from_writer(output)))
+    } else {
+        PairWriter::Json(io::BufWriter::new(output))
+    };
+    let mut exported = 0;
+    while let Some(pair) = stream.message().await? {
+        let pair = Pair { key: pair.key, value: pair.value };
+        match output {
+            PairWriter::Csv(ref mut writer) => writer.serialize(&pair)?,
+            PairWriter::Json(ref mut writer) => writeln!(writer, "{}", serde_json::to_string(&pair)?)?,
+        }
+        exported += 1;
+        if exported % batch_size == 0 {
+            info!("{} pairs exported", exported);
+        }
+    }
+    match output {
+        PairWriter::Csv(mut writer) => writer.flush()?,
+        PairWriter::Json(mut writer) => writer.flush()?,
+    }
+    Ok(exported)
+}
+
+const REPL_HELP: &str = "\
+get <key>                 print the value of the key
+set <key> <value>         set the key, the value being the rest of the line
+del <key>                 remove the key
+scan [prefix] [limit]     print the pairs whose key starts with the prefix
+stats                     print the statistics of the server
+help                      print this help
+exit                      leave the shell (or Ctrl-D)";
+
+// The commands share the connection of the client. A failed command only prints its error.
+async fn repl(
+    node_addr: &str,
+    client: &CrabeClient,
+    admin: &mut AdminClient<Channel>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut editor = Editor::<()>::new()?;
+    let history = std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".crabedb_history"));
+    if let Some(history) = &history {
+        // There's no history yet on the first run.
+        let _ = editor.load_history(history);
+    }
+
+    let prompt = format!("{}> ", node_addr);
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C only clears the line.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line);
+        if line == "exit" || line == "quit" {
+            break;
+        }
+        if let Err(err) = repl_command(line, client, admin).await {
+            println!("error: {}", err);
+        }
+    }
+
+    if let Some(history) = &history {
+        editor.save_history(history)?;
+    }
+    Ok(())
+}
+
+async fn repl_command(
+    line: &str,
+    client: &CrabeClient,
+    admin: &mut AdminClient<Channel>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (command, args) = match line.find(char::is_whitespace) {
+        Some(end) => (&line[..end], line[end..].trim_start()),
+        None => (line, ""),
+    };
+    let mut words = args.split_whitespace();
+    match command {
+        "get" => {
+            let key = words.next().ok_or("usage: get <key>")?;
+            match client.get(key).await? {
+                Some(value) => println!("{:?}", value),
+                None => println!("(not found)"),
+            }
+        },
+        "set" => {
+            let key = words.next().ok_or("usage: set <key> <value>")?;
+            let value = args[key.len()..].trim_start();
+            let seq = client.set(key, value).await?;
+            println!("OK (sequence number: {})", seq);
+        },
+        "del" => {
+            let key = words.next().ok_or("usage: del <key>")?;
+            let seq = client.remove(key).await?;
+            println!("OK (sequence number: {})", seq);
+        },
+        "scan" => {
+            let prefix = words.next().unwrap_or("");
+            let limit = match words.next() {
+                Some(l) => l.parse::<u64>()?,
+                None => 0,
+            };
+            let mut stream = client.scan(prefix, limit).await?;
+            let mut count = 0;
+            while let Some(pair) = stream.message().await? {
+                println!("{:?} => {:?}", pair.key, pair.value);
+                count += 1;
+            }
+            println!("({} pairs)", count);
+        },
+        "stats" => {
+            let stats = admin.stats(StatsRequest {
+                start: String::new(),
+                end: String::new(),
+            }).await?.into_inner();
+            println!(
+                "approximate key count: {}, approximate size: {} bytes",
+                stats.approximate_key_count,
+                stats.approximate_size
+            );
+        },
+        "help" => println!("{}", REPL_HELP),
+        _ => println!("unknown command {:?}, type help for the list of commands", command),
+    }
+    Ok(())
+}
+
+// Defaults of the connection options.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_RETRIES: u32 = 2;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// The delay before the retry following `attempt` failed ones.
+fn retry_delay(attempt: u32) -> Duration {
+    FIRST_RETRY_DELAY.checked_mul(1 << attempt.min(16)).map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+async fn connect(endpoint: &Endpoint, timeout: Duration, retries: u32) -> Result<Channel, String> {
+    let mut attempt = 0;
+    loop {
+        let error = match tokio::time::timeout(timeout, endpoint.connect()).await {
+            Ok(Ok(channel)) => return Ok(channel),
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => format!("no connection within {:?}", timeout),
+        };
+        if attempt == retries {
+            return Err(format!(
+                "Couldn't connect to {} after {} attempts: {}",
+                endpoint.uri(),
+                attempt + 1,
+                error
+            ));
+        }
+        warn!("Couldn't connect to {}: {}, retrying", endpoint.uri(), error);
+        tokio::time::sleep(retry_delay(attempt)).await;
+        attempt += 1;
+    }
+}
+
+// Retry a request failing with a transient error. Transport errors, e.g. a connection
+// reset by a restarting server, surface as UNKNOWN, the server never returns it itself.
+async fn with_retries<R, T, F, Fut>(
+    retries: u32,
+    tx: &KvstoreClient<Channel>,
+    request: &R,
+    call: F,
+) -> Result<T, tonic::Status>
+where
+    R: Clone,
+    F: Fn(KvstoreClient<Channel>, R) -> Fut,
+    Fut: std::future::Future<Output = Result<T, tonic::Status>>,
+{
+    let mut attempt = 0;
+    loop {
+        match call(tx.clone(), request.clone()).await {
+            Err(status) if attempt < retries && matches!(status.code(), tonic::Code::Unavailable | tonic::Code::Unknown) => {
+                warn!("Request failed: {}, retrying", status.message());
+                tokio::time::sleep(retry_delay(attempt)).await;
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+// Send a request of the cluster administration which only the primary of a failover
+// group serves, following the redirection of another node to it.
+async fn on_primary<R, T, F, Fut>(
+    cluster: &ClusterClient<Channel>,
+    request: &R,
+    call: F,
+) -> Result<T, Box<dyn std::error::Error>>
+where
+    R: Clone,
+    F: Fn(ClusterClient<Channel>, R) -> Fut,
+    Fut: std::future::Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+{
+    let status = match call(cluster.clone(), request.clone()).await {
+        Ok(response) => return Ok(response.into_inner()),
+        Err(status) => status,
+    };
+    let not_leader = match status.code() {
+        tonic::Code::FailedPrecondition => <NotLeader as prost::Message>::decode(status.details()).ok(),
+        _ => None,
+    };
+    match not_leader {
+        Some(not_leader) if !not_leader.leader.is_empty() => {
+            info!("Redirected to {}, the primary of term {}", not_leader.leader, not_leader.term);
+            let primary = ClusterClient::connect(format!("http://{}", not_leader.leader)).await?;
+            Ok(call(primary, request.clone()).await?.into_inner())
+        }
+        _ => Err(status.into()),
+    }
+}
+
+// A duration with a unit (ms, s, m or h), seconds without one.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let unit_start = duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(unit_start);
+    let amount = amount.parse::<u64>().map_err(|_| format!("invalid duration {:?}", duration))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount * 60)),
+        "h" => Ok(Duration::from_secs(amount * 3600)),
+        _ => Err(format!("invalid duration {:?}, expected a unit among ms, s, m and h", duration)),
+    }
+}
+
+// Tabs, newlines and backslashes are escaped so each pair stays on its own line.
+fn tsv_escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn crdt_display(crdt: &protobuf::CrdtValue) -> String {
+    match CrdtType::from_i32(crdt.r#type) {
+        Some(CrdtType::GCounter) => crdt.count.to_string(),
+        Some(CrdtType::OrSet) => format!("{:?}", crdt.elements),
+        _ => format!("{:?}", crdt.value),
+    }
+}
@@ -1,45 +1,1109 @@
+// The try_stream! of Replication::tail exceeds the default limit.
+#![recursion_limit = "512"]
+
 use std::str;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::convert::From;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use log::{info, debug};
-use tonic::transport::Server;
-use tonic::{Request, Response, Status};
+use async_stream::{stream, try_stream};
+use futures_core::Stream;
+use futures_util::future::join_all;
+use rand::Rng;
+use log::{info, debug, warn};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use bytes::Bytes;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Context, HttpBody, Poll, Service, StdError};
+use tonic::metadata::AsciiMetadataValue;
+use tonic::transport::{Body, Channel, Endpoint, NamedService, Server};
+use tonic::{Code, Request, Response, Status, Streaming};
 use clap::{Arg, App};
 pub mod protobuf {
     tonic::include_proto!("kvstore");
 }
+use protobuf::admin_server::{Admin, AdminServer};
+use protobuf::cluster_client::ClusterClient;
+use protobuf::cluster_server::{Cluster, ClusterServer};
+use protobuf::election_client::ElectionClient;
+use protobuf::election_server::{Election as ElectionService, ElectionServer};
+use protobuf::kvstore_client::KvstoreClient;
 use protobuf::kvstore_server::{Kvstore, KvstoreServer};
+use protobuf::lease_client::LeaseClient;
+use protobuf::lease_server::{Lease as LeaseService, LeaseServer};
+use protobuf::replication_client::ReplicationClient;
+use protobuf::replication_server::{Replication, ReplicationServer};
 use protobuf::{
-    GetRequest, GetResponse,
+    Consistency, GetRequest, GetResponse,
     SetRequest, SetResponse,
-    RemoveRequest, RemoveResponse
+    RemoveRequest, RemoveResponse,
+    BatchOp, BatchOpType, BatchOpResult, BatchRequest, BatchResponse,
+    GetStreamRequest, ValueChunk, SetStreamRequest, SizeLimitExceeded,
+    ListKeysRequest, ListKeysResponse, ScanRequest, KeyValue, TtlRequest, TtlResponse,
+    PauseCompactionRequest, ResumeCompactionRequest, CompactionControlResponse,
+    CompactionStatusRequest, CompactionStatusResponse,
+    StatsRequest, StatsResponse,
+    FileStatsRequest, FileStatsResponse,
+    CheckConsistencyRequest, CheckConsistencyResponse,
+    PromoteRequest, PromoteResponse, NotLeader,
+    VoteRequest, VoteResponse, HeartbeatRequest, HeartbeatResponse, LeaderRequest, LeaderResponse,
+    TimeoutNowRequest, TimeoutNowResponse, NodeInfoRequest, NodeStatus, ClusterStatusRequest, ClusterStatusResponse,
+    AddNodeRequest, RemoveNodeRequest, MembershipResponse, TransferLeadershipRequest, TransferLeadershipResponse,
+    RebalanceRequest, RebalanceResponse, RoutingTable, RoutingTableRequest, WrongShard,
+    MigrateRequest, MigrateResponse, ImportRecordsRequest, ImportRecordsResponse,
+    TailRequest, TailResponse, TermStart, LogRecord, ValueMetadata, BootstrapRequest, BootstrapResponse,
+    LeaseGrantRequest, LeaseGrantResponse, LeaseRevokeRequest, LeaseRevokeResponse,
+    LeaseKeepAliveRequest, LeaseKeepAliveResponse, LeaseInfoRequest, LeaseInfoResponse,
+    LockRequest, LockResponse, UnlockRequest, UnlockResponse, WatchRequest, WatchEvent,
+    CrdtType, CrdtOp, CrdtUpdateRequest, CrdtGetRequest, CrdtValue
 };
 use regex::Regex;
 
 extern crate crabedb;
-use crabedb::storage::crabe_db::CrabeDB;
-use crabedb::storage::options::{StorageOptions, SyncOptions};
+use crabedb::r#async::{CrabeDB, ValueReader};
+use crabedb::client::STORE_METADATA;
+use crabedb::client::ring::HashRing;
+use crabedb::crdt::Crdt;
+use crabedb::storage::audit::AuditLog;
+use crabedb::storage::bootstrap::SnapshotInstaller;
+use crabedb::storage::crabe_db::CasResult;
+use crabedb::storage::compaction::SizeTieredStrategy;
+use crabedb::storage::encryption::{EnvKeyProvider, Keyring, KmsKeyProvider};
+use crabedb::storage::error::Error;
+use crabedb::storage::lease::LeaseChanges;
+use crabedb::storage::lsm::LogPosition;
+use crabedb::storage::options::{
+    CacheUnit, ChecksumKind, EvictionPolicy, IndexKind, IoEngineKind, RecoveryMode, StorageOptions, SyncOptions,
+};
+use crabedb::storage::rate_limiter::RateLimiter;
+use crabedb::storage::slot::{now_millis, Log, MAX_KEY_SIZE};
+use crabedb::storage::standby::{self, ElectionState};
+use crabedb::storage::stats;
+use crabedb::storage::util::is_new_store_path;
+use crabedb::storage::tiering::{S3ObjectStore, Tiering};
+use crabedb::storage::write_batch::WriteBatch;
+use crabedb::storage::xxhash::xxhash32;
+
+// Page sizes of KvListKeysCall, when the client doesn't ask for one and at most.
+const DEFAULT_LIST_KEYS_LIMIT: usize = 1000;
+const MAX_LIST_KEYS_LIMIT: usize = 10000;
+
+// Keys read from the index at a time by KvScanCall.
+const SCAN_BATCH_SIZE: usize = 1000;
+
+// Records sent per TailResponse, how often an idle tail looks for new records, and how
+// long a standby waits before reconnecting to its primary.
+const TAIL_BATCH_SIZE: usize = 1000;
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const STANDBY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// How often the primary of a failover group sends its heartbeats, how long it keeps
+// accepting writes after a majority last acknowledged them, and how long at least a
+// standby waits for them before it runs for election.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(3);
+// How long a node waits for the answer to a heartbeat or a vote request.
+const ELECTION_RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Keys listed at a time by a rebalance.
+const REBALANCE_BATCH_SIZE: usize = 1000;
+
+// Key of the routing table of a node of a sharded deployment.
+const ROUTING_KEY: &str = "__routing";
+
+// Bytes of a file of the snapshot sent per BootstrapResponse.
+const BOOTSTRAP_CHUNK_SIZE: usize = 1024 * 1024;
+
+// How long a get with a min_seq waits for a standby to catch up.
+const MIN_SEQ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a node waits for a peer of its cluster.
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Metadata of the writes a node forwards to another one with their sequence number,
+// holding the secret of the cluster (`--cluster-secret`).
+const CLUSTER_SECRET_METADATA: &str = "crabedb-cluster-secret";
+
+// Prefix of the hints, the writes a peer couldn't be reached for, kept locally as
+// `__hint/<peer>/<key>` until they are replayed to it, and how often that is tried.
+const HINT_KEY_PREFIX: &str = "__hint/";
+const HINT_REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+const HINT_REPLAY_BATCH_SIZE: usize = 100;
+// The kinds of the hinted writes.
+const HINT_SET: u8 = 0;
+const HINT_REMOVE: u8 = 1;
+const HINT_LEASED: u8 = 2;
+const HINT_STREAMED: u8 = 3;
+
+// Prefix of the keys holding the locks, and how often a waiting lock call retries.
+const LOCK_KEY_PREFIX: &str = "__lock/";
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Number of client addresses beyond which the idle ones are forgotten by the write limits.
+const MAX_RATE_LIMITED_PEERS: usize = 10_000;
+
+// Room left in a request message for the fields other than its key and value.
+const MESSAGE_OVERHEAD: usize = 1024;
+// Length of the header of a gRPC message: a compression flag and the length of the message.
+const GRPC_HEADER_SIZE: usize = 5;
+
+// Deadline of a request, from the timeout set by its client (the `grpc-timeout` header).
+fn request_deadline<T>(request: &Request<T>) -> Option<Instant> {
+    let timeout = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+    let (value, unit) = timeout.split_at(timeout.len().checked_sub(1)?);
+    let value = value.parse::<u64>().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(value * 3600),
+        "M" => Duration::from_secs(value * 60),
+        "S" => Duration::from_secs(value),
+        "m" => Duration::from_millis(value),
+        "u" => Duration::from_micros(value),
+        "n" => Duration::from_nanos(value),
+        _ => return None,
+    };
+    Some(Instant::now() + timeout)
+}
+
+// Identity of the client recorded in the audit log: its address, as TLS client
+// certificates aren't used.
+fn peer_identity<T>(request: &Request<T>) -> Option<String> {
+    request.remote_addr().map(|addr| addr.to_string())
+}
 
 pub struct KvStoreAPI {
-    db: CrabeDB,
+    stores: Stores,
+    chunk_size: usize,
+    peers: Peers,
+    write_limits: WriteLimits,
+    size_limits: SizeLimits,
+    // Identity of the node in the CRDT values it updates.
+    node_id: String,
+    election: Option<Election>,
+    routing: Routing,
     //telemetry: Option<Telemetry>,
 }
 
+// Token buckets of the mutating RPCs, in requests per second: one shared by every client
+// and one for each client address, the peers of a cluster forwarding their writes
+// included.
+pub struct WriteLimits {
+    global: Option<RateLimiter>,
+    per_peer: u64,
+    peers: Mutex<HashMap<IpAddr, RateLimiter>>,
+}
+
+impl WriteLimits {
+    // 0 disables a limit.
+    pub fn new(global: u64, per_peer: u64) -> WriteLimits {
+        WriteLimits {
+            global: if global > 0 { Some(RateLimiter::new(global)) } else { None },
+            per_peer,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, addr: Option<SocketAddr>) -> Result<(), WriteLimitExceeded> {
+        if self.per_peer > 0 {
+            if let Some(addr) = addr {
+                let mut peers = self.peers.lock().unwrap();
+                if peers.len() >= MAX_RATE_LIMITED_PEERS {
+                    peers.retain(|_, limiter| !limiter.is_idle());
+                }
+                let limiter = peers.entry(addr.ip()).or_insert_with(|| RateLimiter::new(self.per_peer));
+                if !limiter.try_request(1) {
+                    return Err(WriteLimitExceeded(format!(
+                        "more than {} writes per second from {}",
+                        self.per_peer,
+                        addr.ip()
+                    )));
+                }
+            }
+        }
+        if let Some(ref global) = self.global {
+            if !global.try_request(1) {
+                return Err(WriteLimitExceeded("too many writes per second".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct WriteLimitExceeded(String);
+
+impl From<WriteLimitExceeded> for Status {
+    fn from(exceeded: WriteLimitExceeded) -> Self {
+        Status::resource_exhausted(exceeded.0)
+    }
+}
+
+// The stores opened by the server: the default one, in the `--dump` directory, and the
+// named ones (`--store name=path`), each with its own background threads. A request is
+// routed by its `crabedb-store` metadata. Only the default store is replicated, to the
+// peers of a cluster and to the standbys.
+#[derive(Clone)]
+pub struct Stores {
+    default: CrabeDB,
+    named: Arc<HashMap<String, CrabeDB>>,
+}
+
+impl Stores {
+    fn get<T>(&self, request: &Request<T>) -> Result<&CrabeDB, UnknownStore> {
+        match store_name(request) {
+            "" => Ok(&self.default),
+            name => self.named.get(name).ok_or_else(|| UnknownStore(name.to_string())),
+        }
+    }
+}
+
+pub struct UnknownStore(String);
+
+impl From<UnknownStore> for Status {
+    fn from(unknown: UnknownStore) -> Self {
+        Status::not_found(format!("Unknown store {:?}", unknown.0))
+    }
+}
+
+fn store_name<T>(request: &Request<T>) -> &str {
+    request.metadata().get(STORE_METADATA).and_then(|name| name.to_str().ok()).unwrap_or("")
+}
+
+fn is_default_store<T>(request: &Request<T>) -> bool {
+    store_name(request).is_empty()
+}
+
+pub struct NotReplicated;
+
+impl From<NotReplicated> for Status {
+    fn from(_: NotReplicated) -> Self {
+        Status::failed_precondition("Only the default store is replicated to the peers")
+    }
+}
+
+// A key of the server a client asked for, see `check_client_key`.
+pub struct ReservedKey(String);
+
+impl From<ReservedKey> for Status {
+    fn from(ReservedKey(key): ReservedKey) -> Self {
+        Status::invalid_argument(format!("Key {:?} is reserved for the server", key))
+    }
+}
+
+// Largest keys and values accepted from the clients, in bytes.
+#[derive(Clone, Copy)]
+pub struct SizeLimits {
+    key: usize,
+    value: usize,
+}
+
+impl SizeLimits {
+    fn check_key(&self, key: &str) -> Result<(), SizeLimitExceeded> {
+        check_size("key", key.len(), self.key)
+    }
+
+    fn check_value(&self, size: usize) -> Result<(), SizeLimitExceeded> {
+        check_size("value", size, self.value)
+    }
+
+    // Largest gRPC message of a request.
+    fn message(&self) -> usize {
+        self.key + self.value + MESSAGE_OVERHEAD
+    }
+}
+
+fn check_size(field: &str, size: usize, limit: usize) -> Result<(), SizeLimitExceeded> {
+    if size > limit {
+        return Err(SizeLimitExceeded { field: field.to_string(), limit: limit as u64, size: size as u64 });
+    }
+    Ok(())
+}
+
+impl From<SizeLimitExceeded> for Status {
+    fn from(exceeded: SizeLimitExceeded) -> Self {
+        let message = format!("The {} is {} bytes long, more than the limit of {} bytes", exceeded.field, exceeded.size, exceeded.limit);
+        let mut details = Vec::new();
+        match prost::Message::encode(&exceeded, &mut details) {
+            Ok(()) => Status::with_details(Code::InvalidArgument, message, Bytes::from(details)),
+            Err(_) => Status::invalid_argument(message),
+        }
+    }
+}
+
+impl From<NotLeader> for Status {
+    fn from(not_leader: NotLeader) -> Self {
+        let message = if not_leader.leader.is_empty() {
+            format!("The node isn't the primary, an election is going on in term {}", not_leader.term)
+        } else {
+            format!("The node isn't the primary, {} is in term {}", not_leader.leader, not_leader.term)
+        };
+        let mut details = Vec::new();
+        match prost::Message::encode(&not_leader, &mut details) {
+            Ok(()) => Status::with_details(Code::FailedPrecondition, message, Bytes::from(details)),
+            Err(_) => Status::failed_precondition(message),
+        }
+    }
+}
+
+impl From<WrongShard> for Status {
+    fn from(wrong_shard: WrongShard) -> Self {
+        let message = format!("The key is served by {}", wrong_shard.owner);
+        let mut details = Vec::new();
+        match prost::Message::encode(&wrong_shard, &mut details) {
+            Ok(()) => Status::with_details(Code::FailedPrecondition, message, Bytes::from(details)),
+            Err(_) => Status::failed_precondition(message),
+        }
+    }
+}
+
+// Wraps a service so that a request message larger than `limit` is refused as soon as its
+// header is received, instead of being buffered in full before it's decoded.
+#[derive(Clone)]
+pub struct MessageSizeLimit<S> {
+    inner: S,
+    limit: usize,
+}
+
+impl<S> MessageSizeLimit<S> {
+    pub fn new(inner: S, limit: usize) -> MessageSizeLimit<S> {
+        MessageSizeLimit { inner, limit }
+    }
+}
+
+impl<S: NamedService> NamedService for MessageSizeLimit<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<Body>> for MessageSizeLimit<S>
+where
+    S: Service<http::Request<LimitedBody>, Response = http::Response<BoxBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let limit = self.limit;
+        self.inner.call(request.map(|body| LimitedBody {
+            inner: body,
+            limit,
+            header: [0; GRPC_HEADER_SIZE],
+            header_len: 0,
+            remaining: 0,
+        }))
+    }
+}
+
+// Body of a request which fails with an INVALID_ARGUMENT status once a gRPC message
+// header announces a message larger than `limit`.
+pub struct LimitedBody {
+    inner: Body,
+    limit: usize,
+    // The bytes of the current message header received so far, then those of the message
+    // still to receive.
+    header: [u8; GRPC_HEADER_SIZE],
+    header_len: usize,
+    remaining: usize,
+}
+
+impl LimitedBody {
+    fn scan(&mut self, mut data: &[u8]) -> Result<(), SizeLimitExceeded> {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let skipped = self.remaining.min(data.len());
+                self.remaining -= skipped;
+                data = &data[skipped..];
+                continue;
+            }
+
+            let read = (GRPC_HEADER_SIZE - self.header_len).min(data.len());
+            self.header[self.header_len..self.header_len + read].copy_from_slice(&data[..read]);
+            self.header_len += read;
+            data = &data[read..];
+            if self.header_len == GRPC_HEADER_SIZE {
+                let mut len = [0; 4];
+                len.copy_from_slice(&self.header[1..]);
+                let len = u32::from_be_bytes(len) as usize;
+                check_size("message", len, self.limit)?;
+                self.header_len = 0;
+                self.remaining = len;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HttpBody for LimitedBody {
+    type Data = Bytes;
+    type Error = StdError;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, StdError>>> {
+        let body = &mut *self;
+        match Pin::new(&mut body.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(data))) => match body.scan(&data) {
+                Ok(()) => Poll::Ready(Some(Ok(data))),
+                Err(exceeded) => Poll::Ready(Some(Err(Box::new(Status::from(exceeded))))),
+            },
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(Box::new(err)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, StdError>> {
+        Pin::new(&mut self.inner).poll_trailers(cx).map_err(|err| Box::new(err) as StdError)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+// The other nodes of a cluster. Each node accepts reads and writes: a write is applied
+// locally, then forwarded to the peers with its sequence number, which orders the versions
+// of a key across nodes (see `CrabeDB::merge`). A request waits for as many nodes as its
+// consistency level asks for, the other peers being written to in the background.
+//
+// The writes forwarded with their sequence number, by the peers or by a rebalance, carry
+// the secret shared by the nodes, without which a node refuses them.
+#[derive(Clone, Default)]
+pub struct Peers {
+    clients: Vec<(String, KvstoreClient<Channel>)>,
+    leases: Vec<(String, LeaseClient<Channel>)>,
+    secret: Option<AsciiMetadataValue>,
+}
+
+impl Peers {
+    fn new(addrs: &[&str], secret: Option<&str>) -> Result<Peers, Box<dyn std::error::Error>> {
+        let secret = match secret {
+            Some(secret) => Some(secret.parse::<AsciiMetadataValue>().map_err(|_| "invalid cluster secret")?),
+            None if !addrs.is_empty() => return Err("the peers of a cluster need a --cluster-secret".into()),
+            None => None,
+        };
+        let mut clients = Vec::with_capacity(addrs.len());
+        let mut leases = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let channel = Endpoint::from_shared(format!("http://{}", addr))?
+                .timeout(PEER_TIMEOUT)
+                .connect_lazy()?;
+            clients.push((addr.to_string(), KvstoreClient::new(channel.clone())));
+            leases.push((addr.to_string(), LeaseClient::new(channel)));
+        }
+        Ok(Peers { clients, leases, secret })
+    }
+
+    // Forward a keep-alive or the revocation of a lease to the peers, in the background:
+    // they mirror the leases of the keys forwarded to them. A peer which missed it lets
+    // the lease expire.
+    fn forward_lease(&self, id: u64, revoke: bool) {
+        for (addr, mut client) in self.leases.iter().cloned() {
+            let peers = self.clone();
+            tokio::spawn(async move {
+                let result = if revoke {
+                    match peers.replica_request(LeaseRevokeRequest { id }) {
+                        Ok(request) => client.lease_revoke(request).await.map(|_| ()),
+                        Err(refused) => Err(Status::from(refused)),
+                    }
+                } else {
+                    match peers.replica_request(tokio_stream::iter(vec![LeaseKeepAliveRequest { id }])) {
+                        // The response is awaited, so that the request isn't cancelled first.
+                        Ok(request) => match client.lease_keep_alive(request).await {
+                            Ok(response) => response.into_inner().message().await.map(|_| ()),
+                            Err(status) => Err(status),
+                        },
+                        Err(refused) => Err(Status::from(refused)),
+                    }
+                };
+                if let Err(status) = result {
+                    debug!("Couldn't forward lease {} to {}: {}", id, addr, status.message());
+                }
+            });
+        }
+    }
+
+    // The peer serving the locks of the cluster, the one with the lowest address, lest two
+    // nodes grant the same lock at once. `None` when it's this node, at `address`.
+    fn lock_node(&self, address: &str) -> Option<(String, KvstoreClient<Channel>)> {
+        self.clients
+            .iter()
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .filter(|(addr, _)| addr.as_str() < address)
+            .cloned()
+    }
+
+    // A write forwarded to another node, with the secret of the cluster.
+    fn replica_request<T>(&self, message: T) -> Result<Request<T>, ReplicaRefused> {
+        let secret = self.secret.clone().ok_or(ReplicaRefused::NoSecret)?;
+        let mut request = Request::new(message);
+        request.metadata_mut().insert(CLUSTER_SECRET_METADATA, secret);
+        Ok(request)
+    }
+
+    // Check that a write forwarded with its sequence number comes from a node sharing the
+    // secret of the cluster.
+    fn check_replica<T>(&self, request: &Request<T>) -> Result<(), ReplicaRefused> {
+        let secret = self.secret.as_ref().ok_or(ReplicaRefused::NoSecret)?;
+        match request.metadata().get(CLUSTER_SECRET_METADATA) {
+            Some(value) if constant_time_eq(value.as_bytes(), secret.as_bytes()) => Ok(()),
+            _ => Err(ReplicaRefused::WrongSecret),
+        }
+    }
+
+    // Number of nodes, this one included, a request waits for.
+    fn required(&self, consistency: i32) -> usize {
+        let nodes = self.clients.len() + 1;
+        match Consistency::from_i32(consistency) {
+            Some(Consistency::All) => nodes,
+            Some(Consistency::Quorum) => nodes / 2 + 1,
+            _ => 1,
+        }
+    }
+}
+
+pub enum ReplicaRefused {
+    NoSecret,
+    WrongSecret,
+}
+
+impl From<ReplicaRefused> for Status {
+    fn from(refused: ReplicaRefused) -> Self {
+        match refused {
+            ReplicaRefused::NoSecret => {
+                Status::failed_precondition("The node has no --cluster-secret to forward or accept replica writes")
+            }
+            ReplicaRefused::WrongSecret => Status::unauthenticated("A replica write needs the secret of the cluster"),
+        }
+    }
+}
+
+// Compare two secrets in a time which doesn't depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// A write applied locally, forwarded to the peers. A key set with a time to live or a
+// lease gets it on the peers too: `ttl_ms` is then the time to live of the lease, which
+// the peers mirror and whose keep-alives they get. A value set with KvSetStreamCall is
+// read back from the store as it's forwarded, rather than held in memory.
+#[derive(Clone, Debug)]
+enum PeerWrite {
+    Set { key: String, value: Vec<u8>, seq: u64, ttl_ms: u64, lease: u64 },
+    Streamed { key: String, seq: u64 },
+    Remove { key: String, seq: u64 },
+}
+
+impl PeerWrite {
+    fn set(key: String, value: impl Into<Vec<u8>>, seq: u64) -> PeerWrite {
+        PeerWrite::Set { key, value: value.into(), seq, ttl_ms: 0, lease: 0 }
+    }
+}
+
+// The messages of a KvSetStreamCall writing the value, at least one.
+fn value_chunks(key: &str, value: &[u8], replica_seq: u64) -> Vec<SetStreamRequest> {
+    let total_size = value.len() as u64;
+    let mut chunks: Vec<SetStreamRequest> = value
+        .chunks(BOOTSTRAP_CHUNK_SIZE)
+        .map(|chunk| SetStreamRequest { key: key.to_string(), data: chunk.to_vec(), replica_seq, total_size })
+        .collect();
+    if chunks.is_empty() {
+        chunks.push(SetStreamRequest { key: key.to_string(), data: Vec::new(), replica_seq, total_size });
+    }
+    chunks
+}
+
+// Like `value_chunks`, the value being read as it's sent. The peer doesn't write a value
+// cut short by a read error, whose size differs from `total_size`.
+fn reader_chunks(key: String, reader: ValueReader, replica_seq: u64) -> impl Stream<Item = SetStreamRequest> {
+    let total_size = reader.size();
+    stream! {
+        let mut reader = reader;
+        let mut first = true;
+        loop {
+            let data = match reader.read(BOOTSTRAP_CHUNK_SIZE).await {
+                Ok((next, data)) => {
+                    reader = next;
+                    data
+                }
+                Err(err) => {
+                    warn!("Couldn't read the value of {} to forward it: {}", key, err);
+                    break;
+                }
+            };
+            if data.is_empty() && !first {
+                break;
+            }
+            first = false;
+            yield SetStreamRequest { key: key.clone(), data: data.to_vec(), replica_seq, total_size };
+        }
+    }
+}
+
+async fn forward_write(
+    db: &CrabeDB,
+    peers: &Peers,
+    mut client: KvstoreClient<Channel>,
+    write: PeerWrite,
+) -> Result<(), Status> {
+    let success = match write {
+        // A SetRequest only carries text, a binary value is streamed. Only the values set
+        // with KvSetStreamCall, without a time to live or a lease, are binary.
+        PeerWrite::Set { key, value, seq, ttl_ms, lease } => match String::from_utf8(value) {
+            Ok(value) => {
+                client.kv_set_call(peers.replica_request(SetRequest {
+                    key,
+                    value,
+                    lease,
+                    consistency: Consistency::One as i32,
+                    replica_seq: seq,
+                    ttl_ms,
+                })?).await?.into_inner().success
+            }
+            Err(err) => {
+                let chunks = value_chunks(&key, err.as_bytes(), seq);
+                client.kv_set_stream_call(peers.replica_request(tokio_stream::iter(chunks))?).await?.into_inner().success
+            }
+        },
+        // A value removed since is left alone, the removal is forwarded too. One written
+        // again is sent with the sequence number of this write, which the next one's
+        // supersedes on the peer.
+        PeerWrite::Streamed { key, seq } => match db.value_reader(key.clone()).await? {
+            Some(reader) => {
+                let chunks = reader_chunks(key, reader, seq);
+                client.kv_set_stream_call(peers.replica_request(chunks)?).await?.into_inner().success
+            }
+            None => true,
+        },
+        PeerWrite::Remove { key, seq } => {
+            client.kv_remove_call(peers.replica_request(RemoveRequest {
+                key,
+                consistency: Consistency::One as i32,
+                replica_seq: seq,
+            })?).await?.into_inner().success
+        }
+    };
+    if success {
+        Ok(())
+    } else {
+        Err(Status::internal("the peer couldn't apply the write"))
+    }
+}
+
+fn hint_prefix(addr: &str) -> String {
+    format!("{}{}/", HINT_KEY_PREFIX, addr)
+}
+
+// A hint only holds the latest write of a key: seq(8) + kind(1) + [ttl_ms(8) + lease(8)]
+// + value, the kind being 1 for a removal, 2 for a write with a time to live or a lease,
+// 3 for a streamed value, which isn't kept in the hint, and 0 for another write.
+fn encode_hint(write: &PeerWrite) -> (&str, Vec<u8>) {
+    let mut hint;
+    let key = match write {
+        PeerWrite::Set { key, value, seq, ttl_ms, lease } => {
+            hint = seq.to_le_bytes().to_vec();
+            if *ttl_ms > 0 || *lease > 0 {
+                hint.push(HINT_LEASED);
+                hint.extend_from_slice(&ttl_ms.to_le_bytes());
+                hint.extend_from_slice(&lease.to_le_bytes());
+            } else {
+                hint.push(HINT_SET);
+            }
+            hint.extend_from_slice(value);
+            key
+        }
+        PeerWrite::Streamed { key, seq } => {
+            hint = seq.to_le_bytes().to_vec();
+            hint.push(HINT_STREAMED);
+            key
+        }
+        PeerWrite::Remove { key, seq } => {
+            hint = seq.to_le_bytes().to_vec();
+            hint.push(HINT_REMOVE);
+            key
+        }
+    };
+    (key, hint)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+fn hint_seq(hint: &[u8]) -> u64 {
+    read_u64(hint)
+}
+
+fn decode_hint(key: String, hint: &[u8]) -> Option<PeerWrite> {
+    if hint.len() < 9 {
+        return None;
+    }
+    let seq = hint_seq(hint);
+    match hint[8] {
+        HINT_REMOVE => Some(PeerWrite::Remove { key, seq }),
+        HINT_STREAMED => Some(PeerWrite::Streamed { key, seq }),
+        HINT_LEASED if hint.len() >= 25 => Some(PeerWrite::Set {
+            key,
+            value: hint[25..].to_vec(),
+            seq,
+            ttl_ms: read_u64(&hint[9..]),
+            lease: read_u64(&hint[17..]),
+        }),
+        HINT_SET => Some(PeerWrite::set(key, &hint[9..], seq)),
+        _ => None,
+    }
+}
+
+// The time to live of a lease, in milliseconds, 0 once it expired.
+async fn lease_ttl_ms(db: &CrabeDB, lease: u64) -> Result<u64, Status> {
+    Ok(db.lease_info(lease).await?.map_or(0, |info| info.ttl.as_millis() as u64))
+}
+
+// Keep the write for the peer, unless it already has a hint for a more recent one.
+async fn store_hint(db: &CrabeDB, addr: &str, write: &PeerWrite) -> Result<(), Error> {
+    let (key, hint) = encode_hint(write);
+    let hint_key = format!("{}{}", hint_prefix(addr), key);
+    loop {
+        let current = db.get(hint_key.clone()).await?;
+        if current.as_ref().is_some_and(|current| current.len() >= 8 && hint_seq(current) >= hint_seq(&hint)) {
+            return Ok(());
+        }
+        match db.compare_and_swap_as(None, hint_key.clone(), current, Some(hint.clone()), None).await? {
+            CasResult::Swapped(_) => return Ok(()),
+            CasResult::Mismatch(_) => continue,
+        }
+    }
+}
+
+// Replay the hints of every peer, in the background. A peer still unreachable keeps its
+// hints until the next attempt.
+async fn replay_hints(db: CrabeDB, peers: Peers) {
+    loop {
+        tokio::time::sleep(HINT_REPLAY_INTERVAL).await;
+        for (addr, client) in &peers.clients {
+            match replay_peer_hints(&db, &peers, addr, client.clone()).await {
+                Ok(0) => {}
+                Ok(replayed) => info!("Replayed {} hinted writes to {}", replayed, addr),
+                Err(status) => debug!("Couldn't replay the hints of {}: {}", addr, status.message()),
+            }
+        }
+    }
+}
+
+async fn replay_peer_hints(
+    db: &CrabeDB,
+    peers: &Peers,
+    addr: &str,
+    client: KvstoreClient<Channel>,
+) -> Result<usize, Status> {
+    let prefix = hint_prefix(addr);
+    let mut cursor = Vec::new();
+    let mut replayed = 0;
+    loop {
+        let hint_keys = db.list_keys(prefix.clone(), cursor, HINT_REPLAY_BATCH_SIZE).await?;
+        let hint_key = match hint_keys.last() {
+            Some(hint_key) => hint_key.clone(),
+            None => return Ok(replayed),
+        };
+        for hint_key in hint_keys {
+            let hint = match db.get(hint_key.clone()).await? {
+                Some(hint) => hint,
+                None => continue,
+            };
+            let key = String::from_utf8_lossy(&hint_key[prefix.len()..]).into_owned();
+            if let Some(write) = decode_hint(key, &hint) {
+                forward_write(db, peers, client.clone(), write).await?;
+                replayed += 1;
+            }
+            // A hint written again in the meantime is replayed on the next attempt.
+            db.compare_and_swap_as(None, hint_key, Some(hint), None, None).await?;
+        }
+        cursor = hint_key;
+    }
+}
+
+impl KvStoreAPI {
+    // In a failover group, the writes to the default store go to its primary.
+    async fn check_primary(&self, replicated: bool) -> Result<(), Status> {
+        match self.election {
+            Some(ref election) if replicated => election.check_leader().await,
+            _ => Ok(()),
+        }
+    }
+
+    // The locks of the default store of a sharded deployment would be moved by its
+    // rebalances away from their lease, which stays on its node.
+    async fn check_lockable(&self, replicated: bool) -> Result<(), Status> {
+        if replicated && self.routing.is_sharded().await {
+            return Err(Status::failed_precondition("A sharded deployment doesn't serve locks"));
+        }
+        Ok(())
+    }
+
+    // Try to acquire a lock on this node, once.
+    async fn try_lock(
+        &self,
+        db: &CrabeDB,
+        peer: Option<String>,
+        replicated: bool,
+        payload: &LockRequest,
+    ) -> Result<LockResponse, Status> {
+        let key = lock_key(&payload.name);
+        let token = format!("{:032x}", rand::random::<u128>());
+        let result = db.compare_and_swap_as(
+            peer,
+            key.clone(),
+            None,
+            Some(lock_value(payload.lease, &token)),
+            Some(payload.lease),
+        ).await.map_err(lease_status)?;
+
+        let current = match result {
+            CasResult::Swapped(seq) => {
+                let write = PeerWrite::Set {
+                    key: key.clone(),
+                    value: lock_value(payload.lease, &token),
+                    seq,
+                    ttl_ms: lease_ttl_ms(db, payload.lease).await?,
+                    lease: payload.lease,
+                };
+                self.replicate_write(replicated, write, Consistency::One as i32).await?;
+                return Ok(LockResponse { acquired: true, key, seq, owner: payload.lease, token });
+            }
+            CasResult::Mismatch(current) => current.unwrap_or_default(),
+        };
+
+        // Already held by the lease with the token, a single unlock releases it all the same.
+        if !payload.token.is_empty() && current == lock_value(payload.lease, &payload.token) {
+            if let Some((_, metadata)) = db.get_with_metadata(key.clone()).await? {
+                return Ok(LockResponse {
+                    acquired: true,
+                    key,
+                    seq: metadata.seq,
+                    owner: payload.lease,
+                    token: payload.token.clone(),
+                });
+            }
+        }
+        Ok(LockResponse { acquired: false, key, seq: 0, owner: lock_owner(&current), token: String::new() })
+    }
+
+    // Try to acquire a lock once on the peer serving the locks, which mirrors the lease.
+    async fn forward_lock(
+        &self,
+        db: &CrabeDB,
+        mut client: KvstoreClient<Channel>,
+        payload: &LockRequest,
+    ) -> Result<LockResponse, Status> {
+        let lease_ttl_ms = lease_ttl_ms(db, payload.lease).await?;
+        if lease_ttl_ms == 0 {
+            return Err(lease_status(Error::LeaseNotFound(payload.lease)));
+        }
+        let request = self.peers.replica_request(LockRequest {
+            timeout_ms: 0,
+            lease_ttl_ms,
+            ..payload.clone()
+        })?;
+        Ok(client.kv_lock_call(request).await?.into_inner())
+    }
+
+    // Only the default store is replicated: the requests to the named ones can't wait for
+    // more nodes than this one.
+    fn check_consistency(&self, replicated: bool, consistency: i32) -> Result<(), NotReplicated> {
+        if !replicated && self.peers.required(consistency) > 1 {
+            return Err(NotReplicated);
+        }
+        Ok(())
+    }
+
+    // Forward a write to the peers and wait for enough of them to acknowledge it. A peer
+    // which can't be reached gets a hint, replayed once it's back.
+    async fn replicate_write(&self, replicated: bool, write: PeerWrite, consistency: i32) -> Result<(), Status> {
+        let required = self.peers.required(consistency) - 1;
+        if !replicated || self.peers.clients.is_empty() {
+            return Ok(());
+        }
+
+        let (tx, mut rx) = mpsc::channel(self.peers.clients.len());
+        for (addr, client) in self.peers.clients.iter().cloned() {
+            let (tx, write, db, peers) = (tx.clone(), write.clone(), self.stores.default.clone(), self.peers.clone());
+            tokio::spawn(async move {
+                let result = forward_write(&db, &peers, client, write.clone()).await;
+                if let Err(ref status) = result {
+                    warn!("Couldn't forward a write to {}: {}", addr, status.message());
+                    if let Err(err) = store_hint(&db, &addr, &write).await {
+                        warn!("Couldn't keep a hint for {}: {}", addr, err);
+                    }
+                }
+                let _ = tx.send(result.is_ok()).await;
+            });
+        }
+        drop(tx);
+
+        let mut acks = 0;
+        while acks < required {
+            match rx.recv().await {
+                Some(true) => acks += 1,
+                Some(false) => {}
+                None => {
+                    return Err(Status::unavailable(format!(
+                        "the write reached {} of the {} nodes required",
+                        acks + 1,
+                        required + 1
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Merge a CRDT value forwarded by a peer into the local version of the key. Returns
+    // false when there's no local version of the same type, the most recent one winning.
+    async fn merge_crdt(&self, key: &str, crdt: &Crdt) -> Result<bool, Status> {
+        loop {
+            let current = self.stores.default.get(key).await?;
+            let merged = match current.as_deref().and_then(Crdt::decode).and_then(|local| local.merge(crdt)) {
+                Some(merged) => merged,
+                None => return Ok(false),
+            };
+            if current.as_deref() == Some(merged.encode().as_bytes()) {
+                return Ok(true);
+            }
+            let result = self.stores.default.compare_and_swap_as(
+                None,
+                key,
+                current,
+                Some(merged.encode().into_bytes()),
+                None,
+            ).await?;
+            if let CasResult::Swapped(_) = result {
+                return Ok(true);
+            }
+        }
+    }
+
+    // Read the key from enough nodes and return the most recent version found.
+    async fn quorum_get(&self, payload: GetRequest, required: usize) -> Result<GetResponse, Status> {
+        let (tx, mut rx) = mpsc::channel(self.peers.clients.len());
+        for (addr, mut client) in self.peers.clients.iter().cloned() {
+            let tx = tx.clone();
+            let request = GetRequest {
+                key: payload.key.clone(),
+                min_seq: 0,
+                with_metadata: true,
+                consistency: Consistency::One as i32,
+            };
+            tokio::spawn(async move {
+                let result = client.kv_get_call(request).await.map(Response::into_inner);
+                if let Err(ref status) = result {
+                    warn!("Couldn't read from {}: {}", addr, status.message());
+                }
+                let _ = tx.send(result.ok()).await;
+            });
+        }
+        drop(tx);
+
+        let mut latest = match self.stores.default.get_with_metadata(payload.key).await? {
+            Some((val, metadata)) => GetResponse {
+                exist: true,
+                value: String::from_utf8_lossy(&val).into_owned(),
+                metadata: Some(value_metadata(&metadata)),
+            },
+            None => GetResponse {
+                exist: false,
+                value: String::new(),
+                metadata: None,
+            },
+        };
+        let mut replies = 1;
+        while replies < required {
+            let response = match rx.recv().await {
+                Some(Some(response)) => response,
+                Some(None) => continue,
+                None => {
+                    return Err(Status::unavailable(format!(
+                        "the read reached {} of the {} nodes required",
+                        replies,
+                        required
+                    )));
+                }
+            };
+            replies += 1;
+            let seq = |response: &GetResponse| response.metadata.as_ref().map_or(0, |metadata| metadata.seq);
+            if response.exist && (!latest.exist || seq(&response) > seq(&latest)) {
+                latest = response;
+            }
+        }
+
+        if !payload.with_metadata {
+            latest.metadata = None;
+        }
+        Ok(latest)
+    }
+}
+
 #[tonic::async_trait]
 impl Kvstore for KvStoreAPI {
+    type KvGetStreamCallStream = Pin<Box<dyn Stream<Item = Result<ValueChunk, Status>> + Send + Sync>>;
+    type KvWatchCallStream = Pin<Box<dyn Stream<Item = Result<WatchEvent, Status>> + Send + Sync>>;
+    type KvScanCallStream = Pin<Box<dyn Stream<Item = Result<KeyValue, Status>> + Send + Sync>>;
+
     async fn kv_get_call(
         &self,
         request: Request<GetRequest>
     ) -> Result<Response<GetResponse>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let replicated = is_default_store(&request);
         let payload = request.into_inner();
         debug!("Key in payload: {:?}", &payload.key);
+        check_client_key(&payload.key)?;
+        let _routing = self.routing.check(replicated, &[&payload.key]).await?;
+        self.check_consistency(replicated, payload.consistency)?;
 
-        let v = self.db.get(&payload.key)?;
-        match v {
-            Some(val) => {
+        let required = self.peers.required(payload.consistency);
+        if required > 1 {
+            return Ok(Response::new(self.quorum_get(payload, required).await?));
+        }
+
+        let v = if payload.min_seq > 0 {
+            db.get_at_least(payload.key.clone(), payload.min_seq, MIN_SEQ_TIMEOUT).await
+                .map_err(|err| match err {
+                    Error::SequenceNotApplied { .. } => Status::unavailable(err.to_string()),
+                    err => Status::from(err),
+                })?
+        } else if payload.with_metadata {
+            None
+        } else {
+            db.get(payload.key.clone()).await?
+        };
+        // Once the sequence number is visible, the value is read again with its metadata.
+        let (v, metadata) = if payload.with_metadata {
+            match db.get_with_metadata(payload.key).await? {
+                Some((val, metadata)) => (Some(val), Some(value_metadata(&metadata))),
+                None => (None, None),
+            }
+        } else {
+            (v, None)
+        };
+        match v {
+            Some(val) => {
                 let response = GetResponse {
                     exist: true,
-                    value: String::from(str::from_utf8(&val).unwrap()),
+                    // A binary value, only set with KvSetStreamCall, can't be sent whole.
+                    value: String::from_utf8_lossy(&val).into_owned(),
+                    metadata,
                 };
                 Ok(Response::new(response))
             }
@@ -47,56 +1111,2455 @@ impl Kvstore for KvStoreAPI {
                 let response = GetResponse {
                     exist: false,
                     value: String::from(""),
+                    metadata: None,
+                };
+                Ok(Response::new(response))
+            }
+        }
+    }
+
+    async fn kv_set_call(
+        &self,
+        request: Request<SetRequest>
+    ) -> Result<Response<SetResponse>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let replicated = is_default_store(&request);
+        if request.get_ref().replica_seq > 0 {
+            self.peers.check_replica(&request)?;
+        }
+        let peer = peer_identity(&request);
+        let remote_addr = request.remote_addr();
+        let payload = request.into_inner();
+        debug!("Key in payload: {:?}, Value in payload : {:?}", &payload.key, &payload.value);
+
+        self.check_primary(replicated).await?;
+        let _routing = self.routing.check(replicated, &[&payload.key]).await?;
+        self.write_limits.check(remote_addr)?;
+        self.check_consistency(replicated, payload.consistency)?;
+        self.size_limits.check_key(&payload.key)?;
+        self.size_limits.check_value(payload.value.len())?;
+        if payload.replica_seq == 0 {
+            check_client_key(&payload.key)?;
+        }
+
+        if payload.replica_seq > 0 {
+            // Concurrent versions of a CRDT value are merged rather than ordered.
+            if let Some(crdt) = Crdt::decode(payload.value.as_bytes()) {
+                if self.merge_crdt(&payload.key, &crdt).await? {
+                    return Ok(Response::new(SetResponse { success: true, seq: payload.replica_seq }));
+                }
+            }
+            let log = Log::new(payload.replica_seq, payload.key.into_bytes(), payload.value.into_bytes())?;
+            if payload.ttl_ms > 0 || payload.lease > 0 {
+                db.merge_with_lease(log, payload.lease, Duration::from_millis(payload.ttl_ms)).await?;
+            } else {
+                db.merge(log).await?;
+            }
+            return Ok(Response::new(SetResponse { success: true, seq: payload.replica_seq }));
+        }
+
+        if payload.lease > 0 && payload.ttl_ms > 0 {
+            return Err(Status::invalid_argument("A key can't have both a lease and a time to live"));
+        }
+        let result = if payload.ttl_ms > 0 {
+            let ttl = Duration::from_millis(payload.ttl_ms);
+            db.set_with_ttl_as(peer, payload.key.clone(), payload.value.clone(), ttl).await
+        } else if payload.lease > 0 {
+            db.set_with_lease_as(peer, payload.key.clone(), payload.value.clone(), payload.lease).await
+        } else {
+            db.set_as(peer, payload.key.clone(), payload.value.clone()).await
+        };
+        match result {
+            Ok(seq) => {
+                let ttl_ms = match payload.lease {
+                    0 => payload.ttl_ms,
+                    lease => lease_ttl_ms(&db, lease).await?,
+                };
+                let write = PeerWrite::Set { key: payload.key, value: payload.value.into_bytes(), seq, ttl_ms, lease: payload.lease };
+                self.replicate_write(replicated, write, payload.consistency).await?;
+                let response = SetResponse {
+                    success: true,
+                    seq,
+                };
+                Ok(Response::new(response))
+            }
+            // The client is expected to back off and retry.
+            Err(err @ (Error::Busy(_) | Error::DeadlineExceeded)) => Err(Status::from(err)),
+            Err(_) => {
+                let response = SetResponse {
+                    success: false,
+                    seq: 0,
+                };
+                Ok(Response::new(response))
+            }
+        }
+    }
+
+    async fn kv_remove_call(
+        &self,
+        request: Request<RemoveRequest>
+    ) -> Result<Response<RemoveResponse>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let replicated = is_default_store(&request);
+        if request.get_ref().replica_seq > 0 {
+            self.peers.check_replica(&request)?;
+        }
+        let peer = peer_identity(&request);
+        let remote_addr = request.remote_addr();
+        let payload = request.into_inner();
+        debug!("Key in payload: {:?}", &payload.key);
+
+        self.check_primary(replicated).await?;
+        let _routing = self.routing.check(replicated, &[&payload.key]).await?;
+        self.write_limits.check(remote_addr)?;
+        self.check_consistency(replicated, payload.consistency)?;
+        self.size_limits.check_key(&payload.key)?;
+        if payload.replica_seq == 0 {
+            check_client_key(&payload.key)?;
+        }
+
+        if payload.replica_seq > 0 {
+            db.merge(Log::deleted(payload.replica_seq, payload.key.into_bytes())).await?;
+            return Ok(Response::new(RemoveResponse { success: true, seq: payload.replica_seq }));
+        }
+
+        match db.remove_as(peer, payload.key.clone()).await {
+            Ok(seq) => {
+                let write = PeerWrite::Remove { key: payload.key, seq };
+                self.replicate_write(replicated, write, payload.consistency).await?;
+                let response = RemoveResponse {
+                    success: true,
+                    seq,
                 };
                 Ok(Response::new(response))
             }
+            Err(err @ Error::DeadlineExceeded) => Err(Status::from(err)),
+            Err(_) => {
+                let response = RemoveResponse {
+                    success: false,
+                    seq: 0,
+                };
+                Ok(Response::new(response))
+            }
+        }
+    }
+
+    // The operations are checked first, then applied together: a failed operation doesn't
+    // stop the others.
+    async fn kv_batch_call(
+        &self,
+        request: Request<BatchRequest>
+    ) -> Result<Response<BatchResponse>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let replicated = is_default_store(&request);
+        self.check_primary(replicated).await?;
+        self.write_limits.check(request.remote_addr())?;
+        let peer = peer_identity(&request);
+        let payload = request.into_inner();
+        debug!("Batch of {} operations", payload.ops.len());
+        let keys: Vec<&str> = payload.ops.iter().map(|op| op.key.as_str()).collect();
+        let _routing = self.routing.check(replicated, &keys).await?;
+        self.check_consistency(replicated, payload.consistency)?;
+
+        let mut batch = WriteBatch::new();
+        for op in &payload.ops {
+            self.size_limits.check_key(&op.key)?;
+            check_client_key(&op.key)?;
+            match BatchOpType::from_i32(op.r#type) {
+                Some(BatchOpType::Set) => {
+                    self.size_limits.check_value(op.value.len())?;
+                    batch.set(op.key.as_str(), op.value.as_str());
+                }
+                Some(BatchOpType::Remove) => {
+                    batch.remove(op.key.as_str());
+                }
+                None => return Err(Status::invalid_argument("Unknown batch operation")),
+            }
+        }
+
+        let results = db.write_as(peer, batch).await?;
+        let mut responses = Vec::with_capacity(results.len());
+        for (op, result) in payload.ops.into_iter().zip(results) {
+            match result {
+                Ok(seq) => {
+                    let write = match op {
+                        BatchOp { r#type, key, .. } if r#type == BatchOpType::Remove as i32 => {
+                            PeerWrite::Remove { key, seq }
+                        }
+                        BatchOp { key, value, .. } => PeerWrite::set(key, value, seq),
+                    };
+                    self.replicate_write(replicated, write, payload.consistency).await?;
+                    responses.push(BatchOpResult { success: true, seq, error: String::new() });
+                }
+                Err(err) => responses.push(BatchOpResult { success: false, seq: 0, error: err.to_string() }),
+            }
+        }
+        Ok(Response::new(BatchResponse { results: responses }))
+    }
+
+    async fn kv_list_keys_call(
+        &self,
+        request: Request<ListKeysRequest>
+    ) -> Result<Response<ListKeysResponse>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let payload = request.into_inner();
+        debug!("Prefix in payload: {:?}, cursor in payload: {:?}", &payload.prefix, &payload.cursor);
+
+        let limit = match payload.limit as usize {
+            0 => DEFAULT_LIST_KEYS_LIMIT,
+            limit => limit.min(MAX_LIST_KEYS_LIMIT),
+        };
+
+        // One more key tells whether there is a next page. The keys of the server are
+        // skipped.
+        let mut keys = Vec::new();
+        let mut cursor = payload.cursor;
+        loop {
+            let page = db.list_keys(payload.prefix.clone(), cursor.clone(), limit + 1).await?;
+            let last = page.len() <= limit;
+            if let Some(key) = page.last() {
+                cursor = key.clone();
+            }
+            keys.extend(page.into_iter().filter(|key| !is_node_key(key)));
+            if last || keys.len() > limit {
+                break;
+            }
+        }
+        let next_cursor = if keys.len() > limit {
+            keys.truncate(limit);
+            keys[limit - 1].clone()
+        } else {
+            Vec::new()
+        };
+
+        Ok(Response::new(ListKeysResponse {
+            keys: keys.iter().map(|key| String::from_utf8_lossy(key).into_owned()).collect(),
+            next_cursor,
+        }))
+    }
+
+    async fn kv_scan_call(
+        &self,
+        request: Request<ScanRequest>
+    ) -> Result<Response<Self::KvScanCallStream>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let payload = request.into_inner();
+        debug!("Prefix in payload: {:?}, limit in payload: {}", &payload.prefix, payload.limit);
+        let stream = scan_pairs(db, payload.prefix.into_bytes(), payload.limit);
+        Ok(Response::new(stream))
+    }
+
+    async fn kv_ttl_call(
+        &self,
+        request: Request<TtlRequest>
+    ) -> Result<Response<TtlResponse>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let replicated = is_default_store(&request);
+        let payload = request.into_inner();
+        debug!("Key in payload: {:?}", &payload.key);
+        check_client_key(&payload.key)?;
+        let _routing = self.routing.check(replicated, &[&payload.key]).await?;
+
+        // A key with neither a lease nor a time to live of its own has none.
+        let lease = db.key_lease(payload.key.clone()).await?;
+        let exist = lease.is_some() || db.get_with_metadata(payload.key).await?.is_some();
+        let response = match lease {
+            Some(lease) => TtlResponse {
+                exist,
+                lease: lease.id,
+                ttl_ms: lease.ttl.as_millis() as u64,
+                remaining_ms: lease.remaining.as_millis() as u64,
+            },
+            None => TtlResponse {
+                exist,
+                lease: 0,
+                ttl_ms: 0,
+                remaining_ms: 0,
+            },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn kv_get_stream_call(
+        &self,
+        request: Request<GetStreamRequest>
+    ) -> Result<Response<Self::KvGetStreamCallStream>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let replicated = is_default_store(&request);
+        let payload = request.into_inner();
+        debug!("Key in payload: {:?}", &payload.key);
+        check_client_key(&payload.key)?;
+        let _routing = self.routing.check(replicated, &[&payload.key]).await?;
+
+        let chunk_size = match payload.chunk_size as usize {
+            0 => self.chunk_size,
+            chunk_size => chunk_size,
+        };
+
+        // A value of a blob file is read a chunk at a time, as the chunks are sent. An empty
+        // or missing value still gets one (empty) chunk.
+        let reader = db.value_reader(payload.key).await?;
+        let stream = try_stream! {
+            let mut reader = match reader {
+                Some(reader) => reader,
+                None => {
+                    yield ValueChunk { exist: false, total_size: 0, data: Vec::new() };
+                    return;
+                }
+            };
+            let total_size = reader.size();
+            let mut first = true;
+            loop {
+                let (next, data) = reader.read(chunk_size).await?;
+                reader = next;
+                if data.is_empty() && !first {
+                    break;
+                }
+                first = false;
+                yield ValueChunk { exist: true, total_size, data: data.to_vec() };
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn kv_set_stream_call(
+        &self,
+        request: Request<Streaming<SetStreamRequest>>
+    ) -> Result<Response<SetResponse>, Status> {
+        let db = self.stores.get(&request)?.with_deadline(request_deadline(&request));
+        let replicated = is_default_store(&request);
+        self.check_primary(replicated).await?;
+        self.write_limits.check(request.remote_addr())?;
+        let peer = peer_identity(&request);
+        let replica = self.peers.check_replica(&request);
+        let mut stream = request.into_inner();
+
+        let first = match stream.message().await? {
+            Some(first) => first,
+            None => return Err(Status::invalid_argument("empty stream")),
+        };
+        let (key, replica_seq, total_size) = (first.key, first.replica_seq, first.total_size);
+        if replica_seq > 0 {
+            replica?;
+        } else {
+            check_client_key(&key)?;
+        }
+        self.size_limits.check_key(&key)?;
+        self.size_limits.check_value(first.data.len())?;
+        let _routing = self.routing.check(replicated, &[&key]).await?;
+
+        // The chunks are written through as they arrive, see `ValueWriter`: the value is
+        // dropped unless it was received whole.
+        let mut writer = db.value_writer(key.clone()).await?.write(first.data).await?;
+        while let Some(chunk) = stream.message().await? {
+            self.size_limits.check_value(writer.size() as usize + chunk.data.len())?;
+            writer = writer.write(chunk.data).await?;
+        }
+        debug!("Key in payload: {:?}, value of {} bytes", &key, writer.size());
+        if total_size > 0 && writer.size() != total_size {
+            return Err(Status::data_loss(format!(
+                "received {} of the {} bytes of the value",
+                writer.size(),
+                total_size
+            )));
+        }
+
+        if replica_seq > 0 {
+            writer.merge(replica_seq).await?;
+            return Ok(Response::new(SetResponse { success: true, seq: replica_seq }));
+        }
+
+        let response = match writer.commit(peer).await {
+            Ok(seq) => {
+                let write = PeerWrite::Streamed { key, seq };
+                self.replicate_write(replicated, write, Consistency::One as i32).await?;
+                SetResponse { success: true, seq }
+            }
+            Err(err @ (Error::Busy(_) | Error::DeadlineExceeded)) => return Err(Status::from(err)),
+            Err(_) => SetResponse { success: false, seq: 0 },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn kv_watch_call(
+        &self,
+        request: Request<WatchRequest>
+    ) -> Result<Response<Self::KvWatchCallStream>, Status> {
+        let db = self.stores.get(&request)?;
+        let payload = request.into_inner();
+        let stream = watch_events(db.clone(), payload.prefix.into_bytes(), payload.start_seq);
+        Ok(Response::new(stream))
+    }
+
+    // A lock is a key holding the id of its lease and a random token, created only when it
+    // doesn't exist: it's removed by an unlock with the token, or along with the lease. A
+    // lock must be granted by a single node: in a cluster of peers, the other nodes forward
+    // the requests to the one serving the locks, see `Peers::lock_node`, and a sharded
+    // deployment, whose rebalances would move them away from their lease, refuses them.
+    async fn kv_lock_call(
+        &self,
+        request: Request<LockRequest>
+    ) -> Result<Response<LockResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let replicated = is_default_store(&request);
+        self.check_primary(replicated).await?;
+        self.write_limits.check(request.remote_addr())?;
+        let forwarded = request.metadata().contains_key(CLUSTER_SECRET_METADATA);
+        if forwarded {
+            self.peers.check_replica(&request)?;
+        }
+        let peer = peer_identity(&request);
+        let payload = request.into_inner();
+        if payload.lease == 0 {
+            return Err(Status::invalid_argument("A lock must be held by a lease"));
+        }
+        self.check_lockable(replicated).await?;
+        let lock_node = if replicated && !forwarded { self.peers.lock_node(&self.routing.address) } else { None };
+        if forwarded && payload.lease_ttl_ms > 0 {
+            db.mirror_lease(payload.lease, Duration::from_millis(payload.lease_ttl_ms)).await.map_err(lease_status)?;
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(payload.timeout_ms);
+        loop {
+            let response = match lock_node {
+                Some((_, ref client)) => self.forward_lock(db, client.clone(), &payload).await?,
+                None => self.try_lock(db, peer.clone(), replicated, &payload).await?,
+            };
+            if response.acquired || Instant::now() >= deadline {
+                return Ok(Response::new(response));
+            }
+            tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn kv_unlock_call(
+        &self,
+        request: Request<UnlockRequest>
+    ) -> Result<Response<UnlockResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let replicated = is_default_store(&request);
+        self.check_primary(replicated).await?;
+        self.write_limits.check(request.remote_addr())?;
+        let forwarded = request.metadata().contains_key(CLUSTER_SECRET_METADATA);
+        if forwarded {
+            self.peers.check_replica(&request)?;
+        }
+        let peer = peer_identity(&request);
+        let payload = request.into_inner();
+        self.check_lockable(replicated).await?;
+        if replicated && !forwarded {
+            if let Some((_, mut client)) = self.peers.lock_node(&self.routing.address) {
+                let response = client.kv_unlock_call(self.peers.replica_request(payload)?).await?;
+                return Ok(Response::new(response.into_inner()));
+            }
+        }
+
+        let key = lock_key(&payload.name);
+        let result = db.compare_and_swap_as(
+            peer,
+            key.clone(),
+            Some(lock_value(payload.lease, &payload.token)),
+            None,
+            None,
+        ).await?;
+        let response = match result {
+            CasResult::Swapped(seq) => {
+                self.replicate_write(replicated, PeerWrite::Remove { key, seq }, Consistency::One as i32).await?;
+                UnlockResponse { released: true, seq }
+            }
+            CasResult::Mismatch(_) => UnlockResponse { released: false, seq: 0 },
+        };
+        Ok(Response::new(response))
+    }
+
+    // Updated with a compare-and-swap, so concurrent updates on the node all apply, then
+    // forwarded to the peers like a set, which merge it with their own version.
+    async fn kv_crdt_update_call(
+        &self,
+        request: Request<CrdtUpdateRequest>
+    ) -> Result<Response<CrdtValue>, Status> {
+        let db = self.stores.get(&request)?;
+        let replicated = is_default_store(&request);
+        self.check_primary(replicated).await?;
+        self.write_limits.check(request.remote_addr())?;
+        let peer = peer_identity(&request);
+        let payload = request.into_inner();
+        check_client_key(&payload.key)?;
+        let _routing = self.routing.check(replicated, &[&payload.key]).await?;
+        self.check_consistency(replicated, payload.consistency)?;
+        self.size_limits.check_key(&payload.key)?;
+        self.size_limits.check_value(payload.value.len())?;
+        let op = CrdtOp::from_i32(payload.op)
+            .ok_or_else(|| Status::invalid_argument("Unknown CRDT operation"))?;
+        let empty = match op {
+            CrdtOp::Assign => Crdt::lww_register(),
+            CrdtOp::Increment => Crdt::g_counter(),
+            CrdtOp::Add | CrdtOp::Remove => Crdt::or_set(),
+        };
+
+        loop {
+            let current = db.get(payload.key.clone()).await?;
+            let mut crdt = match current.as_deref().map(Crdt::decode) {
+                Some(Some(crdt)) if crdt.is_same_type(&empty) => crdt,
+                Some(_) => {
+                    return Err(Status::failed_precondition(format!(
+                        "Key {:?} doesn't hold a CRDT value of the type updated by {:?}",
+                        payload.key,
+                        op
+                    )));
+                }
+                None => empty.clone(),
+            };
+            match op {
+                CrdtOp::Assign => crdt.assign(&self.node_id, payload.value.clone(), now_millis()),
+                CrdtOp::Increment => crdt.increment(&self.node_id, payload.increment),
+                CrdtOp::Add => {
+                    let tag = format!("{}/{:016x}", self.node_id, rand::random::<u64>());
+                    crdt.add(payload.value.clone(), tag);
+                }
+                CrdtOp::Remove => crdt.remove(&payload.value),
+            }
+
+            let value = crdt.encode();
+            let result = db.compare_and_swap_as(
+                peer.clone(),
+                payload.key.clone(),
+                current,
+                Some(value.clone().into_bytes()),
+                None,
+            ).await?;
+            if let CasResult::Swapped(seq) = result {
+                let write = PeerWrite::set(payload.key, value, seq);
+                self.replicate_write(replicated, write, payload.consistency).await?;
+                return Ok(Response::new(crdt_value(&crdt, seq)));
+            }
+        }
+    }
+
+    async fn kv_crdt_get_call(
+        &self,
+        request: Request<CrdtGetRequest>
+    ) -> Result<Response<CrdtValue>, Status> {
+        let db = self.stores.get(&request)?;
+        let replicated = is_default_store(&request);
+        let payload = request.into_inner();
+        check_client_key(&payload.key)?;
+        let _routing = self.routing.check(replicated, &[&payload.key]).await?;
+        let (value, metadata) = match db.get_with_metadata(payload.key.clone()).await? {
+            Some(found) => found,
+            None => return Ok(Response::new(CrdtValue::default())),
+        };
+        let crdt = Crdt::decode(&value).ok_or_else(|| {
+            Status::failed_precondition(format!("Key {:?} doesn't hold a CRDT value", payload.key))
+        })?;
+        Ok(Response::new(crdt_value(&crdt, metadata.seq)))
+    }
+}
+
+// Events are read from the data files, the missed ones being replayed from `start_seq` on.
+// Records rewritten by a compaction after newer ones aren't replayed, like with `Tail`.
+fn watch_events(
+    db: CrabeDB,
+    prefix: Vec<u8>,
+    start_seq: u64,
+) -> Pin<Box<dyn Stream<Item = Result<WatchEvent, Status>> + Send + Sync>> {
+    let stream = try_stream! {
+        let mut tail = if start_seq > 0 {
+            db.tail(0, 0)
+        } else {
+            let position = db.tail_position().await?;
+            db.tail(position.file_id, position.pos)
+        };
+        loop {
+            let (next_tail, logs) = db.read_tail(tail, TAIL_BATCH_SIZE).await?;
+            tail = next_tail;
+            if logs.is_empty() {
+                tokio::time::sleep(TAIL_POLL_INTERVAL).await;
+                continue;
+            }
+            for log in logs.iter().filter(|log| log.seq >= start_seq && watches(&prefix, log)) {
+                if !log.range && is_node_key(&log.key) {
+                    continue;
+                }
+                yield WatchEvent {
+                    key: String::from_utf8_lossy(&log.key).into_owned(),
+                    value: if log.deleted {
+                        String::new()
+                    } else {
+                        String::from_utf8_lossy(&log.value).into_owned()
+                    },
+                    seq: log.seq,
+                    deleted: log.deleted,
+                    range: log.range,
+                    range_end: if log.range {
+                        String::from_utf8_lossy(&log.value).into_owned()
+                    } else {
+                        String::new()
+                    },
+                };
+            }
+        }
+    };
+    Box::pin(stream)
+}
+
+// The pairs are read one page of keys at a time, as they are sent, instead of all at once.
+fn scan_pairs(
+    db: CrabeDB,
+    prefix: Vec<u8>,
+    limit: u64,
+) -> Pin<Box<dyn Stream<Item = Result<KeyValue, Status>> + Send + Sync>> {
+    let stream = try_stream! {
+        let mut cursor = Vec::new();
+        let mut sent = 0;
+        loop {
+            let keys = db.list_keys(prefix.clone(), cursor, SCAN_BATCH_SIZE).await?;
+            cursor = match keys.last() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            for key in keys.into_iter().filter(|key| !is_node_key(key)) {
+                // The key may have been removed since it was listed.
+                let value = match db.get(key.clone()).await? {
+                    Some(value) => value,
+                    None => continue,
+                };
+                yield KeyValue {
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    value: String::from_utf8_lossy(&value).into_owned(),
+                };
+                sent += 1;
+                if sent == limit {
+                    return;
+                }
+            }
+        }
+    };
+    Box::pin(stream)
+}
+
+// Whether the record touches a key starting with `prefix`.
+fn watches(prefix: &[u8], log: &Log) -> bool {
+    if !log.range {
+        return log.key.starts_with(prefix);
+    }
+    // The range [key, value) overlaps the keys starting with the prefix.
+    let (start, end) = (&log.key[..], &log.value[..]);
+    (end.is_empty() || end > prefix) && (start <= prefix || start.starts_with(prefix))
+}
+
+fn crdt_value(crdt: &Crdt, seq: u64) -> CrdtValue {
+    let crdt_type = match crdt {
+        Crdt::LwwRegister { .. } => CrdtType::LwwRegister,
+        Crdt::GCounter { .. } => CrdtType::GCounter,
+        Crdt::OrSet { .. } => CrdtType::OrSet,
+    };
+    CrdtValue {
+        exist: true,
+        r#type: crdt_type as i32,
+        value: crdt.value().unwrap_or_default().to_string(),
+        count: crdt.count(),
+        elements: crdt.elements(),
+        seq,
+    }
+}
+
+fn lock_key(name: &str) -> String {
+    format!("{}{}", LOCK_KEY_PREFIX, name)
+}
+
+// The value of a lock, `<lease>/<token>`.
+fn lock_value(lease: u64, token: &str) -> Vec<u8> {
+    format!("{}/{}", lease, token).into_bytes()
+}
+
+fn lock_owner(value: &[u8]) -> u64 {
+    let value = String::from_utf8_lossy(value);
+    value.split('/').next().unwrap_or_default().parse().unwrap_or(0)
+}
+
+// The keys the server keeps for itself, the hints of its peers, its locks and its routing
+// table.
+fn is_node_key(key: &[u8]) -> bool {
+    key.starts_with(HINT_KEY_PREFIX.as_bytes())
+        || key.starts_with(LOCK_KEY_PREFIX.as_bytes())
+        || key == ROUTING_KEY.as_bytes()
+}
+
+// The clients can't read or write the keys of the server, which are left out of the lists,
+// scans and watches too. Only the other nodes of a cluster forward writes to them.
+fn check_client_key(key: &str) -> Result<(), ReservedKey> {
+    if is_node_key(key.as_bytes()) {
+        return Err(ReservedKey(key.to_string()));
+    }
+    Ok(())
+}
+
+pub struct AdminAPI {
+    stores: Stores,
+}
+
+#[tonic::async_trait]
+impl Admin for AdminAPI {
+    async fn pause_compaction(
+        &self,
+        request: Request<PauseCompactionRequest>
+    ) -> Result<Response<CompactionControlResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        // Returns once the compaction in progress, if any, is finished.
+        db.pause_compaction().await?;
+        Ok(Response::new(CompactionControlResponse { paused: true }))
+    }
+
+    async fn resume_compaction(
+        &self,
+        request: Request<ResumeCompactionRequest>
+    ) -> Result<Response<CompactionControlResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        db.resume_compaction();
+        Ok(Response::new(CompactionControlResponse { paused: false }))
+    }
+
+    async fn compaction_status(
+        &self,
+        request: Request<CompactionStatusRequest>
+    ) -> Result<Response<CompactionStatusResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let status = db.compaction_status();
+        let unix_secs = |time: Option<SystemTime>| {
+            time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |elapsed| elapsed.as_secs())
+        };
+
+        Ok(Response::new(CompactionStatusResponse {
+            paused: status.paused,
+            running: status.running,
+            files: status.files,
+            records_processed: status.records_processed,
+            bytes_written: status.bytes_written,
+            started_at: unix_secs(status.started_at),
+            finished_at: unix_secs(status.finished_at),
+        }))
+    }
+
+    async fn stats(
+        &self,
+        request: Request<StatsRequest>
+    ) -> Result<Response<StatsResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let payload = request.into_inner();
+        let stats = db.stats();
+        let approximate_size = db.approximate_size(payload.start, payload.end).await?;
+
+        Ok(Response::new(StatsResponse {
+            approximate_key_count: stats.keys as u64,
+            approximate_size,
+            descriptor_cache_hits: stats.chunk_queue.hits,
+            descriptor_cache_misses: stats.chunk_queue.misses,
+            descriptor_cache_evictions: stats.chunk_queue.evictions,
+            index_memory: stats.index_memory as u64,
+        }))
+    }
+
+    async fn file_stats(
+        &self,
+        request: Request<FileStatsRequest>
+    ) -> Result<Response<FileStatsResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let files = db.file_stats().await?.into_iter().map(|file| protobuf::FileStats {
+            file_id: file.file_id,
+            active: file.active,
+            entries: file.entries,
+            dead_entries: file.dead_entries,
+            dead_bytes: file.dead_bytes,
+            size: file.size,
+            fragmentation: file.fragmentation,
+            remote: file.remote,
+        });
+
+        Ok(Response::new(FileStatsResponse { files: files.collect() }))
+    }
+
+    async fn check_consistency(
+        &self,
+        request: Request<CheckConsistencyRequest>
+    ) -> Result<Response<CheckConsistencyResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let report = db.check_consistency().await?;
+
+        Ok(Response::new(CheckConsistencyResponse {
+            entries: report.entries,
+            inconsistencies: report.inconsistencies.iter().map(|inconsistency| inconsistency.to_string()).collect(),
+        }))
+    }
+
+    async fn promote(
+        &self,
+        request: Request<PromoteRequest>
+    ) -> Result<Response<PromoteResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let next_seq = db.promote().await
+            .map_err(|err| Status::failed_precondition(err.to_string()))?;
+        Ok(Response::new(PromoteResponse { next_seq }))
+    }
+}
+
+fn value_metadata(metadata: &stats::ValueMetadata) -> ValueMetadata {
+    ValueMetadata {
+        seq: metadata.seq,
+        timestamp: metadata.timestamp
+            .and_then(|timestamp| timestamp.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |elapsed| elapsed.as_millis() as u64),
+        file_id: metadata.file_id,
+        size: metadata.size,
+    }
+}
+
+fn term_starts(history: &[(u64, u64)]) -> Vec<TermStart> {
+    history.iter().map(|&(term, seq)| TermStart { term, seq }).collect()
+}
+
+fn log_record(log: &Log) -> LogRecord {
+    LogRecord {
+        key: log.key.to_vec(),
+        value: log.value.to_vec(),
+        seq: log.seq,
+        deleted: log.deleted,
+        range: log.range,
+        timestamp: log.timestamp.unwrap_or(0),
+    }
+}
+
+fn record_log(record: LogRecord) -> Log<'static> {
+    Log {
+        key: Cow::from(record.key),
+        value: Cow::from(record.value),
+        seq: record.seq,
+        deleted: record.deleted,
+        blob: false,
+        range: record.range,
+        timestamp: Some(record.timestamp),
+        // Rewritten with the checksum of the files of the standby.
+        wide_checksum: false,
+    }
+}
+
+pub struct ReplicationAPI {
+    db: CrabeDB,
+    election: Option<Election>,
+}
+
+#[tonic::async_trait]
+impl Replication for ReplicationAPI {
+    type TailStream = Pin<Box<dyn Stream<Item = Result<TailResponse, Status>> + Send + Sync>>;
+
+    async fn tail(
+        &self,
+        request: Request<TailRequest>
+    ) -> Result<Response<Self::TailStream>, Status> {
+        let payload = request.into_inner();
+        let db = self.db.clone();
+        // A standby of a newer term follows another primary, which this one will hear of.
+        let (term, history) = match self.election {
+            Some(ref election) => {
+                election.check_leader().await?;
+                let round = election.round.lock().await;
+                if payload.term > round.state.term {
+                    return Err(Status::failed_precondition(format!(
+                        "The standby is in term {}, newer than the term {} of the primary",
+                        payload.term,
+                        round.state.term
+                    )));
+                }
+                (round.state.term, round.state.history.clone())
+            }
+            None => (0, Vec::new()),
+        };
+        // A standby which was a primary may hold records this one never got, written in a
+        // term the others don't know of: it can't just catch up from its last one.
+        if payload.after_seq > 0 {
+            let standby_history: Vec<(u64, u64)> = payload.history.iter().map(|start| (start.term, start.seq)).collect();
+            let common_seq = standby::common_seq(&standby_history, payload.after_seq, &history, db.last_seq());
+            if common_seq < payload.after_seq {
+                return Err(Status::out_of_range(format!(
+                    "The log of the standby diverged from the one of the primary after sequence number {}",
+                    common_seq
+                )));
+            }
+        }
+        let history = term_starts(&history);
+
+        let stream = try_stream! {
+            let mut tail = db.tail(payload.file_id, payload.pos);
+            if payload.after_seq > 0 {
+                let position = db.tail_position().await?;
+                tail = db.tail(position.file_id, position.pos);
+
+                // Fails with OUT_OF_RANGE when the standby has to be seeded again.
+                let mut after_seq = payload.after_seq;
+                loop {
+                    let logs = db.records_after(after_seq, TAIL_BATCH_SIZE).await?;
+                    if logs.is_empty() {
+                        break;
+                    }
+                    yield TailResponse {
+                        records: logs.iter().map(log_record).collect(),
+                        snapshot: true,
+                        file_id: 0,
+                        pos: 0,
+                        term,
+                        history: history.clone(),
+                        lease_changes: Vec::new(),
+                        leases_reset: false,
+                        leases_feed_id: 0,
+                        leases_seq: 0,
+                    };
+                    match logs.last() {
+                        Some(log) if logs.len() == TAIL_BATCH_SIZE => after_seq = log.seq,
+                        _ => break,
+                    }
+                }
+            } else if payload.snapshot {
+                // The tail starts before the copy, the writes made meanwhile are sent again.
+                let position = db.tail_position().await?;
+                tail = db.tail(position.file_id, position.pos);
+
+                let mut cursor = Vec::new();
+                loop {
+                    let (logs, next_cursor) = db.live_records(cursor, TAIL_BATCH_SIZE).await?;
+                    yield TailResponse {
+                        records: logs.iter().map(log_record).collect(),
+                        snapshot: true,
+                        file_id: 0,
+                        pos: 0,
+                        term,
+                        history: history.clone(),
+                        lease_changes: Vec::new(),
+                        leases_reset: false,
+                        leases_feed_id: 0,
+                        leases_seq: 0,
+                    };
+                    match next_cursor {
+                        Some(next_cursor) => cursor = next_cursor,
+                        None => break,
+                    }
+                }
+            }
+
+            // The first batch is sent even when empty, so that a standby gets its position.
+            // The changes of the leases go along with the records, or on their own.
+            let mut first = true;
+            let mut leases = (payload.leases_feed_id, payload.leases_seq);
+            loop {
+                if db.is_standby() {
+                    Err(Status::unavailable("The primary stepped down"))?;
+                }
+                let (next_tail, logs) = db.read_tail(tail, TAIL_BATCH_SIZE).await?;
+                tail = next_tail;
+                let changes = db.lease_changes(leases.0, leases.1);
+                let leases_changed = changes.reset || !changes.changes.is_empty();
+                if logs.is_empty() && !leases_changed && !first {
+                    tokio::time::sleep(TAIL_POLL_INTERVAL).await;
+                    continue;
+                }
+                first = false;
+                leases = (changes.feed_id, changes.seq);
+
+                let position = tail.position();
+                yield TailResponse {
+                    records: logs.iter().map(log_record).collect(),
+                    snapshot: false,
+                    file_id: position.file_id,
+                    pos: position.pos,
+                    term,
+                    history: history.clone(),
+                    lease_changes: changes.changes,
+                    leases_reset: changes.reset,
+                    leases_feed_id: changes.feed_id,
+                    leases_seq: changes.seq,
+                };
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type BootstrapStream = Pin<Box<dyn Stream<Item = Result<BootstrapResponse, Status>> + Send + Sync>>;
+
+    // The files of a hot backup, then the position to tail from. The backup is removed
+    // once the stream ends, or the standby goes away.
+    async fn bootstrap(
+        &self,
+        _request: Request<BootstrapRequest>
+    ) -> Result<Response<Self::BootstrapStream>, Status> {
+        let db = self.db.clone();
+        let snapshot = db.bootstrap_snapshot().await?;
+        info!(
+            "Sending a snapshot of {} files up to sequence number {} to a standby",
+            snapshot.files().len(),
+            snapshot.last_seq
+        );
+
+        let stream = try_stream! {
+            for name in snapshot.files() {
+                let mut file = snapshot.open(name)?;
+                loop {
+                    let (next_file, data) = db.read_snapshot_chunk(file, BOOTSTRAP_CHUNK_SIZE).await?;
+                    file = next_file;
+                    if data.is_empty() {
+                        break;
+                    }
+                    yield BootstrapResponse {
+                        name: name.clone(),
+                        data,
+                        ..BootstrapResponse::default()
+                    };
+                }
+                yield BootstrapResponse {
+                    name: name.clone(),
+                    last: true,
+                    checksum: file.checksum(),
+                    ..BootstrapResponse::default()
+                };
+            }
+            yield BootstrapResponse {
+                done: true,
+                file_id: snapshot.position.file_id,
+                pos: snapshot.position.pos,
+                last_seq: snapshot.last_seq,
+                ..BootstrapResponse::default()
+            };
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+pub struct LeaseAPI {
+    stores: Stores,
+    peers: Peers,
+}
+
+impl LeaseAPI {
+    // The keep-alives and revocations of the leases of the default store are forwarded to
+    // the peers, unless they were forwarded by one.
+    fn forwarded<T>(&self, request: &Request<T>) -> bool {
+        is_default_store(request) && self.peers.check_replica(request).is_err()
+    }
+}
+
+fn lease_status(err: Error) -> Status {
+    match err {
+        Error::LeaseNotFound(..) => Status::not_found(err.to_string()),
+        Error::ReadOnly => Status::failed_precondition(err.to_string()),
+        err => Status::from(err),
+    }
+}
+
+#[tonic::async_trait]
+impl LeaseService for LeaseAPI {
+    type LeaseKeepAliveStream =
+        Pin<Box<dyn Stream<Item = Result<LeaseKeepAliveResponse, Status>> + Send + Sync>>;
+
+    async fn lease_grant(
+        &self,
+        request: Request<LeaseGrantRequest>
+    ) -> Result<Response<LeaseGrantResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let ttl = request.into_inner().ttl;
+        if ttl == 0 {
+            return Err(Status::invalid_argument("The time to live of a lease can't be 0"));
+        }
+        let id = db.grant_lease(Duration::from_secs(ttl)).await.map_err(lease_status)?;
+        info!("Granted lease {} with a time to live of {}s", id, ttl);
+        Ok(Response::new(LeaseGrantResponse { id, ttl }))
+    }
+
+    async fn lease_revoke(
+        &self,
+        request: Request<LeaseRevokeRequest>
+    ) -> Result<Response<LeaseRevokeResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let forwarded = self.forwarded(&request);
+        let id = request.into_inner().id;
+        let seq = db.revoke_lease(id).await.map_err(lease_status)?;
+        if forwarded {
+            self.peers.forward_lease(id, true);
+        }
+        Ok(Response::new(LeaseRevokeResponse { seq }))
+    }
+
+    // Answers every request, with a time to live of 0 once the lease expired.
+    async fn lease_keep_alive(
+        &self,
+        request: Request<Streaming<LeaseKeepAliveRequest>>
+    ) -> Result<Response<Self::LeaseKeepAliveStream>, Status> {
+        let db = self.stores.get(&request)?;
+        let peers = if self.forwarded(&request) { Some(self.peers.clone()) } else { None };
+        let mut requests = request.into_inner();
+        let (tx, rx) = mpsc::channel(1);
+        let db = db.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let response = match requests.message().await {
+                    Ok(Some(LeaseKeepAliveRequest { id })) => {
+                        let ttl = match db.keep_alive_lease(id) {
+                            Ok(ttl) => {
+                                if let Some(ref peers) = peers {
+                                    peers.forward_lease(id, false);
+                                }
+                                ttl.as_secs()
+                            }
+                            Err(_) => 0,
+                        };
+                        Ok(LeaseKeepAliveResponse { id, ttl })
+                    }
+                    Ok(None) => break,
+                    Err(status) => Err(status),
+                };
+                if tx.send(response).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn lease_info(
+        &self,
+        request: Request<LeaseInfoRequest>
+    ) -> Result<Response<LeaseInfoResponse>, Status> {
+        let db = self.stores.get(&request)?;
+        let id = request.into_inner().id;
+        let response = match db.lease_info(id).await? {
+            Some(info) => LeaseInfoResponse {
+                exist: true,
+                ttl: info.ttl.as_secs(),
+                remaining: info.remaining.as_secs(),
+                keys: info.keys
+                    .iter()
+                    .map(|key| String::from_utf8_lossy(key).into_owned())
+                    .collect(),
+            },
+            None => LeaseInfoResponse {
+                exist: false,
+                ttl: 0,
+                remaining: 0,
+                keys: Vec::new(),
+            },
+        };
+        Ok(Response::new(response))
+    }
+}
+
+// Install a snapshot of the primary into the empty directory of a new standby, which then
+// tails the primary from the snapshot instead of being seeded with a copy of its live
+// records. Retried until it succeeds, unless the primary can't send snapshots.
+async fn bootstrap(primary: &str, path: &str) {
+    loop {
+        match install_snapshot(primary, path).await {
+            Ok(()) => return,
+            Err(err) => match err.downcast_ref::<Status>() {
+                Some(status) if status.code() == Code::Unimplemented => {
+                    warn!("{} can't send a snapshot, the standby is seeded with its live records", primary);
+                    return;
+                }
+                _ => warn!("Bootstrap from {} interrupted: {}", primary, err),
+            },
+        }
+        tokio::time::sleep(STANDBY_RETRY_DELAY).await;
+    }
+}
+
+async fn install_snapshot(primary: &str, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = ReplicationClient::connect(format!("http://{}", primary)).await?;
+    let mut stream = client.bootstrap(BootstrapRequest {}).await?.into_inner();
+    let mut installer = SnapshotInstaller::new(path)?;
+    while let Some(chunk) = stream.message().await? {
+        if chunk.done {
+            let position = LogPosition {
+                file_id: chunk.file_id,
+                pos: chunk.pos,
+            };
+            let files = installer.finish(position)?;
+            info!(
+                "Bootstrapped from {} with {} files up to sequence number {}",
+                primary,
+                files,
+                chunk.last_seq
+            );
+            return Ok(());
+        }
+        installer.write(&chunk.name, &chunk.data)?;
+        if chunk.last {
+            installer.finish_file(chunk.checksum)?;
+        }
+    }
+    Err("the snapshot ended before its last file".into())
+}
+
+// Apply the records of the primary until the standby is promoted, reconnecting whenever
+// the stream is interrupted.
+async fn replicate(db: CrabeDB, primary: String) {
+    while db.is_standby() {
+        if let Err(err) = follow(&db, &primary, None).await {
+            if db.is_standby() {
+                warn!("Replication from {} interrupted: {}", primary, err);
+            }
+        }
+        tokio::time::sleep(STANDBY_RETRY_DELAY).await;
+    }
+    info!("Standby promoted, replication from {} stopped", primary);
+}
+
+async fn follow(
+    db: &CrabeDB,
+    primary: &str,
+    election: Option<&Election>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let position = db.standby_position().await?;
+    let (term, history) = match election {
+        Some(election) => {
+            let round = election.round.lock().await;
+            (round.state.term, term_starts(&round.state.history))
+        }
+        None => (0, Vec::new()),
+    };
+    let (leases_feed_id, leases_seq) = db.applied_lease_changes().unwrap_or((0, 0));
+    let request = match position {
+        Some(position) => TailRequest {
+            file_id: position.file_id,
+            pos: position.pos,
+            snapshot: false,
+            term,
+            after_seq: 0,
+            history,
+            leases_feed_id,
+            leases_seq,
+        },
+        // The records of a seed which was interrupted are dropped, and the standby is seeded
+        // again from the start.
+        None if db.is_seeding() => {
+            if db.approximate_key_count() > 0 {
+                db.reset_standby().await?;
+            }
+            TailRequest {
+                file_id: 0,
+                pos: 0,
+                snapshot: true,
+                term,
+                after_seq: 0,
+                history,
+                leases_feed_id,
+                leases_seq,
+            }
+        }
+        // In a failover group, a standby which applied the records of a previous primary
+        // catches up from its last sequence number.
+        None if election.is_some() && db.last_seq() > 0 => TailRequest {
+            file_id: 0,
+            pos: 0,
+            snapshot: false,
+            term,
+            after_seq: db.last_seq(),
+            history,
+            leases_feed_id,
+            leases_seq,
+        },
+        // Without a position, i.e. when it couldn't be bootstrapped from a snapshot, the
+        // standby is seeded with a copy of the live records of the primary. It has to be
+        // empty, or the keys removed from the primary before the copy would remain.
+        None if db.approximate_key_count() > 0 => {
+            return Err("a standby must be empty to be seeded from its primary".into());
+        }
+        None => TailRequest {
+            file_id: 0,
+            pos: 0,
+            snapshot: true,
+            term,
+            after_seq: 0,
+            history,
+            leases_feed_id,
+            leases_seq,
+        },
+    };
+
+    let mut client = ReplicationClient::connect(format!("http://{}", primary)).await?;
+    info!("Replicating from {} with {:?}", primary, request);
+    if request.snapshot {
+        db.start_seed().await?;
+    }
+
+    let after_seq = request.after_seq;
+    let mut stream = match client.tail(request).await {
+        Ok(response) => response.into_inner(),
+        Err(status) => return Err(reset_if_refused(db, status, after_seq).await),
+    };
+    loop {
+        let batch = match stream.message().await {
+            Ok(Some(batch)) => batch,
+            Ok(None) => break,
+            Err(status) => return Err(reset_if_refused(db, status, after_seq).await),
+        };
+        let position = if batch.snapshot {
+            None
+        } else {
+            Some(LogPosition {
+                file_id: batch.file_id,
+                pos: batch.pos,
+            })
+        };
+        let history: Vec<(u64, u64)> = batch.history.iter().map(|start| (start.term, start.seq)).collect();
+        let logs = batch.records.into_iter().map(record_log).collect();
+        let lease_changes = LeaseChanges {
+            reset: batch.leases_reset,
+            changes: batch.lease_changes,
+            feed_id: batch.leases_feed_id,
+            seq: batch.leases_seq,
+        };
+        match election {
+            // Applied under the lock of the election, so that neither the records nor the
+            // position of a primary which was replaced meanwhile are kept.
+            Some(election) => {
+                let mut round = election.round.lock().await;
+                if batch.term != round.state.term || round.state.leader != primary {
+                    return Err(format!("{} isn't the primary of term {}", primary, round.state.term).into());
+                }
+                // The history of the terms is the one of the records applied.
+                if round.state.history != history {
+                    round.state.history = history;
+                    db.save_election_state(round.state.clone()).await?;
+                }
+                db.apply(logs, position).await?;
+            }
+            None => db.apply(logs, position).await?,
+        }
+        // The batches of the snapshot come without them.
+        if lease_changes.feed_id != 0 {
+            db.apply_lease_changes(lease_changes).await?;
+        }
+    }
+    Ok(())
+}
+
+// A primary which can't send the records written after the last sequence number of a
+// standby refuses with OUT_OF_RANGE: the standby then drops its records, to be seeded again.
+async fn reset_if_refused(
+    db: &CrabeDB,
+    status: Status,
+    after_seq: u64,
+) -> Box<dyn std::error::Error + Send + Sync> {
+    if status.code() == Code::OutOfRange && after_seq > 0 {
+        warn!("Can't catch up from sequence number {}: {}", after_seq, status.message());
+        if let Err(err) = db.reset_standby().await {
+            return err.into();
+        }
+    }
+    status.into()
+}
+
+// A failover group (`--failover-group`): a primary and its standbys, which elect a new
+// primary once it's gone, like the leader election of Raft. Each election opens a term, in
+// which a node votes once, for a candidate with all the records it applied: the candidate
+// voted for by a majority of the group is promoted, and the other nodes follow it. The
+// primary only accepts writes while a majority acknowledges its heartbeats, and steps down
+// as soon as it hears of a newer term, so that a primary cut from the group stops taking
+// writes before another one can be elected. A learner (`--learner`) follows the primary
+// without being a member of the group: it neither votes nor runs for election, and isn't
+// counted in the majorities.
+#[derive(Clone)]
+pub struct Election {
+    db: CrabeDB,
+    // The address of this node, as the other nodes of the group know it.
+    address: String,
+    learner: bool,
+    round: Arc<tokio::sync::Mutex<ElectionRound>>,
+}
+
+struct ElectionRound {
+    // Persisted before a node acts on it, so that it never votes twice in a term.
+    state: ElectionState,
+    // The other members of the group, or all of them for a learner.
+    nodes: Vec<(String, ElectionClient<Channel>)>,
+    // When a standby last heard from its primary or voted, or when the primary sent the
+    // last heartbeats acknowledged by a majority.
+    last_contact: Instant,
+    // How long a standby waits for its primary before it runs for election.
+    timeout: Duration,
+    // The last sequence number of the other nodes, as they answered the last heartbeats.
+    progress: HashMap<String, u64>,
+    // Set on the primary while it hands over to another node, the writes being refused.
+    transfer: bool,
+}
+
+impl ElectionRound {
+    // Replace the members of the group, keeping the connections to the nodes which remain.
+    fn set_members(&mut self, address: &str, members: Vec<String>) -> io::Result<()> {
+        let mut nodes = Vec::with_capacity(members.len());
+        for member in members.iter().filter(|member| *member != address) {
+            match self.nodes.iter().find(|(addr, _)| addr == member) {
+                Some(node) => nodes.push(node.clone()),
+                None => nodes.push((member.clone(), election_client(member)?)),
+            }
+        }
+        self.nodes = nodes;
+        self.progress.retain(|addr, _| members.contains(addr));
+        self.state.members = members;
+        Ok(())
+    }
+
+    // Whether a node is a member of the group, i.e. votes and may be elected.
+    fn is_member(&self, address: &str) -> bool {
+        self.state.members.is_empty() || self.state.members.iter().any(|member| member == address)
+    }
+
+    // Whether `count` members, this one included, make up a majority of the group.
+    fn is_majority(&self, count: usize) -> bool {
+        2 * count > self.nodes.len() + 1
+    }
+}
+
+fn election_client(addr: &str) -> io::Result<ElectionClient<Channel>> {
+    let invalid = |err: &dyn std::fmt::Display| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address {:?}: {}", addr, err))
+    };
+    let channel = Endpoint::from_shared(format!("http://{}", addr))
+        .map_err(|err| invalid(&err))?
+        .timeout(ELECTION_RPC_TIMEOUT)
+        .connect_lazy()
+        .map_err(|err| invalid(&err))?;
+    Ok(ElectionClient::new(channel))
+}
+
+// Randomized, so that the standbys of a group seldom run for election at the same time.
+fn election_timeout() -> Duration {
+    ELECTION_TIMEOUT + ELECTION_TIMEOUT.mul_f64(rand::thread_rng().gen_range(0.0, 1.0))
+}
+
+impl Election {
+    async fn new(
+        db: CrabeDB,
+        address: &str,
+        addrs: &[&str],
+        primary: Option<&str>,
+        learner: bool,
+    ) -> Result<Election, Box<dyn std::error::Error>> {
+        if learner && !db.is_standby() {
+            return Err("a learner must be a standby".into());
+        }
+
+        // Until it hears of an election, a node follows the primary it's started with.
+        let mut state = db.election_state().await?;
+        if state.leader.is_empty() {
+            state.leader = match primary {
+                Some(primary) => primary.to_string(),
+                None if !db.is_standby() => address.to_string(),
+                None => String::new(),
+            };
+            db.save_election_state(state.clone()).await?;
+        }
+        if !db.is_standby() && state.leader != address {
+            warn!("{} is the primary of term {}, starting as a standby", state.leader, state.term);
+            db.demote().await?;
+        }
+        // The primary waits for a majority to acknowledge it before it accepts writes, and a
+        // learner asks for the primary right away.
+        let last_contact = if db.is_standby() && !learner {
+            Instant::now()
+        } else {
+            Instant::now().checked_sub(2 * ELECTION_TIMEOUT).unwrap_or_else(Instant::now)
+        };
+
+        let mut round = ElectionRound {
+            state,
+            nodes: Vec::new(),
+            last_contact,
+            timeout: election_timeout(),
+            progress: HashMap::new(),
+            transfer: false,
+        };
+        // The members changed at runtime take over from those the node is started with.
+        if round.state.members.is_empty() {
+            for addr in addrs {
+                round.nodes.push((addr.to_string(), election_client(addr)?));
+            }
+        } else {
+            let members = round.state.members.clone();
+            round.set_members(address, members)?;
+        }
+
+        Ok(Election {
+            db,
+            address: address.to_string(),
+            learner,
+            round: Arc::new(tokio::sync::Mutex::new(round)),
+        })
+    }
+
+    // The current term and its primary, unknown during an election.
+    async fn leader(&self) -> (u64, String) {
+        let round = self.round.lock().await;
+        (round.state.term, round.state.leader.clone())
+    }
+
+    // The addresses of the other members of the group, or all of them for a learner.
+    async fn nodes(&self) -> Vec<String> {
+        self.round.lock().await.nodes.iter().map(|(addr, _)| addr.clone()).collect()
+    }
+
+    // Refuse a request which only the primary serves, redirecting the client to it.
+    async fn check_leader(&self) -> Result<(), Status> {
+        let round = self.round.lock().await;
+        if round.state.leader == self.address {
+            if !self.db.is_standby() && round.last_contact.elapsed() < ELECTION_TIMEOUT && !round.transfer {
+                return Ok(());
+            }
+            return Err(NotLeader { leader: String::new(), term: round.state.term }.into());
+        }
+        Err(NotLeader { leader: round.state.leader.clone(), term: round.state.term }.into())
+    }
+
+    // Move to a newer term heard of from another node, the primary stepping down.
+    async fn adopt_term(&self, round: &mut ElectionRound, term: u64) -> Result<(), Error> {
+        round.state.term = term;
+        round.state.voted_for.clear();
+        round.state.leader.clear();
+        if !self.db.is_standby() {
+            warn!("Stepping down, another node is in term {}", term);
+            self.db.demote().await?;
+        }
+        Ok(())
+    }
+
+    // Send the heartbeats of the primary, or run for election once it's silent.
+    async fn run(self) {
+        loop {
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            let result = if self.learner {
+                self.find_leader().await
+            } else if self.db.is_standby() {
+                self.run_for_election(false).await
+            } else {
+                self.send_heartbeats().await
+            };
+            if let Err(err) = result {
+                warn!("Election failed: {}", err);
+            }
+        }
+    }
+
+    async fn send_heartbeats(&self) -> Result<(), Error> {
+        let started = Instant::now();
+        let (request, nodes) = {
+            let round = self.round.lock().await;
+            let request = HeartbeatRequest {
+                term: round.state.term,
+                leader: self.address.clone(),
+                members: round.state.members.clone(),
+            };
+            (request, round.nodes.clone())
+        };
+        let responses = join_all(nodes.into_iter().map(|(addr, mut client)| {
+            let request = request.clone();
+            async move {
+                match tokio::time::timeout(ELECTION_RPC_TIMEOUT, client.heartbeat(request)).await {
+                    Ok(Ok(response)) => Some((addr, response.into_inner())),
+                    _ => None,
+                }
+            }
+        })).await;
+
+        let mut round = self.round.lock().await;
+        let mut acks = 1;
+        let mut newer_term = request.term;
+        for (addr, response) in responses.into_iter().flatten() {
+            if response.accepted {
+                acks += 1;
+                round.progress.insert(addr, response.last_seq);
+            } else {
+                newer_term = newer_term.max(response.term);
+            }
+        }
+        if newer_term > round.state.term {
+            self.adopt_term(&mut round, newer_term).await?;
+            return self.db.save_election_state(round.state.clone()).await;
+        }
+        if round.state.term == request.term && round.is_majority(acks) {
+            round.last_contact = started;
+        }
+        Ok(())
+    }
+
+    // A learner gets no heartbeats: it asks the nodes of the group for the primary of the
+    // newest term they know of, until the primary it follows fails.
+    async fn find_leader(&self) -> Result<(), Error> {
+        let nodes = {
+            let mut round = self.round.lock().await;
+            if round.last_contact.elapsed() < round.timeout {
+                return Ok(());
+            }
+            round.last_contact = Instant::now();
+            round.nodes.clone()
+        };
+        let responses = join_all(nodes.into_iter().map(|(_, mut client)| async move {
+            match tokio::time::timeout(ELECTION_RPC_TIMEOUT, client.leader(LeaderRequest {})).await {
+                Ok(Ok(response)) => Some(response.into_inner()),
+                _ => None,
+            }
+        })).await;
+        let newest = responses
+            .into_iter()
+            .flatten()
+            .filter(|response| !response.leader.is_empty())
+            .max_by_key(|response| response.term);
+
+        let mut round = self.round.lock().await;
+        let newest = match newest {
+            Some(newest) if newest.term >= round.state.term => newest,
+            _ => return Ok(()),
+        };
+        let mut changed = false;
+        if newest.term > round.state.term || newest.leader != round.state.leader {
+            info!("Following {}, the primary of term {}", newest.leader, newest.term);
+            round.state.term = newest.term;
+            round.state.leader = newest.leader;
+            changed = true;
+        }
+        if !newest.members.is_empty() && newest.members != round.state.members {
+            round.set_members(&self.address, newest.members)?;
+            changed = true;
+        }
+        if changed {
+            self.db.save_election_state(round.state.clone()).await?;
+        }
+        Ok(())
+    }
+
+    // Run for election once the primary is silent, or right away for the target of a
+    // leadership transfer.
+    async fn run_for_election(&self, transfer: bool) -> Result<(), Error> {
+        let started = Instant::now();
+        let (request, nodes) = {
+            let mut round = self.round.lock().await;
+            // A node removed from the group only follows it.
+            if !round.is_member(&self.address) || (!transfer && round.last_contact.elapsed() < round.timeout) {
+                return Ok(());
+            }
+            round.state.term += 1;
+            round.state.voted_for = self.address.clone();
+            round.state.leader.clear();
+            round.last_contact = started;
+            round.timeout = election_timeout();
+            self.db.save_election_state(round.state.clone()).await?;
+            let request = VoteRequest {
+                term: round.state.term,
+                candidate: self.address.clone(),
+                last_seq: self.db.last_seq(),
+                transfer,
+            };
+            (request, round.nodes.clone())
+        };
+        info!("Running for election in term {} with sequence number {}", request.term, request.last_seq);
+
+        let responses = join_all(nodes.into_iter().map(|(_, mut client)| {
+            let request = request.clone();
+            async move {
+                match tokio::time::timeout(ELECTION_RPC_TIMEOUT, client.request_vote(request)).await {
+                    Ok(Ok(response)) => Some(response.into_inner()),
+                    _ => None,
+                }
+            }
+        })).await;
+        let mut votes = 1;
+        let mut newer_term = request.term;
+        for response in responses.into_iter().flatten() {
+            if response.granted {
+                votes += 1;
+            } else {
+                newer_term = newer_term.max(response.term);
+            }
+        }
+
+        let mut round = self.round.lock().await;
+        if newer_term > round.state.term {
+            self.adopt_term(&mut round, newer_term).await?;
+            return self.db.save_election_state(round.state.clone()).await;
+        }
+        // The term may have moved on meanwhile, or another node been elected in it.
+        if round.state.term != request.term || !round.state.leader.is_empty() || !round.is_majority(votes) {
+            return Ok(());
+        }
+        let next_seq = self.db.promote().await?;
+        round.state.leader = self.address.clone();
+        round.state.start_term(next_seq);
+        round.last_contact = started;
+        round.progress.clear();
+        self.db.save_election_state(round.state.clone()).await?;
+        info!("Elected primary of term {} with {} votes", request.term, votes);
+        Ok(())
+    }
+
+    // Add a node to the group, or remove one, on its primary. The removed node is told
+    // so, and stops running for election.
+    async fn change_members(&self, address: &str, add: bool) -> Result<Vec<String>, Status> {
+        self.check_leader().await?;
+        if address.is_empty() {
+            return Err(Status::invalid_argument("A node must have an address"));
+        }
+        let mut round = self.round.lock().await;
+        let mut members = if round.state.members.is_empty() {
+            std::iter::once(self.address.clone()).chain(round.nodes.iter().map(|(addr, _)| addr.clone())).collect()
+        } else {
+            round.state.members.clone()
+        };
+        let removed = round.nodes.iter().find(|(addr, _)| addr == address).map(|(_, client)| client.clone());
+        match (add, members.iter().any(|member| member == address)) {
+            (true, true) | (false, false) => return Ok(members),
+            (true, false) => members.push(address.to_string()),
+            (false, true) if address == self.address => {
+                return Err(Status::failed_precondition(
+                    "The primary can't be removed from its group, its leadership has to be transferred first",
+                ));
+            }
+            (false, true) => members.retain(|member| member != address),
+        }
+        round.set_members(&self.address, members.clone())?;
+        self.db.save_election_state(round.state.clone()).await?;
+        info!(
+            "{} {} the failover group, members: {}",
+            address,
+            if add { "added to" } else { "removed from" },
+            members.join(", ")
+        );
+        let request = HeartbeatRequest { term: round.state.term, leader: self.address.clone(), members: members.clone() };
+        drop(round);
+
+        if let (false, Some(mut client)) = (add, removed) {
+            let _ = tokio::time::timeout(ELECTION_RPC_TIMEOUT, client.heartbeat(request)).await;
+        }
+        Ok(members)
+    }
+
+    // Hand the primary over to another member of the group: the writes are refused until
+    // it applied the last one, then it runs for election right away. Returns the term in
+    // which it was elected.
+    async fn transfer_leadership(&self, target: &str) -> Result<u64, Status> {
+        self.check_leader().await?;
+        let (term, client) = {
+            let mut round = self.round.lock().await;
+            let client = match round.nodes.iter().find(|(addr, _)| addr == target) {
+                Some((_, client)) => client.clone(),
+                None => return Err(Status::invalid_argument(format!("{} isn't a member of the failover group", target))),
+            };
+            round.transfer = true;
+            (round.state.term, client)
+        };
+        info!("Transferring the leadership of term {} to {}", term, target);
+        let result = self.hand_over(term, target, client).await;
+        self.round.lock().await.transfer = false;
+        result
+    }
+
+    async fn hand_over(&self, term: u64, target: &str, mut client: ElectionClient<Channel>) -> Result<u64, Status> {
+        let last_seq = self.db.last_seq();
+        let deadline = Instant::now() + ELECTION_TIMEOUT;
+        while self.round.lock().await.progress.get(target).is_none_or(|seq| *seq < last_seq) {
+            if Instant::now() >= deadline {
+                return Err(Status::deadline_exceeded(format!(
+                    "{} didn't catch up with sequence number {}",
+                    target,
+                    last_seq
+                )));
+            }
+            tokio::time::sleep(TAIL_POLL_INTERVAL).await;
+        }
+
+        client.timeout_now(TimeoutNowRequest { term }).await?;
+        let deadline = Instant::now() + ELECTION_TIMEOUT;
+        loop {
+            let (current_term, leader) = self.leader().await;
+            if current_term > term && leader == target {
+                return Ok(current_term);
+            }
+            if Instant::now() >= deadline {
+                return Err(Status::deadline_exceeded(format!("{} wasn't elected", target)));
+            }
+            tokio::time::sleep(TAIL_POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ElectionService for Election {
+    async fn request_vote(
+        &self,
+        request: Request<VoteRequest>
+    ) -> Result<Response<VoteResponse>, Status> {
+        let payload = request.into_inner();
+        let mut round = self.round.lock().await;
+        // A node which hears from its primary doesn't let a candidate disrupt the group,
+        // unless the primary hands over to it.
+        let heard = !round.state.leader.is_empty() && round.last_contact.elapsed() < ELECTION_TIMEOUT;
+        if payload.term < round.state.term
+            || (heard && !payload.transfer)
+            || self.learner
+            || !round.is_member(&payload.candidate)
+        {
+            return Ok(Response::new(VoteResponse { term: round.state.term, granted: false }));
+        }
+        if payload.term > round.state.term {
+            self.adopt_term(&mut round, payload.term).await?;
+        }
+        let granted = (round.state.voted_for.is_empty() || round.state.voted_for == payload.candidate)
+            && payload.last_seq >= self.db.last_seq();
+        if granted {
+            round.state.voted_for = payload.candidate;
+            round.last_contact = Instant::now();
+        }
+        self.db.save_election_state(round.state.clone()).await?;
+        Ok(Response::new(VoteResponse { term: round.state.term, granted }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let payload = request.into_inner();
+        let mut round = self.round.lock().await;
+        if payload.term < round.state.term {
+            return Ok(Response::new(HeartbeatResponse {
+                term: round.state.term,
+                accepted: false,
+                last_seq: self.db.last_seq(),
+            }));
+        }
+        let mut changed = false;
+        if payload.term > round.state.term {
+            self.adopt_term(&mut round, payload.term).await?;
+            changed = true;
+        }
+        round.last_contact = Instant::now();
+        if round.state.leader != payload.leader {
+            info!("Following {}, the primary of term {}", payload.leader, payload.term);
+            round.state.leader = payload.leader;
+            changed = true;
+        }
+        if !payload.members.is_empty() && payload.members != round.state.members {
+            info!("Members of the failover group: {}", payload.members.join(", "));
+            round.set_members(&self.address, payload.members)?;
+            changed = true;
+        }
+        if changed {
+            self.db.save_election_state(round.state.clone()).await?;
+        }
+        Ok(Response::new(HeartbeatResponse {
+            term: round.state.term,
+            accepted: true,
+            last_seq: self.db.last_seq(),
+        }))
+    }
+
+    async fn leader(
+        &self,
+        _request: Request<LeaderRequest>
+    ) -> Result<Response<LeaderResponse>, Status> {
+        let round = self.round.lock().await;
+        Ok(Response::new(LeaderResponse {
+            leader: round.state.leader.clone(),
+            term: round.state.term,
+            members: round.state.members.clone(),
+        }))
+    }
+
+    async fn timeout_now(
+        &self,
+        request: Request<TimeoutNowRequest>
+    ) -> Result<Response<TimeoutNowResponse>, Status> {
+        let payload = request.into_inner();
+        if self.learner || !self.db.is_standby() {
+            return Err(Status::failed_precondition("Only a standby of the group can be handed the leadership"));
+        }
+        if payload.term != self.leader().await.0 {
+            return Err(Status::failed_precondition(format!("The node isn't in term {}", payload.term)));
+        }
+        let election = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = election.run_for_election(true).await {
+                warn!("Election failed: {}", err);
+            }
+        });
+        Ok(Response::new(TimeoutNowResponse {}))
+    }
+}
+
+// Apply the records of the primary of the failover group while this node is a standby,
+// following each newly elected primary.
+async fn replicate_group(db: CrabeDB, election: Election) {
+    loop {
+        let (term, primary) = election.leader().await;
+        if db.is_standby() && !primary.is_empty() && primary != election.address {
+            tokio::select! {
+                result = follow(&db, &primary, Some(&election)) => {
+                    if let Err(err) = result {
+                        warn!("Replication from {} interrupted: {}", primary, err);
+                    }
+                }
+                _ = primary_changed(&election, term, &primary) => {
+                    info!("{} isn't the primary anymore, replication from it stopped", primary);
+                }
+            }
+        }
+        tokio::time::sleep(STANDBY_RETRY_DELAY).await;
+    }
+}
+
+async fn primary_changed(election: &Election, term: u64, primary: &str) {
+    loop {
+        tokio::time::sleep(TAIL_POLL_INTERVAL).await;
+        let (current_term, current_primary) = election.leader().await;
+        if current_term != term || current_primary != primary {
+            return;
+        }
+    }
+}
+
+// The administration of a multi-node deployment: its failover group, its peers, or the
+// servers of a sharded one.
+// The routing table of the default store of a node of a sharded deployment, persisted
+// under `ROUTING_KEY`: the node only serves the keys the ring of its table maps to it, and
+// redirects the requests for the others with a `WrongShard`. A node without a table
+// serves every key.
+#[derive(Clone)]
+pub struct Routing {
+    // The address of this node in the ring.
+    address: String,
+    state: Arc<tokio::sync::RwLock<RoutingState>>,
+    // Held while a migration runs on the node.
+    migration: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Default)]
+pub struct RoutingState {
+    table: RoutingTable,
+    ring: HashRing,
+    // The table before the last migration, and the nodes which still serve the keys it
+    // moved to this node, not having switched to the current table yet.
+    previous: RoutingTable,
+    previous_ring: HashRing,
+    pending: HashSet<String>,
+    // The nodes which switched to a table this node doesn't serve yet, by version.
+    switched: HashMap<u64, HashSet<String>>,
+}
+
+impl Routing {
+    async fn load(db: &CrabeDB, address: &str) -> Result<Routing, Error> {
+        let mut state = RoutingState::default();
+        if let Some(table) = db.get(ROUTING_KEY).await? {
+            state.table = prost::Message::decode(table.as_slice())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            state.ring = routing_ring(&state.table.nodes);
+        }
+        Ok(Routing {
+            address: address.to_string(),
+            state: Arc::new(tokio::sync::RwLock::new(state)),
+            migration: Arc::new(tokio::sync::Mutex::new(())),
+        })
+    }
+
+    // Check that the node serves the keys of a request to the default store. The table
+    // can't change until the guard returned is dropped, once the request is done.
+    async fn check(
+        &self,
+        replicated: bool,
+        keys: &[&str],
+    ) -> Result<tokio::sync::RwLockReadGuard<'_, RoutingState>, Status> {
+        let state = self.state.read().await;
+        if !replicated {
+            return Ok(state);
+        }
+        for key in keys {
+            if let Some(owner) = state.ring.node(key.as_bytes()) {
+                if owner != self.address {
+                    return Err(WrongShard { owner: owner.to_string(), table: Some(state.table.clone()) }.into());
+                }
+            }
+            if state.pending.is_empty() {
+                continue;
+            }
+            // The node which served a key moved to this one does until it switched.
+            match state.previous_ring.node(key.as_bytes()) {
+                Some(previous) if state.pending.contains(previous) => {
+                    return Err(WrongShard { owner: previous.to_string(), table: Some(state.previous.clone()) }.into());
+                }
+                Some(_) => {}
+                None => return Err(Status::unavailable(format!("Key {:?} is being moved to this node", key))),
+            }
+        }
+        Ok(state)
+    }
+
+    async fn is_sharded(&self) -> bool {
+        !self.state.read().await.ring.is_empty()
+    }
+
+    // A node which moved keys to this one switched to the table `version`.
+    async fn switched(&self, source: String, version: u64) {
+        let mut state = self.state.write().await;
+        if version == state.table.version {
+            state.pending.remove(&source);
+        } else if version > state.table.version {
+            state.switched.entry(version).or_default().insert(source);
+        }
+    }
+}
+
+fn routing_ring(nodes: &[String]) -> HashRing {
+    let mut ring = HashRing::default();
+    for node in nodes {
+        ring.add_node(node);
+    }
+    ring
+}
+
+// The keys a node moves to the other nodes of a new routing table.
+struct KeyMigration {
+    address: String,
+    ring: HashRing,
+    version: u64,
+    clients: HashMap<String, ClusterClient<Channel>>,
+}
+
+impl KeyMigration {
+    fn new(address: &str, ring: HashRing, version: u64, nodes: &[String]) -> io::Result<KeyMigration> {
+        let mut clients = HashMap::with_capacity(nodes.len());
+        for node in nodes {
+            let invalid = |err: &dyn std::fmt::Display| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("invalid node {:?}: {}", node, err))
+            };
+            let channel = Endpoint::from_shared(format!("http://{}", node))
+                .map_err(|err| invalid(&err))?
+                .timeout(PEER_TIMEOUT)
+                .connect_lazy()
+                .map_err(|err| invalid(&err))?;
+            clients.insert(node.clone(), ClusterClient::new(channel));
+        }
+        Ok(KeyMigration {
+            address: address.to_string(),
+            ring,
+            version,
+            clients,
+        })
+    }
+
+    // The node a key moves to, if it leaves this one. The keys the node keeps for itself
+    // stay.
+    fn owner(&self, key: &[u8]) -> Option<&str> {
+        match self.ring.node(key) {
+            Some(owner) if owner != self.address && !is_node_key(key) => Some(owner),
+            _ => None,
+        }
+    }
+
+    // Send the records of the keys leaving the node to their new nodes, in order. The range
+    // tombstones, which the server never writes, are left out.
+    async fn send(&self, logs: &[Log<'static>]) -> Result<u64, Status> {
+        let mut batches: HashMap<&str, Vec<LogRecord>> = HashMap::new();
+        for log in logs.iter().filter(|log| !log.range) {
+            if let Some(owner) = self.owner(&log.key) {
+                batches.entry(owner).or_default().push(log_record(log));
+            }
+        }
+        let mut sent = 0;
+        for (owner, records) in batches {
+            sent += records.len() as u64;
+            self.import(owner, records).await?;
+        }
+        Ok(sent)
+    }
+
+    async fn import(&self, node: &str, records: Vec<LogRecord>) -> Result<(), Status> {
+        let mut client = self.clients.get(node).cloned()
+            .ok_or_else(|| Status::internal(format!("no connection to {}", node)))?;
+        client.import_records(ImportRecordsRequest {
+            records,
+            source: self.address.clone(),
+            version: self.version,
+            done: false,
+        }).await?;
+        Ok(())
+    }
+}
+
+pub struct ClusterAPI {
+    db: CrabeDB,
+    // The address of this node, as the other nodes know it.
+    address: String,
+    election: Option<Election>,
+    peers: Peers,
+    routing: Routing,
+}
+
+impl ClusterAPI {
+    async fn node_info(&self) -> NodeStatus {
+        let (role, term, leader) = match self.election {
+            Some(ref election) => {
+                let (term, leader) = election.leader().await;
+                let role = if election.learner {
+                    "learner"
+                } else if self.db.is_standby() {
+                    "standby"
+                } else {
+                    "primary"
+                };
+                (role, term, leader)
+            }
+            None if !self.peers.clients.is_empty() => ("peer", 0, String::new()),
+            None if self.db.is_standby() => ("standby", 0, String::new()),
+            None => ("primary", 0, String::new()),
+        };
+        NodeStatus {
+            address: self.address.clone(),
+            role: role.to_string(),
+            term,
+            leader,
+            last_seq: self.db.last_seq(),
+            keys: self.db.approximate_key_count() as u64,
+            reachable: true,
+        }
+    }
+}
+
+fn no_failover_group() -> Status {
+    Status::failed_precondition("The node isn't in a failover group")
+}
+
+async fn remote_node_info(addr: String) -> NodeStatus {
+    let info = async {
+        let channel = Endpoint::from_shared(format!("http://{}", addr))?.timeout(PEER_TIMEOUT).connect_lazy()?;
+        let info = ClusterClient::new(channel).node_info(NodeInfoRequest {}).await?.into_inner();
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(info)
+    };
+    match info.await {
+        Ok(info) => info,
+        Err(err) => {
+            warn!("Couldn't get the status of {}: {}", addr, err);
+            NodeStatus { address: addr, ..NodeStatus::default() }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Cluster for ClusterAPI {
+    async fn node_info(
+        &self,
+        _request: Request<NodeInfoRequest>
+    ) -> Result<Response<NodeStatus>, Status> {
+        Ok(Response::new(self.node_info().await))
+    }
+
+    async fn cluster_status(
+        &self,
+        _request: Request<ClusterStatusRequest>
+    ) -> Result<Response<ClusterStatusResponse>, Status> {
+        let addrs = match self.election {
+            Some(ref election) => election.nodes().await,
+            None => self.peers.clients.iter().map(|(addr, _)| addr.clone()).collect(),
+        };
+        let mut nodes = vec![self.node_info().await];
+        nodes.extend(join_all(addrs.into_iter().map(remote_node_info)).await);
+        Ok(Response::new(ClusterStatusResponse { nodes }))
+    }
+
+    async fn add_node(
+        &self,
+        request: Request<AddNodeRequest>
+    ) -> Result<Response<MembershipResponse>, Status> {
+        let address = request.into_inner().address;
+        let members = self.election.as_ref().ok_or_else(no_failover_group)?.change_members(&address, true).await?;
+        Ok(Response::new(MembershipResponse { members }))
+    }
+
+    async fn remove_node(
+        &self,
+        request: Request<RemoveNodeRequest>
+    ) -> Result<Response<MembershipResponse>, Status> {
+        let address = request.into_inner().address;
+        let members = self.election.as_ref().ok_or_else(no_failover_group)?.change_members(&address, false).await?;
+        Ok(Response::new(MembershipResponse { members }))
+    }
+
+    async fn transfer_leadership(
+        &self,
+        request: Request<TransferLeadershipRequest>
+    ) -> Result<Response<TransferLeadershipResponse>, Status> {
+        let address = request.into_inner().address;
+        let term = self.election.as_ref().ok_or_else(no_failover_group)?.transfer_leadership(&address).await?;
+        Ok(Response::new(TransferLeadershipResponse { term }))
+    }
+
+    // The keys are moved one by one: a key written to this node meanwhile is moved by the
+    // next rebalance. The hints and locks of the node stay.
+    async fn rebalance(
+        &self,
+        request: Request<RebalanceRequest>
+    ) -> Result<Response<RebalanceResponse>, Status> {
+        let payload = request.into_inner();
+        let address = if payload.address.is_empty() { self.address.clone() } else { payload.address };
+        let mut ring = HashRing::default();
+        for node in &payload.nodes {
+            ring.add_node(node);
+        }
+        if ring.is_empty() {
+            return Err(Status::invalid_argument("A ring must have nodes"));
+        }
+
+        let mut clients: HashMap<String, KvstoreClient<Channel>> = HashMap::new();
+        let mut response = RebalanceResponse::default();
+        let mut cursor = Vec::new();
+        loop {
+            let keys = self.db.list_keys(Vec::new(), cursor, REBALANCE_BATCH_SIZE).await?;
+            for key in &keys {
+                let owner = match ring.node(key) {
+                    Some(owner) if owner != address && !is_node_key(key) => owner,
+                    _ => continue,
+                };
+                let name = match str::from_utf8(key) {
+                    Ok(name) => name.to_string(),
+                    Err(_) => {
+                        response.skipped += 1;
+                        continue;
+                    }
+                };
+                let (value, metadata) = match self.db.get_with_metadata(key.clone()).await? {
+                    Some(found) => found,
+                    None => continue,
+                };
+                let client = match clients.get_mut(owner) {
+                    Some(client) => client,
+                    None => {
+                        let channel = Endpoint::from_shared(format!("http://{}", owner))
+                            .map_err(|err| Status::invalid_argument(format!("invalid node {:?}: {}", owner, err)))?
+                            .timeout(PEER_TIMEOUT)
+                            .connect_lazy()
+                            .map_err(|err| Status::invalid_argument(format!("invalid node {:?}: {}", owner, err)))?;
+                        clients.entry(owner.to_string()).or_insert_with(|| KvstoreClient::new(channel))
+                    }
+                };
+
+                // The versions are ordered by their sequence numbers, like the records merged
+                // from a peer: the owner keeps the newer one.
+                let existing = client.kv_get_call(GetRequest {
+                    key: name.clone(),
+                    min_seq: 0,
+                    with_metadata: true,
+                    consistency: Consistency::One as i32,
+                }).await?.into_inner();
+                let existing_seq = existing.metadata.map_or(0, |metadata| metadata.seq);
+                let moved = if existing.exist && existing_seq >= metadata.seq {
+                    false
+                } else {
+                    match String::from_utf8(value.clone()) {
+                        Ok(text) => {
+                            client.kv_set_call(self.peers.replica_request(SetRequest {
+                                key: name.clone(),
+                                value: text,
+                                replica_seq: metadata.seq,
+                                ..Default::default()
+                            })?).await?;
+                        }
+                        Err(_) => {
+                            let chunks = value_chunks(&name, &value, metadata.seq);
+                            client.kv_set_stream_call(self.peers.replica_request(tokio_stream::iter(chunks))?).await?;
+                        }
+                    }
+                    true
+                };
+
+                // Only the version compared is removed: a write made meanwhile stays.
+                match self.db.compare_and_swap_as(None, key.clone(), Some(value), None, None).await? {
+                    CasResult::Swapped(_) if moved => response.moved += 1,
+                    CasResult::Swapped(_) => response.dropped += 1,
+                    CasResult::Mismatch(_) => {}
+                }
+            }
+
+            if keys.len() < REBALANCE_BATCH_SIZE {
+                break;
+            }
+            cursor = keys.last().cloned().unwrap_or_default();
         }
+        info!(
+            "Rebalanced over {} nodes: {} keys moved, {} dropped and {} skipped",
+            payload.nodes.len(),
+            response.moved,
+            response.dropped,
+            response.skipped
+        );
+        Ok(Response::new(response))
     }
 
-    async fn kv_set_call(
+    async fn get_routing_table(
         &self,
-        request: Request<SetRequest>
-    ) -> Result<Response<SetResponse>, Status> {
+        _request: Request<RoutingTableRequest>
+    ) -> Result<Response<RoutingTable>, Status> {
+        Ok(Response::new(self.routing.state.read().await.table.clone()))
+    }
+
+    // The node goes on serving the keys it moves while their records are copied: its live
+    // keys first, then the writes made meanwhile, from the tail of its log. The requests
+    // for its keys then wait while the last writes are moved and the node switches to the
+    // new table, after which it redirects them to the new nodes and removes the moved keys.
+    // Until then, the new nodes redirect the requests for these keys to this one.
+    async fn migrate(
+        &self,
+        request: Request<MigrateRequest>
+    ) -> Result<Response<MigrateResponse>, Status> {
         let payload = request.into_inner();
-        debug!("Key in payload: {:?}, Value in payload : {:?}", &payload.key, &payload.value);
+        let _migration = self.routing.migration.try_lock()
+            .map_err(|_| Status::aborted("A migration is already running"))?;
+        let ring = routing_ring(&payload.nodes);
+        if ring.is_empty() {
+            return Err(Status::invalid_argument("A ring must have nodes"));
+        }
+        let table = RoutingTable { version: payload.version, nodes: payload.nodes };
+        let mut previous = self.routing.state.read().await.table.clone();
+        if previous.nodes.is_empty() {
+            previous = payload.current.unwrap_or_default();
+        }
+        if table.version <= previous.version {
+            return Err(Status::failed_precondition(format!(
+                "The routing table is at version {} already",
+                previous.version
+            )));
+        }
+        // Every node of both tables may move keys to this one, and gets told when it switched.
+        let mut nodes: Vec<String> = previous.nodes.iter()
+            .chain(&table.nodes)
+            .filter(|node| **node != self.routing.address)
+            .cloned()
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        let migration = KeyMigration::new(&self.routing.address, ring, table.version, &nodes)?;
+        info!("Migrating to version {} of the routing table, over {:?}", table.version, table.nodes);
 
-        match self.db.set(&*payload.key, &*payload.value) {
-            Ok(_) => {
-                let response = SetResponse {
-                    success: true,
-                };
-                Ok(Response::new(response))
+        let mut response = MigrateResponse::default();
+        // The tail starts before the copy, the writes made meanwhile are sent again.
+        let position = self.db.tail_position().await?;
+        let mut tail = self.db.tail(position.file_id, position.pos);
+        let mut cursor = Vec::new();
+        loop {
+            let (logs, next_cursor) = self.db.live_records(cursor, TAIL_BATCH_SIZE).await?;
+            response.moved += migration.send(&logs).await?;
+            match next_cursor {
+                Some(next_cursor) => cursor = next_cursor,
+                None => break,
             }
-            Err(_) => {
-                let response = SetResponse {
-                    success: false,
-                };
-                Ok(Response::new(response))
+        }
+        // Until the writes come in slower than they are moved.
+        loop {
+            let (next_tail, logs) = self.db.read_tail(tail, TAIL_BATCH_SIZE).await?;
+            tail = next_tail;
+            response.moved += migration.send(&logs).await?;
+            if logs.len() < TAIL_BATCH_SIZE {
+                break;
+            }
+        }
+
+        {
+            let mut state = self.routing.state.write().await;
+            loop {
+                let (next_tail, logs) = self.db.read_tail(tail, TAIL_BATCH_SIZE).await?;
+                tail = next_tail;
+                if logs.is_empty() {
+                    break;
+                }
+                response.moved += migration.send(&logs).await?;
+            }
+
+            let mut encoded = Vec::new();
+            prost::Message::encode(&table, &mut encoded)
+                .map_err(|err| Status::internal(err.to_string()))?;
+            self.db.set(ROUTING_KEY, encoded).await?;
+            let switched = state.switched.remove(&table.version).unwrap_or_default();
+            state.switched.retain(|version, _| *version > table.version);
+            state.pending = nodes.iter().filter(|node| !switched.contains(*node)).cloned().collect();
+            state.previous_ring = routing_ring(&previous.nodes);
+            state.previous = previous;
+            state.table = table.clone();
+            state.ring = migration.ring.clone();
+        }
+        info!("Switched to version {} of the routing table", table.version);
+
+        // Until the other nodes are told, they redirect the requests for the keys moved from
+        // this node here, and this node redirects them back: a node which can't be told at
+        // once is retried in the background.
+        let request = ImportRecordsRequest {
+            records: Vec::new(),
+            source: self.routing.address.clone(),
+            version: table.version,
+            done: true,
+        };
+        let notifications = migration.clients.iter().map(|(node, client)| {
+            let (mut client, request) = (client.clone(), request.clone());
+            async move { (node, client.import_records(request).await) }
+        });
+        for (node, result) in join_all(notifications).await {
+            if let Err(status) = result {
+                warn!("Couldn't tell {} about the new routing table: {}", node, status.message());
+                let (node, mut client, request) = (node.clone(), migration.clients[node].clone(), request.clone());
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(STANDBY_RETRY_DELAY).await;
+                        match client.import_records(request.clone()).await {
+                            Ok(_) => break,
+                            Err(status) => warn!("Couldn't tell {} about the new routing table: {}", node, status.message()),
+                        }
+                    }
+                });
+            }
+        }
+
+        let mut cursor = Vec::new();
+        loop {
+            let keys = self.db.list_keys(Vec::new(), cursor, REBALANCE_BATCH_SIZE).await?;
+            for key in &keys {
+                if migration.owner(key).is_some() {
+                    self.db.remove(key.clone()).await?;
+                    response.removed += 1;
+                }
+            }
+            if keys.len() < REBALANCE_BATCH_SIZE {
+                break;
             }
+            cursor = keys.last().cloned().unwrap_or_default();
         }
+        info!(
+            "Migrated to version {} of the routing table: {} records moved, {} keys removed",
+            table.version,
+            response.moved,
+            response.removed
+        );
+        Ok(Response::new(response))
     }
 
-    async fn kv_remove_call(
+    // The records are written as the latest versions of their keys, with the sequence
+    // numbers of this node: a version it already held is from before it lost the key.
+    async fn import_records(
         &self,
-        request: Request<RemoveRequest>
-    ) -> Result<Response<RemoveResponse>, Status> {
+        request: Request<ImportRecordsRequest>
+    ) -> Result<Response<ImportRecordsResponse>, Status> {
         let payload = request.into_inner();
-        debug!("Key in payload: {:?}", &payload.key);
-
-        match self.db.remove(&payload.key) {
-            Ok(_) => {
-                let response = RemoveResponse {
-                    success: true,
-                };
-                Ok(Response::new(response))
-            }
-            Err(_) => {
-                let response = RemoveResponse {
-                    success: false,
-                };
-                Ok(Response::new(response))
+        for record in payload.records {
+            if record.deleted {
+                self.db.remove(record.key).await?;
+            } else {
+                self.db.set(record.key, record.value).await?;
             }
         }
+        if payload.done {
+            self.routing.switched(payload.source, payload.version).await;
+        }
+        Ok(Response::new(ImportRecordsResponse {}))
     }
 }
 
@@ -129,11 +3592,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .help("Path of a dump file for memory recovery and data persistence. (default: crabe.db)")
         .takes_value(true)
     )
+    .arg(Arg::with_name("store")
+        .long("store")
+        .help("Another store to open, as <name>=<path>, to which the requests with the crabedb-store metadata <name> are routed. Can be repeated. (default: none)")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+    )
     .arg(Arg::with_name("sync-frequency")
         .long("sync-frequency")
         .help("In milliseconds, it describes the frequency of the synchronisation process the in-mem data and the dump. (default: 2000)")
         .takes_value(true)
     )
+    .arg(Arg::with_name("sync-bytes")
+        .long("sync-bytes")
+        .help("Sync the data files once this many bytes have been appended since the last sync, instead of every --sync-frequency milliseconds. 0 keeps the time-based sync. (default: 0)")
+        .takes_value(true)
+    )
     .arg(Arg::with_name("max-file-size")
         .long("max-file-size")
         .help("Set the max file size, in bytes, for a dump. Then, another dump will be created. (default: 1073741824) => 1GB")
@@ -156,7 +3631,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     )
     .arg(Arg::with_name("descriptor-cache-size")
         .long("descriptor-cache-size")
-        .help("Maximum size, in bytes, of the file descriptor cache. (default: 2048)")
+        .help("Capacity of the file descriptor cache, in descriptors or bytes (see --descriptor-cache-unit). (default: 2048)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("descriptor-cache-policy")
+        .long("descriptor-cache-policy")
+        .help("Eviction policy of the file descriptor cache: 'lru' or 'fifo'. (default: lru)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("descriptor-cache-unit")
+        .long("descriptor-cache-unit")
+        .help("Unit of the descriptor cache size: 'handles' or 'bytes' (total size of the cached files). (default: handles)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("compaction-rate-limit")
+        .long("compaction-rate-limit")
+        .help("Maximum compaction I/O, in bytes per second, 0 means unlimited. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("write-slowdown")
+        .long("write-slowdown")
+        .help("Delay the writes once the dead bytes reach this share of the data files, or once there are this many data files (<dead_ratio>:<file_count>, 0 disables a limit). (default: 0:0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("write-stop")
+        .long("write-stop")
+        .help("Block the writes beyond these limits (<dead_ratio>:<file_count>, 0 disables a limit) until the compaction catches up, failing them with RESOURCE_EXHAUSTED after 10 seconds. (default: 0:0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("write-rate-limit")
+        .long("write-rate-limit")
+        .help("Maximum number of mutating requests per second from all the clients, beyond which they fail with RESOURCE_EXHAUSTED, 0 means unlimited. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("peer-write-rate-limit")
+        .long("peer-write-rate-limit")
+        .help("Maximum number of mutating requests per second from a single client address, beyond which they fail with RESOURCE_EXHAUSTED, 0 means unlimited. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("max-key-size")
+        .long("max-key-size")
+        .help("Largest key accepted from the clients, in bytes, beyond which a request fails with INVALID_ARGUMENT. (default: 65535)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("max-value-size")
+        .long("max-value-size")
+        .help("Largest value accepted from the clients, in bytes, beyond which a request fails with INVALID_ARGUMENT. (default: 67108864)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("compaction-parallelism")
+        .long("compaction-parallelism")
+        .help("Maximum number of groups of files merged in parallel by a compaction. (default: 1)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("compaction-concurrency")
+        .long("compaction-concurrency")
+        .help("Maximum number of partitions compacted at once, the most fragmented first. (default: 1)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("compaction-strategy")
+        .long("compaction-strategy")
+        .help("File selection policy of the compaction: 'fragmentation' or 'size-tiered'. (default: fragmentation)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("tombstone-ttl")
+        .long("tombstone-ttl")
+        .help("Minimum age, in seconds, of a delete marker before a compaction may purge it. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("tombstone-seq-gap")
+        .long("tombstone-seq-gap")
+        .help("Minimum number of writes since a delete marker before a compaction may purge it. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("handles-per-file")
+        .long("handles-per-file")
+        .help("Maximum number of cached descriptors per data file, handed out to concurrent readers. (default: 1)")
         .takes_value(true)
     )
     .arg(Arg::with_name("fragmentation-trigger")
@@ -184,6 +3734,172 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .help("the minimum size a file must have to be excluded from compaction. (default: 10485760) => 10MB")
         .takes_value(true)
     )
+    .arg(Arg::with_name("recovery-mode")
+        .long("recovery-mode")
+        .help("How to handle corrupted records while loading the store: 'strict' refuses to open, 'skip-corrupt' skips them. (default: strict)")
+        .possible_values(&["strict", "skip-corrupt"])
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("read-optimized")
+        .long("read-optimized")
+        .help("Serve reads from a lock-free snapshot of the index so they never wait for writers. (default: false)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("read-only")
+        .long("read-only")
+        .help("Serve the files of a store written by another server, without ever writing to it. (default: false)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("in-memory")
+        .long("in-memory")
+        .help("Keep the store in RAM only (Linux), e.g. for a cache: it starts empty and is lost when the server stops. (default: false)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("index-kind")
+        .long("index-kind")
+        .help("Structure of the index in memory: `hash`, or `art` (adaptive radix tree) to keep the keys ordered for the scans and the key listings. (default: hash)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("index-batch-size")
+        .long("index-batch-size")
+        .help("In read-optimized mode, the number of index updates buffered before the snapshot is rebuilt. (default: 1024)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("index-memory-budget")
+        .long("index-memory-budget")
+        .help("Memory in bytes of the index, beyond which the least recently written keys are spilled to disk, 0 for no limit. Not supported in read-optimized mode. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("group-commit")
+        .long("group-commit")
+        .help("Apply writes from a dedicated writer thread so concurrent writes share a single file sync. (default: false)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("partitions")
+        .long("partitions")
+        .help("Number of partitions the keys of a store are split into by hash, each with its own data files, writer and compaction, fixed when the store is created. Can't be used with a standby, tiering, replication tails or backups. (default: 1)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("io-engine")
+        .long("io-engine")
+        .help("I/O engine used for the data files: 'sync' (pread/pwrite) or 'io-uring' (Linux, requires the io-uring feature). (default: sync)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("direct-io")
+        .long("direct-io")
+        .help("Append to the data files with direct I/O (O_DIRECT, Linux only) so a large ingest doesn't evict the page cache of the reads. (default: false)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("checksum")
+        .long("checksum")
+        .help("Checksum of the records of the new data files: 'xxhash32', 'crc32c' which uses the CRC instructions of SSE 4.2 or ARMv8 when available, or 'xxhash64' for 64-bit checksums of the records and hint files. (default: xxhash32)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("value-cache-size")
+        .long("value-cache-size")
+        .help("Size in bytes of the in-memory cache of recently read values, 0 disables it. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("warm-files")
+        .long("warm-files")
+        .help("Number of the most recently written data files opened at startup, whose values also fill the value cache. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("stream-chunk-size")
+        .long("stream-chunk-size")
+        .help("Size in bytes of the chunks sent by KvGetStreamCall when the client doesn't ask for one. (default: 1048576) => 1MB")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("audit-log")
+        .long("audit-log")
+        .help("Path of an append-only file recording every set and remove with its time, key, sequence number and client address. (default: disabled)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("blob-threshold")
+        .long("blob-threshold")
+        .help("Size in bytes from which values are stored in separate blob files, 0 disables it. (default: 0)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("compression")
+        .long("compression")
+        .help("zstd level at which the compaction compresses the values of its output files, with a dictionary trained from the values it compacts. (default: disabled)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("encryption-keys")
+        .long("encryption-keys")
+        .help("Path of a key file holding a '<id> <hex key>' line per 256-bit key: the values of the new data files are encrypted with the key of the highest id, and the files encrypted with the others stay readable. With --key-provider kms, the keys are wrapped by AWS KMS and base64-encoded instead. (default: disabled)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("key-provider")
+        .long("key-provider")
+        .help("Where the encryption keys come from: 'file' (--encryption-keys), 'env' (hex-encoded keys of the CRABEDB_ENCRYPTION_KEY_<id> variables) or 'kms' (keys of --encryption-keys wrapped by AWS KMS, unwrapped with the credentials of AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY in the region of AWS_REGION). (default: file)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("kms-endpoint")
+        .long("kms-endpoint")
+        .help("URL of the AWS KMS service of --key-provider kms. (default: https://kms.<AWS_REGION>.amazonaws.com)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("tiering-bucket")
+        .long("tiering-bucket")
+        .help("S3 bucket to which the data files are offloaded once they weren't written to for --tiering-min-age, with the credentials of AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY in the region of AWS_REGION. (default: disabled)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("tiering-endpoint")
+        .long("tiering-endpoint")
+        .help("URL of the S3-compatible service holding --tiering-bucket. (default: https://s3.amazonaws.com)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("tiering-prefix")
+        .long("tiering-prefix")
+        .help("Prefix of the names of the data files offloaded to --tiering-bucket. (default: none)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("tiering-min-age")
+        .long("tiering-min-age")
+        .help("Time in seconds since the last write to a data file after which it is offloaded. (default: 604800) => 7 days")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("tiering-cache-size")
+        .long("tiering-cache-size")
+        .help("Size in bytes of the local copies kept of the offloaded data files read again. (default: 1073741824) => 1GB")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("peers")
+        .long("peers")
+        .help("Comma-separated addresses (<ip>:<port>) of the other nodes of a cluster, to which the writes are forwarded and from which quorum reads are served. (default: none)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("cluster-secret")
+        .long("cluster-secret")
+        .help("Secret shared by the nodes of a cluster or a sharded deployment, sent with the writes they forward to each other: the writes with a sequence number (replica_seq) are refused without it. Required with --peers. (default: none)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("node-id")
+        .long("node-id")
+        .help("Identity of the node in the CRDT values it updates and in the sequence numbers of its writes, which must differ between the nodes of a cluster. (default: the address)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("standby")
+        .long("standby")
+        .help("Address (<ip>:<port>) of a primary server to replicate: the server refuses the writes of clients until it is promoted. (default: disabled)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("failover-group")
+        .long("failover-group")
+        .help("Comma-separated addresses (<ip>:<port>) of the nodes of a failover group, which elect their primary and promote a standby when it fails: a majority has to be up, so at least 3 nodes are needed to fail over. (default: disabled)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("advertise-address")
+        .long("advertise-address")
+        .help("Address (<ip>:<port>) of the server as the other nodes of its failover group, its peers or the cluster administration know it. (default: the address)")
+        .takes_value(true)
+    )
+    .arg(Arg::with_name("learner")
+        .long("learner")
+        .help("Follow the primary of the failover group as a standby which neither votes nor is elected, and isn't part of the group. (default: false)")
+        .takes_value(true)
+    )
     .get_matches();
 
     let addr = match matches.value_of("address") {
@@ -197,43 +3913,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
         None => "127.0.0.1:5000",
     };
-    let dump_path = match matches.value_of("dump") {
-        Some(path) => path,
-        None => "crabe.db",
-    };
+    let dump_path = matches.value_of("dump").unwrap_or("crabe.db");
     let sync_freq = match matches.value_of("sync-frequency") {
         Some(sf) => {
-            match sf.parse::<usize>() {
-                Ok(arg) => arg,
-                Err(_) => 2000,
-            }
+            sf.parse::<usize>().unwrap_or(2000)
         },
         None => 2000,
     };
+    let sync_bytes = match matches.value_of("sync-bytes") {
+        Some(sb) => {
+            sb.parse::<usize>().unwrap_or(0)
+        },
+        None => 0,
+    };
+    let sync = if sync_bytes > 0 {
+        SyncOptions::EveryBytes(sync_bytes)
+    } else {
+        SyncOptions::Frequency(sync_freq)
+    };
     let max_file_size = match matches.value_of("max-file-size") {
         Some(mfs) => {
-            match mfs.parse::<usize>() {
-                Ok(arg) => arg,
-                Err(_) => 1073741824,
-            }
+            mfs.parse::<usize>().unwrap_or(1073741824)
         },
         None => 1073741824,
     };
     let enable_compaction = match matches.value_of("enable-compaction") {
         Some(ec) => {
-            match ec.parse::<bool>() {
-                Ok(arg) => arg,
-                Err(_) => true,
-            }
+            ec.parse::<bool>().unwrap_or(true)
         },
         None => true,
     };
     let compaction_frequency = match matches.value_of("compaction-frequency") {
         Some(cf) => {
-            match cf.parse::<u64>() {
-                Ok(arg) => arg,
-                Err(_) => 3600,
-            }
+            cf.parse::<u64>().unwrap_or(3600)
         },
         None => 3600,
     };
@@ -258,62 +3970,242 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     let descriptor_cache_size = match matches.value_of("descriptor-cache-size") {
         Some(dcs) => {
-            match dcs.parse::<usize>() {
-                Ok(arg) => arg,
-                Err(_) => 2048,
-            }
+            dcs.parse::<usize>().unwrap_or(2048)
         },
         None => 2048,
     };
+    let compaction_rate_limit = match matches.value_of("compaction-rate-limit") {
+        Some(crl) => {
+            crl.parse::<u64>().unwrap_or(0)
+        },
+        None => 0,
+    };
+    let write_limits = |name: &str| -> (f64, usize) {
+        match matches.value_of(name).map(|limits| limits.split(':').collect::<Vec<_>>()) {
+            Some(ref limits) if limits.len() == 2 => (
+                limits[0].parse::<f64>().unwrap_or(0.0),
+                limits[1].parse::<usize>().unwrap_or(0),
+            ),
+            _ => (0.0, 0),
+        }
+    };
+    let (slowdown_dead_ratio, slowdown_files) = write_limits("write-slowdown");
+    let (stop_dead_ratio, stop_files) = write_limits("write-stop");
+    let write_rate_limit = match matches.value_of("write-rate-limit") {
+        Some(wrl) => {
+            wrl.parse::<u64>().unwrap_or(0)
+        },
+        None => 0,
+    };
+    let peer_write_rate_limit = match matches.value_of("peer-write-rate-limit") {
+        Some(pwrl) => {
+            pwrl.parse::<u64>().unwrap_or(0)
+        },
+        None => 0,
+    };
+    let max_key_size = match matches.value_of("max-key-size") {
+        Some(mks) => {
+            mks.parse::<usize>().unwrap_or(MAX_KEY_SIZE as usize).min(MAX_KEY_SIZE as usize)
+        },
+        None => MAX_KEY_SIZE as usize,
+    };
+    let max_value_size = match matches.value_of("max-value-size") {
+        Some(mvs) => {
+            mvs.parse::<usize>().unwrap_or(64 * 1024 * 1024)
+        },
+        None => 64 * 1024 * 1024,
+    };
+    let compaction_parallelism = match matches.value_of("compaction-parallelism") {
+        Some(cp) => {
+            cp.parse::<usize>().unwrap_or(1)
+        },
+        None => 1,
+    };
+    let compaction_concurrency = match matches.value_of("compaction-concurrency") {
+        Some(cc) => {
+            cc.parse::<usize>().unwrap_or(1)
+        },
+        None => 1,
+    };
+    let size_tiered = matches.value_of("compaction-strategy") == Some("size-tiered");
+    let tombstone_ttl = match matches.value_of("tombstone-ttl") {
+        Some(tt) => {
+            tt.parse::<u64>().unwrap_or(0)
+        },
+        None => 0,
+    };
+    let tombstone_seq_gap = match matches.value_of("tombstone-seq-gap") {
+        Some(tsg) => {
+            tsg.parse::<u64>().unwrap_or(0)
+        },
+        None => 0,
+    };
+    let descriptor_cache_policy = match matches.value_of("descriptor-cache-policy") {
+        Some("fifo") => EvictionPolicy::Fifo,
+        _ => EvictionPolicy::Lru,
+    };
+    let descriptor_cache_unit = match matches.value_of("descriptor-cache-unit") {
+        Some("bytes") => CacheUnit::Bytes,
+        _ => CacheUnit::Handles,
+    };
+    let handles_per_file = match matches.value_of("handles-per-file") {
+        Some(hpf) => {
+            hpf.parse::<usize>().unwrap_or(1)
+        },
+        None => 1,
+    };
     let fragmentation_trigger = match matches.value_of("fragmentation-trigger") {
         Some(ftrig) => {
-            match ftrig.parse::<f64>() {
-                Ok(arg) => arg,
-                Err(_) => 0.6,
-            }
+            ftrig.parse::<f64>().unwrap_or(0.6)
         },
         None => 0.6,
     };
     let fragmentation_threshold = match matches.value_of("fragmentation-threshold") {
         Some(fthres) => {
-            match fthres.parse::<f64>() {
-                Ok(arg) => arg,
-                Err(_) => 0.4,
-            }
+            fthres.parse::<f64>().unwrap_or(0.4)
         },
         None => 0.4,
     };
     let dead_bytes_trigger = match matches.value_of("dead-bytes-trigger") {
         Some(dbytestrig) => {
-            match dbytestrig.parse::<u64>() {
-                Ok(arg) => arg,
-                Err(_) => 536870912,
-            }
+            dbytestrig.parse::<u64>().unwrap_or(536870912)
         },
         None => 536870912,
     };
     let dead_bytes_threshold = match matches.value_of("dead-bytes-threshold") {
         Some(dbytesthres) =>
-            match dbytesthres.parse::<u64>() {
-                Ok(arg) => arg,
-                Err(_) => 134217728,
-            },
+            dbytesthres.parse::<u64>().unwrap_or(134217728),
         None => 134217728
     };
     let small_file_threshold = match matches.value_of("small-file-threshold") {
         Some(sft) => {
-            match sft.parse::<u64>() {
-                Ok(arg) => arg,
-                Err(_) => 10485760,
-            }
+            sft.parse::<u64>().unwrap_or(10485760)
         },
         None => 10485760,
     };
+    let recovery_mode = match matches.value_of("recovery-mode") {
+        Some("skip-corrupt") => RecoveryMode::SkipCorrupt,
+        _ => RecoveryMode::Strict,
+    };
+    let read_optimized = match matches.value_of("read-optimized") {
+        Some(ro) => {
+            ro.parse::<bool>().unwrap_or(false)
+        },
+        None => false,
+    };
+    let read_only = match matches.value_of("read-only") {
+        Some(ro) => {
+            ro.parse::<bool>().unwrap_or(false)
+        },
+        None => false,
+    };
+    let in_memory = match matches.value_of("in-memory") {
+        Some(im) => {
+            im.parse::<bool>().unwrap_or(false)
+        },
+        None => false,
+    };
+    let index_batch_size = match matches.value_of("index-batch-size") {
+        Some(ibs) => {
+            ibs.parse::<usize>().unwrap_or(1024)
+        },
+        None => 1024,
+    };
+    let index_kind = match matches.value_of("index-kind") {
+        Some("art") => IndexKind::Art,
+        _ => IndexKind::Hash,
+    };
+    let index_memory_budget = match matches.value_of("index-memory-budget") {
+        Some(imb) => {
+            imb.parse::<usize>().unwrap_or(0)
+        },
+        None => 0,
+    };
+    let group_commit = match matches.value_of("group-commit") {
+        Some(gc) => {
+            gc.parse::<bool>().unwrap_or(false)
+        },
+        None => false,
+    };
+    let partitions = match matches.value_of("partitions") {
+        Some(p) => {
+            p.parse::<usize>().unwrap_or(1)
+        },
+        None => 1,
+    };
+    let direct_io = match matches.value_of("direct-io") {
+        Some(dio) => {
+            dio.parse::<bool>().unwrap_or(false)
+        },
+        None => false,
+    };
+    let checksum = match matches.value_of("checksum") {
+        Some("crc32c") => ChecksumKind::Crc32c,
+        Some("xxhash64") => ChecksumKind::XxHash64,
+        _ => ChecksumKind::XxHash32,
+    };
+    let io_engine = match matches.value_of("io-engine") {
+        Some("io-uring") => IoEngineKind::IoUring,
+        _ => IoEngineKind::Sync,
+    };
+    let value_cache_size = match matches.value_of("value-cache-size") {
+        Some(vcs) => {
+            vcs.parse::<usize>().unwrap_or(0)
+        },
+        None => 0,
+    };
+
+    let warm_files = match matches.value_of("warm-files") {
+        Some(wf) => {
+            wf.parse::<usize>().unwrap_or(0)
+        },
+        None => 0,
+    };
+
+    let stream_chunk_size = match matches.value_of("stream-chunk-size") {
+        Some(scs) => {
+            scs.parse::<usize>().unwrap_or(1024 * 1024)
+        },
+        None => 1024 * 1024,
+    };
+
+    let blob_threshold = match matches.value_of("blob-threshold") {
+        Some(bt) => {
+            bt.parse::<usize>().unwrap_or(0)
+        },
+        None => 0,
+    };
+
+    let tiering_min_age = match matches.value_of("tiering-min-age") {
+        Some(tma) => {
+            tma.parse::<u64>().unwrap_or(7 * 24 * 3600)
+        },
+        None => 7 * 24 * 3600,
+    };
+
+    let tiering_cache_size = match matches.value_of("tiering-cache-size") {
+        Some(tcs) => {
+            tcs.parse::<u64>().unwrap_or(1024 * 1024 * 1024)
+        },
+        None => 1024 * 1024 * 1024,
+    };
+
+    let standby = matches.value_of("standby");
+    let learner = match matches.value_of("learner") {
+        Some(l) => {
+            l.parse::<bool>().unwrap_or(false)
+        },
+        None => false,
+    };
 
-    let db = StorageOptions::default()
-        .sync(SyncOptions::Frequency(sync_freq))
+    let mut options = StorageOptions::default();
+    options
+        .sync(sync)
         .max_file_size(max_file_size)
         .file_chunk_queue_size(descriptor_cache_size)
+        .file_chunk_queue_policy(descriptor_cache_policy)
+        .file_chunk_queue_unit(descriptor_cache_unit)
+        .handles_per_file(handles_per_file)
         .compaction(enable_compaction)
         .compaction_check_frequency(compaction_frequency)
         .compaction_window(start_compaction, end_compaction)
@@ -322,12 +4214,159 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .dead_bytes_trigger(dead_bytes_trigger)
         .dead_bytes_threshold(dead_bytes_threshold)
         .small_file_threshold(small_file_threshold)
-        .load(dump_path)?;
+        .compaction_rate_limit(compaction_rate_limit)
+        .write_slowdown(slowdown_dead_ratio, slowdown_files)
+        .write_stop(stop_dead_ratio, stop_files)
+        .compaction_parallelism(compaction_parallelism)
+        .compaction_concurrency(compaction_concurrency)
+        .tombstone_ttl(Duration::from_secs(tombstone_ttl))
+        .tombstone_seq_gap(tombstone_seq_gap)
+        .recovery_mode(recovery_mode)
+        .read_optimized(read_optimized)
+        .read_only(read_only)
+        .in_memory(in_memory)
+        .index_kind(index_kind)
+        .index_batch_size(index_batch_size)
+        .index_memory_budget(index_memory_budget)
+        .group_commit(group_commit)
+        .partitions(partitions)
+        .io_engine(io_engine)
+        .direct_io(direct_io)
+        .checksum(checksum)
+        .value_cache_size(value_cache_size)
+        .warm_files(warm_files)
+        .blob_threshold(blob_threshold)
+        .standby(standby.is_some());
+    let node_id = match matches.value_of("node-id") {
+        Some(id) => id.to_string(),
+        None => addr.to_string(),
+    };
+    // The nodes of a cluster write the same keys, their sequence numbers tell them apart.
+    if matches.value_of("peers").is_some() {
+        options.node_id(xxhash32(node_id.as_bytes()) as u16);
+    }
+    if let Some(path) = matches.value_of("audit-log") {
+        options.audit(AuditLog::open(path)?);
+    }
+    if let Some(level) = matches.value_of("compression").and_then(|level| level.parse::<i32>().ok()) {
+        options.compression(level);
+    }
+    match (matches.value_of("key-provider").unwrap_or("file"), matches.value_of("encryption-keys")) {
+        ("file", Some(path)) => {
+            options.encryption(Keyring::load(path)?);
+        }
+        ("file", None) => {}
+        ("env", _) => {
+            options.encryption(Keyring::with_provider(EnvKeyProvider::default()));
+        }
+        ("kms", Some(path)) => {
+            let provider = KmsKeyProvider::from_env(path, matches.value_of("kms-endpoint"))?;
+            options.encryption(Keyring::with_provider(provider));
+        }
+        (provider, _) => {
+            return Err(format!("invalid key provider {:?}, or --encryption-keys missing", provider).into())
+        }
+    }
+    if size_tiered {
+        options.compaction_strategy(SizeTieredStrategy::default());
+    }
+    if let Some(bucket) = matches.value_of("tiering-bucket") {
+        let endpoint = matches.value_of("tiering-endpoint").unwrap_or("https://s3.amazonaws.com");
+        let mut store = S3ObjectStore::from_env(endpoint, bucket)?;
+        store.prefix(matches.value_of("tiering-prefix").unwrap_or(""));
+        let mut tiering = Tiering::new(store);
+        tiering
+            .min_age(Duration::from_secs(tiering_min_age))
+            .cache_size(tiering_cache_size);
+        options.tiering(tiering);
+    }
+    // The named stores are neither standbys nor tiered, the bucket being the default one's.
+    let mut store_options = options.clone();
+    store_options.standby = false;
+    store_options.tiering = None;
+    if let Some(primary) = standby {
+        if is_new_store_path(Path::new(dump_path))? {
+            bootstrap(primary, dump_path).await;
+        }
+    }
+    let db = CrabeDB::load(dump_path, options).await?;
+
+    let address = matches.value_of("advertise-address").unwrap_or(addr);
+    let election = match matches.value_of("failover-group") {
+        Some(_) if matches.value_of("peers").is_some() => {
+            return Err("a failover group can't have peers".into());
+        }
+        Some(group) => {
+            let nodes = group.split(',').filter(|node| !node.is_empty() && *node != address).collect::<Vec<_>>();
+            let election = Election::new(db.clone(), address, &nodes, standby, learner).await?;
+            tokio::spawn(election.clone().run());
+            tokio::spawn(replicate_group(db.clone(), election.clone()));
+            Some(election)
+        }
+        None => {
+            if let Some(primary) = standby {
+                tokio::spawn(replicate(db.clone(), primary.to_string()));
+            }
+            None
+        }
+    };
 
-    let kv_store_api = KvStoreAPI { db };
+    let mut named = HashMap::new();
+    for store in matches.values_of("store").into_iter().flatten() {
+        let (name, path) = match store.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => (name, path),
+            _ => return Err(format!("invalid store {:?}, expected <name>=<path>", store).into()),
+        };
+        if named.contains_key(name) {
+            return Err(format!("store {:?} given twice", name).into());
+        }
+        info!("Opening store {:?} in {}", name, path);
+        named.insert(name.to_string(), CrabeDB::load(path, store_options.clone()).await?);
+    }
+    let stores = Stores { default: db.clone(), named: Arc::new(named) };
+
+    let admin_api = AdminAPI { stores: stores.clone() };
+    let replication_api = ReplicationAPI { db: db.clone(), election: election.clone() };
+    let cluster_secret = matches.value_of("cluster-secret");
+    let peers = match matches.value_of("peers") {
+        Some(p) => {
+            Peers::new(&p.split(',').filter(|addr| !addr.is_empty()).collect::<Vec<_>>(), cluster_secret)?
+        },
+        None => Peers::new(&[], cluster_secret)?,
+    };
+    if !peers.clients.is_empty() {
+        tokio::spawn(replay_hints(db.clone(), peers.clone()));
+    }
+    let lease_api = LeaseAPI { stores: stores.clone(), peers: peers.clone() };
+    let write_limits = WriteLimits::new(write_rate_limit, peer_write_rate_limit);
+    let size_limits = SizeLimits { key: max_key_size, value: max_value_size };
+    let routing = Routing::load(&db, address).await?;
+    let cluster_api = ClusterAPI {
+        db: db.clone(),
+        address: address.to_string(),
+        election: election.clone(),
+        peers: peers.clone(),
+        routing: routing.clone(),
+    };
+    let kv_store_api = KvStoreAPI {
+        stores,
+        chunk_size: stream_chunk_size.max(1),
+        peers,
+        write_limits,
+        size_limits,
+        node_id,
+        election: election.clone(),
+        routing,
+    };
+    let message_limit = size_limits.message();
     info!("CrabeDB Server listening on {}", addr);
     Server::builder()
-        .add_service(KvstoreServer::new(kv_store_api))
+        .add_service(MessageSizeLimit::new(KvstoreServer::new(kv_store_api), message_limit))
+        .add_service(MessageSizeLimit::new(AdminServer::new(admin_api), message_limit))
+        .add_service(MessageSizeLimit::new(ReplicationServer::new(replication_api), message_limit))
+        .add_service(MessageSizeLimit::new(LeaseServer::new(lease_api), message_limit))
+        .add_service(MessageSizeLimit::new(ClusterServer::new(cluster_api), message_limit))
+        .add_optional_service(election.map(|election| MessageSizeLimit::new(ElectionServer::new(election), message_limit)))
         .serve(addr.parse().unwrap())
         .await?;
 
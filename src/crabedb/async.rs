@@ -0,0 +1,517 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tokio::task;
+
+use crate::storage::bootstrap::{SnapshotFile, StoreSnapshot};
+use crate::storage::blob::ValueReader as SyncValueReader;
+use crate::storage::crabe_db::{CasResult, CrabeDB as SyncCrabeDB, ValueWriter as SyncValueWriter};
+use crate::storage::deadline;
+use crate::storage::error::{BackgroundError, Error, Result};
+use crate::storage::lease::{LeaseChanges, LeaseInfo};
+use crate::storage::lsm::{LogPosition, Tail};
+use crate::storage::options::StorageOptions;
+use crate::storage::slot::Log;
+use crate::storage::standby::ElectionState;
+use crate::storage::stats::{CompactionStatus, FileStats, Stats, ValueMetadata};
+use crate::storage::verify::ConsistencyReport;
+use crate::storage::write_batch::WriteBatch;
+
+// Async facade over the storage engine for use inside a tokio runtime. Every call that
+// may touch the disk (or wait for a lock) runs on the blocking thread pool, so the
+// executor threads are never stalled by file I/O.
+#[derive(Clone)]
+pub struct CrabeDB {
+    db: Arc<SyncCrabeDB>,
+    deadline: Option<Instant>,
+}
+
+impl From<SyncCrabeDB> for CrabeDB {
+    fn from(db: SyncCrabeDB) -> CrabeDB {
+        CrabeDB { db: Arc::new(db), deadline: None }
+    }
+}
+
+impl CrabeDB {
+    pub async fn load(path: &str, options: StorageOptions) -> Result<CrabeDB> {
+        let path = path.to_string();
+        run_blocking(move || SyncCrabeDB::load(&path, options))
+            .await
+            .map(CrabeDB::from)
+    }
+
+    pub fn blocking(&self) -> &SyncCrabeDB {
+        &self.db
+    }
+
+    // A handle on the same store whose calls fail with `Error::DeadlineExceeded` once the
+    // deadline has passed, e.g. the one of the gRPC request they serve: a call still
+    // waiting for a blocking thread then doesn't start, and the long ones stop early
+    // (see `storage::deadline`).
+    pub fn with_deadline(&self, deadline: Option<Instant>) -> CrabeDB {
+        CrabeDB { db: self.db.clone(), deadline }
+    }
+
+    async fn run_blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        run_blocking_until(self.deadline, f).await
+    }
+
+    pub async fn get<K: Into<Vec<u8>>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        let db = self.db.clone();
+        let key = key.into();
+        self.run_blocking(move || db.get(key)).await
+    }
+
+    pub async fn get_with_metadata<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+    ) -> Result<Option<(Vec<u8>, ValueMetadata)>> {
+        let db = self.db.clone();
+        let key = key.into();
+        self.run_blocking(move || db.get_with_metadata(key)).await
+    }
+
+    pub async fn get_at_least<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        seq: u64,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>> {
+        let db = self.db.clone();
+        let key = key.into();
+        self.run_blocking(move || db.get_at_least(key, seq, timeout)).await
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.db.last_seq()
+    }
+
+    pub async fn get_bytes<K: Into<Vec<u8>>>(&self, key: K) -> Result<Option<Bytes>> {
+        let db = self.db.clone();
+        let key = key.into();
+        self.run_blocking(move || db.get_bytes(key)).await
+    }
+
+    pub async fn value_reader<K: Into<Vec<u8>>>(&self, key: K) -> Result<Option<ValueReader>> {
+        let db = self.db.clone();
+        let key = key.into();
+        let deadline = self.deadline;
+        let reader = self.run_blocking(move || db.value_reader(key)).await?;
+        Ok(reader.map(|reader| ValueReader { reader, deadline }))
+    }
+
+    pub async fn value_writer<K: Into<Vec<u8>>>(&self, key: K) -> Result<ValueWriter> {
+        let db = self.db.clone();
+        let key = key.into();
+        let deadline = self.deadline;
+        let writer = self.run_blocking(move || db.value_writer(key)).await?;
+        Ok(ValueWriter { db: self.db.clone(), writer, deadline })
+    }
+
+    pub async fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&self, key: K, value: V) -> Result<u64> {
+        let db = self.db.clone();
+        let (key, value) = (key.into(), value.into());
+        self.run_blocking(move || db.set(key, value)).await
+    }
+
+    pub async fn set_as<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        peer: Option<String>,
+        key: K,
+        value: V,
+    ) -> Result<u64> {
+        let db = self.db.clone();
+        let (key, value) = (key.into(), value.into());
+        self.run_blocking(move || db.set_as(peer.as_deref(), key, value)).await
+    }
+
+    // The result of each write of the batch, see `storage::crabe_db::CrabeDB::write`.
+    pub async fn write_as(&self, peer: Option<String>, batch: WriteBatch) -> Result<Vec<Result<u64>>> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.write_as(peer.as_deref(), batch)).await
+    }
+
+    // See `storage::crabe_db::CrabeDB::ingest_files`.
+    pub async fn ingest_files(&self, paths: Vec<PathBuf>) -> Result<u64> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.ingest_files(&paths)).await
+    }
+
+    // See `storage::crabe_db::CrabeDB::hot_backup`.
+    pub async fn hot_backup(&self, dest: &str) -> Result<u64> {
+        let db = self.db.clone();
+        let dest = dest.to_string();
+        self.run_blocking(move || db.hot_backup(&dest)).await
+    }
+
+    pub async fn bootstrap_snapshot(&self) -> Result<StoreSnapshot> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.bootstrap_snapshot()).await
+    }
+
+    // The next chunk of a file of a snapshot, see `SnapshotFile::read_chunk`.
+    pub async fn read_snapshot_chunk(&self, mut file: SnapshotFile, size: usize) -> Result<(SnapshotFile, Vec<u8>)> {
+        self.run_blocking(move || {
+            let chunk = file.read_chunk(size)?;
+            Ok((file, chunk))
+        }).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.flush()).await
+    }
+
+    // See `storage::crabe_db::CrabeDB::close`, the other handles can't write afterwards.
+    pub async fn close(self) -> Result<()> {
+        let db = SyncCrabeDB::clone(&self.db);
+        self.run_blocking(move || db.close()).await
+    }
+
+    pub async fn remove_as<K: Into<Vec<u8>>>(&self, peer: Option<String>, key: K) -> Result<u64> {
+        let db = self.db.clone();
+        let key = key.into();
+        self.run_blocking(move || db.remove_as(peer.as_deref(), key)).await
+    }
+
+    pub async fn remove<K: Into<Vec<u8>>>(&self, key: K) -> Result<u64> {
+        let db = self.db.clone();
+        let key = key.into();
+        self.run_blocking(move || db.remove(key)).await
+    }
+
+    pub async fn delete_range<S: Into<Vec<u8>>, E: Into<Vec<u8>>>(&self, start: S, end: E) -> Result<u64> {
+        let db = self.db.clone();
+        let (start, end) = (start.into(), end.into());
+        self.run_blocking(move || db.delete_range(start, end)).await
+    }
+
+    pub async fn delete_prefix<P: Into<Vec<u8>>>(&self, prefix: P) -> Result<u64> {
+        let db = self.db.clone();
+        let prefix = prefix.into();
+        self.run_blocking(move || db.delete_prefix(prefix)).await
+    }
+
+    pub async fn list_keys<P: Into<Vec<u8>>, C: Into<Vec<u8>>>(
+        &self,
+        prefix: P,
+        cursor: C,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let db = self.db.clone();
+        let (prefix, cursor) = (prefix.into(), cursor.into());
+        self.run_blocking(move || db.list_keys(prefix, cursor, limit)).await
+    }
+
+    pub async fn scan<P: Into<Vec<u8>>>(&self, prefix: P) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let db = self.db.clone();
+        let prefix = prefix.into();
+        self.run_blocking(move || db.scan(prefix)).await
+    }
+
+    pub async fn pause_compaction(&self) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || {
+            db.pause_compaction();
+            Ok(())
+        }).await
+    }
+
+    pub fn resume_compaction(&self) {
+        self.db.resume_compaction()
+    }
+
+    pub fn is_compaction_paused(&self) -> bool {
+        self.db.is_compaction_paused()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.db.stats()
+    }
+
+    pub async fn file_stats(&self) -> Result<Vec<FileStats>> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.file_stats()).await
+    }
+
+    pub async fn check_consistency(&self) -> Result<ConsistencyReport> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.check_consistency()).await
+    }
+
+    pub fn approximate_key_count(&self) -> usize {
+        self.db.approximate_key_count()
+    }
+
+    pub async fn approximate_size<S: Into<Vec<u8>>, E: Into<Vec<u8>>>(&self, start: S, end: E) -> Result<u64> {
+        let db = self.db.clone();
+        let (start, end) = (start.into(), end.into());
+        self.run_blocking(move || db.approximate_size(start, end)).await
+    }
+
+    pub fn compaction_status(&self) -> CompactionStatus {
+        self.db.compaction_status()
+    }
+
+    pub async fn tail_position(&self) -> Result<LogPosition> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.tail_position()).await
+    }
+
+    pub fn tail(&self, from_file_id: u32, from_pos: u64) -> Tail {
+        self.db.tail(from_file_id, from_pos)
+    }
+
+    // Read at most `max` records from the tail, which is handed back to read the next ones.
+    pub async fn read_tail(&self, mut tail: Tail, max: usize) -> Result<(Tail, Vec<Log<'static>>)> {
+        self.run_blocking(move || {
+            let logs = tail.by_ref().take(max).collect::<Result<Vec<_>>>()?;
+            Ok((tail, logs))
+        }).await
+    }
+
+    pub fn background_error(&self) -> Option<BackgroundError> {
+        self.db.background_error()
+    }
+
+    pub async fn clear_background_error(&self) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.clear_background_error()).await
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.db.is_standby()
+    }
+
+    pub fn lease_changes(&self, feed_id: u64, seq: u64) -> LeaseChanges {
+        self.db.lease_changes(feed_id, seq)
+    }
+
+    pub async fn apply_lease_changes(&self, changes: LeaseChanges) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.apply_lease_changes(&changes)).await
+    }
+
+    pub fn applied_lease_changes(&self) -> Option<(u64, u64)> {
+        self.db.applied_lease_changes()
+    }
+
+    pub async fn start_seed(&self) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.start_seed()).await
+    }
+
+    pub fn is_seeding(&self) -> bool {
+        self.db.is_seeding()
+    }
+
+    pub async fn standby_position(&self) -> Result<Option<LogPosition>> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.standby_position()).await
+    }
+
+    pub async fn live_records<C: Into<Vec<u8>>>(
+        &self,
+        cursor: C,
+        limit: usize,
+    ) -> Result<(Vec<Log<'static>>, Option<Vec<u8>>)> {
+        let db = self.db.clone();
+        let cursor = cursor.into();
+        self.run_blocking(move || db.live_records(cursor, limit)).await
+    }
+
+    pub async fn apply(&self, logs: Vec<Log<'static>>, position: Option<LogPosition>) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.apply(logs, position)).await
+    }
+
+    pub async fn merge(&self, log: Log<'static>) -> Result<bool> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.merge(log)).await
+    }
+
+    pub async fn merge_with_lease(&self, log: Log<'static>, lease: u64, ttl: Duration) -> Result<bool> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.merge_with_lease(log, lease, ttl)).await
+    }
+
+    pub async fn promote(&self) -> Result<u64> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.promote()).await
+    }
+
+    pub async fn demote(&self) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.demote()).await
+    }
+
+    pub async fn reset_standby(&self) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.reset_standby()).await
+    }
+
+    pub async fn election_state(&self) -> Result<ElectionState> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.election_state()).await
+    }
+
+    pub async fn save_election_state(&self, state: ElectionState) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.save_election_state(&state)).await
+    }
+
+    pub async fn records_after(&self, seq: u64, limit: usize) -> Result<Vec<Log<'static>>> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.records_after(seq, limit)).await
+    }
+
+    pub async fn grant_lease(&self, ttl: Duration) -> Result<u64> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.grant_lease(ttl)).await
+    }
+
+    pub fn keep_alive_lease(&self, id: u64) -> Result<Duration> {
+        self.db.keep_alive_lease(id)
+    }
+
+    pub async fn mirror_lease(&self, id: u64, ttl: Duration) -> Result<()> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.mirror_lease(id, ttl)).await
+    }
+
+    pub async fn revoke_lease(&self, id: u64) -> Result<u64> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.revoke_lease(id)).await
+    }
+
+    pub async fn lease_info(&self, id: u64) -> Result<Option<LeaseInfo>> {
+        let db = self.db.clone();
+        self.run_blocking(move || db.lease_info(id)).await
+    }
+
+    pub async fn key_lease<K: Into<Vec<u8>>>(&self, key: K) -> Result<Option<LeaseInfo>> {
+        let db = self.db.clone();
+        let key = key.into();
+        self.run_blocking(move || db.key_lease(key)).await
+    }
+
+    pub async fn compare_and_swap_as<K: Into<Vec<u8>>>(
+        &self,
+        peer: Option<String>,
+        key: K,
+        expected: Option<Vec<u8>>,
+        value: Option<Vec<u8>>,
+        lease: Option<u64>,
+    ) -> Result<CasResult> {
+        let db = self.db.clone();
+        let key = key.into();
+        self.run_blocking(move || {
+            db.compare_and_swap_as(peer.as_deref(), key, expected.as_deref(), value.as_deref(), lease)
+        }).await
+    }
+
+    pub async fn set_with_lease_as<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        peer: Option<String>,
+        key: K,
+        value: V,
+        lease: u64,
+    ) -> Result<u64> {
+        let db = self.db.clone();
+        let (key, value) = (key.into(), value.into());
+        self.run_blocking(move || db.set_with_lease_as(peer.as_deref(), key, value, lease)).await
+    }
+
+    pub async fn set_with_ttl_as<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        peer: Option<String>,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<u64> {
+        let db = self.db.clone();
+        let (key, value) = (key.into(), value.into());
+        self.run_blocking(move || db.set_with_ttl_as(peer.as_deref(), key, value, ttl)).await
+    }
+}
+
+// See `storage::blob::ValueReader`. The reads run on the blocking thread pool too.
+pub struct ValueReader {
+    reader: SyncValueReader,
+    deadline: Option<Instant>,
+}
+
+impl ValueReader {
+    pub fn size(&self) -> u64 {
+        self.reader.size()
+    }
+
+    pub async fn read(mut self, len: usize) -> Result<(ValueReader, Bytes)> {
+        let deadline = self.deadline;
+        run_blocking_until(deadline, move || {
+            let chunk = self.reader.read(len)?;
+            Ok((self, chunk))
+        }).await
+    }
+}
+
+// See `storage::crabe_db::ValueWriter`. The writes run on the blocking thread pool too.
+pub struct ValueWriter {
+    db: Arc<SyncCrabeDB>,
+    writer: SyncValueWriter,
+    deadline: Option<Instant>,
+}
+
+impl ValueWriter {
+    pub fn size(&self) -> u64 {
+        self.writer.size()
+    }
+
+    pub async fn write(mut self, chunk: Vec<u8>) -> Result<ValueWriter> {
+        let deadline = self.deadline;
+        run_blocking_until(deadline, move || {
+            self.writer.write(&self.db, &chunk)?;
+            Ok(self)
+        }).await
+    }
+
+    pub async fn commit(self, peer: Option<String>) -> Result<u64> {
+        let (db, writer) = (self.db, self.writer);
+        run_blocking_until(self.deadline, move || writer.commit(&db, peer.as_deref())).await
+    }
+
+    pub async fn merge(self, seq: u64) -> Result<bool> {
+        let (db, writer) = (self.db, self.writer);
+        run_blocking_until(self.deadline, move || writer.merge(&db, seq)).await
+    }
+}
+
+async fn run_blocking_until<T, F>(deadline: Option<Instant>, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    run_blocking(move || {
+        deadline::with_deadline(deadline, || {
+            deadline::check()?;
+            f()
+        })
+    }).await
+}
+
+async fn run_blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| Err(Error::Io(io::Error::other(err.to_string()))))
+}
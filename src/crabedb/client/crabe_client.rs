@@ -0,0 +1,112 @@
+use tonic::metadata::errors::InvalidMetadataValue;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Status, Streaming};
+
+use super::protobuf::kvstore_client::KvstoreClient;
+use super::store_interceptor;
+use super::protobuf::{
+    BatchOp, BatchOpResult, BatchRequest, Consistency, GetRequest, KeyValue, RemoveRequest, ScanRequest, SetRequest, WatchEvent,
+    WatchRequest,
+};
+
+// Typed async client of a single server. It's cheap to clone, the clones sharing the
+// connection, and every request may be made through a shared reference.
+#[derive(Clone, Debug)]
+pub struct CrabeClient {
+    kv: KvstoreClient<Channel>,
+}
+
+impl CrabeClient {
+    // `addr` is the <ip>:<port> of the server.
+    pub async fn connect(addr: &str) -> Result<CrabeClient, Box<dyn std::error::Error + Send + Sync>> {
+        let channel = Endpoint::from_shared(format!("http://{}", addr))?.connect().await?;
+        Ok(CrabeClient::new(channel))
+    }
+
+    // Over a channel built by the application, e.g. with its own timeouts or TLS settings.
+    pub fn new(channel: Channel) -> CrabeClient {
+        CrabeClient {
+            kv: KvstoreClient::new(channel),
+        }
+    }
+
+    // Over `channel`, with every request routed to the store `store` of the server.
+    pub fn with_store(channel: Channel, store: &str) -> Result<CrabeClient, InvalidMetadataValue> {
+        Ok(CrabeClient {
+            kv: KvstoreClient::with_interceptor(channel, store_interceptor(store)?),
+        })
+    }
+
+    // The generated client, for the requests and options this one doesn't cover.
+    pub fn kv(&self) -> KvstoreClient<Channel> {
+        self.kv.clone()
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, Status> {
+        let response = self.kv().kv_get_call(GetRequest {
+            key: key.to_string(),
+            min_seq: 0,
+            with_metadata: false,
+            consistency: Consistency::One as i32,
+        }).await?.into_inner();
+        Ok(if response.exist { Some(response.value) } else { None })
+    }
+
+    // Returns the sequence number of the write.
+    pub async fn set(&self, key: &str, value: &str) -> Result<u64, Status> {
+        let response = self.kv().kv_set_call(SetRequest {
+            key: key.to_string(),
+            value: value.to_string(),
+            lease: 0,
+            consistency: Consistency::One as i32,
+            replica_seq: 0,
+            ttl_ms: 0,
+        }).await?.into_inner();
+        if !response.success {
+            return Err(Status::internal(format!("Key {:?} couldn't be set", key)));
+        }
+        Ok(response.seq)
+    }
+
+    pub async fn remove(&self, key: &str) -> Result<u64, Status> {
+        let response = self.kv().kv_remove_call(RemoveRequest {
+            key: key.to_string(),
+            consistency: Consistency::One as i32,
+            replica_seq: 0,
+        }).await?.into_inner();
+        if !response.success {
+            return Err(Status::internal(format!("Key {:?} couldn't be removed", key)));
+        }
+        Ok(response.seq)
+    }
+
+    // Apply the sets and removals in a single request. Returns the result of each one, in
+    // order: a failed operation doesn't stop the others.
+    pub async fn batch(&self, ops: Vec<BatchOp>) -> Result<Vec<BatchOpResult>, Status> {
+        let response = self.kv().kv_batch_call(BatchRequest {
+            ops,
+            consistency: Consistency::One as i32,
+        }).await?.into_inner();
+        Ok(response.results)
+    }
+
+    // The pairs whose key starts with `prefix`, ordered by key, as the server sends them.
+    // `limit` bounds their number, 0 for all of them.
+    pub async fn scan(&self, prefix: &str, limit: u64) -> Result<Streaming<KeyValue>, Status> {
+        let request = ScanRequest {
+            prefix: prefix.to_string(),
+            limit,
+        };
+        Ok(self.kv().kv_scan_call(request).await?.into_inner())
+    }
+
+    // The changes to the keys starting with `prefix`, replayed from `start_seq` on (0 to
+    // only get the changes to come). The stream never ends while the server is up.
+    pub async fn watch(&self, prefix: &str, start_seq: u64) -> Result<Streaming<WatchEvent>, Status> {
+        let request = WatchRequest {
+            prefix: prefix.to_string(),
+            start_seq,
+        };
+        Ok(self.kv().kv_watch_call(request).await?.into_inner())
+    }
+}
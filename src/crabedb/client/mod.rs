@@ -0,0 +1,27 @@
+use tonic::metadata::errors::InvalidMetadataValue;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::{Interceptor, Request};
+
+pub mod crabe_client;
+pub mod ring;
+
+// The gRPC stubs of the server, for the applications talking to it.
+pub mod protobuf {
+    tonic::include_proto!("kvstore");
+}
+
+// Metadata naming the store of the server a request is routed to (see its `--store`
+// option), the default store when it's missing or empty.
+pub const STORE_METADATA: &str = "crabedb-store";
+
+// Route the requests of a generated client to the store `name`, e.g. with
+// `KvstoreClient::with_interceptor(channel, store_interceptor("users")?)`.
+// The `Status` error of the closure is the one tonic expects from an interceptor.
+#[allow(clippy::result_large_err)]
+pub fn store_interceptor(name: &str) -> Result<Interceptor, InvalidMetadataValue> {
+    let value = AsciiMetadataValue::from_str(name)?;
+    Ok(Interceptor::new(move |mut request: Request<()>| {
+        request.metadata_mut().insert(STORE_METADATA, value.clone());
+        Ok(request)
+    }))
+}
@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tonic::transport::Endpoint;
+use tonic::{Code, Status};
+
+use super::crabe_client::CrabeClient;
+use super::protobuf::cluster_client::ClusterClient;
+use super::protobuf::{Consistency, GetRequest, ListKeysRequest, RoutingTable, RoutingTableRequest, WrongShard};
+use crate::storage::xxhash::xxhash32;
+
+// Points of each node on the ring: the more there are, the more evenly the keys spread.
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+// Redirections to another node a request of a `ShardedClient` follows, and how long it
+// waits before following one back to a node it was sent to, while the nodes switch to a
+// new routing table.
+const MAX_REDIRECTS: usize = 5;
+const REDIRECT_DELAY: Duration = Duration::from_millis(50);
+
+// Consistent-hash ring mapping keys to node addresses. Adding or removing a node only
+// moves the keys of the ring segments it gains or loses.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    ring: BTreeMap<u32, String>,
+}
+
+impl HashRing {
+    pub fn new(virtual_nodes: usize) -> HashRing {
+        HashRing {
+            virtual_nodes: virtual_nodes.max(1),
+            ring: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_node(&mut self, addr: &str) {
+        for i in 0..self.virtual_nodes {
+            self.ring.insert(xxhash32(format!("{}#{}", addr, i).as_bytes()), addr.to_string());
+        }
+    }
+
+    pub fn remove_node(&mut self, addr: &str) {
+        self.ring.retain(|_, node| node != addr);
+    }
+
+    // The first node clockwise from the hash of the key.
+    pub fn node(&self, key: &[u8]) -> Option<&str> {
+        let hash = xxhash32(key);
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+// Client of independent servers sharding the keys among them with a `HashRing`, without
+// any proxy. Nodes can be added and removed, but the keys aren't moved by the client: once
+// the servers migrated them (`crabedb-client cluster migrate`), a request sent to a node
+// which lost its key is redirected to the new one, and the client switches to the newer
+// routing table it gets along. Its clones share the routing table.
+#[derive(Clone, Default)]
+pub struct ShardedClient {
+    routing: Arc<RwLock<Routing>>,
+}
+
+#[derive(Default)]
+struct Routing {
+    // The version of the routing table of the servers, 0 while the nodes are set by hand.
+    version: u64,
+    ring: HashRing,
+    clients: HashMap<String, CrabeClient>,
+}
+
+impl Routing {
+    // The client of a node, connected to on its first request.
+    fn client(&mut self, addr: &str) -> Result<CrabeClient, Box<dyn std::error::Error>> {
+        if let Some(client) = self.clients.get(addr) {
+            return Ok(client.clone());
+        }
+        let channel = Endpoint::from_shared(format!("http://{}", addr))?.connect_lazy()?;
+        let client = CrabeClient::new(channel);
+        self.clients.insert(addr.to_string(), client.clone());
+        Ok(client)
+    }
+}
+
+impl ShardedClient {
+    // The nodes are connected to on their first request.
+    pub fn connect(addrs: &[&str]) -> Result<ShardedClient, Box<dyn std::error::Error>> {
+        let mut client = ShardedClient::default();
+        for addr in addrs {
+            client.add_node(addr)?;
+        }
+        Ok(client)
+    }
+
+    // Over the nodes of the routing table of a server of the deployment.
+    pub async fn discover(addr: &str) -> Result<ShardedClient, Box<dyn std::error::Error>> {
+        let channel = Endpoint::from_shared(format!("http://{}", addr))?.connect().await?;
+        let table = ClusterClient::new(channel).get_routing_table(RoutingTableRequest {}).await?.into_inner();
+        if table.nodes.is_empty() {
+            return Err(format!("{} has no routing table", addr).into());
+        }
+        let client = ShardedClient::default();
+        client.update(&table)?;
+        Ok(client)
+    }
+
+    pub fn add_node(&mut self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut routing = self.routing.write().unwrap();
+        routing.client(addr)?;
+        routing.ring.add_node(addr);
+        Ok(())
+    }
+
+    pub fn remove_node(&mut self, addr: &str) {
+        let mut routing = self.routing.write().unwrap();
+        routing.ring.remove_node(addr);
+        routing.clients.remove(addr);
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self.routing.read().unwrap().clients.keys().cloned().collect();
+        nodes.sort();
+        nodes
+    }
+
+    // The version of the routing table the client follows.
+    pub fn version(&self) -> u64 {
+        self.routing.read().unwrap().version
+    }
+
+    // The node owning the key.
+    pub fn node(&self, key: &str) -> Option<String> {
+        self.routing.read().unwrap().ring.node(key.as_bytes()).map(String::from)
+    }
+
+    // Switch to a routing table, unless the client follows a newer one.
+    fn update(&self, table: &RoutingTable) -> Result<(), Box<dyn std::error::Error>> {
+        let mut routing = self.routing.write().unwrap();
+        if table.version <= routing.version && !routing.ring.is_empty() {
+            return Ok(());
+        }
+        let mut ring = HashRing::new(routing.ring.virtual_nodes);
+        for node in &table.nodes {
+            routing.client(node)?;
+            ring.add_node(node);
+        }
+        routing.clients.retain(|addr, _| table.nodes.contains(addr));
+        routing.ring = ring;
+        routing.version = table.version;
+        Ok(())
+    }
+
+    // Send a request for a key to its node, following the redirections of the nodes which
+    // don't serve it.
+    async fn call<T, F, Fut>(&self, key: &str, call: F) -> Result<T, Status>
+    where
+        F: Fn(CrabeClient) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut node = self.node(key).ok_or_else(no_node)?;
+        let mut visited = Vec::new();
+        loop {
+            let client = self.routing.write().unwrap().client(&node)
+                .map_err(|err| Status::invalid_argument(format!("invalid node {:?}: {}", node, err)))?;
+            let status = match call(client).await {
+                Ok(response) => return Ok(response),
+                Err(status) => status,
+            };
+            let wrong_shard = match wrong_shard(&status) {
+                Some(wrong_shard) if visited.len() < MAX_REDIRECTS => wrong_shard,
+                _ => return Err(status),
+            };
+            if let Some(ref table) = wrong_shard.table {
+                self.update(table)
+                    .map_err(|err| Status::invalid_argument(format!("invalid routing table: {}", err)))?;
+            }
+            visited.push(node);
+            if visited.contains(&wrong_shard.owner) {
+                tokio::time::sleep(REDIRECT_DELAY).await;
+            }
+            node = wrong_shard.owner;
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, Status> {
+        self.call(key, |client| async move { client.get(key).await }).await
+    }
+
+    // Returns the sequence number of the write on its node.
+    pub async fn set(&self, key: &str, value: &str) -> Result<u64, Status> {
+        self.call(key, |client| async move { client.set(key, value).await }).await
+    }
+
+    pub async fn remove(&self, key: &str) -> Result<u64, Status> {
+        self.call(key, |client| async move { client.remove(key).await }).await
+    }
+
+    // Every pair whose key starts with `prefix`, ordered by key, gathered from every node.
+    // A key is only returned by the node owning it, like `get` would find it.
+    pub async fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>, Status> {
+        let clients: Vec<(String, CrabeClient)> = self.routing.read().unwrap().clients.clone().into_iter().collect();
+        let mut pairs = Vec::new();
+        for (node, client) in clients {
+            let mut client = client.kv();
+            let mut cursor = Vec::new();
+            loop {
+                let page = client.kv_list_keys_call(ListKeysRequest {
+                    prefix: prefix.to_string(),
+                    cursor,
+                    limit: 0,
+                }).await?.into_inner();
+
+                for key in page.keys {
+                    if self.node(&key).as_deref() != Some(node.as_str()) {
+                        continue;
+                    }
+                    // The key may have been removed since it was listed.
+                    let response = client.kv_get_call(GetRequest {
+                        key: key.clone(),
+                        min_seq: 0,
+                        with_metadata: false,
+                        consistency: Consistency::One as i32,
+                    }).await?.into_inner();
+                    if response.exist {
+                        pairs.push((key, response.value));
+                    }
+                }
+
+                if page.next_cursor.is_empty() {
+                    break;
+                }
+                cursor = page.next_cursor;
+            }
+        }
+        pairs.sort();
+        Ok(pairs)
+    }
+}
+
+fn no_node() -> Status {
+    Status::failed_precondition("No node to send the request to")
+}
+
+// The redirection of a node which doesn't serve the key of a request.
+fn wrong_shard(status: &Status) -> Option<WrongShard> {
+    if status.code() != Code::FailedPrecondition {
+        return None;
+    }
+    let wrong_shard: WrongShard = prost::Message::decode(status.details()).ok()?;
+    if wrong_shard.owner.is_empty() {
+        return None;
+    }
+    Some(wrong_shard)
+}
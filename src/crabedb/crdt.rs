@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+// Opt-in value types whose versions written concurrently on different nodes of a cluster
+// are merged, instead of the most recent one replacing the others. They are stored as
+// JSON, e.g. `{"crdt":"g-counter","counts":{"10.0.0.1:5000":3}}`, so a plain value is
+// never mistaken for one unless it holds such an object.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "crdt", rename_all = "kebab-case")]
+pub enum Crdt {
+    // The value with the latest timestamp, the node breaking ties.
+    LwwRegister {
+        value: String,
+        timestamp: u64,
+        node: String,
+    },
+    // A counter only growing, each node counting its own increments.
+    GCounter {
+        counts: BTreeMap<String, u64>,
+    },
+    // A set whose elements are added with a unique tag: a removal only removes the tags it
+    // observed, so an element added concurrently with its removal remains.
+    OrSet {
+        elements: BTreeMap<String, BTreeSet<String>>,
+        removed: BTreeSet<String>,
+    },
+}
+
+impl Crdt {
+    pub fn lww_register() -> Crdt {
+        Crdt::LwwRegister {
+            value: String::new(),
+            timestamp: 0,
+            node: String::new(),
+        }
+    }
+
+    pub fn g_counter() -> Crdt {
+        Crdt::GCounter {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn or_set() -> Crdt {
+        Crdt::OrSet {
+            elements: BTreeMap::new(),
+            removed: BTreeSet::new(),
+        }
+    }
+
+    // None when the value isn't one.
+    pub fn decode(value: &[u8]) -> Option<Crdt> {
+        serde_json::from_slice(value).ok()
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn is_same_type(&self, other: &Crdt) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    // The union of both versions, None when they don't have the same type.
+    pub fn merge(&self, other: &Crdt) -> Option<Crdt> {
+        match (self, other) {
+            (Crdt::LwwRegister { timestamp, node, .. }, Crdt::LwwRegister { timestamp: other_timestamp, node: other_node, .. }) => {
+                if (other_timestamp, other_node) > (timestamp, node) {
+                    Some(other.clone())
+                } else {
+                    Some(self.clone())
+                }
+            }
+            (Crdt::GCounter { counts }, Crdt::GCounter { counts: other_counts }) => {
+                let mut counts = counts.clone();
+                for (node, &count) in other_counts {
+                    let current = counts.entry(node.clone()).or_insert(0);
+                    *current = (*current).max(count);
+                }
+                Some(Crdt::GCounter { counts })
+            }
+            (Crdt::OrSet { elements, removed }, Crdt::OrSet { elements: other_elements, removed: other_removed }) => {
+                let removed: BTreeSet<String> = removed.union(other_removed).cloned().collect();
+                let mut elements = elements.clone();
+                for (element, tags) in other_elements {
+                    elements.entry(element.clone()).or_default().extend(tags.iter().cloned());
+                }
+                for tags in elements.values_mut() {
+                    tags.retain(|tag| !removed.contains(tag));
+                }
+                elements.retain(|_, tags| !tags.is_empty());
+                Some(Crdt::OrSet { elements, removed })
+            }
+            _ => None,
+        }
+    }
+
+    // The timestamp only moves forward, even when the clock of the node is behind.
+    pub fn assign(&mut self, node: &str, new_value: String, now: u64) {
+        if let Crdt::LwwRegister { value, timestamp, node: writer } = self {
+            *value = new_value;
+            *timestamp = now.max(*timestamp + 1);
+            *writer = node.to_string();
+        }
+    }
+
+    pub fn increment(&mut self, node: &str, by: u64) {
+        if let Crdt::GCounter { counts } = self {
+            *counts.entry(node.to_string()).or_insert(0) += by;
+        }
+    }
+
+    // `tag` must be unique among all the additions of the set.
+    pub fn add(&mut self, element: String, tag: String) {
+        if let Crdt::OrSet { elements, .. } = self {
+            elements.entry(element).or_default().insert(tag);
+        }
+    }
+
+    pub fn remove(&mut self, element: &str) {
+        if let Crdt::OrSet { elements, removed } = self {
+            if let Some(tags) = elements.remove(element) {
+                removed.extend(tags);
+            }
+        }
+    }
+
+    // The value of a register.
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            Crdt::LwwRegister { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    // The total of a counter.
+    pub fn count(&self) -> u64 {
+        match self {
+            Crdt::GCounter { counts } => counts.values().sum(),
+            _ => 0,
+        }
+    }
+
+    // The elements of a set, ordered.
+    pub fn elements(&self) -> Vec<String> {
+        match self {
+            Crdt::OrSet { elements, .. } => elements.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
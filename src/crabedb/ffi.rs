@@ -0,0 +1,230 @@
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use crate::storage::crabe_db::CrabeDB;
+use crate::storage::error::Error;
+use crate::storage::options::StorageOptions;
+
+// C API of the storage engine, for services embedding a store in their own process. The
+// store is an opaque pointer returned by `crabedb_open`, and every function returns one
+// of the codes below. See include/crabedb.h for the matching declarations.
+
+pub const CRABEDB_OK: c_int = 0;
+// `crabedb_get` on a key which doesn't exist.
+pub const CRABEDB_NOT_FOUND: c_int = 1;
+// A null pointer, a path which isn't UTF-8, or a key or value too large.
+pub const CRABEDB_INVALID_ARGUMENT: c_int = -1;
+pub const CRABEDB_IO_ERROR: c_int = -2;
+// The files of the store are corrupted or written by a newer version.
+pub const CRABEDB_CORRUPTION: c_int = -3;
+// The store is opened read-only or is a standby.
+pub const CRABEDB_READ_ONLY: c_int = -4;
+pub const CRABEDB_ERROR: c_int = -5;
+// The library panicked, the store shouldn't be used anymore but closed.
+pub const CRABEDB_PANIC: c_int = -6;
+
+// Runs the body of an exported function: a panic must not unwind into the C caller,
+// which is undefined behavior, so it's caught and reported with `CRABEDB_PANIC`.
+fn guard<F: FnOnce() -> c_int>(body: F) -> c_int {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(CRABEDB_PANIC)
+}
+
+fn error_code(err: &Error) -> c_int {
+    match *err {
+        Error::Io(..) => CRABEDB_IO_ERROR,
+        Error::InvalidKeySize(..) | Error::InvalidValueSize(..) | Error::InvalidPath(..) => {
+            CRABEDB_INVALID_ARGUMENT
+        }
+        Error::InvalidFileId(..)
+        | Error::InvalidChecksum { .. }
+        | Error::InvalidManifest(..)
+        | Error::InvalidArchive(..)
+        | Error::UnsupportedFormat { .. } => CRABEDB_CORRUPTION,
+        Error::ReadOnly => CRABEDB_READ_ONLY,
+        _ => CRABEDB_ERROR,
+    }
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        // An empty key or value may come without any buffer.
+        if len == 0 {
+            return Some(&[]);
+        }
+        return None;
+    }
+    Some(slice::from_raw_parts(data, len))
+}
+
+/// Opens (or creates) the store in the directory `path` with the default options, and
+/// stores its handle in `*db`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string and `db` a valid pointer to write to. The
+/// handle must be released with `crabedb_close`.
+#[no_mangle]
+pub unsafe extern "C" fn crabedb_open(path: *const c_char, db: *mut *mut CrabeDB) -> c_int {
+    guard(|| {
+        if path.is_null() || db.is_null() {
+            return CRABEDB_INVALID_ARGUMENT;
+        }
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => path,
+            Err(_) => return CRABEDB_INVALID_ARGUMENT,
+        };
+        match CrabeDB::load(path, StorageOptions::default()) {
+            Ok(store) => {
+                *db = Box::into_raw(Box::new(store));
+                CRABEDB_OK
+            }
+            Err(err) => error_code(&err),
+        }
+    })
+}
+
+/// Reads the value of a key into a buffer allocated by the library, stored in `*value`
+/// along with its size in `*value_len`, and to be released with `crabedb_free_value`.
+/// Returns `CRABEDB_NOT_FOUND` when the key doesn't exist.
+///
+/// # Safety
+///
+/// `db` must come from `crabedb_open`, `key` must point to `key_len` readable bytes, and
+/// `value` and `value_len` must be valid pointers to write to.
+#[no_mangle]
+pub unsafe extern "C" fn crabedb_get(
+    db: *const CrabeDB,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> c_int {
+    guard(|| {
+        let key = match bytes(key, key_len) {
+            Some(key) => key,
+            None => return CRABEDB_INVALID_ARGUMENT,
+        };
+        if db.is_null() || value.is_null() || value_len.is_null() {
+            return CRABEDB_INVALID_ARGUMENT;
+        }
+        match (*db).get(key) {
+            Ok(Some(found)) => {
+                let found = found.into_boxed_slice();
+                *value_len = found.len();
+                *value = Box::into_raw(found) as *mut u8;
+                CRABEDB_OK
+            }
+            Ok(None) => {
+                *value = ptr::null_mut();
+                *value_len = 0;
+                CRABEDB_NOT_FOUND
+            }
+            Err(err) => error_code(&err),
+        }
+    })
+}
+
+/// Releases a value returned by `crabedb_get`.
+///
+/// # Safety
+///
+/// `value` and `value_len` must be the ones returned by `crabedb_get`, or `value` null.
+#[no_mangle]
+pub unsafe extern "C" fn crabedb_free_value(value: *mut u8, value_len: usize) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        if !value.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(value, value_len)));
+        }
+    }));
+}
+
+/// Writes a key, and stores the sequence number of the write in `*seq` unless it's null.
+///
+/// # Safety
+///
+/// `db` must come from `crabedb_open`, `key` and `value` must point to `key_len` and
+/// `value_len` readable bytes, and `seq` must be null or a valid pointer to write to.
+#[no_mangle]
+pub unsafe extern "C" fn crabedb_set(
+    db: *const CrabeDB,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+    seq: *mut u64,
+) -> c_int {
+    guard(|| {
+        let (key, value) = match (bytes(key, key_len), bytes(value, value_len)) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return CRABEDB_INVALID_ARGUMENT,
+        };
+        if db.is_null() {
+            return CRABEDB_INVALID_ARGUMENT;
+        }
+        match (*db).set(key, value) {
+            Ok(written) => {
+                if !seq.is_null() {
+                    *seq = written;
+                }
+                CRABEDB_OK
+            }
+            Err(err) => error_code(&err),
+        }
+    })
+}
+
+/// Removes a key, and stores the sequence number of the removal in `*seq` unless it's
+/// null. Removing a key which doesn't exist isn't an error.
+///
+/// # Safety
+///
+/// `db` must come from `crabedb_open`, `key` must point to `key_len` readable bytes, and
+/// `seq` must be null or a valid pointer to write to.
+#[no_mangle]
+pub unsafe extern "C" fn crabedb_remove(
+    db: *const CrabeDB,
+    key: *const u8,
+    key_len: usize,
+    seq: *mut u64,
+) -> c_int {
+    guard(|| {
+        let key = match bytes(key, key_len) {
+            Some(key) => key,
+            None => return CRABEDB_INVALID_ARGUMENT,
+        };
+        if db.is_null() {
+            return CRABEDB_INVALID_ARGUMENT;
+        }
+        match (*db).remove(key) {
+            Ok(written) => {
+                if !seq.is_null() {
+                    *seq = written;
+                }
+                CRABEDB_OK
+            }
+            Err(err) => error_code(&err),
+        }
+    })
+}
+
+/// Closes the store, once the compaction in progress, if any, is over. The handle is
+/// released even when sealing the active data file fails.
+///
+/// # Safety
+///
+/// `db` must come from `crabedb_open` and not be used anymore afterwards, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn crabedb_close(db: *mut CrabeDB) -> c_int {
+    guard(|| {
+        if db.is_null() {
+            return CRABEDB_OK;
+        }
+        match Box::from_raw(db).close() {
+            Ok(()) => CRABEDB_OK,
+            Err(err) => error_code(&err),
+        }
+    })
+}
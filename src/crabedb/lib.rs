@@ -1 +1,8 @@
-pub mod storage;
\ No newline at end of file
+pub mod r#async;
+pub mod client;
+pub mod crdt;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod storage;
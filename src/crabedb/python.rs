@@ -0,0 +1,209 @@
+// The wrapper pyo3 generates for the default argument of `get` converts its error to itself.
+#![allow(clippy::useless_conversion)]
+
+use std::collections::VecDeque;
+
+use pyo3::exceptions::{PyIOError, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::storage::crabe_db::CrabeDB;
+use crate::storage::error::Error;
+use crate::storage::options::StorageOptions;
+
+// Keys listed at once by the iterators, which page through the store like the server.
+const KEYS_BATCH_SIZE: usize = 1000;
+
+fn to_py_err(err: Error) -> PyErr {
+    match err {
+        Error::InvalidKeySize(..) | Error::InvalidValueSize(..) => PyValueError::new_err(err.to_string()),
+        _ => PyIOError::new_err(err.to_string()),
+    }
+}
+
+// Embedded store with the semantics of a dict of bytes:
+//
+//     with crabedb.CrabeDB("crabe.db") as db:
+//         db[b"k"] = b"v"
+//         for key in db:
+//             print(key, db[key])
+//
+// The GIL is released during the reads and writes.
+#[pyclass(name = "CrabeDB", module = "crabedb")]
+pub struct PyCrabeDB {
+    db: Option<CrabeDB>,
+}
+
+impl PyCrabeDB {
+    fn db(&self) -> PyResult<&CrabeDB> {
+        self.db
+            .as_ref()
+            .ok_or_else(|| PyValueError::new_err("I/O operation on closed store"))
+    }
+}
+
+#[pymethods]
+impl PyCrabeDB {
+    // Opens (or creates) the store in the directory `path` with the default options.
+    #[new]
+    fn new(py: Python<'_>, path: &str) -> PyResult<Self> {
+        let db = py.allow_threads(|| CrabeDB::load(path, StorageOptions::default())).map_err(to_py_err)?;
+        Ok(PyCrabeDB { db: Some(db) })
+    }
+
+    // Waits for the compaction in progress, if any, and seals the active data file. The
+    // store can't be used afterwards, even when this raises.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        match self.db.take() {
+            Some(db) => py.allow_threads(|| db.close()).map_err(to_py_err),
+            None => Ok(()),
+        }
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.db.is_none()
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<bool> {
+        self.close(py)?;
+        Ok(false)
+    }
+
+    fn __getitem__<'py>(&self, py: Python<'py>, key: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        let db = self.db()?;
+        match py.allow_threads(|| db.get(key)).map_err(to_py_err)? {
+            Some(value) => Ok(PyBytes::new_bound(py, &value)),
+            None => Err(PyKeyError::new_err(PyBytes::new_bound(py, key).unbind())),
+        }
+    }
+
+    fn __setitem__(&self, py: Python<'_>, key: &[u8], value: &[u8]) -> PyResult<()> {
+        let db = self.db()?;
+        py.allow_threads(|| db.set(key, value)).map_err(to_py_err)?;
+        Ok(())
+    }
+
+    fn __delitem__(&self, py: Python<'_>, key: &[u8]) -> PyResult<()> {
+        if !self.__contains__(py, key)? {
+            return Err(PyKeyError::new_err(PyBytes::new_bound(py, key).unbind()));
+        }
+        let db = self.db()?;
+        py.allow_threads(|| db.remove(key)).map_err(to_py_err)?;
+        Ok(())
+    }
+
+    fn __contains__(&self, py: Python<'_>, key: &[u8]) -> PyResult<bool> {
+        let db = self.db()?;
+        Ok(py.allow_threads(|| db.get(key)).map_err(to_py_err)?.is_some())
+    }
+
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.db()?.approximate_key_count())
+    }
+
+    // The keys, ordered. Keys written during the iteration after the current one are
+    // returned as well.
+    fn __iter__(slf: Py<Self>) -> Keys {
+        Keys::new(slf, false)
+    }
+
+    #[pyo3(signature = (key, default = None))]
+    fn get<'py>(
+        &self,
+        py: Python<'py>,
+        key: &[u8],
+        default: Option<Bound<'py, PyAny>>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let db = self.db()?;
+        match py.allow_threads(|| db.get(key)).map_err(to_py_err)? {
+            Some(value) => Ok(PyBytes::new_bound(py, &value).into_any()),
+            None => Ok(default.unwrap_or_else(|| py.None().into_bound(py))),
+        }
+    }
+
+    fn keys(slf: Py<Self>) -> Keys {
+        Keys::new(slf, false)
+    }
+
+    // (key, value) pairs, ordered by key.
+    fn items(slf: Py<Self>) -> Keys {
+        Keys::new(slf, true)
+    }
+}
+
+// Iterator over the keys of a store, or its (key, value) pairs, reading them by batches.
+#[pyclass(module = "crabedb")]
+pub struct Keys {
+    store: Py<PyCrabeDB>,
+    with_values: bool,
+    cursor: Vec<u8>,
+    batch: VecDeque<Vec<u8>>,
+    done: bool,
+}
+
+impl Keys {
+    fn new(store: Py<PyCrabeDB>, with_values: bool) -> Keys {
+        Keys {
+            store,
+            with_values,
+            cursor: Vec::new(),
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+#[pymethods]
+impl Keys {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let store = self.store.borrow(py);
+        loop {
+            if let Some(key) = self.batch.pop_front() {
+                if !self.with_values {
+                    return Ok(Some(PyBytes::new_bound(py, &key).into_any().unbind()));
+                }
+                // The key may have been removed since it was listed.
+                let db = store.db()?;
+                if let Some(value) = py.allow_threads(|| db.get(&key)).map_err(to_py_err)? {
+                    let pair = (PyBytes::new_bound(py, &key), PyBytes::new_bound(py, &value));
+                    return Ok(Some(pair.into_py(py)));
+                }
+                continue;
+            }
+            if self.done {
+                return Ok(None);
+            }
+
+            let db = store.db()?;
+            let cursor = &self.cursor;
+            let keys = py.allow_threads(|| db.list_keys(b"", cursor, KEYS_BATCH_SIZE)).map_err(to_py_err)?;
+            self.done = keys.len() < KEYS_BATCH_SIZE;
+            if let Some(last) = keys.last() {
+                self.cursor = last.clone();
+            }
+            self.batch.extend(keys);
+        }
+    }
+}
+
+#[pymodule]
+fn crabedb(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyCrabeDB>()?;
+    m.add_class::<Keys>()?;
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::io::prelude::*;
+use std::result::Result::Ok;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::error::{Error, Result};
+use super::xxhash::XxHash32;
+
+// Archives are a stream of live key/value pairs, independent of the on-disk layout:
+//
+//   magic(8) + version(2)
+//   [ tag(1) = ENTRY + key_size(4) + value_size(4) + key + value + checksum(4) ]*
+//   tag(1) = END + count(8) + checksum(4)
+const ARCHIVE_MAGIC: &[u8; 8] = b"CRABEXP\0";
+const ARCHIVE_VERSION: u16 = 1;
+const ARCHIVE_ENTRY_TAG: u8 = 1;
+const ARCHIVE_END_TAG: u8 = 0;
+
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    count: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W) -> Result<ArchiveWriter<W>> {
+        writer.write_all(ARCHIVE_MAGIC)?;
+        writer.write_u16::<LittleEndian>(ARCHIVE_VERSION)?;
+        Ok(ArchiveWriter { writer, count: 0 })
+    }
+
+    pub fn write(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut hasher = XxHash32::new();
+        hasher.write_u32::<LittleEndian>(key.len() as u32)?;
+        hasher.write_u32::<LittleEndian>(value.len() as u32)?;
+        hasher.update(key);
+        hasher.update(value);
+
+        self.writer.write_u8(ARCHIVE_ENTRY_TAG)?;
+        self.writer.write_u32::<LittleEndian>(key.len() as u32)?;
+        self.writer.write_u32::<LittleEndian>(value.len() as u32)?;
+        self.writer.write_all(key)?;
+        self.writer.write_all(value)?;
+        self.writer.write_u32::<LittleEndian>(hasher.get())?;
+
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<u64> {
+        let mut hasher = XxHash32::new();
+        hasher.write_u64::<LittleEndian>(self.count)?;
+
+        self.writer.write_u8(ARCHIVE_END_TAG)?;
+        self.writer.write_u64::<LittleEndian>(self.count)?;
+        self.writer.write_u32::<LittleEndian>(hasher.get())?;
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+pub struct ArchiveReader<R: Read> {
+    reader: R,
+    count: u64,
+    done: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> Result<ArchiveReader<R>> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != ARCHIVE_MAGIC {
+            return Err(Error::InvalidArchive("not a CrabeDB archive".to_string()));
+        }
+
+        let version = reader.read_u16::<LittleEndian>()?;
+        if version != ARCHIVE_VERSION {
+            return Err(Error::InvalidArchive(
+                format!("unsupported archive version: {}", version),
+            ));
+        }
+
+        Ok(ArchiveReader {
+            reader,
+            count: 0,
+            done: false,
+        })
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.reader.read_u8()? {
+            ARCHIVE_ENTRY_TAG => {
+                let key_size = self.reader.read_u32::<LittleEndian>()?;
+                let value_size = self.reader.read_u32::<LittleEndian>()?;
+
+                let mut key = Vec::new();
+                self.reader.by_ref().take(key_size as u64).read_to_end(&mut key)?;
+                let mut value = Vec::new();
+                self.reader.by_ref().take(value_size as u64).read_to_end(&mut value)?;
+                if key.len() != key_size as usize || value.len() != value_size as usize {
+                    return Err(Error::InvalidArchive("truncated entry".to_string()));
+                }
+
+                let mut hasher = XxHash32::new();
+                hasher.write_u32::<LittleEndian>(key_size)?;
+                hasher.write_u32::<LittleEndian>(value_size)?;
+                hasher.update(&key);
+                hasher.update(&value);
+
+                let checksum = self.reader.read_u32::<LittleEndian>()?;
+                if checksum != hasher.get() {
+                    return Err(Error::InvalidChecksum {
+                        expected: u64::from(checksum),
+                        found: u64::from(hasher.get()),
+                    });
+                }
+
+                self.count += 1;
+                Ok(Some((key, value)))
+            }
+            ARCHIVE_END_TAG => {
+                let count = self.reader.read_u64::<LittleEndian>()?;
+
+                let mut hasher = XxHash32::new();
+                hasher.write_u64::<LittleEndian>(count)?;
+                let checksum = self.reader.read_u32::<LittleEndian>()?;
+                if checksum != hasher.get() || count != self.count {
+                    return Err(Error::InvalidArchive(format!(
+                        "archive announces {} entries, {} were read",
+                        count,
+                        self.count
+                    )));
+                }
+
+                Ok(None)
+            }
+            tag => Err(Error::InvalidArchive(format!("unknown tag: {}", tag))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        if self.done {
+            return None;
+        }
+
+        match self.read_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
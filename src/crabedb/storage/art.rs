@@ -0,0 +1,680 @@
+use std::mem;
+
+use super::key_arena::{ArenaKey, KeyArena};
+use super::key_map::{Entries, KeyMap};
+use super::slot::MemIdxEntry;
+
+// Adaptive radix tree of the keys of the index (`IndexKind::Art`). Each inner node holds
+// the bytes its keys have in common (the path compression of the paper) and its children
+// indexed by the next byte of their keys, in one of four layouts sized for up to 4, 16, 48
+// and 256 children, grown and shrunk as children come and go. A key which ends at an inner
+// node is stored in that node, every other one in a leaf. The keys are kept ordered, so
+// the scans and the prefix queries only visit the keys they return, and the tree is walked
+// with loops rather than recursion, whatever the length of the keys.
+pub struct ArtKeyMap {
+    root: Option<Node>,
+    arena: KeyArena,
+    len: usize,
+}
+
+struct Leaf {
+    key: ArenaKey,
+    entry: MemIdxEntry,
+}
+
+struct Inner {
+    prefix: Box<[u8]>,
+    leaf: Option<Box<Leaf>>,
+    children: Children,
+}
+
+enum Node {
+    Leaf(Box<Leaf>),
+    Inner(Box<Inner>),
+}
+
+// Up to N children, their bytes sorted.
+struct Sorted<const N: usize> {
+    len: usize,
+    bytes: [u8; N],
+    nodes: [Option<Node>; N],
+}
+
+// Up to 48 children, `index` mapping a byte to the slot of its child, plus one.
+struct Indexed {
+    len: usize,
+    index: [u8; 256],
+    nodes: [Option<Node>; 48],
+}
+
+// A slot for every byte.
+struct Direct {
+    len: usize,
+    nodes: [Option<Node>; 256],
+}
+
+enum Children {
+    N4(Sorted<4>),
+    N16(Box<Sorted<16>>),
+    N48(Box<Indexed>),
+    N256(Box<Direct>),
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+impl<const N: usize> Sorted<N> {
+    fn new() -> Sorted<N> {
+        Sorted {
+            len: 0,
+            bytes: [0; N],
+            nodes: std::array::from_fn(|_| None),
+        }
+    }
+
+    fn from_children(children: Vec<(u8, Node)>) -> Sorted<N> {
+        let mut sorted = Sorted::new();
+        for (byte, node) in children {
+            sorted.add(byte, node);
+        }
+        sorted
+    }
+
+    fn find(&self, byte: u8) -> Option<&Node> {
+        let slot = self.bytes[..self.len].binary_search(&byte).ok()?;
+        self.nodes[slot].as_ref()
+    }
+
+    fn find_mut(&mut self, byte: u8) -> Option<&mut Node> {
+        let slot = self.bytes[..self.len].binary_search(&byte).ok()?;
+        self.nodes[slot].as_mut()
+    }
+
+    fn next_from(&self, byte: usize) -> Option<(u8, &Node)> {
+        let slot = self.bytes[..self.len].partition_point(|&b| (b as usize) < byte);
+        if slot < self.len {
+            Some((self.bytes[slot], self.nodes[slot].as_ref().unwrap()))
+        } else {
+            None
+        }
+    }
+
+    fn add(&mut self, byte: u8, node: Node) {
+        let slot = self.bytes[..self.len].partition_point(|&b| b < byte);
+        for i in (slot..self.len).rev() {
+            self.bytes[i + 1] = self.bytes[i];
+            self.nodes[i + 1] = self.nodes[i].take();
+        }
+        self.bytes[slot] = byte;
+        self.nodes[slot] = Some(node);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, byte: u8) -> Option<Node> {
+        let slot = self.bytes[..self.len].binary_search(&byte).ok()?;
+        let node = self.nodes[slot].take();
+        for i in slot + 1..self.len {
+            self.bytes[i - 1] = self.bytes[i];
+            self.nodes[i - 1] = self.nodes[i].take();
+        }
+        self.len -= 1;
+        node
+    }
+
+    fn drain(&mut self) -> Vec<(u8, Node)> {
+        let len = mem::take(&mut self.len);
+        (0..len).map(|i| (self.bytes[i], self.nodes[i].take().unwrap())).collect()
+    }
+}
+
+impl Indexed {
+    fn from_children(children: Vec<(u8, Node)>) -> Indexed {
+        let mut indexed = Indexed {
+            len: 0,
+            index: [0; 256],
+            nodes: std::array::from_fn(|_| None),
+        };
+        for (byte, node) in children {
+            indexed.add(byte, node);
+        }
+        indexed
+    }
+
+    fn find(&self, byte: u8) -> Option<&Node> {
+        match self.index[byte as usize] {
+            0 => None,
+            slot => self.nodes[slot as usize - 1].as_ref(),
+        }
+    }
+
+    fn find_mut(&mut self, byte: u8) -> Option<&mut Node> {
+        match self.index[byte as usize] {
+            0 => None,
+            slot => self.nodes[slot as usize - 1].as_mut(),
+        }
+    }
+
+    fn next_from(&self, byte: usize) -> Option<(u8, &Node)> {
+        (byte..256).find_map(|b| self.find(b as u8).map(|node| (b as u8, node)))
+    }
+
+    fn add(&mut self, byte: u8, node: Node) {
+        let slot = self.nodes.iter().position(Option::is_none).unwrap();
+        self.nodes[slot] = Some(node);
+        self.index[byte as usize] = slot as u8 + 1;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, byte: u8) -> Option<Node> {
+        let slot = mem::take(&mut self.index[byte as usize]);
+        if slot == 0 {
+            return None;
+        }
+        self.len -= 1;
+        self.nodes[slot as usize - 1].take()
+    }
+
+    fn drain(&mut self) -> Vec<(u8, Node)> {
+        (0..256).filter_map(|b| self.remove(b as u8).map(|node| (b as u8, node))).collect()
+    }
+}
+
+impl Direct {
+    fn from_children(children: Vec<(u8, Node)>) -> Direct {
+        let mut direct = Direct {
+            len: 0,
+            nodes: std::array::from_fn(|_| None),
+        };
+        for (byte, node) in children {
+            direct.add(byte, node);
+        }
+        direct
+    }
+
+    fn next_from(&self, byte: usize) -> Option<(u8, &Node)> {
+        (byte..256).find_map(|b| self.nodes[b].as_ref().map(|node| (b as u8, node)))
+    }
+
+    fn add(&mut self, byte: u8, node: Node) {
+        self.nodes[byte as usize] = Some(node);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, byte: u8) -> Option<Node> {
+        let node = self.nodes[byte as usize].take();
+        if node.is_some() {
+            self.len -= 1;
+        }
+        node
+    }
+
+    fn drain(&mut self) -> Vec<(u8, Node)> {
+        (0..256).filter_map(|b| self.remove(b as u8).map(|node| (b as u8, node))).collect()
+    }
+}
+
+impl Children {
+    fn len(&self) -> usize {
+        match *self {
+            Children::N4(ref n) => n.len,
+            Children::N16(ref n) => n.len,
+            Children::N48(ref n) => n.len,
+            Children::N256(ref n) => n.len,
+        }
+    }
+
+    fn find(&self, byte: u8) -> Option<&Node> {
+        match *self {
+            Children::N4(ref n) => n.find(byte),
+            Children::N16(ref n) => n.find(byte),
+            Children::N48(ref n) => n.find(byte),
+            Children::N256(ref n) => n.nodes[byte as usize].as_ref(),
+        }
+    }
+
+    fn find_mut(&mut self, byte: u8) -> Option<&mut Node> {
+        match *self {
+            Children::N4(ref mut n) => n.find_mut(byte),
+            Children::N16(ref mut n) => n.find_mut(byte),
+            Children::N48(ref mut n) => n.find_mut(byte),
+            Children::N256(ref mut n) => n.nodes[byte as usize].as_mut(),
+        }
+    }
+
+    // The child with the smallest byte from `byte` on, `byte` going up to 256.
+    fn next_from(&self, byte: usize) -> Option<(u8, &Node)> {
+        match *self {
+            Children::N4(ref n) => n.next_from(byte),
+            Children::N16(ref n) => n.next_from(byte),
+            Children::N48(ref n) => n.next_from(byte),
+            Children::N256(ref n) => n.next_from(byte),
+        }
+    }
+
+    fn add(&mut self, byte: u8, node: Node) {
+        let grown = match *self {
+            Children::N4(ref mut n) if n.len == 4 => {
+                Some(Children::N16(Box::new(Sorted::from_children(n.drain()))))
+            }
+            Children::N16(ref mut n) if n.len == 16 => {
+                Some(Children::N48(Box::new(Indexed::from_children(n.drain()))))
+            }
+            Children::N48(ref mut n) if n.len == 48 => {
+                Some(Children::N256(Box::new(Direct::from_children(n.drain()))))
+            }
+            _ => None,
+        };
+        if let Some(grown) = grown {
+            *self = grown;
+        }
+
+        match *self {
+            Children::N4(ref mut n) => n.add(byte, node),
+            Children::N16(ref mut n) => n.add(byte, node),
+            Children::N48(ref mut n) => n.add(byte, node),
+            Children::N256(ref mut n) => n.add(byte, node),
+        }
+    }
+
+    // The layouts are shrunk below the size of the smaller one, with some slack so that a
+    // node doesn't switch back and forth.
+    fn remove(&mut self, byte: u8) -> Option<Node> {
+        let node = match *self {
+            Children::N4(ref mut n) => n.remove(byte),
+            Children::N16(ref mut n) => n.remove(byte),
+            Children::N48(ref mut n) => n.remove(byte),
+            Children::N256(ref mut n) => n.remove(byte),
+        };
+
+        let shrunk = match *self {
+            Children::N16(ref mut n) if n.len <= 3 => Some(Children::N4(Sorted::from_children(n.drain()))),
+            Children::N48(ref mut n) if n.len <= 12 => {
+                Some(Children::N16(Box::new(Sorted::from_children(n.drain()))))
+            }
+            Children::N256(ref mut n) if n.len <= 37 => {
+                Some(Children::N48(Box::new(Indexed::from_children(n.drain()))))
+            }
+            _ => None,
+        };
+        if let Some(shrunk) = shrunk {
+            *self = shrunk;
+        }
+        node
+    }
+
+    // Memory allocated for the layout, besides the inner node.
+    fn heap_size(&self) -> usize {
+        match *self {
+            Children::N4(_) => 0,
+            Children::N16(_) => mem::size_of::<Sorted<16>>(),
+            Children::N48(_) => mem::size_of::<Indexed>(),
+            Children::N256(_) => mem::size_of::<Direct>(),
+        }
+    }
+}
+
+impl Inner {
+    fn new(prefix: &[u8]) -> Inner {
+        Inner {
+            prefix: prefix.into(),
+            leaf: None,
+            children: Children::N4(Sorted::new()),
+        }
+    }
+
+    // Add a leaf below this node, whose path is `depth` bytes long.
+    fn add_leaf(&mut self, leaf: Box<Leaf>, depth: usize) {
+        let key = leaf.key.as_slice();
+        if key.len() == depth {
+            self.leaf = Some(leaf);
+        } else {
+            let byte = key[depth];
+            self.children.add(byte, Node::Leaf(leaf));
+        }
+    }
+}
+
+fn new_leaf(arena: &mut KeyArena, key: &[u8], entry: MemIdxEntry) -> Box<Leaf> {
+    Box::new(Leaf {
+        key: arena.alloc(key),
+        entry,
+    })
+}
+
+// Replace an inner node left with a single key or child by that key or child.
+fn collapse(node: &mut Node) {
+    let replacement = match *node {
+        Node::Inner(ref mut inner) if inner.children.len() == 0 => inner.leaf.take().map(Node::Leaf),
+        Node::Inner(ref mut inner) if inner.children.len() == 1 && inner.leaf.is_none() => {
+            let (byte, _) = inner.children.next_from(0).unwrap();
+            match inner.children.remove(byte).unwrap() {
+                Node::Leaf(leaf) => Some(Node::Leaf(leaf)),
+                Node::Inner(mut child) => {
+                    let mut prefix = Vec::with_capacity(inner.prefix.len() + 1 + child.prefix.len());
+                    prefix.extend_from_slice(&inner.prefix);
+                    prefix.push(byte);
+                    prefix.extend_from_slice(&child.prefix);
+                    child.prefix = prefix.into_boxed_slice();
+                    Some(Node::Inner(child))
+                }
+            }
+        }
+        _ => None,
+    };
+    if let Some(replacement) = replacement {
+        *node = replacement;
+    }
+}
+
+impl Default for ArtKeyMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArtKeyMap {
+    pub fn new() -> ArtKeyMap {
+        ArtKeyMap {
+            root: None,
+            arena: KeyArena::new(),
+            len: 0,
+        }
+    }
+
+    // The entries from the first key at least equal to `start` on, ordered.
+    pub fn iter_from<'a>(&'a self, start: &[u8]) -> Iter<'a> {
+        let mut iter = Iter {
+            stack: Vec::new(),
+            pending: None,
+        };
+        let mut node = match self.root {
+            Some(ref root) => root,
+            None => return iter,
+        };
+
+        let mut depth = 0;
+        loop {
+            let inner = match *node {
+                Node::Leaf(ref leaf) => {
+                    if leaf.key.as_slice() >= start {
+                        iter.pending = Some(leaf);
+                    }
+                    return iter;
+                }
+                Node::Inner(ref inner) => inner,
+            };
+
+            let rest = &start[depth..];
+            let common = inner.prefix.len().min(rest.len());
+            if inner.prefix[..common] < rest[..common] {
+                return iter;
+            }
+            if inner.prefix[..common] > rest[..common] || rest.len() <= inner.prefix.len() {
+                // Every key below is after `start`.
+                iter.push(inner);
+                return iter;
+            }
+
+            // The key of the node itself is a prefix of `start`, it's before it.
+            depth += inner.prefix.len();
+            let byte = start[depth];
+            iter.stack.push((inner, byte as usize + 1));
+            node = match inner.children.find(byte) {
+                Some(child) => child,
+                None => return iter,
+            };
+            depth += 1;
+        }
+    }
+
+    fn compact_arena(&mut self) {
+        let mut rebuilt = ArtKeyMap::new();
+        for (key, entry) in self.iter_from(&[]) {
+            rebuilt.insert(key, entry);
+        }
+        *self = rebuilt;
+    }
+}
+
+impl KeyMap for ArtKeyMap {
+    fn get(&self, key: &[u8]) -> Option<MemIdxEntry> {
+        let mut node = self.root.as_ref()?;
+        let mut depth = 0;
+        loop {
+            match *node {
+                Node::Leaf(ref leaf) => {
+                    return if leaf.key.as_slice() == key { Some(leaf.entry) } else { None };
+                }
+                Node::Inner(ref inner) => {
+                    if !key[depth..].starts_with(&inner.prefix) {
+                        return None;
+                    }
+                    depth += inner.prefix.len();
+                    if depth == key.len() {
+                        return inner.leaf.as_ref().map(|leaf| leaf.entry);
+                    }
+                    node = inner.children.find(key[depth])?;
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], entry: MemIdxEntry) -> Option<MemIdxEntry> {
+        let ArtKeyMap { root, arena, len } = self;
+        let mut node = match *root {
+            Some(ref mut root) => root,
+            None => {
+                *root = Some(Node::Leaf(new_leaf(arena, key, entry)));
+                *len += 1;
+                return None;
+            }
+        };
+
+        let mut depth = 0;
+        loop {
+            let byte = match *node {
+                Node::Leaf(ref mut leaf) => {
+                    if leaf.key.as_slice() == key {
+                        return Some(mem::replace(&mut leaf.entry, entry));
+                    }
+                    // Split the leaf.
+                    let common = common_prefix_len(&leaf.key.as_slice()[depth..], &key[depth..]);
+                    let mut inner = Inner::new(&key[depth..depth + common]);
+                    inner.add_leaf(new_leaf(arena, key, entry), depth + common);
+                    if let Node::Leaf(leaf) = mem::replace(node, Node::Inner(Box::new(inner))) {
+                        if let Node::Inner(ref mut inner) = *node {
+                            inner.add_leaf(leaf, depth + common);
+                        }
+                    }
+                    *len += 1;
+                    return None;
+                }
+                Node::Inner(ref mut inner) => {
+                    let common = common_prefix_len(&inner.prefix, &key[depth..]);
+                    if common < inner.prefix.len() {
+                        // Split the prefix of the node.
+                        let mut parent = Inner::new(&inner.prefix[..common]);
+                        let byte = inner.prefix[common];
+                        inner.prefix = inner.prefix[common + 1..].into();
+                        parent.add_leaf(new_leaf(arena, key, entry), depth + common);
+                        let child = mem::replace(node, Node::Inner(Box::new(parent)));
+                        if let Node::Inner(ref mut parent) = *node {
+                            parent.children.add(byte, child);
+                        }
+                        *len += 1;
+                        return None;
+                    }
+
+                    depth += common;
+                    if depth == key.len() {
+                        if let Some(ref mut leaf) = inner.leaf {
+                            return Some(mem::replace(&mut leaf.entry, entry));
+                        }
+                        inner.leaf = Some(new_leaf(arena, key, entry));
+                        *len += 1;
+                        return None;
+                    }
+                    let byte = key[depth];
+                    if inner.children.find(byte).is_none() {
+                        inner.children.add(byte, Node::Leaf(new_leaf(arena, key, entry)));
+                        *len += 1;
+                        return None;
+                    }
+                    byte
+                }
+            };
+
+            node = match node {
+                Node::Inner(inner) => inner.children.find_mut(byte).unwrap(),
+                Node::Leaf(_) => unreachable!(),
+            };
+            depth += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<MemIdxEntry> {
+        let removed = self.remove_leaf(key)?;
+        self.arena.free(&removed.key);
+        self.len -= 1;
+        if self.arena.should_compact() {
+            self.compact_arena();
+        }
+        Some(removed.entry)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        Box::new(self.iter_from(&[]))
+    }
+
+    fn entries_from(&self, start: &[u8]) -> Option<Entries<'_>> {
+        Some(Box::new(self.iter_from(start)))
+    }
+
+    fn memory_usage(&self) -> usize {
+        let mut usage = self.arena.size();
+        let mut nodes: Vec<&Node> = self.root.iter().collect();
+        while let Some(node) = nodes.pop() {
+            match *node {
+                Node::Leaf(_) => usage += mem::size_of::<Leaf>(),
+                Node::Inner(ref inner) => {
+                    usage += mem::size_of::<Inner>() + inner.prefix.len() + inner.children.heap_size();
+                    if inner.leaf.is_some() {
+                        usage += mem::size_of::<Leaf>();
+                    }
+                    let mut byte = 0;
+                    while let Some((b, child)) = inner.children.next_from(byte) {
+                        nodes.push(child);
+                        byte = b as usize + 1;
+                    }
+                }
+            }
+        }
+        usage
+    }
+}
+
+impl ArtKeyMap {
+    fn remove_leaf(&mut self, key: &[u8]) -> Option<Box<Leaf>> {
+        let mut node = match self.root {
+            None => return None,
+            Some(Node::Leaf(ref leaf)) => {
+                if leaf.key.as_slice() != key {
+                    return None;
+                }
+                return match self.root.take() {
+                    Some(Node::Leaf(leaf)) => Some(leaf),
+                    _ => unreachable!(),
+                };
+            }
+            Some(ref mut root) => root,
+        };
+
+        let mut depth = 0;
+        loop {
+            let byte = match *node {
+                Node::Leaf(_) => unreachable!(),
+                Node::Inner(ref mut inner) => {
+                    if !key[depth..].starts_with(&inner.prefix) {
+                        return None;
+                    }
+                    depth += inner.prefix.len();
+                    if depth == key.len() {
+                        let leaf = inner.leaf.take();
+                        collapse(node);
+                        return leaf;
+                    }
+
+                    let byte = key[depth];
+                    match inner.children.find(byte) {
+                        None => return None,
+                        Some(Node::Leaf(ref leaf)) => {
+                            if leaf.key.as_slice() != key {
+                                return None;
+                            }
+                            let leaf = match inner.children.remove(byte) {
+                                Some(Node::Leaf(leaf)) => leaf,
+                                _ => unreachable!(),
+                            };
+                            collapse(node);
+                            return Some(leaf);
+                        }
+                        Some(Node::Inner(_)) => byte,
+                    }
+                }
+            };
+
+            node = match node {
+                Node::Inner(inner) => inner.children.find_mut(byte).unwrap(),
+                Node::Leaf(_) => unreachable!(),
+            };
+            depth += 1;
+        }
+    }
+}
+
+// In-order walk of the tree: the key of an inner node comes before those of its children.
+pub struct Iter<'a> {
+    // The inner nodes being walked, with the byte of their next child.
+    stack: Vec<(&'a Inner, usize)>,
+    pending: Option<&'a Leaf>,
+}
+
+impl<'a> Iter<'a> {
+    fn push(&mut self, inner: &'a Inner) {
+        self.stack.push((inner, 0));
+        self.pending = inner.leaf.as_deref();
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a [u8], MemIdxEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(leaf) = self.pending.take() {
+                return Some((leaf.key.as_slice(), leaf.entry));
+            }
+
+            let (inner, next) = self.stack.last_mut()?;
+            let inner: &'a Inner = inner;
+            match inner.children.next_from(*next) {
+                None => {
+                    self.stack.pop();
+                }
+                Some((byte, child)) => {
+                    *next = byte as usize + 1;
+                    match *child {
+                        Node::Leaf(ref leaf) => self.pending = Some(leaf),
+                        Node::Inner(ref child) => self.push(child),
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::json;
+
+use super::error::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditOp {
+    Set,
+    Remove,
+    RemoveRange,
+}
+
+impl AuditOp {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AuditOp::Set => "set",
+            AuditOp::Remove => "remove",
+            AuditOp::RemoveRange => "remove_range",
+        }
+    }
+}
+
+// A mutation applied to the store. `end` is the end of the range of a `RemoveRange`, and
+// `peer` the identity of the client, when the write came from the network.
+#[derive(Debug)]
+pub struct AuditRecord<'a> {
+    pub timestamp: SystemTime,
+    pub op: AuditOp,
+    pub key: &'a [u8],
+    pub end: Option<&'a [u8]>,
+    pub seq: u64,
+    pub peer: Option<&'a str>,
+}
+
+// Receives every mutation once it has been appended to the data files, under the write
+// lock: records come in sequence order, and a slow sink slows down the writers.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(&AuditRecord) + Send + Sync,
+{
+    fn record(&self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+// Append-only audit file holding one JSON object per line. Keys which aren't valid UTF-8
+// are written lossily.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for AuditLog {
+    fn record(&self, record: &AuditRecord) {
+        let timestamp = record.timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let mut line = json!({
+            "timestamp": timestamp,
+            "op": record.op.name(),
+            "key": String::from_utf8_lossy(record.key),
+            "seq": record.seq,
+            "peer": record.peer,
+        });
+        if let Some(end) = record.end {
+            line["end"] = json!(String::from_utf8_lossy(end));
+        }
+
+        let mut line = line.to_string();
+        line.push('\n');
+        // The write already happened, it can't be failed because of its audit record.
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Couldn't write to the audit log: {}", err);
+        }
+    }
+}
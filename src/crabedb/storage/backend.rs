@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+use std::sync::{Arc, Mutex};
+
+use super::io_engine::{IoEngine, SyncEngine};
+use super::util::{create_new_file, get_file_handle, sync_dir};
+
+// The file operations of the `Lsm` on its data, hint and blob files, so that another backend can
+// stand in for the file system without touching the LSM logic, e.g. one keeping the files
+// in memory, mirroring them to an object storage or injecting faults in tests. Files are
+// `std::fs::File`s addressed by their path, and they are read and written at explicit
+// offsets with the methods of `IoEngine`.
+pub trait Storage: IoEngine {
+    // Create a file for writing, failing if it already exists when `exclusive`, truncating
+    // it otherwise.
+    fn create(&self, path: &Path, exclusive: bool) -> io::Result<File>;
+    // Open a file for reading.
+    fn open(&self, path: &Path) -> io::Result<File>;
+    // Write `buf` at the end of a file created for writing.
+    fn append(&self, file: &File, buf: &[u8]) -> io::Result<()>;
+    // Make the content of a file durable.
+    fn sync(&self, file: &File) -> io::Result<()>;
+    // Cut the file at `path` to `len` bytes, durably.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    // Whether a file exists at `path`.
+    fn exists(&self, path: &Path) -> bool;
+    // The paths of the regular files in a directory.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    // Make the files created, renamed and removed in a directory durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+// The local file system, read and written through an `IoEngine`. It's the backend of a
+// store unless `StorageOptions::storage` sets another one.
+pub struct StdStorage {
+    io_engine: Arc<dyn IoEngine>,
+}
+
+impl StdStorage {
+    pub fn new(io_engine: Arc<dyn IoEngine>) -> StdStorage {
+        StdStorage { io_engine }
+    }
+}
+
+impl Default for StdStorage {
+    fn default() -> StdStorage {
+        StdStorage::new(Arc::new(SyncEngine))
+    }
+}
+
+impl IoEngine for StdStorage {
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.io_engine.read_at(file, buf, offset)
+    }
+
+    fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.io_engine.write_at(file, buf, offset)
+    }
+}
+
+impl Storage for StdStorage {
+    fn create(&self, path: &Path, exclusive: bool) -> io::Result<File> {
+        if exclusive {
+            create_new_file(path)
+        } else {
+            get_file_handle(path, true)
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<File> {
+        get_file_handle(path, false)
+    }
+
+    fn append(&self, mut file: &File, buf: &[u8]) -> io::Result<()> {
+        file.write_all(buf)
+    }
+
+    fn sync(&self, file: &File) -> io::Result<()> {
+        file.sync_data()
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for file in fs::read_dir(dir)? {
+            let file = file?;
+            if file.metadata()?.is_file() {
+                paths.push(file.path());
+            }
+        }
+        Ok(paths)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        sync_dir(dir)
+    }
+}
+
+// The files of `StorageOptions::in_memory`, kept in RAM rather than on a disk: each one
+// is an anonymous memory file, released once it's removed and no handle to it is left.
+// Syncs are no-ops, and everything is lost when the store is dropped.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<PathBuf, File>>,
+}
+
+impl MemoryStorage {
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path))
+    }
+}
+
+impl IoEngine for MemoryStorage {
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        SyncEngine.read_at(file, buf, offset)
+    }
+
+    fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        SyncEngine.write_at(file, buf, offset)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self, path: &Path, exclusive: bool) -> io::Result<File> {
+        let mut files = self.files.lock().unwrap();
+        if exclusive && files.contains_key(path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} already exists", path)));
+        }
+        let file = create_memory_file(path)?;
+        files.insert(path.to_path_buf(), file.try_clone()?);
+        Ok(file)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<File> {
+        match self.files.lock().unwrap().get(path) {
+            Some(file) => reopen_memory_file(file),
+            None => Err(MemoryStorage::not_found(path)),
+        }
+    }
+
+    fn append(&self, mut file: &File, buf: &[u8]) -> io::Result<()> {
+        file.write_all(buf)
+    }
+
+    fn sync(&self, _file: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        match self.files.lock().unwrap().get(path) {
+            Some(file) => file.set_len(len),
+            None => Err(MemoryStorage::not_found(path)),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.remove(from).ok_or_else(|| MemoryStorage::not_found(from))?;
+        files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.files.lock().unwrap().remove(path).map(|_| ()).ok_or_else(|| MemoryStorage::not_found(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.files.lock().unwrap().keys().filter(|path| path.parent() == Some(dir)).cloned().collect())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn create_memory_file(path: &Path) -> io::Result<File> {
+    use std::ffi::CString;
+    use std::os::unix::io::FromRawFd;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let name = CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// A handle of its own on a memory file, with its own offset for the sequential readers.
+#[cfg(target_os = "linux")]
+fn reopen_memory_file(file: &File) -> io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    File::open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_memory_file(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "in-memory stores require Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reopen_memory_file(_file: &File) -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "in-memory stores require Linux"))
+}
+
+// Appends to a file through a backend, for the encoders writing to a `Write`.
+pub struct Appender<'a> {
+    storage: &'a dyn Storage,
+    file: &'a File,
+}
+
+impl<'a> Appender<'a> {
+    pub fn new(storage: &'a dyn Storage, file: &'a File) -> Appender<'a> {
+        Appender { storage, file }
+    }
+}
+
+impl<'a> Write for Appender<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.storage.append(self.file, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
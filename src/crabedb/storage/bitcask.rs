@@ -0,0 +1,162 @@
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, ErrorKind, Take};
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use crc32fast::Hasher as Crc32;
+use lazy_static::lazy_static;
+use log::info;
+use regex::Regex;
+
+use super::crabe_db::CrabeDB;
+use super::error::{Error, Result};
+
+// Bitcask data files are named `<file_id>.bitcask.data` and hold big-endian records:
+// crc32(4) + timestamp(4) + key_size(2) + value_size(4) + key + value
+const BITCASK_DATA_FILE_EXTENSION: &str = "bitcask.data";
+const BITCASK_HEADER_SIZE: u64 = 14;
+const BITCASK_TOMBSTONE: &[u8] = b"bitcask_tombstone";
+const BITCASK_TOMBSTONE_PREFIXES: [&[u8]; 2] = [b"bitcask_tombstone1", b"bitcask_tombstone2"];
+
+#[derive(Debug, Default)]
+pub struct BitcaskImport {
+    pub files: usize,
+    pub records: u64,
+    pub tombstones: u64,
+}
+
+pub struct BitcaskRecord {
+    pub timestamp: u32,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl BitcaskRecord {
+    pub fn is_tombstone(&self) -> bool {
+        self.value == BITCASK_TOMBSTONE ||
+            BITCASK_TOMBSTONE_PREFIXES.iter().any(|prefix| {
+                self.value.len() == prefix.len() + 4 && self.value.starts_with(prefix)
+            })
+    }
+}
+
+pub struct BitcaskRecords {
+    data_file: Take<BufReader<File>>,
+}
+
+impl BitcaskRecords {
+    pub fn open(path: &Path) -> Result<BitcaskRecords> {
+        let data_file = File::open(path)?;
+        let data_file_size = data_file.metadata()?.len();
+        Ok(BitcaskRecords { data_file: BufReader::new(data_file).take(data_file_size) })
+    }
+
+    fn read_record(&mut self) -> Result<BitcaskRecord> {
+        if self.data_file.limit() < BITCASK_HEADER_SIZE {
+            return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+        }
+
+        let checksum = self.data_file.read_u32::<BigEndian>()?;
+        let timestamp = self.data_file.read_u32::<BigEndian>()?;
+        let key_size = self.data_file.read_u16::<BigEndian>()?;
+        let value_size = self.data_file.read_u32::<BigEndian>()?;
+
+        if (key_size as u64 + value_size as u64) > self.data_file.limit() {
+            return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+        }
+
+        let mut key = vec![0u8; key_size as usize];
+        self.data_file.read_exact(&mut key)?;
+        let mut value = vec![0u8; value_size as usize];
+        self.data_file.read_exact(&mut value)?;
+
+        let mut crc = Crc32::new();
+        crc.update(&timestamp.to_be_bytes());
+        crc.update(&key_size.to_be_bytes());
+        crc.update(&value_size.to_be_bytes());
+        crc.update(&key);
+        crc.update(&value);
+        let found = crc.finalize();
+
+        if found != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: u64::from(checksum),
+                found: u64::from(found),
+            });
+        }
+
+        Ok(BitcaskRecord {
+            timestamp,
+            key,
+            value,
+        })
+    }
+}
+
+impl Iterator for BitcaskRecords {
+    type Item = Result<BitcaskRecord>;
+
+    fn next(&mut self) -> Option<Result<BitcaskRecord>> {
+        if self.data_file.limit() == 0 {
+            None
+        } else {
+            Some(self.read_record())
+        }
+    }
+}
+
+pub fn find_bitcask_data_files(path: &Path) -> Result<Vec<(u32, PathBuf)>> {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(&format!("^(\\d+).{}$", BITCASK_DATA_FILE_EXTENSION)).unwrap();
+    }
+
+    let mut data_files = Vec::new();
+    for file in fs::read_dir(path)? {
+        let file = file?;
+        if file.metadata()?.is_file() {
+            let file_name = file.file_name();
+            let file_id = RE.captures(&file_name.to_string_lossy())
+                .and_then(|c| c.get(1).and_then(|n| n.as_str().parse::<u32>().ok()));
+            if let Some(file_id) = file_id {
+                data_files.push((file_id, file.path()));
+            }
+        }
+    }
+
+    data_files.sort();
+    Ok(data_files)
+}
+
+// Replay every record of a Bitcask directory, oldest file first, into the store. Hint
+// files are not needed: the data files hold the values and the history is replayed in
+// order, so the latest write of each key wins just like it did in Bitcask.
+pub fn import_bitcask(db: &CrabeDB, path: &str) -> Result<BitcaskImport> {
+    let path = PathBuf::from(path);
+    if !path.is_dir() {
+        return Err(Error::InvalidPath(path.to_string_lossy().into_owned()));
+    }
+
+    let mut import = BitcaskImport::default();
+
+    for (file_id, data_file_path) in find_bitcask_data_files(&path)? {
+        info!("Importing Bitcask data file {} ({:?})", file_id, data_file_path);
+
+        for record in BitcaskRecords::open(&data_file_path)? {
+            let record = record?;
+            if record.is_tombstone() {
+                db.remove(&record.key)?;
+                import.tombstones += 1;
+            } else {
+                db.set(record.key, record.value)?;
+            }
+            import.records += 1;
+        }
+        import.files += 1;
+    }
+
+    Ok(import)
+}
@@ -0,0 +1,398 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use log::info;
+use regex::Regex;
+
+use super::backend::{Appender, StdStorage, Storage};
+use super::error::{Error, Result};
+use super::format::{FileHeader, BLOB_FILE_MAGIC};
+use super::io_engine::PositionedReader;
+use super::lsm::sort_file_ids;
+use super::util::human_readable_byte_count;
+use super::xxhash::{xxhash32, XxHash32};
+
+const BLOB_FILE_EXTENSION: &str = "crabe.blob";
+const BLOB_STATIC_SIZE: u64 = 4; // checksum(4)
+pub const BLOB_POINTER_SIZE: usize = 20; // file_id(4) + pos(8) + size(8)
+
+// Location of a value stored in a blob file, which is what the data file holds in place
+// of the value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobPointer {
+    pub file_id: u32,
+    pub pos: u64,
+    pub size: u64,
+}
+
+impl BlobPointer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::with_capacity(BLOB_POINTER_SIZE));
+        // Writing to a Vec can't fail.
+        cursor.write_u32::<LittleEndian>(self.file_id).unwrap();
+        cursor.write_u64::<LittleEndian>(self.pos).unwrap();
+        cursor.write_u64::<LittleEndian>(self.size).unwrap();
+        cursor.into_inner()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<BlobPointer> {
+        if bytes.len() != BLOB_POINTER_SIZE {
+            return Err(Error::Io(io::ErrorKind::InvalidData.into()));
+        }
+
+        let mut cursor = Cursor::new(bytes);
+        Ok(BlobPointer {
+            file_id: cursor.read_u32::<LittleEndian>()?,
+            pos: cursor.read_u64::<LittleEndian>()?,
+            size: cursor.read_u64::<LittleEndian>()?,
+        })
+    }
+}
+
+// Appends large values to blob files: checksum(4) + value. A new blob file is started on
+// every load, so the files of previous runs are never written to again.
+pub struct BlobWriter {
+    path: PathBuf,
+    sync: bool,
+    max_file_size: usize,
+    storage: Arc<dyn Storage>,
+    next_file_id: u32,
+    active: Option<(u32, File, u64)>,
+    // The files of the values being streamed, which no record points to yet.
+    streams: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl BlobWriter {
+    pub fn new(
+        path: &Path,
+        sync: bool,
+        max_file_size: usize,
+        storage: Arc<dyn Storage>,
+        // The last blob file id used outside of `path`, e.g. in the archive of the store.
+        last_file_id: u32,
+    ) -> Result<BlobWriter> {
+        let first_file_id = list_blob_files(&*storage, path)?
+            .last()
+            .cloned()
+            .unwrap_or(0)
+            .max(last_file_id)
+            .checked_add(1)
+            .ok_or(Error::FileIdsExhausted)?;
+
+        Ok(BlobWriter {
+            path: path.to_path_buf(),
+            sync,
+            max_file_size,
+            storage,
+            next_file_id: first_file_id,
+            active: None,
+            streams: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    // Whether a value is still being written to the file, which can't be removed even if
+    // no record points to it.
+    pub fn is_writing(&self, file_id: u32) -> bool {
+        self.active.as_ref().is_some_and(|&(active_file_id, _, _)| active_file_id == file_id)
+            || self.streams.lock().unwrap().contains(&file_id)
+    }
+
+    pub fn append(&mut self, value: &[u8]) -> Result<BlobPointer> {
+        let entry_size = BLOB_STATIC_SIZE + value.len() as u64;
+        // A value larger than the file limit still gets a file of its own.
+        let full = match self.active {
+            Some((_, _, pos)) => {
+                pos > FileHeader::current().size() && pos + entry_size > self.max_file_size as u64
+            }
+            None => true,
+        };
+        if full {
+            self.new_file()?;
+        }
+
+        let (file_id, ref file, ref mut pos) = *self.active.as_mut().unwrap();
+        let mut entry = Vec::with_capacity(entry_size as usize);
+        entry.write_u32::<LittleEndian>(xxhash32(value))?;
+        entry.extend_from_slice(value);
+        self.storage.write_all_at(file, &entry, *pos)?;
+
+        if self.sync {
+            self.storage.sync(file)?;
+        }
+
+        let pointer = BlobPointer {
+            file_id,
+            pos: *pos,
+            size: value.len() as u64,
+        };
+        *pos += entry_size;
+        Ok(pointer)
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        if let Some((_, ref file, _)) = self.active {
+            self.storage.sync(file)?;
+        }
+        Ok(())
+    }
+
+    // Close the file being written, the next value starts a new one.
+    pub fn seal(&mut self) -> Result<()> {
+        self.sync()?;
+        self.active = None;
+        Ok(())
+    }
+
+    // Start a value written chunk by chunk in a blob file of its own, see `BlobStream`.
+    pub fn stream(&mut self) -> Result<BlobStream> {
+        let file_id = self.next_file_id;
+        self.next_file_id = file_id.checked_add(1).ok_or(Error::FileIdsExhausted)?;
+        let (file, pos) = self.create_file(file_id)?;
+        self.streams.lock().unwrap().insert(file_id);
+        Ok(BlobStream {
+            path: get_blob_file_path(&self.path, file_id),
+            storage: self.storage.clone(),
+            streams: self.streams.clone(),
+            file_id,
+            file,
+            start: pos,
+            size: 0,
+            hasher: XxHash32::new(),
+            finished: false,
+        })
+    }
+
+    fn new_file(&mut self) -> Result<()> {
+        if let Some((file_id, ref file, pos)) = self.active {
+            self.storage.sync(file)?;
+            info!(
+                "Closed blob file {} of {}",
+                file_id,
+                human_readable_byte_count(pos as usize, true)
+            );
+        }
+
+        let file_id = self.next_file_id;
+        self.next_file_id = file_id.checked_add(1).ok_or(Error::FileIdsExhausted)?;
+        let (file, pos) = self.create_file(file_id)?;
+        self.active = Some((file_id, file, pos));
+        Ok(())
+    }
+
+    // Returns the position of the first value.
+    fn create_file(&self, file_id: u32) -> Result<(File, u64)> {
+        let blob_file_path = get_blob_file_path(&self.path, file_id);
+        let file = self.storage.create(&blob_file_path, true)?;
+        let file_header = FileHeader::current();
+        file_header.write_bytes(BLOB_FILE_MAGIC, &mut Appender::new(&*self.storage, &file))?;
+        info!("Created new blob file {:?}", blob_file_path);
+        self.storage.sync_dir(&self.path)?;
+        Ok((file, file_header.size()))
+    }
+}
+
+// A value written to a blob file of its own as its chunks arrive, e.g. from a stream, so
+// that it's never held in memory whole. Its checksum goes in front of it once it's
+// complete. The file is removed unless the value was finished, and its pointer written.
+pub struct BlobStream {
+    path: PathBuf,
+    storage: Arc<dyn Storage>,
+    streams: Arc<Mutex<HashSet<u32>>>,
+    file_id: u32,
+    file: File,
+    start: u64,
+    size: u64,
+    hasher: XxHash32,
+    finished: bool,
+}
+
+impl BlobStream {
+    pub fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        let pos = self.start + BLOB_STATIC_SIZE + self.size;
+        self.storage.write_all_at(&self.file, chunk, pos)?;
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Write the checksum and sync the value. The file is still removed on drop, unless
+    // it's kept once its pointer was written.
+    pub fn finish(&mut self) -> Result<BlobPointer> {
+        let mut checksum = Vec::with_capacity(BLOB_STATIC_SIZE as usize);
+        checksum.write_u32::<LittleEndian>(self.hasher.get())?;
+        self.storage.write_all_at(&self.file, &checksum, self.start)?;
+        self.storage.sync(&self.file)?;
+        Ok(BlobPointer {
+            file_id: self.file_id,
+            pos: self.start,
+            size: self.size,
+        })
+    }
+
+    pub fn keep(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for BlobStream {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.storage.remove(&self.path);
+        }
+        self.streams.lock().unwrap().remove(&self.file_id);
+    }
+}
+
+// Reads a value of a blob file a chunk at a time, e.g. to stream it, its checksum being
+// checked along with the last chunk. The file stays readable if it's removed meanwhile.
+pub struct BlobReader {
+    storage: Arc<dyn Storage>,
+    file: File,
+    pos: u64,
+    remaining: u64,
+    size: u64,
+    checksum: u32,
+    hasher: XxHash32,
+}
+
+impl BlobReader {
+    pub fn open(storage: Arc<dyn Storage>, path: &Path, pointer: &BlobPointer) -> Result<BlobReader> {
+        let file = storage.open(&get_blob_file_path(path, pointer.file_id))?;
+        let checksum = PositionedReader::new(&*storage, &file, pointer.pos).read_u32::<LittleEndian>()?;
+        Ok(BlobReader {
+            storage,
+            file,
+            pos: pointer.pos + BLOB_STATIC_SIZE,
+            remaining: pointer.size,
+            size: pointer.size,
+            checksum,
+            hasher: XxHash32::new(),
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // The next `len` bytes of the value at most, empty once it was read whole.
+    pub fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut chunk = vec![0u8; self.remaining.min(len as u64) as usize];
+        PositionedReader::new(&*self.storage, &self.file, self.pos).read_exact(&mut chunk)?;
+        self.hasher.update(&chunk);
+        self.pos += chunk.len() as u64;
+        self.remaining -= chunk.len() as u64;
+
+        if self.remaining == 0 && !chunk.is_empty() {
+            let hash = self.hasher.get();
+            if hash != self.checksum {
+                return Err(Error::InvalidChecksum {
+                    expected: u64::from(self.checksum),
+                    found: u64::from(hash),
+                });
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+// Blob files are looked up by much larger reads than data files, so they are opened on
+// every read rather than cached.
+// A value read a chunk at a time: one of a blob file is read from the file as it goes,
+// other values are small enough to be read whole.
+pub enum ValueReader {
+    Inline(Bytes),
+    Blob(BlobReader),
+}
+
+impl ValueReader {
+    pub fn size(&self) -> u64 {
+        match self {
+            ValueReader::Inline(value) => value.len() as u64,
+            ValueReader::Blob(reader) => reader.size(),
+        }
+    }
+
+    // The next `len` bytes of the value at most, empty once it was read whole.
+    pub fn read(&mut self, len: usize) -> Result<Bytes> {
+        match self {
+            ValueReader::Inline(value) => Ok(value.split_to(value.len().min(len))),
+            ValueReader::Blob(reader) => reader.read(len).map(Bytes::from),
+        }
+    }
+}
+
+pub fn read_blob(storage: &dyn Storage, path: &Path, pointer: &BlobPointer) -> Result<Bytes> {
+    let mut value = Vec::new();
+    read_blob_into(storage, path, pointer, &mut value)?;
+    Ok(Bytes::from(value))
+}
+
+pub fn read_blob_into(
+    storage: &dyn Storage,
+    path: &Path,
+    pointer: &BlobPointer,
+    buf: &mut Vec<u8>,
+) -> Result<usize> {
+    let file = storage.open(&get_blob_file_path(path, pointer.file_id))?;
+    let mut reader = PositionedReader::new(storage, &file, pointer.pos);
+    let checksum = reader.read_u32::<LittleEndian>()?;
+
+    buf.clear();
+    buf.resize(pointer.size as usize, 0);
+    reader.read_exact(buf)?;
+
+    let hash = xxhash32(buf);
+    if hash != checksum {
+        return Err(Error::InvalidChecksum {
+            expected: u64::from(checksum),
+            found: u64::from(hash),
+        });
+    }
+    Ok(buf.len())
+}
+
+pub fn get_blob_file_path(path: &Path, file_id: u32) -> PathBuf {
+    let file_id = format!("{:010}", file_id);
+    path.join(file_id).with_extension(BLOB_FILE_EXTENSION)
+}
+
+pub fn find_blob_files(path: &Path) -> Result<Vec<u32>> {
+    list_blob_files(&StdStorage::default(), path)
+}
+
+pub fn list_blob_files(storage: &dyn Storage, path: &Path) -> Result<Vec<u32>> {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(&format!("(\\d+).{}$", BLOB_FILE_EXTENSION)).unwrap();
+    }
+
+    let mut blob_files = Vec::new();
+    for file_path in storage.list(path)? {
+        let file_name = file_path.file_name().unwrap_or_default();
+        if let Some(n) = RE.captures(&file_name.to_string_lossy())
+            .and_then(|c| c.get(1).and_then(|n| n.as_str().parse::<u32>().ok()))
+        {
+            blob_files.push(n);
+        }
+    }
+    sort_file_ids(blob_files)
+}
@@ -0,0 +1,222 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use regex::Regex;
+
+use super::checksum::ChecksumHasher;
+use super::error::{Error, Result};
+use super::lsm::LogPosition;
+use super::options::ChecksumKind;
+use super::standby::save_position;
+use super::util::{get_file_handle, is_new_store_path, sync_dir};
+
+// The directory of a store holding the snapshots being sent to standbys.
+pub(crate) const SNAPSHOTS_DIR_NAME: &str = "crabe.snapshots";
+
+static NEXT_SNAPSHOT_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // The files of a snapshot, i.e. of a hot backup.
+    static ref SNAPSHOT_FILE_RE: Regex =
+        Regex::new("^(\\d{10}\\.crabe\\.(sst|cpct|blob)|crabe\\.manifest|crabe\\.leases)$").unwrap();
+}
+
+// A consistent copy of a store to send to a joining standby, see
+// `CrabeDB::bootstrap_snapshot`: the files of a hot backup, taken in a directory of the
+// store so that they are hard linked, and removed once dropped. The standby tails the
+// store from `position` once it installed them.
+pub struct StoreSnapshot {
+    dir: PathBuf,
+    files: Vec<String>,
+    pub position: LogPosition,
+    pub last_seq: u64,
+}
+
+impl StoreSnapshot {
+    // A new, empty directory for a snapshot of the store at `path`.
+    pub(crate) fn create_dir(path: &Path) -> Result<PathBuf> {
+        let id = NEXT_SNAPSHOT_ID.fetch_add(1, Ordering::Relaxed);
+        let dir = path.join(SNAPSHOTS_DIR_NAME).join(format!("{}-{}", std::process::id(), id));
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    pub(crate) fn new(dir: PathBuf, position: LogPosition, last_seq: u64) -> Result<StoreSnapshot> {
+        let mut files = Vec::new();
+        for file in fs::read_dir(&dir)? {
+            files.push(file?.file_name().to_string_lossy().into_owned());
+        }
+        // The manifest goes last: a standby only loads the files it lists.
+        files.sort_by_key(|name| (name == "crabe.manifest", name.clone()));
+
+        Ok(StoreSnapshot {
+            dir,
+            files,
+            position,
+            last_seq,
+        })
+    }
+
+    // The names of the files, in the order to send them.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn open(&self, name: &str) -> Result<SnapshotFile> {
+        Ok(SnapshotFile {
+            file: get_file_handle(&self.dir.join(name), false)?,
+            hasher: ChecksumHasher::new(ChecksumKind::XxHash64),
+        })
+    }
+}
+
+impl Drop for StoreSnapshot {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.dir) {
+            warn!("Failed to remove the snapshot {:?}: {}", self.dir, err);
+        }
+        // Fails while another snapshot is being sent.
+        if let Some(parent) = self.dir.parent() {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+}
+
+// Remove the snapshots left over by a crash while they were sent.
+pub(crate) fn remove_snapshots(path: &Path) -> Result<()> {
+    let dir = path.join(SNAPSHOTS_DIR_NAME);
+    if dir.is_dir() {
+        warn!("Removing leftover snapshots: {:?}", dir);
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+// A file of a snapshot, read chunk by chunk.
+pub struct SnapshotFile {
+    file: File,
+    hasher: ChecksumHasher,
+}
+
+impl SnapshotFile {
+    // The next chunk of at most `size` bytes, empty at the end of the file.
+    pub fn read_chunk(&mut self, size: usize) -> Result<Vec<u8>> {
+        let mut chunk = Vec::with_capacity(size);
+        (&mut self.file).take(size as u64).read_to_end(&mut chunk)?;
+        self.hasher.update(&chunk);
+        Ok(chunk)
+    }
+
+    // The xxHash64 of the chunks read so far, of the whole file once they all were.
+    pub fn checksum(&self) -> u64 {
+        self.hasher.get()
+    }
+}
+
+// The file of a snapshot being received.
+struct ReceivedFile {
+    name: String,
+    file: File,
+    hasher: ChecksumHasher,
+}
+
+// Installs the snapshot of a primary, received file by file, as a new standby store. The
+// files are written to a staging directory next to the store, which replaces it once
+// they all were received and checked, so that an interrupted bootstrap leaves no partial
+// store behind: it's started over on the next attempt.
+pub struct SnapshotInstaller {
+    path: PathBuf,
+    staging: PathBuf,
+    current: Option<ReceivedFile>,
+    files: usize,
+}
+
+impl SnapshotInstaller {
+    pub fn new(path: &str) -> Result<SnapshotInstaller> {
+        let path = PathBuf::from(path);
+        if !is_new_store_path(&path)? {
+            return Err(Error::InvalidPath(path.to_string_lossy().into_owned()));
+        }
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".bootstrap");
+        let staging = path.with_file_name(name);
+        if staging.exists() {
+            warn!("Removing the leftover of an interrupted bootstrap: {:?}", staging);
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+
+        Ok(SnapshotInstaller {
+            path,
+            staging,
+            current: None,
+            files: 0,
+        })
+    }
+
+    // Append a chunk to the file `name`, which must be the file being received, if any.
+    pub fn write(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let received = match self.current {
+            Some(ref mut received) if received.name == name => received,
+            Some(ref received) => {
+                return Err(Error::InvalidPath(format!("{} sent before the end of {}", name, received.name)))
+            }
+            None => {
+                if !SNAPSHOT_FILE_RE.is_match(name) {
+                    return Err(Error::InvalidPath(name.to_string()));
+                }
+                let file = get_file_handle(&self.staging.join(name), true)?;
+                self.current.insert(ReceivedFile {
+                    name: name.to_string(),
+                    file,
+                    hasher: ChecksumHasher::new(ChecksumKind::XxHash64),
+                })
+            }
+        };
+        received.file.write_all(data)?;
+        received.hasher.update(data);
+        Ok(())
+    }
+
+    // End the file being received, whose content must have the xxHash64 `checksum`.
+    pub fn finish_file(&mut self, checksum: u64) -> Result<()> {
+        let received = match self.current.take() {
+            Some(received) => received,
+            None => return Err(Error::InvalidPath("no file being received".to_string())),
+        };
+        let found = received.hasher.get();
+        if found != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: checksum,
+                found,
+            });
+        }
+        received.file.sync_all()?;
+        self.files += 1;
+        Ok(())
+    }
+
+    // Record the position of the primary to tail from and move the store in place.
+    // Returns the number of files installed.
+    pub fn finish(self, position: LogPosition) -> Result<usize> {
+        if let Some(received) = self.current {
+            return Err(Error::InvalidPath(format!("{} not received entirely", received.name)));
+        }
+        save_position(&self.staging, position)?;
+        // Only an empty directory can be replaced.
+        if self.path.is_dir() {
+            fs::remove_dir(&self.path)?;
+        }
+        fs::rename(&self.staging, &self.path)?;
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            sync_dir(parent)?;
+        }
+        info!("Installed a snapshot of {} files into {:?}", self.files, self.path);
+        Ok(self.files)
+    }
+}
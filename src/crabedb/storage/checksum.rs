@@ -0,0 +1,77 @@
+use std::hash::Hasher;
+use std::io::{Read, Result, Write};
+use std::result::Result::Ok;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use twox_hash::XxHash64;
+
+use super::crc32c::Crc32c;
+use super::options::ChecksumKind;
+use super::xxhash::XxHash32;
+
+// Hasher of the checksum of the records of a data file, or of a whole hint file, whose
+// algorithm is recorded in the file header. The 32-bit checksums are widened to a `u64`.
+pub enum ChecksumHasher {
+    XxHash32(XxHash32),
+    Crc32c(Crc32c),
+    XxHash64(XxHash64),
+}
+
+impl ChecksumHasher {
+    pub fn new(kind: ChecksumKind) -> ChecksumHasher {
+        match kind {
+            ChecksumKind::XxHash32 => ChecksumHasher::XxHash32(XxHash32::new()),
+            ChecksumKind::Crc32c => ChecksumHasher::Crc32c(Crc32c::new()),
+            ChecksumKind::XxHash64 => ChecksumHasher::XxHash64(XxHash64::with_seed(0)),
+        }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            ChecksumHasher::XxHash32(hasher) => hasher.update(buf),
+            ChecksumHasher::Crc32c(hasher) => hasher.update(buf),
+            ChecksumHasher::XxHash64(hasher) => hasher.write(buf),
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        match self {
+            ChecksumHasher::XxHash32(hasher) => u64::from(hasher.get()),
+            ChecksumHasher::Crc32c(hasher) => u64::from(hasher.get()),
+            ChecksumHasher::XxHash64(hasher) => hasher.finish(),
+        }
+    }
+}
+
+impl Write for ChecksumHasher {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Size of the checksums of a kind in the files.
+pub fn checksum_size(kind: ChecksumKind) -> usize {
+    match kind {
+        ChecksumKind::XxHash32 | ChecksumKind::Crc32c => 4,
+        ChecksumKind::XxHash64 => 8,
+    }
+}
+
+pub fn write_checksum<W: Write>(writer: &mut W, kind: ChecksumKind, checksum: u64) -> Result<()> {
+    match kind {
+        ChecksumKind::XxHash32 | ChecksumKind::Crc32c => writer.write_u32::<LittleEndian>(checksum as u32),
+        ChecksumKind::XxHash64 => writer.write_u64::<LittleEndian>(checksum),
+    }
+}
+
+pub fn read_checksum<R: Read>(reader: &mut R, kind: ChecksumKind) -> Result<u64> {
+    match kind {
+        ChecksumKind::XxHash32 | ChecksumKind::Crc32c => reader.read_u32::<LittleEndian>().map(u64::from),
+        ChecksumKind::XxHash64 => reader.read_u64::<LittleEndian>(),
+    }
+}
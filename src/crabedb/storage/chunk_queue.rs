@@ -1,84 +1,137 @@
 use std::collections::{HashMap, VecDeque};
-use std::collections::hash_map::Entry;
 use std::fs::File;
+use std::sync::Arc;
 
+use super::options::{CacheUnit, EvictionPolicy};
+use super::stats::ChunkQueueStats;
+
+struct CachedFile {
+    handles: Vec<Arc<File>>,
+    size: u64,
+}
+
+// Cache of the open data file handles. Reads are positioned, so a handle can be shared by
+// every reader: the cache lock is only held to look the handle up. A hot file may get up
+// to `handles_per_file` handles, which are handed out to concurrent readers so that they
+// don't all go through the same descriptor. The capacity is either a number of handles
+// or the total size of the cached files, and whole files are evicted once it is reached.
 pub struct ChunkQueue {
     queue: VecDeque<u32>,
-    files: HashMap<u32, Vec<File>>,
-    capacity: usize,
-    size: usize,
+    files: HashMap<u32, CachedFile>,
+    usage: u64,
+    capacity: u64,
+    handles_per_file: usize,
+    policy: EvictionPolicy,
+    unit: CacheUnit,
+    stats: ChunkQueueStats,
 }
 
 impl ChunkQueue {
-    pub fn new(capacity: usize) -> ChunkQueue {
+    pub fn new(
+        capacity: usize,
+        handles_per_file: usize,
+        policy: EvictionPolicy,
+        unit: CacheUnit,
+    ) -> ChunkQueue {
         ChunkQueue {
             queue: VecDeque::new(),
             files: HashMap::new(),
-            capacity: capacity,
-            size: 0,
+            usage: 0,
+            capacity: capacity as u64,
+            handles_per_file: handles_per_file.max(1),
+            policy,
+            unit,
+            stats: ChunkQueueStats::default(),
         }
     }
 
-    pub fn get(&mut self, file_id: u32) -> Option<File> {
-        let mut remove = false;
+    // Return an idle handle of the file, or `None` when every cached handle is in use and
+    // the file may get one more: the caller then opens it and `put`s it in the queue.
+    pub fn get(&mut self, file_id: u32) -> Option<Arc<File>> {
+        let file = self.files.get(&file_id).and_then(|file| {
+            let least_used = file.handles.iter().min_by_key(|handle| Arc::strong_count(handle))?;
 
-        let f = self.files.get_mut(&file_id).and_then(|v| {
-            let f = v.pop();
-            if v.is_empty() {
-                remove = true;
+            if Arc::strong_count(least_used) > 1 && file.handles.len() < self.handles_per_file {
+                None
+            } else {
+                Some(least_used.clone())
             }
-            f
         });
 
-        if f.is_some() {
-            if remove {
-                self.files.remove(&file_id);
-            }
+        match file {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
 
+        if file.is_some() && self.policy == EvictionPolicy::Lru {
             if let Some(index) = self.queue.iter().position(|&f| f == file_id) {
                 self.queue.remove(index);
             }
-
-            self.size -= 1;
+            self.queue.push_back(file_id);
         }
-        f
+        file
     }
 
-    pub fn put(&mut self, file_id: u32, file: File) {
-        self.queue.push_back(file_id);
+    // `size` is the size of the file when the handle was opened, used when the capacity
+    // is expressed in bytes.
+    pub fn put(&mut self, file_id: u32, handle: Arc<File>, size: u64) {
+        let file = self.files.entry(file_id).or_insert_with(|| CachedFile {
+            handles: Vec::new(),
+            size,
+        });
+        if file.handles.len() >= self.handles_per_file {
+            return;
+        }
 
-        match self.files.entry(file_id) {
-            Entry::Occupied(mut occupied) => {
-                occupied.get_mut().push(file);
-            }
-            Entry::Vacant(entry) => {
-                entry.insert(vec![file]);
-            }
+        file.handles.push(handle);
+        if file.handles.len() == 1 {
+            self.queue.push_back(file_id);
+            self.usage += match self.unit {
+                CacheUnit::Handles => 1,
+                CacheUnit::Bytes => file.size,
+            };
+        } else if self.unit == CacheUnit::Handles {
+            self.usage += 1;
         }
-        self.size += 1;
 
-        if self.size > self.capacity {
-            self.remove_lru();
+        while self.usage > self.capacity && !self.queue.is_empty() {
+            self.evict();
         }
     }
 
-    fn remove_lru(&mut self) {
-        if let Some(file_id) = self.queue.pop_front() {
-            let mut remove = false;
-
-            if let Some(files) = self.files.get_mut(&file_id) {
-                files.pop();
+    pub fn remove(&mut self, file_id: u32) {
+        if let Some(file) = self.files.remove(&file_id) {
+            self.usage -= self.weight(&file);
+            if let Some(index) = self.queue.iter().position(|&f| f == file_id) {
+                self.queue.remove(index);
+            }
+        }
+    }
 
-                if files.is_empty() {
-                    remove = true;
-                }
+    pub fn stats(&self) -> ChunkQueueStats {
+        ChunkQueueStats {
+            files: self.files.len(),
+            handles: self.files.values().map(|file| file.handles.len()).sum(),
+            usage: self.usage,
+            ..self.stats
+        }
+    }
 
-                self.size -= 1;
-            }
+    fn weight(&self, file: &CachedFile) -> u64 {
+        match self.unit {
+            CacheUnit::Handles => file.handles.len() as u64,
+            CacheUnit::Bytes => file.size,
+        }
+    }
 
-            if remove {
-                self.files.remove(&file_id);
+    // The front of the queue is the least recently used file with the LRU policy, and
+    // the oldest one with the FIFO policy.
+    fn evict(&mut self) {
+        if let Some(file_id) = self.queue.pop_front() {
+            if let Some(file) = self.files.remove(&file_id) {
+                self.usage -= self.weight(&file);
+                self.stats.evictions += 1;
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeSet;
+
+use log::info;
+
+use super::options::StorageOptions;
+use super::util::human_readable_byte_count;
+
+// What a `CompactionFilter` does with a live record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterDecision {
+    Keep,
+    Drop,
+    Replace(Vec<u8>),
+}
+
+// Called for every live record rewritten by a compaction, e.g. to garbage collect the
+// records which are obsolete for the application. A dropped record is deleted as if
+// `remove` was called, and a replaced one keeps its sequence number.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &[u8], value: &[u8], seq: u64) -> FilterDecision;
+}
+
+impl<F> CompactionFilter for F
+where
+    F: Fn(&[u8], &[u8], u64) -> FilterDecision + Send + Sync,
+{
+    fn filter(&self, key: &[u8], value: &[u8], seq: u64) -> FilterDecision {
+        self(key, value, seq)
+    }
+}
+
+// What a `CompactionStrategy` knows about a candidate data file. The active file is
+// never a candidate.
+#[derive(Clone, Copy, Debug)]
+pub struct FileInfo {
+    pub file_id: u32,
+    pub fragmentation: f64,
+    pub dead_bytes: u64,
+    pub size: u64,
+}
+
+// File selection policy of `CrabeDB::compact`: it returns the files to merge together,
+// none when no compaction is needed.
+pub trait CompactionStrategy: Send + Sync {
+    fn select(&self, files: &[FileInfo], options: &StorageOptions) -> Vec<u32>;
+}
+
+// The default policy: a file which is fragmented or holds enough dead bytes triggers
+// the compaction, which then also picks up every file above the lower thresholds and
+// the small files.
+pub struct FragmentationStrategy;
+
+impl CompactionStrategy for FragmentationStrategy {
+    fn select(&self, files: &[FileInfo], options: &StorageOptions) -> Vec<u32> {
+        let mut selected = BTreeSet::new();
+        let mut triggered = false;
+
+        for file in files {
+            let file_id = file.file_id;
+
+            if !triggered {
+                if file.fragmentation >= options.fragmentation_trigger {
+                    info!(
+                        "File {} has fragmentation factor of {:.1}%, compaction will start",
+                        file_id,
+                        file.fragmentation * 100.0
+                    );
+                    triggered = true;
+                    selected.insert(file_id);
+                } else if file.dead_bytes >= options.dead_bytes_trigger && !selected.contains(&file_id) {
+                    info!(
+                        "File {} has {} of dead data, triggered compaction",
+                        file_id,
+                        human_readable_byte_count(file.dead_bytes as usize, true)
+                    );
+                    triggered = true;
+                    selected.insert(file_id);
+                }
+            }
+
+            if file.fragmentation >= options.fragmentation_threshold && !selected.contains(&file_id) {
+                info!(
+                    "File {} has fragmentation factor of {:.1}%, adding for compaction",
+                    file_id,
+                    file.fragmentation * 100.0
+                );
+                selected.insert(file_id);
+            } else if file.dead_bytes >= options.dead_bytes_threshold && !selected.contains(&file_id) {
+                info!(
+                    "File {} has {} of dead data, adding for compaction",
+                    file_id,
+                    human_readable_byte_count(file.dead_bytes as usize, true)
+                );
+                selected.insert(file_id);
+            }
+
+            if !selected.contains(&file_id) && file.size <= options.small_file_threshold {
+                info!(
+                    "File {} has total size of {}, adding for compaction",
+                    file_id,
+                    human_readable_byte_count(file.size as usize, true)
+                );
+                selected.insert(file_id);
+            }
+        }
+
+        if !triggered {
+            if !selected.is_empty() {
+                info!("Compaction of files {:?} aborted due to missing trigger", &selected);
+            }
+            return Vec::new();
+        }
+        selected.into_iter().collect()
+    }
+}
+
+// Merges files of similar sizes: files are grouped in buckets whose sizes are within
+// [`bucket_low`, `bucket_high`] times the average size of the bucket, and the largest
+// bucket holding at least `min_threshold` files is compacted, `max_threshold` files at
+// most. This bounds the write amplification of workloads with few overwrites.
+pub struct SizeTieredStrategy {
+    pub min_threshold: usize,
+    pub max_threshold: usize,
+    pub bucket_low: f64,
+    pub bucket_high: f64,
+}
+
+impl Default for SizeTieredStrategy {
+    fn default() -> SizeTieredStrategy {
+        SizeTieredStrategy {
+            min_threshold: 4,
+            max_threshold: 32,
+            bucket_low: 0.5,
+            bucket_high: 1.5,
+        }
+    }
+}
+
+impl CompactionStrategy for SizeTieredStrategy {
+    fn select(&self, files: &[FileInfo], _options: &StorageOptions) -> Vec<u32> {
+        let mut files = files.to_vec();
+        files.sort_by_key(|file| file.size);
+
+        // (total size, files) of each bucket, the files being sorted by size.
+        let mut buckets: Vec<(u64, Vec<FileInfo>)> = Vec::new();
+        for file in files {
+            let bucket = buckets.iter_mut().find(|(total, bucket)| {
+                let average = *total as f64 / bucket.len() as f64;
+                file.size as f64 >= average * self.bucket_low && file.size as f64 <= average * self.bucket_high
+            });
+
+            match bucket {
+                Some((total, bucket)) => {
+                    *total += file.size;
+                    bucket.push(file);
+                }
+                None => buckets.push((file.size, vec![file])),
+            }
+        }
+
+        let bucket = buckets
+            .into_iter()
+            .filter(|(_, bucket)| bucket.len() >= self.min_threshold.max(2))
+            .max_by_key(|(_, bucket)| bucket.len());
+
+        match bucket {
+            Some((total, bucket)) => {
+                info!(
+                    "{} files of about {} each, compaction will start",
+                    bucket.len(),
+                    human_readable_byte_count((total / bucket.len() as u64) as usize, true)
+                );
+                bucket.iter().take(self.max_threshold).map(|file| file.file_id).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+}
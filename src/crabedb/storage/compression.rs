@@ -0,0 +1,125 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io;
+use std::result::Result::Ok;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use zstd::bulk::Compressor;
+use zstd::dict::DecoderDictionary;
+use zstd::zstd_safe::{get_error_name, DCtx};
+
+use super::error::Result;
+use super::slot::Log;
+
+// The largest dictionary trained by a compaction.
+pub const MAX_DICTIONARY_SIZE: usize = 64 * 1024;
+// At most this many values, and this many bytes of them, are sampled by a compaction to
+// train its dictionary.
+pub const MAX_SAMPLES: u64 = 4096;
+pub const MAX_SAMPLES_SIZE: usize = 4 * 1024 * 1024;
+
+// The values of the records of a compressed file start with a tag: a value which doesn't
+// shrink is kept as is, after `RAW_VALUE`, and the others are stored after
+// `COMPRESSED_VALUE` as their size followed by their zstd frame.
+const RAW_VALUE: u8 = 0;
+const COMPRESSED_VALUE: u8 = 1;
+
+thread_local! {
+    // Decompression contexts are reused, the read path decompresses values one by one.
+    static DECOMPRESSION_CONTEXT: RefCell<DCtx<'static>> = RefCell::new(DCtx::create());
+}
+
+// Train a dictionary from sampled values, or `None` when there are too few of them for
+// zstd to train one: the values are then compressed on their own.
+pub fn train_dictionary(samples: &[Vec<u8>]) -> Option<Vec<u8>> {
+    zstd::dict::from_samples(samples, MAX_DICTIONARY_SIZE).ok()
+}
+
+// Compresses the values of the records written to a compressed file.
+pub struct ValueCompressor {
+    compressor: Compressor<'static>,
+    dictionary: Vec<u8>,
+}
+
+impl ValueCompressor {
+    pub fn new(level: i32, dictionary: Vec<u8>) -> Result<ValueCompressor> {
+        Ok(ValueCompressor {
+            compressor: Compressor::with_dictionary(level, &dictionary)?,
+            dictionary,
+        })
+    }
+
+    // The dictionary stored in the header of the files, possibly empty.
+    pub fn dictionary(&self) -> &[u8] {
+        &self.dictionary
+    }
+
+    // Replace the value of a record by its stored form. Tombstones and blob pointers are
+    // left alone.
+    pub fn compress(&mut self, log: &mut Log) -> Result<()> {
+        if log.deleted || log.range || log.blob {
+            return Ok(());
+        }
+        let compressed = self.compressor.compress(&log.value)?;
+        let value = if compressed.len() + 4 < log.value.len() {
+            let mut value = Vec::with_capacity(compressed.len() + 5);
+            value.push(COMPRESSED_VALUE);
+            value.write_u32::<LittleEndian>(log.value.len() as u32)?;
+            value.extend_from_slice(&compressed);
+            value
+        } else {
+            let mut value = Vec::with_capacity(log.value.len() + 1);
+            value.push(RAW_VALUE);
+            value.extend_from_slice(&log.value);
+            value
+        };
+        log.value = Cow::Owned(value);
+        Ok(())
+    }
+}
+
+// Decompresses the values of the records of a compressed file.
+pub struct ValueDecompressor {
+    dictionary: Option<DecoderDictionary<'static>>,
+}
+
+impl ValueDecompressor {
+    pub fn new(dictionary: &[u8]) -> ValueDecompressor {
+        ValueDecompressor {
+            dictionary: if dictionary.is_empty() { None } else { Some(DecoderDictionary::copy(dictionary)) },
+        }
+    }
+
+    // The value of a record from its stored form.
+    pub fn decompress(&self, stored: &[u8]) -> Result<Vec<u8>> {
+        match stored.split_first() {
+            Some((&RAW_VALUE, value)) => Ok(value.to_vec()),
+            Some((&COMPRESSED_VALUE, mut frame)) => {
+                let size = frame.read_u32::<LittleEndian>()? as usize;
+                let mut value = Vec::with_capacity(size);
+                DECOMPRESSION_CONTEXT.with(|dctx| {
+                    let mut dctx = dctx.borrow_mut();
+                    match self.dictionary {
+                        Some(ref dictionary) => dctx.decompress_using_ddict(&mut value, frame, dictionary.as_ddict()),
+                        None => dctx.decompress(&mut value, frame),
+                    }
+                })
+                .map_err(|code| io::Error::new(io::ErrorKind::InvalidData, get_error_name(code)))?;
+                if value.len() != size {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+                }
+                Ok(value)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid compressed value").into()),
+        }
+    }
+
+    // Same as `decompress`, but a value kept as is isn't copied.
+    pub fn decompress_bytes(&self, stored: Bytes) -> Result<Bytes> {
+        match stored.first() {
+            Some(&RAW_VALUE) => Ok(stored.slice(1..)),
+            _ => Ok(self.decompress(&stored)?.into()),
+        }
+    }
+}
@@ -1,48 +1,164 @@
-use std::collections::{BTreeSet, HashMap};
-use std::collections::hash_map::{Entry as HashMapEntry, Keys};
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry as HashMapEntry;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::result::Result::Ok;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
 
+use bytes::Bytes;
 use time;
-use log::{info, warn, debug};
+use log::{info, warn, debug, error};
 
-use super::options::{StorageOptions, SyncOptions};
-use super::slot::{MemIdx, MemIdxEntry, Log, CompactionHint};
-use super::error::Result;
-use super::lsm::{Lsm, LsmWrite};
-use super::util::human_readable_byte_count;
+use super::archive::{ArchiveReader, ArchiveWriter};
+use super::audit::{AuditOp, AuditRecord, AuditSink};
+use super::blob::{BlobStream, ValueReader};
+use super::bootstrap::StoreSnapshot;
+use super::compaction::{FileInfo, FilterDecision};
+use super::compression::{train_dictionary, ValueCompressor, MAX_SAMPLES, MAX_SAMPLES_SIZE};
+use super::deadline;
+use super::options::{RecoveryMode, StorageOptions, SyncOptions};
+use super::partition;
+use super::slot::{MemIdx, MemIdxEntry, Log, CompactionHint, SharedIdx, Tombstone};
+use super::standby;
+use super::stats::{CompactionStatus, FileStats, Stats, ValueMetadata};
+use super::summary::FileSummary;
+use super::error::{BackgroundError, Error, Result};
+use super::group_commit::{GroupCommitWriter, WriteHandle, WriteOp};
+use super::lease::{LeaseChanges, LeaseInfo, Leases};
+use super::lsm::{self, LogPosition, Lsm, LsmReader, Tail};
+use super::manifest::FileSeal;
+use super::merge::StoreVersions;
+use super::rate_limiter::RateLimiter;
+use super::util::is_new_store_path;
+use super::value_cache::ValueCache;
+use super::verify::{ConsistencyReport, Inconsistency};
+use super::write_batch::WriteBatch;
+
+// How often the expired leases are looked for.
+const LEASE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// The background threads sleep by slices of at most this, so that they exit, and release
+// the store, soon after it's dropped.
+const DROP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// How often a write blocked by `StorageOptions::write_stop` checks whether it can go on.
+const WRITE_STOP_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+// A value written a chunk at a time goes to a blob file from this size on when the blobs
+// are disabled, see `ValueWriter`.
+const STREAMED_BLOB_THRESHOLD: usize = 1 << 20;
 
 pub struct CrabeDBinternal {
-    current_seq: u64,
+    // The next sequence number, shared by the partitions of a store.
+    current_seq: Arc<AtomicU64>,
+    node_id: Option<u16>,
     idx: MemIdx,
     lsm: Lsm,
+    cache: Option<Arc<Mutex<ValueCache>>>,
+    blob_threshold: usize,
+    audit: Option<Arc<dyn AuditSink>>,
+    // Writes only come from `apply` until the standby is promoted.
+    standby: bool,
+    // Set by the first failure of a background task, which fences the writes.
+    background_error: Option<BackgroundError>,
+    // Keys written since the last publish, evicted from the cache once it is done.
+    stale_keys: Vec<Vec<u8>>,
+}
+
+// The first sequence number from `seq` on whose low 16 bits are `node_id`, the first
+// 65536 ones excluded.
+fn node_seq(seq: u64, node_id: u16) -> u64 {
+    let node_id = node_id as u64;
+    ((seq.max(1 << 16) - node_id).saturating_add(0xffff) & !0xffff) | node_id
+}
+
+fn live_value(log: Log, file_id: u32) -> Option<Vec<u8>> {
+    if log.deleted {
+        warn!(
+            "Index pointed to dead log: Log {{ key: {:?}, sequence: {} }} at \
+            file: {}",
+            log.key,
+            log.seq,
+            file_id
+        );
+        None
+    } else {
+        Some(log.value.into_owned())
+    }
+}
+
+fn cached_value(cache: &Option<Arc<Mutex<ValueCache>>>, key: &[u8]) -> Option<Bytes> {
+    cache.as_ref().and_then(|cache| cache.lock().unwrap().get(key))
+}
+
+// Open the `count` most recently written local data files, and read their live values into
+// the value cache while it has room, the newest files first.
+fn warm_caches(
+    lsm: &Lsm,
+    idx: &MemIdx,
+    cache: &Option<Arc<Mutex<ValueCache>>>,
+    count: usize,
+) -> Result<()> {
+    let recent: Vec<u32> = lsm.files()
+        .into_iter()
+        .rev()
+        .filter(|&file_id| !lsm.is_remote(file_id))
+        .take(count)
+        .collect();
+
+    // The newest files are opened last, so that they stay in a cache too small for all.
+    for &file_id in recent.iter().rev() {
+        lsm.warm(file_id)?;
+    }
+
+    let cache = match *cache {
+        Some(ref cache) => cache,
+        None => return Ok(()),
+    };
+    let mut cache = cache.lock().unwrap();
+    for &file_id in &recent {
+        let chs = match lsm.compaction_hints(file_id)? {
+            Some(chs) => chs,
+            None => continue,
+        };
+        for ch in chs {
+            let ch = ch?;
+            let entry = match idx.get(&ch.key)? {
+                Some(entry) if entry.file_id == file_id && entry.pos == ch.log_pos => entry,
+                _ => continue,
+            };
+            if let Some(value) = lsm.read_value(file_id, entry.pos, entry.size)? {
+                if ch.key.len() + value.len() > cache.available() {
+                    return Ok(());
+                }
+                cache.insert(&ch.key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The index never points to tombstones, warn when the data says otherwise.
+fn check_live<T>(value: Option<T>, key: &[u8], file_id: u32) -> Option<T> {
+    if value.is_none() {
+        warn!("Index pointed to dead log: key {:?} at file: {}", key, file_id);
+    }
+    value
 }
 
 impl CrabeDBinternal {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let val = match self.idx.get(key) {
+        let val = match self.idx.get(key)? {
             Some(idx_log) => {
                 let log = self.lsm.read_log(
                     idx_log.file_id,
                     idx_log.pos,
                 )?;
-                if log.deleted {
-                    warn!(
-                        "Index pointed to dead log: Log {{ key: {:?}, sequence: {} }} at \
-                        file: {}",
-                        log.key,
-                        log.seq,
-                        idx_log.file_id
-                    );
-                    None
-                } else {
-                    Some(log.value.into_owned())
-                }
+                let log = self.lsm.resolve(log)?;
+                live_value(log, idx_log.file_id)
             }
             _ => None,
         };
@@ -50,171 +166,2398 @@ impl CrabeDBinternal {
         Ok(val)
     }
 
-    fn put(&mut self, key: Vec<u8>, value: &[u8]) -> Result<()> {
-        let idx_log = {
-            let log = Log::new(self.current_seq, &*key, value)?;
-            let (file_id, file_pos) = self.lsm.append_log(&log)?;
-            self.current_seq += 1;
+    fn get_with_metadata(&self, key: &[u8]) -> Result<Option<(Vec<u8>, ValueMetadata)>> {
+        let idx_log = match self.idx.get(key)? {
+            Some(idx_log) => idx_log,
+            None => return Ok(None),
+        };
+        let log = self.lsm.read_log(idx_log.file_id, idx_log.pos)?;
+        // The size of the record as stored.
+        let size = idx_log.size;
+        let timestamp = log.timestamp
+            .filter(|&timestamp| timestamp > 0)
+            .map(|timestamp| UNIX_EPOCH + Duration::from_millis(timestamp));
+        let seq = log.seq;
+        let log = self.lsm.resolve(log)?;
+
+        Ok(live_value(log, idx_log.file_id).map(|value| {
+            (value, ValueMetadata {
+                seq,
+                timestamp,
+                file_id: idx_log.file_id,
+                size,
+            })
+        }))
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if let Some(value) = cached_value(&self.cache, key) {
+            return Ok(Some(value));
+        }
+
+        match self.idx.get(key)? {
+            Some(idx_log) => {
+                let value = self.lsm.read_value(idx_log.file_id, idx_log.pos, idx_log.size)?;
+                let value = check_live(value, key, idx_log.file_id);
+                // Writers hold the write lock, the value can't be stale while we hold the
+                // read lock.
+                if let (Some(cache), Some(value)) = (&self.cache, &value) {
+                    cache.lock().unwrap().insert(key, value.clone());
+                }
+                Ok(value)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        match self.idx.get(key)? {
+            Some(idx_log) => {
+                let len = self.lsm.read_value_into(idx_log.file_id, idx_log.pos, idx_log.size, buf)?;
+                Ok(check_live(len, key, idx_log.file_id))
+            }
+            None => {
+                buf.clear();
+                Ok(None)
+            }
+        }
+    }
+
+    fn open_value(&self, key: &[u8]) -> Result<Option<ValueReader>> {
+        if let Some(value) = cached_value(&self.cache, key) {
+            return Ok(Some(ValueReader::Inline(value)));
+        }
 
-            MemIdxEntry {
-                pos: file_pos,
-                seq: log.seq,
-                size: log.size(),
-                file_id: file_id,
+        match self.idx.get(key)? {
+            Some(idx_log) => {
+                let value = self.lsm.open_value(idx_log.file_id, idx_log.pos, idx_log.size)?;
+                Ok(check_live(value, key, idx_log.file_id))
             }
-        };
+            None => Ok(None),
+        }
+    }
 
-        self.idx.set(key, idx_log);
+    fn audit(&self, op: AuditOp, key: &[u8], end: Option<&[u8]>, seq: u64, peer: Option<&str>) {
+        if let Some(ref audit) = self.audit {
+            audit.record(&AuditRecord {
+                timestamp: SystemTime::now(),
+                op,
+                key,
+                end,
+                seq,
+                peer,
+            });
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.lsm.is_closed() {
+            return Err(Error::Closed);
+        }
+        if self.lsm.is_read_only() || self.standby {
+            return Err(Error::ReadOnly);
+        }
+        if let Some(ref error) = self.background_error {
+            return Err(Error::WritesFenced(error.clone()));
+        }
         Ok(())
     }
 
-    fn delete(&mut self, key: &[u8]) -> Result<()> {
-        if self.idx.remove(key).is_some() {
-            let log = Log::deleted(self.current_seq, key);
+    // `timestamp` overrides the creation time of the record, e.g. the one of the primary.
+    fn append_value(
+        &mut self,
+        seq: u64,
+        key: &[u8],
+        value: &[u8],
+        timestamp: Option<u64>,
+    ) -> Result<MemIdxEntry> {
+        let mut log = if self.blob_threshold > 0 && value.len() >= self.blob_threshold {
+            let pointer = self.lsm.append_blob(value)?;
+            Log::blob(seq, key, &pointer)?
+        } else {
+            Log::new(seq, key, value)?
+        };
+        if timestamp.is_some() {
+            log.timestamp = timestamp;
+        }
+        let (file_id, file_pos, size) = self.lsm.append_log(&log)?;
+
+        Ok(MemIdxEntry {
+            pos: file_pos,
+            seq,
+            size,
+            file_id,
+        })
+    }
+
+    // The sequence number of the last write, 0 before the first one.
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.current_seq.load(Ordering::SeqCst) - 1
+    }
+
+    // Taken before the record is appended: the other partitions write concurrently. With a
+    // node id, the next one whose low bits are the id.
+    fn next_seq(&self) -> u64 {
+        match self.node_id {
+            Some(node_id) => {
+                let previous = self.current_seq
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |seq| {
+                        Some(node_seq(seq, node_id).saturating_add(1))
+                    })
+                    .unwrap();
+                node_seq(previous, node_id)
+            }
+            None => self.current_seq.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+
+    pub(crate) fn put(&mut self, key: Vec<u8>, value: &[u8], peer: Option<&str>) -> Result<u64> {
+        self.check_writable()?;
+        let seq = self.next_seq();
+        let idx_log = self.append_value(seq, &key, value, None)?;
+
+        self.audit(AuditOp::Set, &key, None, idx_log.seq, peer);
+        if self.cache.is_some() {
+            self.stale_keys.push(key.clone());
+        }
+        self.idx.set(key, idx_log)?;
+        Ok(seq)
+    }
+
+    // Like `put`, for a value already written to a blob file, see `ValueWriter`.
+    fn put_blob(&mut self, key: Vec<u8>, stream: BlobStream, peer: Option<&str>) -> Result<u64> {
+        self.check_writable()?;
+        let seq = self.next_seq();
+        let idx_log = self.append_blob_log(seq, &key, stream)?;
+
+        self.audit(AuditOp::Set, &key, None, seq, peer);
+        if self.cache.is_some() {
+            self.stale_keys.push(key.clone());
+        }
+        self.idx.set(key, idx_log)?;
+        Ok(seq)
+    }
+
+    // Like `merge`, for a value already written to a blob file.
+    fn merge_blob(&mut self, seq: u64, key: Vec<u8>, stream: BlobStream) -> Result<bool> {
+        self.follow_seq(seq)?;
+        if self.superseded(&key, seq)? {
+            return Ok(false);
+        }
+        let idx_log = self.append_blob_log(seq, &key, stream)?;
+        if self.cache.is_some() {
+            self.stale_keys.push(key.clone());
+        }
+        self.idx.set(key, idx_log)?;
+        Ok(true)
+    }
+
+    // The blob file is synced before the record pointing to it is appended, and kept once
+    // it is.
+    fn append_blob_log(&mut self, seq: u64, key: &[u8], mut stream: BlobStream) -> Result<MemIdxEntry> {
+        let pointer = stream.finish()?;
+        let (file_id, file_pos, size) = self.lsm.append_log(&Log::blob(seq, key, &pointer)?)?;
+        stream.keep();
+
+        Ok(MemIdxEntry {
+            pos: file_pos,
+            seq,
+            size,
+            file_id,
+        })
+    }
+
+    // Like `delete_range`, returns the sequence number of the last write when there was
+    // nothing to remove.
+    pub(crate) fn delete(&mut self, key: &[u8], peer: Option<&str>) -> Result<u64> {
+        self.check_writable()?;
+        if self.idx.remove(key)?.is_some() {
+            let log = Log::deleted(self.next_seq(), key);
+            self.idx.tombstone(key, None, log.seq);
+            self.lsm.append_log(&log)?;
+            self.audit(AuditOp::Remove, key, None, log.seq, peer);
+
+            if self.cache.is_some() {
+                self.stale_keys.push(key.to_vec());
+            }
+            return Ok(log.seq);
+        }
+        Ok(self.last_seq())
+    }
+
+    // Remove every key from `start` (included) to `end` (excluded, or no upper bound when
+    // empty) with a single range tombstone.
+    pub(crate) fn delete_range(&mut self, start: &[u8], end: &[u8], peer: Option<&str>) -> Result<u64> {
+        self.check_writable()?;
+        // Every version of the partition is older than the next sequence number.
+        let mut log = Log::deleted_range(self.current_seq.load(Ordering::SeqCst), start, end)?;
+        let keys = self.idx.delete_range(start, end, log.seq)?;
+        if !keys.is_empty() {
+            log.seq = self.next_seq();
+            self.idx.tombstone(start, Some(end), log.seq);
+            self.lsm.append_log(&log)?;
+            self.audit(AuditOp::RemoveRange, start, Some(end), log.seq, peer);
+
+            if self.cache.is_some() {
+                self.stale_keys.extend(keys);
+            }
+            return Ok(log.seq);
+        }
+        Ok(self.last_seq())
+    }
+
+    fn clear(&mut self) -> Result<u64> {
+        self.check_writable()?;
+        self.drop_records()
+    }
+
+    // Empty the index and drop every data file.
+    fn drop_records(&mut self) -> Result<u64> {
+        let keys = self.idx.delete_range(&[], &[], u64::MAX)?;
+        self.idx.clear_tombstones();
+        let count = keys.len() as u64;
+        if self.cache.is_some() {
+            self.stale_keys.extend(keys);
+        }
+        // Lock-free readers must not see the keys anymore before their files are removed.
+        self.publish();
+
+        let files = self.lsm.clear()?;
+        self.idx.compaction_analysis.remove_files(&files);
+        Ok(count)
+    }
+
+    // Write a record of the primary with its own sequence number.
+    fn apply(&mut self, log: Log) -> Result<()> {
+        if !self.standby {
+            return Err(Error::NotStandby);
+        }
+        self.merge(log).map(|_| ())
+    }
+
+    // Write a record with its own sequence number, the next ones following it. A write to
+    // a key already holding a newer version, e.g. from the initial copy of a standby, or
+    // removed since, is skipped. The sequence numbers of the writes of different nodes
+    // differ, see `StorageOptions::node_id`, so the last one wins on every node.
+    fn merge(&mut self, log: Log) -> Result<bool> {
+        self.follow_seq(log.seq)?;
+        if !log.range && self.superseded(&log.key, log.seq)? {
+            return Ok(false);
+        }
+
+        if log.range {
+            let keys = self.idx.delete_range(&log.key, &log.value, log.seq)?;
+            self.idx.tombstone(&log.key, Some(&log.value), log.seq);
+            self.lsm.append_log(&log)?;
+            if self.cache.is_some() {
+                self.stale_keys.extend(keys);
+            }
+        } else if log.deleted {
+            self.idx.remove(&log.key)?;
+            self.idx.tombstone(&log.key, None, log.seq);
             self.lsm.append_log(&log)?;
-            self.current_seq += 1;
+            if self.cache.is_some() {
+                self.stale_keys.push(log.key.to_vec());
+            }
+        } else {
+            let idx_log = self.append_value(log.seq, &log.key, &log.value, log.timestamp)?;
+            if self.cache.is_some() {
+                self.stale_keys.push(log.key.to_vec());
+            }
+            self.idx.set(log.key.into_owned(), idx_log)?;
+        }
+        Ok(true)
+    }
+
+    // The next sequence numbers follow the one of a merged write.
+    fn follow_seq(&self, seq: u64) -> Result<()> {
+        let next_seq = seq.checked_add(1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("sequence number {} out of range", seq))
+        })?;
+        self.current_seq.fetch_max(next_seq, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Whether a merged write of `seq` is older than the version of `key`, or its removal.
+    fn superseded(&self, key: &[u8], seq: u64) -> Result<bool> {
+        let newer = self.idx.get(key)?.map(|entry| entry.seq).max(self.idx.tombstone_seq(key));
+        Ok(newer.is_some_and(|newer| newer >= seq))
+    }
+
+    // Point the index to the new location of a compacted record. Range tombstones aren't
+    // indexed, the keys they hide are already gone.
+    fn relocate(&mut self, ch: CompactionHint, file_id: u32) -> Result<()> {
+        if ch.range_end.is_some() {
+            return Ok(());
+        }
+        if self.cache.is_some() {
+            self.stale_keys.push(ch.key.to_vec());
+        }
+        self.idx.update(ch, file_id)
+    }
+
+    // Index a record of an ingested file, unless its key holds a newer version.
+    fn ingest(&mut self, ch: CompactionHint, file_id: u32) -> Result<()> {
+        if self.cache.is_some() {
+            match ch.range_end {
+                Some(ref end) => {
+                    let keys = self.idx.delete_range(&ch.key, end, ch.seq)?;
+                    self.stale_keys.extend(keys);
+                }
+                None => self.stale_keys.push(ch.key.to_vec()),
+            }
+        }
+        self.idx.update(ch, file_id)
+    }
+
+    // Make the index updates of the previous writes visible to lock-free readers. The
+    // cache is invalidated afterwards so that a reader can't put back a value it read
+    // from the previous view.
+    pub(crate) fn publish(&mut self) {
+        self.idx.publish();
+
+        if let Some(ref cache) = self.cache {
+            let mut cache = cache.lock().unwrap();
+            for key in self.stale_keys.drain(..) {
+                cache.remove(&key);
+            }
+        }
+    }
+
+    // Remove the keys of a revoked lease which weren't written again since they were
+    // attached to it.
+    fn delete_leased(&mut self, keys: &[(Vec<u8>, u64)]) -> Result<u64> {
+        self.check_writable()?;
+        for (key, seq) in keys {
+            if self.idx.get(key)?.is_some_and(|entry| entry.seq == *seq) {
+                self.delete(key, None)?;
+            }
+        }
+        Ok(self.last_seq())
+    }
+
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.lsm.sync()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Result<Cow<'_, [u8]>>> + '_ {
+        self.idx.keys()
+    }
+}
+
+// The discrepancy between an entry of the index and the record it points to, if any.
+fn check_entry(reader: &LsmReader, key: Vec<u8>, entry: &MemIdxEntry) -> Option<Inconsistency> {
+    let (file_id, offset) = (entry.file_id, entry.pos);
+    let log = match reader.read_record(file_id, offset) {
+        Ok(log) => log,
+        Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+            return Some(Inconsistency::MissingFile { key, file_id });
+        }
+        Err(err) => {
+            return Some(Inconsistency::UnreadableRecord {
+                key,
+                file_id,
+                offset,
+                reason: err.to_string(),
+            });
+        }
+    };
+
+    let reason = if log.key != key {
+        format!("the record is the one of {:?}", String::from_utf8_lossy(&log.key))
+    } else if log.deleted {
+        "the record is a tombstone".to_string()
+    } else if log.seq != entry.seq {
+        format!("sequence {} in the index, {} in the record", entry.seq, log.seq)
+    } else if log.size() != entry.size {
+        format!("size {} in the index, {} in the record", entry.size, log.size())
+    } else {
+        return None;
+    };
+    Some(Inconsistency::RecordMismatch {
+        key,
+        file_id,
+        offset,
+        reason,
+    })
+}
+
+// Read path of the read-optimized mode: it never takes the lock of `CrabeDBinternal`.
+#[derive(Clone)]
+struct ReadView {
+    idx: Arc<SharedIdx>,
+    lsm: Arc<LsmReader>,
+    cache: Option<Arc<Mutex<ValueCache>>>,
+}
+
+impl ReadView {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        loop {
+            let view = self.idx.load();
+            let pointer = match view.get(key) {
+                Some(pointer) => pointer,
+                None => return Ok(None),
+            };
+
+            match self.lsm.read_log(pointer.file_id, pointer.pos).and_then(|log| self.lsm.resolve(log)) {
+                Ok(log) => return Ok(live_value(log, pointer.file_id)),
+                // A compaction removed the file after the view was loaded: the key
+                // lives elsewhere in the newer view.
+                Err(_) if !self.idx.is_current(&view) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if let Some(value) = cached_value(&self.cache, key) {
+            return Ok(Some(value));
+        }
+
+        loop {
+            let view = self.idx.load();
+            let pointer = match view.get(key) {
+                Some(pointer) => pointer,
+                None => return Ok(None),
+            };
+
+            match self.lsm.read_value(pointer.file_id, pointer.pos, pointer.size) {
+                Ok(value) => {
+                    let value = check_live(value, key, pointer.file_id);
+                    if let (Some(cache), Some(value)) = (&self.cache, &value) {
+                        // Writers publish before invalidating under the cache lock: an
+                        // outdated view means the value may already be stale.
+                        let mut cache = cache.lock().unwrap();
+                        if self.idx.is_current(&view) {
+                            cache.insert(key, value.clone());
+                        }
+                    }
+                    return Ok(value);
+                }
+                Err(_) if !self.idx.is_current(&view) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        loop {
+            let view = self.idx.load();
+            let pointer = match view.get(key) {
+                Some(pointer) => pointer,
+                None => {
+                    buf.clear();
+                    return Ok(None);
+                }
+            };
+
+            match self.lsm.read_value_into(pointer.file_id, pointer.pos, pointer.size, buf) {
+                Ok(len) => return Ok(check_live(len, key, pointer.file_id)),
+                Err(_) if !self.idx.is_current(&view) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn open_value(&self, key: &[u8]) -> Result<Option<ValueReader>> {
+        if let Some(value) = cached_value(&self.cache, key) {
+            return Ok(Some(ValueReader::Inline(value)));
+        }
+
+        loop {
+            let view = self.idx.load();
+            let pointer = match view.get(key) {
+                Some(pointer) => pointer,
+                None => return Ok(None),
+            };
+
+            match self.lsm.open_value(pointer.file_id, pointer.pos, pointer.size) {
+                Ok(value) => return Ok(check_live(value, key, pointer.file_id)),
+                Err(_) if !self.idx.is_current(&view) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+// Sleeps for `duration`, or until the store is dropped.
+fn sleep_unless_dropped(dropped: &AtomicBool, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !dropped.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep((deadline - now).min(DROP_CHECK_INTERVAL));
+    }
+}
+
+// Like `sleep_unless_dropped`, also returning as soon as `woken` is.
+fn sleep_unless_woken<W: Fn() -> bool>(dropped: &AtomicBool, woken: W, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !dropped.load(Ordering::SeqCst) && !woken() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep((deadline - now).min(DROP_CHECK_INTERVAL));
+    }
+}
+
+// The smallest key greater than every key starting with `prefix`, or an empty key when
+// there is none (the prefix is only made of 0xff bytes).
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            break;
+        }
+    }
+    end
+}
+
+// Outcome of `CrabeDB::compare_and_swap`: the sequence number of the write, or the current
+// value when it isn't the expected one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CasResult {
+    Swapped(u64),
+    Mismatch(Option<Vec<u8>>),
+}
+
+// The compacted files, the new files with their seal and the tombstones dropped.
+type CompactionOutput = (Vec<u32>, Vec<(u32, FileSeal)>, Vec<Tombstone>);
+
+#[derive(Clone)]
+pub struct CrabeDB {
+    path: PathBuf,
+    options: StorageOptions,
+    dropped: Arc<AtomicBool>,
+    internal: Arc<RwLock<CrabeDBinternal>>,
+    read_view: Option<ReadView>,
+    writer: Option<GroupCommitWriter>,
+    cache: Option<Arc<Mutex<ValueCache>>>,
+    compaction: Arc<Mutex<()>>,
+    compaction_paused: Arc<AtomicBool>,
+    compaction_status: Arc<Mutex<CompactionStatus>>,
+    compaction_limiter: Option<Arc<RateLimiter>>,
+    // Set by the stalled writes, to run the compaction without waiting for its next check.
+    compaction_wake_up: Arc<AtomicBool>,
+    // Notified whenever a standby applied records of its primary.
+    applied: Arc<(Mutex<()>, Condvar)>,
+    leases: Arc<Mutex<Leases>>,
+    // The number of keys attached to a lease or with a time to live, for the writes to
+    // only detach their key when there are some.
+    attached_keys: Arc<AtomicUsize>,
+    // The background threads, joined by `close`.
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    // The other partitions of the store, see `StorageOptions::partitions`, the store
+    // itself holding the first one.
+    partitions: Arc<Vec<CrabeDB>>,
+}
+
+impl CrabeDB {
+    pub fn load(path: &str, options: StorageOptions) -> Result<CrabeDB> {
+        let count = options.partitions.max(1);
+        if count > 1 && (options.standby || options.tiering.is_some() || options.archive_dir.is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a partitioned store can't be a standby, tiered or archived",
+            ).into());
+        }
+        if !options.in_memory {
+            partition::check_count(Path::new(path), count, options.create && !options.read_only)?;
+        }
+
+        let current_seq = Arc::new(AtomicU64::new(0));
+        let mut partitions = Vec::with_capacity(count - 1);
+        for index in 1..count {
+            let partition_path = partition::partition_path(Path::new(path), index);
+            let partition_path = partition_path.to_string_lossy();
+            partitions.push(CrabeDB::open(&partition_path, options.clone(), current_seq.clone(), Vec::new())?);
+        }
+        let crabe_db = CrabeDB::open(path, options, current_seq, partitions)?;
+
+        // A single thread schedules the compactions of every partition.
+        if crabe_db.options.compaction && !crabe_db.options.read_only {
+            let scheduler = crabe_db.spawn_compaction_scheduler();
+            crabe_db.threads.lock().unwrap().push(scheduler);
+        }
+        Ok(crabe_db)
+    }
+
+    fn spawn_compaction_scheduler(&self) -> JoinHandle<()> {
+        let crabe_db = self.clone();
+        thread::spawn(move || {
+            let duration = Duration::from_secs(crabe_db.options.compaction_check_frequency);
+            loop {
+                if crabe_db.dropped.load(Ordering::SeqCst) {
+                    info!("CrabeDB has been dropped, background compaction thread is exiting");
+                    break;
+                }
+
+                info!("Compaction thread wake up");
+                let current_hour = time::now().tm_hour as usize;
+                let (window_start, window_end) = crabe_db.options.compaction_window;
+                let in_window = if window_start <= window_end {
+                    current_hour >= window_start && current_hour <= window_end
+                } else {
+                    current_hour >= window_start || current_hour <= window_end
+                };
+
+                if crabe_db.is_compaction_paused() {
+                    info!("Compaction is paused");
+                } else {
+                    crabe_db.schedule_compactions(in_window);
+                }
+
+                let stalled = || crabe_db.all_partitions().any(|db| db.compaction_wake_up.load(Ordering::SeqCst));
+                sleep_unless_woken(&crabe_db.dropped, stalled, duration);
+            }
+        })
+    }
+
+    // Compact the partitions, at most `StorageOptions::compaction_concurrency` at a time:
+    // those whose writes are stalled first, as they can't wait for the compaction window,
+    // then the others by decreasing ratio of dead bytes.
+    fn schedule_compactions(&self, in_window: bool) {
+        let mut queue: Vec<(bool, f64, &CrabeDB)> = self.all_partitions()
+            .map(|db| {
+                let stalled = db.compaction_wake_up.swap(false, Ordering::SeqCst);
+                let dead_ratio = db.internal.read().unwrap().idx.compaction_analysis.dead_ratio();
+                (stalled, dead_ratio, db)
+            })
+            .filter(|&(stalled, _, _)| stalled || in_window)
+            .collect();
+        if queue.is_empty() {
+            info!("Compaction outside defined window {:?}", self.options.compaction_window);
+            return;
+        }
+        queue.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+
+        let workers = self.options.compaction_concurrency.clamp(1, queue.len());
+        let queue = Mutex::new(queue.into_iter());
+        let next = || queue.lock().unwrap().next();
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    while let Some((_, dead_ratio, db)) = next() {
+                        debug!("Compacting {:?}, {:.2} of its data is dead", db.path, dead_ratio);
+                        if let Err(err) = db.compact_partition() {
+                            warn!("Error during compaction: {}", err);
+                            // Only the I/O errors, e.g. a full disk, are worth fencing the
+                            // writes, not a corrupt record or a paused compaction.
+                            if let Error::Io(_) = err {
+                                db.report_background_error("compaction", err);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    fn open(
+        path: &str,
+        options: StorageOptions,
+        current_seq: Arc<AtomicU64>,
+        partitions: Vec<CrabeDB>,
+    ) -> Result<CrabeDB> {
+        info!("loading key/value store: {:?}", &path);
+        if options.index_memory_budget > 0 && options.read_optimized {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the index memory budget can't be used in the read-optimized mode",
+            ).into());
+        }
+        if options.in_memory
+            && (options.read_only
+                || options.standby
+                || options.tiering.is_some()
+                || options.archive_dir.is_some()
+                || options.index_memory_budget > 0
+                || options.storage.is_some())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "an in-memory store can't be read-only, a standby, tiered, archived, spill its index or use another storage",
+            ).into());
+        }
+        let lsm = Lsm::load(path, &options)?;
+
+        let mut idx = MemIdx::with_kind(options.index_kind);
+        if options.node_id.is_some() {
+            idx.keep_tombstones();
+        }
+        if options.index_memory_budget > 0 {
+            idx.spill_to(Path::new(path), options.index_memory_budget)?;
+        }
+        let mut seq = 0;
+        let mut damaged_hints = Vec::new();
+
+        for file_id in lsm.files() {
+            // A hint file missing records can't take the sequence number back.
+            if let Some(max_seq) = lsm.max_seq(file_id)? {
+                seq = seq.max(max_seq);
+            }
+
+            let mut update_idx_func = |ch: CompactionHint| {
+                if ch.seq > seq {
+                    seq = ch.seq;
+                }
+                idx.update(ch, file_id)
+            };
+
+            match (lsm.compaction_hints(file_id)?, options.recovery_mode) {
+                (Some(chs), RecoveryMode::Strict) => {
+                    for ch in chs {
+                        update_idx_func(ch?)?;
+                    }
+                    continue;
+                }
+                (Some(chs), RecoveryMode::SkipCorrupt) => {
+                    match chs.collect::<Result<Vec<_>>>() {
+                        Ok(chs) => {
+                            for ch in chs {
+                                update_idx_func(ch)?;
+                            }
+                            continue;
+                        }
+                        Err(err) => warn!(
+                            "Unreadable compaction file for data file {}, rebuilding it: {}",
+                            file_id,
+                            err
+                        ),
+                    }
+                }
+                (None, _) => {}
+            };
+
+            // The records are indexed right away, from the salvaged hints then the data
+            // file, while the compaction file is rebuilt in the background. Read-only
+            // openers can't rebuild it.
+            for ch in lsm.recover_compaction_hints(file_id)? {
+                update_idx_func(ch?)?;
+            }
+            if !lsm.is_read_only() {
+                damaged_hints.push(file_id);
+            }
+        }
+
+        idx.finish_load();
+        info!("loaded key/value store: {:?}", &path);
+        info!("Current sequence number: {:?}", seq);
+
+        let cache = if options.value_cache_size > 0 {
+            Some(Arc::new(Mutex::new(ValueCache::new(options.value_cache_size))))
+        } else {
+            None
+        };
+
+        if options.warm_files > 0 {
+            if let Err(err) = warm_caches(&lsm, &idx, &cache, options.warm_files) {
+                warn!("Couldn't warm the caches up: {}", err);
+            }
+        }
+
+        let read_view = if options.read_optimized {
+            Some(ReadView {
+                idx: idx.share(options.index_batch_size),
+                lsm: lsm.reader(),
+                cache: cache.clone(),
+            })
+        } else {
+            None
+        };
+
+        current_seq.fetch_max(seq + 1, Ordering::SeqCst);
+        let internal = Arc::new(RwLock::new(CrabeDBinternal {
+            current_seq,
+            node_id: options.node_id,
+            lsm,
+            idx,
+            cache: cache.clone(),
+            blob_threshold: options.blob_threshold,
+            audit: options.audit.clone(),
+            standby: options.standby,
+            background_error: None,
+            stale_keys: Vec::new(),
+        }));
+
+        let writer = if options.group_commit {
+            Some(GroupCommitWriter::spawn(internal.clone()))
+        } else {
+            None
+        };
+
+        let compaction_limiter = if options.compaction_rate_limit > 0 {
+            Some(Arc::new(RateLimiter::new(options.compaction_rate_limit)))
+        } else {
+            None
+        };
+
+        let mut leases = if options.in_memory {
+            Leases::in_memory()
+        } else {
+            Leases::load(Path::new(path), options.sync == SyncOptions::Always, options.read_only)?
+        };
+        if let Some(node_id) = options.node_id {
+            leases.node_id(node_id);
+        }
+
+        let crabe_db = CrabeDB {
+            path: PathBuf::from(path),
+            options,
+            dropped: Arc::new(AtomicBool::new(false)),
+            internal,
+            read_view,
+            writer,
+            cache,
+            compaction: Arc::new(Mutex::new(())),
+            compaction_paused: Arc::new(AtomicBool::new(false)),
+            compaction_status: Arc::new(Mutex::new(CompactionStatus::default())),
+            compaction_limiter,
+            compaction_wake_up: Arc::new(AtomicBool::new(false)),
+            applied: Arc::new((Mutex::new(()), Condvar::new())),
+            attached_keys: leases.attached_count(),
+            leases: Arc::new(Mutex::new(leases)),
+            threads: Arc::new(Mutex::new(Vec::new())),
+            partitions: Arc::new(partitions),
+        };
+        let mut threads = Vec::new();
+
+        if let SyncOptions::Frequency(millis) = crabe_db.options.sync {
+            let crabe_db = crabe_db.clone();
+
+            threads.push(thread::spawn(move || {
+                let duration = Duration::from_millis(millis as u64);
+                loop {
+                    if crabe_db.dropped.load(Ordering::SeqCst) {
+                        info!(
+                            "CrabeDB has been dropped, background file sync thread is exiting"
+                        );
+                        break;
+                    }
+
+                    debug!("Background file sync");
+                    let synced = crabe_db.internal.read().unwrap().lsm.sync();
+                    let synced = synced.and_then(|_| crabe_db.leases.lock().unwrap().sync());
+                    if let Err(err) = synced {
+                        crabe_db.report_background_error("sync", err);
+                    }
+                    sleep_unless_dropped(&crabe_db.dropped, duration);
+                }
+            }));
+        };
+
+        if !crabe_db.options.read_only {
+            let crabe_db = crabe_db.clone();
+
+            threads.push(thread::spawn(move || {
+                loop {
+                    if crabe_db.dropped.load(Ordering::SeqCst) {
+                        info!("CrabeDB has been dropped, background lease thread is exiting");
+                        break;
+                    }
+
+                    // A standby refuses writes, its leases expire once promoted.
+                    if !crabe_db.is_standby() {
+                        let expired = crabe_db.leases.lock().unwrap().expired();
+                        for id in expired {
+                            match crabe_db.revoke_lease(id) {
+                                Ok(_) => info!("Lease {} expired", id),
+                                Err(err) => warn!("Couldn't revoke expired lease {}: {}", id, err),
+                            }
+                        }
+                        match crabe_db.remove_expired_keys() {
+                            Ok(0) => {}
+                            Ok(count) => info!("{} keys expired", count),
+                            Err(err) => warn!("Couldn't remove the expired keys: {}", err),
+                        }
+                    }
+
+                    sleep_unless_dropped(&crabe_db.dropped, LEASE_CHECK_INTERVAL);
+                }
+            }));
+        }
+
+        if let Some(ref tiering) = crabe_db.options.tiering {
+            if !crabe_db.options.read_only {
+                let check_interval = tiering.check_interval;
+                let crabe_db = crabe_db.clone();
+
+                threads.push(thread::spawn(move || {
+                    loop {
+                        if crabe_db.dropped.load(Ordering::SeqCst) {
+                            info!("CrabeDB has been dropped, background tiering thread is exiting");
+                            break;
+                        }
+
+                        if !crabe_db.is_compaction_paused() {
+                            if let Err(err) = crabe_db.offload_cold_files() {
+                                warn!("Error while offloading cold data files: {}", err);
+                            }
+                        }
+
+                        sleep_unless_dropped(&crabe_db.dropped, check_interval);
+                    }
+                }));
+            }
+        }
+
+        // The thread holds no clone of the store, whose drop would stop the others. The
+        // compactions wait for the hint files, which they read.
+        if !damaged_hints.is_empty() {
+            let internal = crabe_db.internal.clone();
+            let compaction = crabe_db.compaction.clone();
+            let dropped = crabe_db.dropped.clone();
+
+            threads.push(thread::spawn(move || {
+                let _lock = compaction.lock().unwrap();
+                for file_id in damaged_hints {
+                    if dropped.load(Ordering::SeqCst) {
+                        info!("CrabeDB has been dropped, background hint rebuild thread is exiting");
+                        break;
+                    }
+
+                    let hints = internal.read().unwrap().lsm.recover_compaction_hints(file_id);
+                    // A file removed since, e.g. by `clear`, has nothing left to rebuild.
+                    if let Err(err) = hints.and_then(|hints| hints.rebuild()) {
+                        warn!("Couldn't rebuild the compaction file of data file {}: {}", file_id, err);
+                    }
+                }
+            }));
+        }
+
+        *crabe_db.threads.lock().unwrap() = threads;
+        Ok(crabe_db)
+    }
+
+    // Sync the active data file and its hint file, e.g. before a snapshot of the
+    // directory, whatever `StorageOptions::sync`.
+    pub fn flush(&self) -> Result<()> {
+        for partition in self.partitions.iter() {
+            partition.flush()?;
+        }
+        self.internal.read().unwrap().lsm.flush()?;
+        self.leases.lock().unwrap().sync()
+    }
+
+    // Shut the store down without relying on the order in which its clones are dropped:
+    // the background threads are joined, the queued writes applied, then the files are
+    // flushed, the active data file sealed and the lock released, so the store can be
+    // opened again as soon as it returns. The other clones can't write afterwards.
+    pub fn close(self) -> Result<()> {
+        self.dropped.store(true, Ordering::SeqCst);
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for thread in threads {
+            if thread.join().is_err() {
+                warn!("A background thread panicked");
+            }
+        }
+        // Once the compaction scheduler is stopped.
+        for partition in self.partitions.iter() {
+            partition.clone().close()?;
+        }
+        if let Some(ref writer) = self.writer {
+            writer.shutdown();
+        }
+
+        let _compaction = self.compaction.lock().unwrap();
+        let mut internal = self.internal.write().unwrap();
+        internal.lsm.flush()?;
+        internal.lsm.close()?;
+        self.leases.lock().unwrap().sync()?;
+        info!("closed key/value store: {:?}", self.path);
+        Ok(())
+    }
+
+    // Remove the store at `path`, which must not be opened by anyone, even read-only:
+    // its data, hint and lock files, then its directory once nothing else is left in it.
+    pub fn destroy(path: &str) -> Result<()> {
+        let path = Path::new(path);
+        // The other partitions are sub-directories of the store.
+        if let Some(count) = partition::load_count(path)? {
+            for index in 1..count {
+                let partition_path = partition::partition_path(path, index);
+                if partition_path.is_dir() {
+                    lsm::destroy(&partition_path)?;
+                }
+            }
+        }
+        lsm::destroy(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = {
+            let internal = self.internal.read().unwrap();
+            Stats {
+                chunk_queue: internal.lsm.chunk_queue_stats(),
+                keys: internal.idx.len(),
+                size: internal.idx.compaction_analysis.live_bytes(),
+                index_memory: internal.idx.memory_usage(),
+            }
+        };
+        for partition in self.partitions.iter() {
+            stats.add(&partition.stats());
+        }
+        stats
+    }
+
+    // Statistics of every data file, ordered by partition then file id, to decide when to
+    // compact.
+    pub fn file_stats(&self) -> Result<Vec<FileStats>> {
+        let mut file_stats = Vec::new();
+        {
+            let internal = self.internal.read().unwrap();
+            let mut files = internal.lsm.files();
+            files.extend(internal.lsm.active_file_id);
+
+            for file_id in files {
+                let (entries, dead_entries, dead_bytes) =
+                    internal.idx.compaction_analysis.file_entries(file_id);
+                file_stats.push(FileStats {
+                    file_id,
+                    partition: 0,
+                    active: internal.lsm.active_file_id == Some(file_id),
+                    remote: internal.lsm.is_remote(file_id),
+                    entries,
+                    dead_entries,
+                    dead_bytes,
+                    size: internal.lsm.file_size(file_id)?,
+                    fragmentation: if entries > 0 { dead_entries as f64 / entries as f64 } else { 0.0 },
+                });
+            }
+        }
+        for (index, partition) in self.partitions.iter().enumerate() {
+            for mut file in partition.file_stats()? {
+                file.partition = index + 1;
+                file_stats.push(file);
+            }
+        }
+        Ok(file_stats)
+    }
+
+    // Cross-check every entry of the index with the record it points to: its file must
+    // exist, and the record must be readable, with a valid checksum, the same key, sequence
+    // number and size. It runs against a live store: the entries are read in the order of
+    // the files without holding the lock, throttled like the compactions when they are, and
+    // an entry which changed since the check started is not reported.
+    pub fn check_consistency(&self) -> Result<ConsistencyReport> {
+        let (mut entries, reader) = {
+            let internal = self.internal.read().unwrap();
+            let entries: Vec<(Vec<u8>, MemIdxEntry)> = internal.idx
+                .entries()
+                .map(|entry| entry.map(|(key, entry)| (key.into_owned(), entry)))
+                .collect::<Result<_>>()?;
+            (entries, internal.lsm.reader())
+        };
+        entries.sort_unstable_by_key(|&(_, entry)| (entry.file_id, entry.pos));
+
+        let mut report = ConsistencyReport::default();
+        for (key, entry) in entries {
+            if let Some(ref limiter) = self.compaction_limiter {
+                limiter.request(entry.size);
+            }
+            report.entries += 1;
+
+            if let Some(inconsistency) = check_entry(&reader, key, &entry) {
+                let current = self.internal.read().unwrap().idx.get(inconsistency.key())?;
+                if current.is_some_and(|current| current.file_id == entry.file_id && current.pos == entry.pos) {
+                    report.inconsistencies.push(inconsistency);
+                }
+            }
+        }
+        for partition in self.partitions.iter() {
+            let partition_report = partition.check_consistency()?;
+            report.entries += partition_report.entries;
+            report.inconsistencies.extend(partition_report.inconsistencies);
+        }
+        Ok(report)
+    }
+
+    // The number of live keys, which may already be outdated by concurrent writes.
+    pub fn approximate_key_count(&self) -> usize {
+        let count = self.internal.read().unwrap().idx.len();
+        count + self.partitions.iter().map(CrabeDB::approximate_key_count).sum::<usize>()
+    }
+
+    // Size on disk of the live records of the keys from `start` (included) to `end`
+    // (excluded, or no upper bound when empty). Values stored in blob files only count for
+    // the size of their pointer.
+    pub fn approximate_size<S: AsRef<[u8]>, E: AsRef<[u8]>>(&self, start: S, end: E) -> Result<u64> {
+        let (start, end) = (start.as_ref(), end.as_ref());
+        let mut size = {
+            let internal = self.internal.read().unwrap();
+            // The whole store is summed up per file rather than per key.
+            if start.is_empty() && end.is_empty() {
+                internal.idx.compaction_analysis.live_bytes()
+            } else {
+                internal.idx.range_size(start, end)?
+            }
+        };
+        for partition in self.partitions.iter() {
+            size += partition.approximate_size(start, end)?;
+        }
+        Ok(size)
+    }
+
+    fn partition_index(&self, key: &[u8]) -> usize {
+        if self.partitions.is_empty() {
+            return 0;
+        }
+        partition::partition_of(key, self.partitions.len() + 1)
+    }
+
+    // The partition holding `key`, `None` when it's the store itself.
+    fn partition(&self, key: &[u8]) -> Option<&CrabeDB> {
+        match self.partition_index(key) {
+            0 => None,
+            index => Some(&self.partitions[index - 1]),
+        }
+    }
+
+    // Every partition, the store itself first.
+    fn all_partitions(&self) -> impl Iterator<Item = &CrabeDB> {
+        std::iter::once(self).chain(self.partitions.iter())
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        if let Some(partition) = self.partition(key.as_ref()) {
+            return partition.get(key);
+        }
+        // The cache holds shared buffers, which only `get_bytes` reads into.
+        if self.cache.is_some() {
+            return Ok(self.get_bytes(key)?.map(|value| value.to_vec()));
+        }
+
+        match self.read_view {
+            Some(ref read_view) => read_view.get(key.as_ref()),
+            None => self.internal.read().unwrap().get(key.as_ref()),
+        }
+    }
+
+    // Like `get`, but the record is read into a single reference-counted buffer and the
+    // value is handed out as a slice of it, without any further copy.
+    pub fn get_bytes<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Bytes>> {
+        if let Some(partition) = self.partition(key.as_ref()) {
+            return partition.get_bytes(key);
+        }
+        match self.read_view {
+            Some(ref read_view) => read_view.get_bytes(key.as_ref()),
+            None => self.internal.read().unwrap().get_bytes(key.as_ref()),
+        }
+    }
+
+    // Like `get`, but a value stored in a blob file is read a chunk at a time as the
+    // chunks are asked for, e.g. to stream it, see `ValueReader`.
+    pub fn value_reader<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<ValueReader>> {
+        if let Some(partition) = self.partition(key.as_ref()) {
+            return partition.value_reader(key);
+        }
+        match self.read_view {
+            Some(ref read_view) => read_view.open_value(key.as_ref()),
+            None => self.internal.read().unwrap().open_value(key.as_ref()),
+        }
+    }
+
+    // Read the value into `buf`, reusing its allocation, and return its length. `buf` is
+    // left empty when the key doesn't exist.
+    pub fn get_into<K: AsRef<[u8]>>(&self, key: K, buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        if let Some(partition) = self.partition(key.as_ref()) {
+            return partition.get_into(key, buf);
+        }
+        if self.cache.is_some() {
+            buf.clear();
+            return Ok(self.get_bytes(key)?.map(|value| {
+                buf.extend_from_slice(&value);
+                value.len()
+            }));
+        }
+
+        match self.read_view {
+            Some(ref read_view) => read_view.get_into(key.as_ref(), buf),
+            None => self.internal.read().unwrap().get_into(key.as_ref(), buf),
+        }
+    }
+
+    // Like `get`, along with the sequence number, creation time and location of the value.
+    pub fn get_with_metadata<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<(Vec<u8>, ValueMetadata)>> {
+        if let Some(partition) = self.partition(key.as_ref()) {
+            return partition.get_with_metadata(key);
+        }
+        self.internal.read().unwrap().get_with_metadata(key.as_ref())
+    }
+
+    // Like `get`, once the write of sequence number `seq` is visible: a standby waits up to
+    // `timeout` for it to be replicated. Clients reading from several servers pass the
+    // sequence number of their last write to read their own writes.
+    pub fn get_at_least<K: AsRef<[u8]>>(&self, key: K, seq: u64, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        let (ref lock, ref applied) = *self.applied;
+        let mut guard = lock.lock().unwrap();
+        loop {
+            let last_seq = self.last_seq();
+            if last_seq >= seq {
+                break;
+            }
+            deadline::check()?;
+            let deadline = deadline::current().map_or(deadline, |request| request.min(deadline));
+            let now = Instant::now();
+            if !self.is_standby() || now >= deadline {
+                return Err(Error::SequenceNotApplied {
+                    seq,
+                    applied: last_seq,
+                });
+            }
+            guard = applied.wait_timeout(guard, deadline - now).unwrap().0;
+        }
+        drop(guard);
+
+        self.get(key)
+    }
+
+    // The sequence number of the last write, or of the last record applied on a standby.
+    pub fn last_seq(&self) -> u64 {
+        self.internal.read().unwrap().last_seq()
+    }
+
+    // Returns the sequence number of the write, which `get_at_least` waits for on a standby.
+    pub fn set<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<u64> {
+        self.set_as(None, key, value)
+    }
+
+    // Like `set`, `peer` being the identity of the client recorded in the audit log.
+    pub fn set_as<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, peer: Option<&str>, key: K, value: V) -> Result<u64> {
+        let key = key.into();
+        self.partition(&key).unwrap_or(self).throttle_writes()?;
+        let seq = self.write_value(peer, key.clone(), value)?;
+        self.detach(&key, seq);
+        Ok(seq)
+    }
+
+    fn write_value<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, peer: Option<&str>, key: K, value: V) -> Result<u64> {
+        let key = key.into();
+        if let Some(partition) = self.partition(&key) {
+            return partition.write_value(peer, key, value);
+        }
+        match self.writer {
+            Some(ref writer) => writer.submit(
+                WriteOp::Set(key, value.as_ref().to_vec()),
+                peer,
+                self.options.sync == SyncOptions::Always,
+            ).wait(),
+            None => {
+                let mut internal = self.internal.write().unwrap();
+                let seq = internal.put(key, value.as_ref(), peer)?;
+                internal.publish();
+                Ok(seq)
+            }
+        }
+    }
+
+    // Start a write of `key` whose value arrives a chunk at a time, e.g. from a stream,
+    // see `ValueWriter`.
+    pub fn value_writer<K: Into<Vec<u8>>>(&self, key: K) -> Result<ValueWriter> {
+        let key = key.into();
+        let db = self.partition(&key).unwrap_or(self);
+        db.internal.read().unwrap().check_writable()?;
+        db.throttle_writes()?;
+        let blob_threshold = match db.options.blob_threshold {
+            0 => STREAMED_BLOB_THRESHOLD,
+            blob_threshold => blob_threshold,
+        };
+        Ok(ValueWriter {
+            key,
+            buffer: Vec::new(),
+            blob_threshold,
+            stream: None,
+        })
+    }
+
+    // Returns the sequence number of the removal, or of the last write when the key didn't
+    // exist.
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<u64> {
+        self.remove_as(None, key)
+    }
+
+    pub fn remove_as<K: AsRef<[u8]>>(&self, peer: Option<&str>, key: K) -> Result<u64> {
+        let seq = match self.partition(key.as_ref()) {
+            Some(partition) => partition.remove_key(peer, key.as_ref())?,
+            None => self.remove_key(peer, key.as_ref())?,
+        };
+        self.detach(key.as_ref(), seq);
+        Ok(seq)
+    }
+
+    fn remove_key(&self, peer: Option<&str>, key: &[u8]) -> Result<u64> {
+        match self.writer {
+            Some(ref writer) => writer.submit(
+                WriteOp::Remove(key.to_vec()),
+                peer,
+                self.options.sync == SyncOptions::Always,
+            ).wait(),
+            None => {
+                let mut internal = self.internal.write().unwrap();
+                let seq = internal.delete(key, peer)?;
+                internal.publish();
+                Ok(seq)
+            }
+        }
+    }
+
+    // Remove every key from `start` (included) to `end` (excluded). An empty `end` removes
+    // every key from `start` on. A partitioned store writes a range tombstone to each of its
+    // partitions, and returns the sequence number of the last one.
+    pub fn delete_range<S: AsRef<[u8]>, E: AsRef<[u8]>>(&self, start: S, end: E) -> Result<u64> {
+        let mut seq = 0;
+        for partition in self.partitions.iter() {
+            seq = seq.max(partition.delete_range(start.as_ref(), end.as_ref())?);
+        }
+        let partition_seq = match self.writer {
+            Some(ref writer) => writer.submit(
+                WriteOp::RemoveRange(start.as_ref().to_vec(), end.as_ref().to_vec()),
+                None,
+                self.options.sync == SyncOptions::Always,
+            ).wait(),
+            None => {
+                let mut internal = self.internal.write().unwrap();
+                let seq = internal.delete_range(start.as_ref(), end.as_ref(), None)?;
+                internal.publish();
+                Ok(seq)
+            }
+        }?;
+        let seq = seq.max(partition_seq);
+        self.detach_range(start.as_ref(), end.as_ref(), seq);
+        Ok(seq)
+    }
+
+    // Remove every key starting with `prefix`.
+    pub fn delete_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Result<u64> {
+        let prefix = prefix.as_ref();
+        if prefix.is_empty() {
+            return self.delete_range([], []);
+        }
+        self.delete_range(prefix, prefix_end(prefix))
+    }
+
+    // Remove every key at once, without writing any tombstone: the index is emptied and
+    // every data file dropped, the next write starting a new one. The files are deleted
+    // once no read-only opener is attached anymore. Unlike `delete_range`, it's neither
+    // replicated nor seen by the tails. Returns the number of keys removed.
+    pub fn clear(&self) -> Result<u64> {
+        // A compaction in progress would bring the records of the dropped files back.
+        let mut count = 0;
+        for partition in self.partitions.iter() {
+            count += partition.clear()?;
+        }
+        let _compaction = self.compaction.lock().unwrap();
+        let mut internal = self.internal.write().unwrap();
+        count += internal.clear()?;
+        info!("Cleared {} keys", count);
+        Ok(count)
+    }
+
+    // Grant a lease which expires unless it's kept alive within `ttl`. The keys set with
+    // the lease are removed once it expires or is revoked.
+    pub fn grant_lease(&self, ttl: Duration) -> Result<u64> {
+        self.internal.read().unwrap().check_writable()?;
+        self.leases.lock().unwrap().grant(ttl)
+    }
+
+    // Returns the time to live of the lease, its deadline is pushed back by as much.
+    pub fn keep_alive_lease(&self, id: u64) -> Result<Duration> {
+        self.leases.lock().unwrap().keep_alive(id)
+    }
+
+    // Keep a copy of a lease granted by another node, e.g. a peer of a cluster, which lasts
+    // as long as its keep-alives are forwarded. A lease already known is left as is.
+    pub fn mirror_lease(&self, id: u64, ttl: Duration) -> Result<()> {
+        self.leases.lock().unwrap().mirror(id, ttl)
+    }
+
+    // Remove the lease and the keys attached to it, unless they were written again since.
+    // Returns the sequence number of the last removal.
+    pub fn revoke_lease(&self, id: u64) -> Result<u64> {
+        let mut leases = self.leases.lock().unwrap();
+        let keys = leases.keys(id)?;
+        let seq = self.delete_attached(&keys)?;
+        leases.revoke(id)?;
+        Ok(seq)
+    }
+
+    // Remove the keys whose time to live ran out, unless they were written again since.
+    // Returns how many were removed.
+    fn remove_expired_keys(&self) -> Result<usize> {
+        let mut leases = self.leases.lock().unwrap();
+        let keys = leases.expired_keys();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        self.delete_attached(&keys)?;
+        leases.forget(&keys)?;
+        Ok(keys.len())
+    }
+
+    // Remove the keys of a lease or with a time to live, as written with their sequence
+    // number. Returns the sequence number of the last removal.
+    fn delete_attached(&self, keys: &[(Vec<u8>, u64)]) -> Result<u64> {
+        let mut seq = {
+            let internal = self.internal.read().unwrap();
+            internal.check_writable()?;
+            internal.last_seq()
+        };
+        for (index, db) in self.all_partitions().enumerate() {
+            let keys: Vec<(Vec<u8>, u64)> = keys
+                .iter()
+                .filter(|(key, _)| self.partition_index(key) == index)
+                .cloned()
+                .collect();
+            if keys.is_empty() {
+                continue;
+            }
+            let mut internal = db.internal.write().unwrap();
+            seq = seq.max(internal.delete_leased(&keys)?);
+            internal.publish();
+            // The removals must be durable before the lease is forgotten.
+            internal.sync()?;
+        }
+        Ok(seq)
+    }
+
+    // Detach the key from its lease or time to live, once written with `seq` without one.
+    // The write is done, a failure only leaves the key attached for its old sequence
+    // number, which the lease ignores.
+    fn detach(&self, key: &[u8], seq: u64) {
+        if self.attached_keys.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        if let Err(err) = self.leases.lock().unwrap().detach(key, seq) {
+            warn!("Couldn't detach a key from its lease: {}", err);
+        }
+    }
+
+    // Like `detach`, for the keys of a range removal.
+    fn detach_range(&self, start: &[u8], end: &[u8], seq: u64) {
+        if self.attached_keys.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        if let Err(err) = self.leases.lock().unwrap().detach_range(start, end, seq) {
+            warn!("Couldn't detach the removed keys from their leases: {}", err);
+        }
+    }
+
+    // The lease and the keys still attached to it, or `None` once it expired.
+    pub fn lease_info(&self, id: u64) -> Result<Option<LeaseInfo>> {
+        let leases = self.leases.lock().unwrap();
+        let (mut info, keys) = match (leases.info(id), leases.keys(id)) {
+            (Some(info), Ok(keys)) => (info, keys),
+            _ => return Ok(None),
+        };
+        info.keys = Vec::with_capacity(keys.len());
+        for (key, seq) in keys {
+            let internal = self.partition(&key).unwrap_or(self).internal.read().unwrap();
+            if internal.idx.get(&key)?.is_some_and(|entry| entry.seq == seq) {
+                info.keys.push(key);
+            }
+        }
+        info.keys.sort();
+        Ok(Some(info))
+    }
+
+    // The lease the current value of the key is removed with, if any, e.g. to know its
+    // remaining time to live.
+    pub fn key_lease<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<LeaseInfo>> {
+        let key = key.as_ref();
+        let leases = self.leases.lock().unwrap();
+        let entry = self.partition(key).unwrap_or(self).internal.read().unwrap().idx.get(key)?;
+        Ok(entry.and_then(|entry| leases.holding(key, entry.seq)))
+    }
+
+    // Like `set`, the key being removed after `ttl` unless it's written again. Its deadline
+    // is saved with the leases, the `LeaseInfo` of `key_lease` having the id 0.
+    pub fn set_with_ttl_as<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        peer: Option<&str>,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<u64> {
+        let key = key.into();
+        self.partition(&key).unwrap_or(self).throttle_writes()?;
+        let seq = self.write_value(peer, key.clone(), value)?;
+        self.leases.lock().unwrap().expire_after(key, seq, ttl)?;
+        Ok(seq)
+    }
+
+    // Like `set`, the key being removed along with the lease unless it's written again.
+    pub fn set_with_lease<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V, lease: u64) -> Result<u64> {
+        self.set_with_lease_as(None, key, value, lease)
+    }
+
+    pub fn set_with_lease_as<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        peer: Option<&str>,
+        key: K,
+        value: V,
+        lease: u64,
+    ) -> Result<u64> {
+        let key = key.into();
+        self.partition(&key).unwrap_or(self).throttle_writes()?;
+        // Held during the write, so the lease can't be revoked before the key is attached.
+        let mut leases = self.leases.lock().unwrap();
+        if !leases.contains(lease) {
+            return Err(Error::LeaseNotFound(lease));
+        }
+        let seq = self.write_value(peer, key.clone(), value)?;
+        leases.attach(lease, key, seq)?;
+        Ok(seq)
+    }
+
+    // Write `value` (or remove the key when `None`) if the current value is `expected`, or
+    // if the key doesn't exist when `expected` is `None`. The new value is attached to
+    // `lease`, when given.
+    pub fn compare_and_swap<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        expected: Option<&[u8]>,
+        value: Option<&[u8]>,
+        lease: Option<u64>,
+    ) -> Result<CasResult> {
+        self.compare_and_swap_as(None, key, expected, value, lease)
+    }
+
+    pub fn compare_and_swap_as<K: Into<Vec<u8>>>(
+        &self,
+        peer: Option<&str>,
+        key: K,
+        expected: Option<&[u8]>,
+        value: Option<&[u8]>,
+        lease: Option<u64>,
+    ) -> Result<CasResult> {
+        let key = key.into();
+        let db = self.partition(&key).unwrap_or(self);
+        if value.is_some() {
+            db.throttle_writes()?;
+        }
+        let mut leases = self.leases.lock().unwrap();
+        if let Some(lease) = lease {
+            if !leases.contains(lease) {
+                return Err(Error::LeaseNotFound(lease));
+            }
+        }
+
+        // The write lock is held from the read to the write, bypassing the group commit.
+        let seq = {
+            let mut internal = db.internal.write().unwrap();
+            let current = internal.get(&key)?;
+            if current.as_deref() != expected {
+                return Ok(CasResult::Mismatch(current));
+            }
+            let seq = match value {
+                Some(value) => internal.put(key.clone(), value, peer)?,
+                None => internal.delete(&key, peer)?,
+            };
+            internal.publish();
+            if db.writer.is_some() && self.options.sync == SyncOptions::Always {
+                internal.sync()?;
+            }
+            seq
+        };
+
+        match (lease, value) {
+            (Some(lease), Some(_)) => leases.attach(lease, key, seq)?,
+            _ => leases.detach(&key, seq)?,
+        }
+        Ok(CasResult::Swapped(seq))
+    }
+
+    // Apply the writes of the batch under a single acquisition of the store lock, bypassing
+    // the group commit. Returns the result of each write, in the order of the batch.
+    pub fn write(&self, batch: WriteBatch) -> Result<Vec<Result<u64>>> {
+        self.write_as(None, batch)
+    }
+
+    pub fn write_as(&self, peer: Option<&str>, batch: WriteBatch) -> Result<Vec<Result<u64>>> {
+        // The keys to detach from their leases once written, if any are attached.
+        let written: Vec<(Vec<u8>, Option<Vec<u8>>)> = if self.attached_keys.load(Ordering::SeqCst) > 0 {
+            batch.ops.iter().map(|op| match op {
+                WriteOp::Set(key, _) | WriteOp::Remove(key) => (key.clone(), None),
+                WriteOp::RemoveRange(start, end) => (start.clone(), Some(end.clone())),
+            }).collect()
+        } else {
+            Vec::new()
+        };
+
+        let results = if self.partitions.is_empty() {
+            self.write_partition(peer, batch)?
+        } else {
+            self.write_partitioned(peer, batch)?
+        };
+        for ((key, end), result) in written.iter().zip(&results) {
+            match (end, result) {
+                (None, Ok(seq)) => self.detach(key, *seq),
+                (Some(end), Ok(seq)) => self.detach_range(key, end, *seq),
+                (_, Err(_)) => {}
+            }
+        }
+        Ok(results)
+    }
+
+    // The writes of a partitioned store are split by partition, each part being applied
+    // under the lock of its partition, and a range removal goes to every partition.
+    fn write_partitioned(&self, peer: Option<&str>, batch: WriteBatch) -> Result<Vec<Result<u64>>> {
+        let count = self.partitions.len() + 1;
+        let mut parts: Vec<(WriteBatch, Vec<usize>)> = (0..count).map(|_| (WriteBatch::new(), Vec::new())).collect();
+        let len = batch.ops.len();
+        for (index, op) in batch.ops.into_iter().enumerate() {
+            match op {
+                WriteOp::RemoveRange(start, end) => {
+                    for (part, indexes) in parts.iter_mut() {
+                        part.ops.push(WriteOp::RemoveRange(start.clone(), end.clone()));
+                        indexes.push(index);
+                    }
+                }
+                WriteOp::Set(ref key, _) | WriteOp::Remove(ref key) => {
+                    let (part, indexes) = &mut parts[self.partition_index(key)];
+                    part.ops.push(op);
+                    indexes.push(index);
+                }
+            }
+        }
+
+        let mut results: Vec<Option<Result<u64>>> = (0..len).map(|_| None).collect();
+        for (db, (part, indexes)) in self.all_partitions().zip(parts) {
+            if indexes.is_empty() {
+                continue;
+            }
+            for (index, result) in indexes.into_iter().zip(db.write_partition(peer, part)?) {
+                // A range removal fails if any partition fails, or returns the last
+                // sequence number.
+                results[index] = match (results[index].take(), result) {
+                    (Some(Ok(seq)), Ok(other)) => Some(Ok(seq.max(other))),
+                    (Some(Err(err)), _) | (_, Err(err)) => Some(Err(err)),
+                    (None, result) => Some(result),
+                };
+            }
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    fn write_partition(&self, peer: Option<&str>, batch: WriteBatch) -> Result<Vec<Result<u64>>> {
+        self.throttle_writes()?;
+        let mut internal = self.internal.write().unwrap();
+        internal.check_writable()?;
+        let results = batch.ops.into_iter().map(|op| match op {
+            WriteOp::Set(key, value) => internal.put(key, &value, peer),
+            WriteOp::Remove(key) => internal.delete(&key, peer),
+            WriteOp::RemoveRange(start, end) => internal.delete_range(&start, &end, peer),
+        }).collect();
+        internal.publish();
+        if self.writer.is_some() && self.options.sync == SyncOptions::Always {
+            internal.sync()?;
+        }
+        Ok(results)
+    }
+
+    // Queue the write to the writer thread when group commit is enabled, the returned
+    // handle resolves once it is durable. Otherwise, the write is applied right away.
+    pub fn set_async<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V) -> WriteHandle {
+        let key = key.into();
+        if let Some(partition) = self.partition(&key) {
+            return partition.set_async(key, value);
+        }
+        if let Err(err) = self.throttle_writes() {
+            return WriteHandle::ready(Err(err));
+        }
+        match self.writer {
+            Some(ref writer) => writer.submit(
+                WriteOp::Set(key, value.as_ref().to_vec()),
+                None,
+                self.options.sync == SyncOptions::Always,
+            ),
+            None => WriteHandle::ready(self.write_value(None, key, value)),
+        }
+    }
+
+    // Delay or block a write while the compaction falls behind, see
+    // `StorageOptions::write_slowdown` and `StorageOptions::write_stop`.
+    fn throttle_writes(&self) -> Result<()> {
+        let deadline = Instant::now() + self.options.write_stop_timeout;
+        loop {
+            let (dead_ratio, file_count) = {
+                let internal = self.internal.read().unwrap();
+                (internal.idx.compaction_analysis.dead_ratio(), internal.lsm.file_count())
+            };
+            let over = |(max_dead_ratio, max_file_count): (f64, usize)| {
+                (max_dead_ratio > 0.0 && dead_ratio >= max_dead_ratio)
+                    || (max_file_count > 0 && file_count >= max_file_count)
+            };
+
+            if over(self.options.write_stop) {
+                self.compaction_wake_up.store(true, Ordering::SeqCst);
+                deadline::check()?;
+                if Instant::now() >= deadline {
+                    return Err(Error::Busy(format!(
+                        "the compaction is behind, {:.2} of the data is dead in {} data files",
+                        dead_ratio,
+                        file_count
+                    )));
+                }
+                thread::sleep(WRITE_STOP_CHECK_INTERVAL);
+            } else {
+                if over(self.options.write_slowdown) {
+                    self.compaction_wake_up.store(true, Ordering::SeqCst);
+                    thread::sleep(self.options.write_slowdown_delay);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn remove_async<K: AsRef<[u8]>>(&self, key: K) -> WriteHandle {
+        if let Some(partition) = self.partition(key.as_ref()) {
+            return partition.remove_async(key);
+        }
+        match self.writer {
+            Some(ref writer) => writer.submit(
+                WriteOp::Remove(key.as_ref().to_vec()),
+                None,
+                self.options.sync == SyncOptions::Always,
+            ),
+            None => WriteHandle::ready(self.remove(key)),
+        }
+    }
+
+    // Every live pair whose key starts with `prefix`, ordered by key.
+    pub fn scan<P: AsRef<[u8]>>(&self, prefix: P) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let keys = self.list_keys(prefix, [], usize::MAX)?;
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            deadline::check()?;
+            // The key may have been removed since the snapshot of the index was taken.
+            if let Some(value) = self.get(&key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    // A page of at most `limit` keys starting with `prefix`, ordered, after `cursor` (the
+    // last key of the previous page, empty for the first one).
+    pub fn list_keys<P: AsRef<[u8]>, C: AsRef<[u8]>>(
+        &self,
+        prefix: P,
+        cursor: C,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let (prefix, cursor) = (prefix.as_ref(), cursor.as_ref());
+        let keys = self.internal.read().unwrap().idx.list_keys(prefix, cursor, limit)?;
+        if self.partitions.is_empty() {
+            return Ok(keys);
+        }
+
+        // The pages of the partitions are ordered already, they are merged until the page
+        // is full.
+        let mut pages = vec![keys];
+        for partition in self.partitions.iter() {
+            pages.push(partition.list_keys(prefix, cursor, limit)?);
+        }
+        let mut positions = vec![0; pages.len()];
+        let mut keys = Vec::with_capacity(limit);
+        while keys.len() < limit {
+            let next = pages
+                .iter()
+                .zip(&positions)
+                .enumerate()
+                .filter_map(|(i, (page, &pos))| page.get(pos).map(|key| (key, i)))
+                .min();
+            let i = match next {
+                Some((_, i)) => i,
+                None => break,
+            };
+            keys.push(std::mem::take(&mut pages[i][positions[i]]));
+            positions[i] += 1;
+        }
+        Ok(keys)
+    }
+
+    // Position right after the last write, from which `tail` follows the new writes.
+    pub fn tail_position(&self) -> Result<LogPosition> {
+        self.check_unpartitioned("be tailed")?;
+        self.internal.read().unwrap().lsm.end_position()
+    }
+
+    // Follow the records written from the given position on, e.g. to replicate the store
+    // or to feed the changes to another system.
+    pub fn tail(&self, from_file_id: u32, from_pos: u64) -> Tail {
+        self.internal.read().unwrap().lsm.tail(from_file_id, from_pos)
+    }
+
+    // The first error of a background task since the last `clear_background_error`, if any.
+    // The writes are refused with `Error::WritesFenced` in the meantime.
+    pub fn background_error(&self) -> Option<BackgroundError> {
+        let error = self.internal.read().unwrap().background_error.clone();
+        error.or_else(|| self.partitions.iter().find_map(CrabeDB::background_error))
+    }
+
+    // Accept the writes again, e.g. once the disk has been fixed. Syncs the data files
+    // first, which fails if they still can't be.
+    pub fn clear_background_error(&self) -> Result<()> {
+        for partition in self.partitions.iter() {
+            partition.clear_background_error()?;
+        }
+        let mut internal = self.internal.write().unwrap();
+        internal.lsm.sync()?;
+        if let Some(error) = internal.background_error.take() {
+            info!("Cleared the {}", error);
+        }
+        Ok(())
+    }
+
+    // Only the first error is kept and passed to the handler, until it's cleared.
+    fn report_background_error(&self, task: &'static str, err: Error) {
+        let error = BackgroundError {
+            task,
+            kind: match err {
+                Error::Io(ref err) => err.kind(),
+                _ => io::ErrorKind::Other,
+            },
+            message: err.to_string(),
+            time: SystemTime::now(),
+        };
+        {
+            let mut internal = self.internal.write().unwrap();
+            if internal.background_error.is_some() {
+                warn!("Another {} while the writes are fenced", error);
+                return;
+            }
+            error!("Fencing the writes after a {}", error);
+            internal.background_error = Some(error.clone());
+        }
+        if let Some(ref handler) = self.options.background_error_handler {
+            handler.on_error(&error);
+        }
+    }
+
+    // The files of an in-memory store can't be linked to or from a directory.
+    fn check_on_disk(&self, action: &str) -> Result<()> {
+        if self.options.in_memory {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("an in-memory store can't {}", action),
+            ).into());
+        }
+        Ok(())
+    }
+
+    // The operations on the log of a single partition, e.g. a tail or a backup, can't be
+    // run on a partitioned store.
+    fn check_unpartitioned(&self, action: &str) -> Result<()> {
+        if !self.partitions.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("a partitioned store can't {}", action),
+            ).into());
+        }
+        Ok(())
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.internal.read().unwrap().standby
+    }
+
+    // Position in the log of the primary from which a standby resumes tailing it, `None`
+    // when nothing was applied yet.
+    pub fn standby_position(&self) -> Result<Option<LogPosition>> {
+        standby::load_position(&self.path)
+    }
+
+    // Write records tailed from a primary, `position` being the position of the primary
+    // after the last of them, or `None` for the live records copied from the primary
+    // first. The records are made durable before the position is saved.
+    pub fn apply(&self, logs: Vec<Log>, position: Option<LogPosition>) -> Result<()> {
+        let applied = {
+            let mut internal = self.internal.write().unwrap();
+            let applied = logs.into_iter().try_for_each(|log| internal.apply(log));
+            internal.publish();
+            applied
+        };
+        {
+            let (ref lock, ref waiters) = *self.applied;
+            let _guard = lock.lock().unwrap();
+            waiters.notify_all();
+        }
+        applied?;
+
+        if let Some(position) = position {
+            let internal = self.internal.read().unwrap();
+            internal.sync()?;
+            standby::save_position(&self.path, position)?;
+            standby::finish_seed(&self.path)?;
+        }
+        Ok(())
+    }
+
+    // Mark the standby as being seeded with a copy of the live records of its primary, until
+    // `apply` gets its first position: a seed which was interrupted has to start over from an
+    // empty store, see `reset_standby`.
+    pub fn start_seed(&self) -> Result<()> {
+        if !self.is_standby() {
+            return Err(Error::NotStandby);
+        }
+        standby::start_seed(&self.path)
+    }
+
+    pub fn is_seeding(&self) -> bool {
+        standby::is_seeding(&self.path)
+    }
+
+    // The changes of the leases, and of the keys with a time to live, a standby which applied
+    // the first `seq` changes of the feed `feed_id` of this store doesn't have yet, or all of
+    // them when it's too far behind, e.g. when it starts.
+    pub fn lease_changes(&self, feed_id: u64, seq: u64) -> LeaseChanges {
+        self.leases.lock().unwrap().changes(feed_id, seq)
+    }
+
+    // Apply the changes of the leases of the primary on a standby, which revokes the expired
+    // leases itself once promoted.
+    pub fn apply_lease_changes(&self, changes: &LeaseChanges) -> Result<()> {
+        if !self.is_standby() {
+            return Err(Error::NotStandby);
+        }
+        self.leases.lock().unwrap().apply_changes(changes)
+    }
+
+    // The feed of the leases of the primary and the number of its changes applied, to resume
+    // from, `None` until the first ones.
+    pub fn applied_lease_changes(&self) -> Option<(u64, u64)> {
+        self.leases.lock().unwrap().applied()
+    }
+
+    // Write a record of a peer with its sequence number, unless the key already holds a
+    // version at least as recent: the sequence numbers of the nodes order the versions of
+    // a key like a Lamport clock. Returns whether the record was written.
+    pub fn merge(&self, log: Log) -> Result<bool> {
+        let (key, seq, range) = (log.key.to_vec(), log.seq, log.range);
+        let end = if range { log.value.to_vec() } else { Vec::new() };
+        let merged = self.merge_log(log)?;
+        match (merged, range) {
+            (true, false) => self.detach(&key, seq),
+            (true, true) => self.detach_range(&key, &end, seq),
+            (false, _) => {}
+        }
+        Ok(merged)
+    }
+
+    fn merge_log(&self, log: Log) -> Result<bool> {
+        if log.range {
+            // Each partition gets a range tombstone.
+            for partition in self.partitions.iter() {
+                partition.merge_log(Log::deleted_range(log.seq, log.key.as_ref(), log.value.as_ref())?)?;
+            }
+        } else if let Some(partition) = self.partition(&log.key) {
+            return partition.merge_log(log);
+        }
+        let mut internal = self.internal.write().unwrap();
+        internal.check_writable()?;
+        let merged = internal.merge(log)?;
+        internal.publish();
+        if self.writer.is_some() && self.options.sync == SyncOptions::Always {
+            internal.sync()?;
+        }
+        Ok(merged)
+    }
+
+    // Like `merge`, the key being attached once written to the lease `lease` of the peer,
+    // mirrored here with its time to live `ttl`, or, when `lease` is 0, removed after `ttl`,
+    // like with `set_with_ttl`.
+    pub fn merge_with_lease(&self, log: Log, lease: u64, ttl: Duration) -> Result<bool> {
+        let (key, seq) = (log.key.to_vec(), log.seq);
+        // Held during the write, so the lease can't be revoked before the key is attached.
+        let mut leases = self.leases.lock().unwrap();
+        if !self.merge_log(log)? {
+            return Ok(false);
+        }
+        match lease {
+            0 => leases.expire_after(key, seq, ttl)?,
+            lease => {
+                leases.mirror(lease, ttl)?;
+                leases.attach(lease, key, seq)?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Turn a standby into a primary: clients can write again, and the sequence numbers go
+    // on from the last record applied. Returns the next sequence number.
+    pub fn promote(&self) -> Result<u64> {
+        let mut internal = self.internal.write().unwrap();
+        if !internal.standby {
+            return Err(Error::NotStandby);
+        }
+        internal.standby = false;
+        standby::remove_position(&self.path)?;
+        standby::finish_seed(&self.path)?;
+        // Waiting readers fail at once rather than at their timeout.
+        self.applied.1.notify_all();
+        let current_seq = internal.current_seq.load(Ordering::SeqCst);
+        info!("Promoted standby, current sequence number: {}", current_seq);
+        Ok(current_seq)
+    }
+
+    // Turn a primary back into a standby, e.g. once another node of its failover group was
+    // elected: the writes are refused until it's promoted again, and it applies the records
+    // of the new primary. It has no position in the log of that one, see `records_after`,
+    // and its own records the new primary never got make it seeded again, see
+    // `reset_standby`.
+    pub fn demote(&self) -> Result<()> {
+        self.check_unpartitioned("be demoted")?;
+        let mut internal = self.internal.write().unwrap();
+        if internal.standby {
+            return Ok(());
+        }
+        internal.sync()?;
+        internal.standby = true;
+        standby::remove_position(&self.path)?;
+        info!("Demoted to standby, last sequence number: {}", internal.last_seq());
+        Ok(())
+    }
+
+    // Drop every record of a standby which can't catch up with its primary from its last
+    // sequence number, see `records_after`: its sequence numbers start over, and it's seeded
+    // again with a copy of the live records of the primary.
+    pub fn reset_standby(&self) -> Result<()> {
+        self.check_unpartitioned("be seeded again")?;
+        let _compaction = self.compaction.lock().unwrap();
+        let mut internal = self.internal.write().unwrap();
+        if !internal.standby {
+            return Err(Error::NotStandby);
+        }
+        let count = internal.drop_records()?;
+        internal.current_seq.store(1, Ordering::SeqCst);
+        standby::remove_position(&self.path)?;
+        warn!("Dropped the {} keys of the standby, to be seeded again", count);
+        Ok(())
+    }
+
+    // The election state of the store as a node of a failover group.
+    pub fn election_state(&self) -> Result<standby::ElectionState> {
+        standby::load_election(&self.path)
+    }
+
+    // The position of a standby, in the log of its primary, is forgotten first when the
+    // state names another primary: the standby then catches up with the new one from its
+    // last sequence number.
+    pub fn save_election_state(&self, state: &standby::ElectionState) -> Result<()> {
+        if standby::load_election(&self.path)?.leader != state.leader {
+            standby::remove_position(&self.path)?;
+        }
+        standby::save_election(&self.path, state)
+    }
+
+    // The live records of at most `limit` keys following `cursor`, ordered by key, which
+    // seed a new standby, and the cursor of the next ones once the last key is reached.
+    pub fn live_records<C: AsRef<[u8]>>(
+        &self,
+        cursor: C,
+        limit: usize,
+    ) -> Result<(Vec<Log<'static>>, Option<Vec<u8>>)> {
+        let keys = self.list_keys([], cursor, limit)?;
+        let next_cursor = if keys.len() == limit { keys.last().cloned() } else { None };
+
+        let mut logs = Vec::with_capacity(keys.len());
+        // The keys removed since they were listed are skipped.
+        for key in keys {
+            let internal = self.partition(&key).unwrap_or(self).internal.read().unwrap();
+            if let Some(idx_log) = internal.idx.get(&key)? {
+                let log = internal.lsm.read_log(idx_log.file_id, idx_log.pos)?;
+                logs.push(internal.lsm.resolve(log)?);
+            }
         }
-        Ok(())
+        Ok((logs, next_cursor))
     }
 
-    pub fn keys(&self) -> Keys<Vec<u8>, MemIdxEntry> {
-        self.idx.keys()
+    // At most `limit` of the records written after `seq` which the data files still hold,
+    // tombstones included, ordered by sequence number: what a standby which applied the
+    // records of another primary up to `seq` misses, e.g. after a failover, as the sequence
+    // numbers of a standby are those of its primary. The next ones follow the last record
+    // returned. The versions overwritten and compacted away since are left out, their newer
+    // versions being there, but a compaction may also have dropped tombstones written after
+    // `seq`: in that case it fails with `Error::TombstonesPurged`, and the standby has to be
+    // seeded again.
+    pub fn records_after(&self, seq: u64, limit: usize) -> Result<Vec<Log<'static>>> {
+        let mut logs = Vec::new();
+        for db in self.all_partitions() {
+            logs.extend(db.partition_records_after(seq, limit)?);
+        }
+        logs.sort_by_key(|log| log.seq);
+        logs.truncate(limit);
+        Ok(logs)
     }
-}
-
-#[derive(Clone)]
-pub struct CrabeDB {
-    path: PathBuf,
-    options: StorageOptions,
-    dropped: Arc<AtomicBool>,
-    internal: Arc<RwLock<CrabeDBinternal>>,
-    compaction: Arc<Mutex<()>>,
-}
-
-impl CrabeDB {
-    pub fn load(path: &str, options: StorageOptions) -> Result<CrabeDB> {
-        info!("loading key/value store: {:?}", &path);
-        let mut lsm = Lsm::load(
-            path,
-            options.create,
-            options.sync == SyncOptions::Always,
-            options.max_file_size,
-            options.file_chunk_queue_size,
-        )?;
 
-        let mut idx = MemIdx::new();
-        let mut seq = 0;
+    fn partition_records_after(&self, seq: u64, limit: usize) -> Result<Vec<Log<'static>>> {
+        // The files read aren't compacted away meanwhile, and the writes go on: the lock of
+        // the store is only held while the hints of a file are read.
+        let _compaction = self.compaction.lock().unwrap();
+        let (files, active_file_id, reader) = {
+            let lsm = &self.internal.read().unwrap().lsm;
+            (lsm.files(), lsm.active_file_id, lsm.reader())
+        };
 
-        for file_id in lsm.files() {
-            let mut update_idx_func = |ch: CompactionHint| {
+        // The first `limit` positions by sequence number, and the timestamp of the latest
+        // record up to `seq`.
+        let mut positions = BTreeMap::new();
+        let mut written_before: Option<(u64, u64)> = None;
+        for file_id in files.into_iter().chain(active_file_id) {
+            let internal = self.internal.read().unwrap();
+            if internal.lsm.file_summary(file_id)?.is_some_and(|summary| summary.max_seq <= seq) {
+                continue;
+            }
+            // The hints of the active data file may not be written yet.
+            let hints: Box<dyn Iterator<Item = Result<CompactionHint>>> =
+                match internal.lsm.compaction_hints(file_id)? {
+                    Some(chs) if active_file_id != Some(file_id) => Box::new(chs),
+                    _ => Box::new(internal.lsm.entries(file_id)?.map(|(log_pos, log)| {
+                        log.map(|log| CompactionHint::from(log, log_pos))
+                    })),
+                };
+            for ch in hints {
+                let ch = ch?;
+                // A compaction copies a record without changing its sequence number.
                 if ch.seq > seq {
-                    seq = ch.seq;
-                }
-                idx.update(ch, file_id);
-            };
-
-            match lsm.compaction_hints(file_id)? {
-                Some(chs) => {
-                    for ch in chs {
-                        update_idx_func(ch?);
+                    positions.entry(ch.seq).or_insert((file_id, ch.log_pos));
+                    if positions.len() > limit {
+                        positions.pop_last();
                     }
-                }
-                None => {
-                    for ch in lsm.update_compaction_hints(file_id)? {
-                        update_idx_func(ch?);
+                } else if let Some(timestamp) = ch.timestamp.filter(|&timestamp| timestamp > 0) {
+                    if written_before.is_none_or(|(before_seq, _)| ch.seq > before_seq) {
+                        written_before = Some((ch.seq, timestamp));
                     }
                 }
-            };
+            }
         }
 
-        info!("loaded key/value store: {:?}", &path);
-        info!("Current sequence number: {:?}", seq);
+        if !self.keeps_tombstones_after(seq, written_before.map(|(_, timestamp)| timestamp)) {
+            return Err(Error::TombstonesPurged { seq });
+        }
+        let mut logs = Vec::with_capacity(positions.len());
+        for (file_id, pos) in positions.into_values() {
+            let log = reader.read_log(file_id, pos)?;
+            logs.push(reader.resolve(log)?);
+        }
+        Ok(logs)
+    }
 
-        let crabe_db = CrabeDB {
-            path: lsm.path.clone(),
-            options: options,
-            dropped: Arc::new(AtomicBool::new(false)),
-            internal: Arc::new(RwLock::new(CrabeDBinternal {
-                current_seq: seq + 1,
-                lsm: lsm,
-                idx: idx,
-            })),
-            compaction: Arc::new(Mutex::new(())),
-        };
+    // Whether no compaction can have dropped a tombstone written after `seq`: they are kept
+    // until they are `tombstone_seq_gap` writes old and, with a `tombstone_ttl`, until their
+    // file is that old, which it can't be if a record up to `seq` was written more recently
+    // (`written_before`, in milliseconds since the Unix epoch).
+    fn keeps_tombstones_after(&self, seq: u64, written_before: Option<u64>) -> bool {
+        let current_seq = self.internal.read().unwrap().current_seq.load(Ordering::SeqCst);
+        if seq + 1 >= current_seq || current_seq - (seq + 1) < self.options.tombstone_seq_gap {
+            return true;
+        }
+        match (self.options.tombstone_ttl, written_before) {
+            (Some(ttl), Some(written_before)) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                now.saturating_sub(Duration::from_millis(written_before)) < ttl
+            }
+            _ => false,
+        }
+    }
 
-        if let SyncOptions::Frequency(millis) = crabe_db.options.sync {
-            let crabe_db = crabe_db.clone();
+    // The sequence number of each live key, and the latest tombstones of the data files,
+    // see `merge::merge_stores`.
+    pub(crate) fn versions(&self) -> Result<StoreVersions> {
+        let internal = self.internal.read().unwrap();
+        let mut versions = StoreVersions::default();
+        for key in internal.keys() {
+            let key = key?;
+            if let Some(entry) = internal.idx.get(&key)? {
+                versions.live.push((key.into_owned(), entry.seq));
+            }
+        }
 
-            thread::spawn(move || {
-                let duration = Duration::from_millis(millis as u64);
-                loop {
-                    if crabe_db.dropped.load(Ordering::SeqCst) {
-                        info!(
-                            "CrabeDB has been dropped, background file sync thread is exiting"
-                        );
-                        break;
-                    }
+        for file_id in internal.lsm.files() {
+            // Only the files with tombstones are read.
+            if internal.lsm.file_summary(file_id)?.is_some_and(|summary| summary.tombstones == 0) {
+                continue;
+            }
+            // The file still written to, or left open by a crash, may have no hint file.
+            let hints: Box<dyn Iterator<Item = Result<CompactionHint>>> =
+                match internal.lsm.compaction_hints(file_id)? {
+                    Some(chs) => Box::new(chs),
+                    None => Box::new(internal.lsm.entries(file_id)?.map(|(log_pos, log)| {
+                        log.map(|log| CompactionHint::from(log, log_pos))
+                    })),
+                };
 
-                    debug!("Background file sync");
-                    crabe_db.internal.read().unwrap().lsm.sync().unwrap();
-                    thread::sleep(duration);
+            for ch in hints {
+                let ch = ch?;
+                if let Some(end) = ch.range_end {
+                    versions.range_deletes.push((ch.key.into_owned(), end.into_owned(), ch.seq));
+                } else if ch.deleted {
+                    let seq = versions.deletes.entry(ch.key.into_owned()).or_insert(0);
+                    *seq = (*seq).max(ch.seq);
                 }
-            });
-        };
-
-        if crabe_db.options.compaction {
-            let crabe_db = crabe_db.clone();
+            }
+        }
 
-            thread::spawn(move || {
-                let duration = Duration::from_secs(crabe_db.options.compaction_check_frequency);
-                loop {
-                    if crabe_db.dropped.load(Ordering::SeqCst) {
-                        info!(
-                            "CrabeDB has been dropped, background compaction thread is exiting"
-                        );
-                        break;
-                    }
+        for partition in self.partitions.iter() {
+            let partition_versions = partition.versions()?;
+            versions.live.extend(partition_versions.live);
+            for (key, seq) in partition_versions.deletes {
+                let deleted = versions.deletes.entry(key).or_insert(0);
+                *deleted = (*deleted).max(seq);
+            }
+            versions.range_deletes.extend(partition_versions.range_deletes);
+        }
+        Ok(versions)
+    }
 
-                    info!("Compaction thread wake up");
+    pub fn export<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        for db in self.all_partitions() {
+            for key in db.internal.read().unwrap().keys() {
+                keys.push(key?.into_owned());
+            }
+        }
 
-                    let current_hour = time::now().tm_hour as usize;
-                    let (window_start, window_end) = crabe_db.options.compaction_window;
-                    let in_window = if window_start <= window_end {
-                        current_hour >= window_start && current_hour <= window_end
-                    } else {
-                        current_hour >= window_end || current_hour <= window_end
-                    };
+        info!("Exporting {} keys", keys.len());
+        let mut archive_writer = ArchiveWriter::new(writer)?;
+        for key in keys {
+            // The key may have been removed since the snapshot of the index was taken.
+            if let Some(value) = self.get(&key)? {
+                archive_writer.write(&key, &value)?;
+            }
+        }
+        archive_writer.finish()
+    }
 
-                    if !in_window {
-                        info!(
-                            "Compaction outside defined window {:?}",
-                            crabe_db.options.compaction_window
-                        );
-                    } else if let Err(err) = crabe_db.compact() {
-                        warn!("Error during compaction: {}", err);
-                    }
+    pub fn import<R: Read>(&self, reader: R) -> Result<u64> {
+        let mut count = 0;
+        for entry in ArchiveReader::new(reader)? {
+            let (key, value) = entry?;
+            self.set(key, value)?;
+            count += 1;
+        }
+        info!("Imported {} keys", count);
+        Ok(count)
+    }
 
-                    thread::sleep(duration);
-                }
-            });
+    // Back the store up into `dest`, a new directory, while the writes go on: the active
+    // data file is sealed, which only holds the write lock briefly, then the files are
+    // hard linked into `dest` rather than copied. The backup is a store holding every
+    // write up to the returned sequence number, consistent as after a crash.
+    pub fn hot_backup(&self, dest: &str) -> Result<u64> {
+        self.check_on_disk("be backed up")?;
+        self.check_unpartitioned("be backed up")?;
+        let dest_path = Path::new(dest);
+        if !is_new_store_path(dest_path)? {
+            return Err(Error::InvalidPath(dest.to_string()));
         }
+        fs::create_dir_all(dest_path)?;
 
-        Ok(crabe_db)
+        let (last_seq, _) = self.backup_into(dest_path)?;
+        Ok(last_seq)
     }
 
-    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
-        self.internal.read().unwrap().get(key.as_ref())
+    // A hot backup of the store to send to a joining standby, which then tails the store
+    // from the position of the snapshot. It's taken in a directory of the store, so that
+    // its files are hard linked, and removed once the snapshot is dropped.
+    pub fn bootstrap_snapshot(&self) -> Result<StoreSnapshot> {
+        self.check_on_disk("be backed up")?;
+        self.check_unpartitioned("be backed up")?;
+        let dir = StoreSnapshot::create_dir(&self.path)?;
+        match self.backup_into(&dir) {
+            Ok((last_seq, position)) => StoreSnapshot::new(dir, position, last_seq),
+            Err(err) => {
+                let _ = fs::remove_dir_all(&dir);
+                Err(err)
+            }
+        }
     }
 
-    pub fn set<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
-        self.internal.write().unwrap().put(key.into(), value.as_ref())
+    // Returns the last sequence number backed up and the position right after it.
+    fn backup_into(&self, dest: &Path) -> Result<(u64, LogPosition)> {
+        // No file can be compacted away before it's linked.
+        let _compaction = self.compaction.lock().unwrap();
+        let (snapshot, last_seq, position) = {
+            let mut internal = self.internal.write().unwrap();
+            let snapshot = internal.lsm.snapshot()?;
+            (snapshot, internal.last_seq(), internal.lsm.end_position()?)
+        };
+        snapshot.link_into(dest)?;
+        self.leases.lock().unwrap().save_into(dest)?;
+
+        info!("Backed up {} files up to sequence number {} into {:?}", snapshot.files(), last_seq, dest);
+        Ok((last_seq, position))
     }
 
-    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
-        self.internal.write().unwrap().delete(key.as_ref())
+    // Load data files written outside of the store, e.g. by an `SstBuilder`, without going
+    // through the write path: they are checked, brought in under new file ids and their
+    // records indexed like those of the other files. Like the initial copy of a standby,
+    // an ingested record doesn't replace a newer version of its key. The ingested records
+    // aren't seen by the tails, so aren't replicated. Returns the number of records.
+    pub fn ingest_files<P: AsRef<Path>>(&self, paths: &[P]) -> Result<u64> {
+        self.check_on_disk("ingest files")?;
+        self.check_unpartitioned("ingest files")?;
+        let mut ingester = {
+            let internal = self.internal.read().unwrap();
+            internal.check_writable()?;
+            internal.lsm.ingester()
+        };
+
+        let (mut records, mut last_seq) = (0, 0);
+        for path in paths {
+            let ingested = ingester.add(path.as_ref())?;
+            info!(
+                "Ingesting {:?} as data file {} ({} records)",
+                path.as_ref(),
+                ingested.file_id,
+                ingested.records
+            );
+            records += ingested.records;
+            last_seq = last_seq.max(ingested.last_seq);
+        }
+
+        // A compaction would drop the records of a file registered but not indexed yet.
+        let _compaction = self.compaction.lock().unwrap();
+        let new_files = ingester.publish()?;
+        {
+            let mut internal = self.internal.write().unwrap();
+            internal.current_seq.fetch_max(last_seq + 1, Ordering::SeqCst);
+            internal.lsm.swap_files(&[], &new_files)?;
+        }
+
+        for &(file_id, _) in &new_files {
+            let compaction_hints = {
+                self.internal.read().unwrap().lsm.compaction_hints(file_id)?
+            };
+
+            if let Some(chs) = compaction_hints {
+                for ch in chs {
+                    let ch = ch?;
+                    self.internal.write().unwrap().ingest(ch, file_id)?;
+                }
+            }
+        }
+        self.internal.write().unwrap().publish();
+        info!("Ingested {} records from {} files", records, new_files.len());
+        Ok(records)
     }
 
-    fn compact_files_util(&self, files: &[u32]) -> Result<(Vec<u32>, Vec<u32>)> {
+    fn compact_files_util(
+        &self,
+        files: &[u32],
+        drop_tombstones: bool,
+    ) -> Result<CompactionOutput> {
         let active_file_id = {
             self.internal.read().unwrap().lsm.active_file_id
         };
@@ -234,30 +2577,40 @@ impl CrabeDB {
         });
 
         let mut compacted_files = Vec::new();
-        let mut new_files = Vec::new();
-        let mut deletes = HashMap::new();
+        let mut deletes: HashMap<Vec<u8>, (u64, u32)> = HashMap::new();
+        let mut range_deletes = Vec::new();
 
         let mut lsm_writer = {
-            self.internal.read().unwrap().lsm.writer()
+            self.internal.read().unwrap().lsm.writer()?
         };
+        if let Some(level) = self.options.compression {
+            let dictionary = self.train_dictionary(files, active_file_id)?;
+            lsm_writer.compress(ValueCompressor::new(level, dictionary)?)?;
+        }
 
         for (file_id, compaction_hints) in compacted_files_hints {
             let mut inserts = Vec::new();
 
             for ch in compaction_hints {
                 let ch = ch?;
+                self.compaction_status.lock().unwrap().records_processed += 1;
+                // Range tombstones are kept as long as point tombstones are.
+                if let Some(ref end) = ch.range_end {
+                    range_deletes.push((ch.key.to_vec(), end.to_vec(), ch.seq, file_id));
+                    continue;
+                }
                 let internal = self.internal.read().unwrap();
-                let idx_log = internal.idx.get(&*ch.key);
+                let idx_log = internal.idx.get(&ch.key)?;
                 if ch.deleted {
                     if idx_log.is_none() {
                         match deletes.entry(ch.key.to_vec()) {
                             HashMapEntry::Occupied(mut occupied) => {
-                                if *occupied.get() < ch.seq {
-                                    occupied.insert(ch.seq);
+                                if occupied.get().0 < ch.seq {
+                                    occupied.insert((ch.seq, file_id));
                                 }
                             }
                             HashMapEntry::Vacant(entry) => {
-                                entry.insert(ch.seq);
+                                entry.insert((ch.seq, file_id));
                             }
                         }
                     }
@@ -267,28 +2620,242 @@ impl CrabeDB {
             }
 
             for ch in inserts {
-                let lsm = &self.internal.read().unwrap().lsm;
-                let lsm_write = lsm_writer.write(&lsm.read_log(file_id, ch.log_pos)?)?;
+                let log = {
+                    self.internal.read().unwrap().lsm.read_log(file_id, ch.log_pos)?
+                };
+
+                // The filter sees the value itself, but a kept value stays in its blob file.
+                let decision = match self.options.compaction_filter {
+                    Some(ref filter) => match log.blob_pointer()? {
+                        Some(pointer) => {
+                            let value = {
+                                self.internal.read().unwrap().lsm.read_blob(&pointer)?
+                            };
+                            filter.filter(&log.key, &value, log.seq)
+                        }
+                        None => filter.filter(&log.key, &log.value, log.seq),
+                    },
+                    None => FilterDecision::Keep,
+                };
+                // A dropped record is replaced by a tombstone, which removes it from the
+                // index and hides the older versions of its key living in other files.
+                let timestamp = log.timestamp;
+                let mut log = match decision {
+                    FilterDecision::Keep => log,
+                    FilterDecision::Drop => Log::deleted(log.seq, log.key.into_owned()),
+                    FilterDecision::Replace(value) => Log::new(log.seq, log.key.into_owned(), value)?,
+                };
+                // The record keeps its creation time, even when rewritten by the filter.
+                log.timestamp = timestamp;
 
-                if let LsmWrite::NewFile(file_id) = lsm_write {
-                    new_files.push(file_id);
+                // Throttled outside of the lock, the read and the write both count.
+                if let Some(ref limiter) = self.compaction_limiter {
+                    limiter.request(2 * log.size());
                 }
+                lsm_writer.write(&log)?;
+                self.compaction_status.lock().unwrap().bytes_written += log.size();
             }
 
             compacted_files.push(file_id);
         }
 
-        for (key, seq) in deletes {
-            lsm_writer.write(&Log::deleted(seq, key))?;
+        // Tombstones are only useless once every file which could hold an older value
+        // of their key is part of the compaction, and once their grace period is over.
+        // The summaries of the other files tell which ones could.
+        let complete = drop_tombstones && compacted_files.len() == files.len();
+        let mut dropped = Vec::new();
+        if !deletes.is_empty() || !range_deletes.is_empty() {
+            let expired = self.expired_tombstones(&compacted_files)?;
+            let others = if complete { Vec::new() } else { self.other_file_summaries(&compacted_files)? };
+            let others = others.as_slice();
+            deletes.retain(|key, &mut (seq, file_id)| {
+                let kept = !expired(seq, file_id) || others.iter().any(|summary| {
+                    summary.as_ref().is_none_or(|summary| summary.may_hold_older_key(key, seq))
+                });
+                if !kept {
+                    dropped.push((key.clone(), None, seq));
+                }
+                kept
+            });
+            range_deletes.retain(|&(ref start, ref end, seq, file_id)| {
+                let kept = !expired(seq, file_id) || others.iter().any(|summary| {
+                    summary.as_ref().is_none_or(|summary| summary.may_hold_older(start, end, seq))
+                });
+                if !kept {
+                    dropped.push((start.clone(), Some(end.clone()), seq));
+                }
+                kept
+            });
+            info!("Dropping {} tombstones", dropped.len());
+        }
+
+        for (start, end, seq, _) in range_deletes {
+            let log = Log::deleted_range(seq, start, end)?;
+            if let Some(ref limiter) = self.compaction_limiter {
+                limiter.request(log.size());
+            }
+            lsm_writer.write(&log)?;
+            self.compaction_status.lock().unwrap().bytes_written += log.size();
+        }
+
+        for (key, (seq, _)) in deletes {
+            let log = Log::deleted(seq, key);
+            if let Some(ref limiter) = self.compaction_limiter {
+                limiter.request(log.size());
+            }
+            lsm_writer.write(&log)?;
+            self.compaction_status.lock().unwrap().bytes_written += log.size();
+        }
+
+        let new_files = lsm_writer.publish()?;
+
+        Ok((compacted_files, new_files, dropped))
+    }
+
+    // A dictionary for the values of the files of a compaction, trained from live values
+    // sampled evenly across them, or empty when there are too few to train one.
+    fn train_dictionary(&self, files: &[u32], active_file_id: Option<u32>) -> Result<Vec<u8>> {
+        let mut records = 0;
+        for &file_id in files {
+            if let Some(summary) = self.internal.read().unwrap().lsm.file_summary(file_id)? {
+                records += summary.records;
+            }
+        }
+        let step = (records / MAX_SAMPLES).max(1);
+
+        let mut samples = Vec::new();
+        let (mut seen, mut size) = (0, 0);
+        'files: for &file_id in files.iter().filter(|&&file_id| Some(file_id) != active_file_id) {
+            let compaction_hints = match self.internal.read().unwrap().lsm.compaction_hints(file_id)? {
+                Some(compaction_hints) => compaction_hints,
+                None => continue,
+            };
+            for ch in compaction_hints {
+                let ch = ch?;
+                if ch.deleted || ch.range_end.is_some() {
+                    continue;
+                }
+                seen += 1;
+                if seen % step != 0 {
+                    continue;
+                }
+                let internal = self.internal.read().unwrap();
+                if internal.idx.get(&ch.key)?.is_none_or(|idx_log| idx_log.seq != ch.seq) {
+                    continue;
+                }
+                let log = internal.lsm.read_log(file_id, ch.log_pos)?;
+                if log.blob {
+                    continue;
+                }
+                size += log.value.len();
+                samples.push(log.value.into_owned());
+                if samples.len() as u64 >= MAX_SAMPLES || size >= MAX_SAMPLES_SIZE {
+                    break 'files;
+                }
+            }
+        }
+
+        let dictionary = train_dictionary(&samples).unwrap_or_default();
+        info!("Trained a dictionary of {} bytes from {} values", dictionary.len(), samples.len());
+        Ok(dictionary)
+    }
+
+    // The summaries of the data files outside of a compaction, the active one included.
+    fn other_file_summaries(&self, compacted_files: &[u32]) -> Result<Vec<Option<FileSummary>>> {
+        let internal = self.internal.read().unwrap();
+        let lsm = &internal.lsm;
+        lsm.files()
+            .into_iter()
+            .chain(lsm.active_file_id)
+            .filter(|file_id| !compacted_files.contains(file_id))
+            .map(|file_id| lsm.file_summary(file_id))
+            .collect()
+    }
+
+    // Whether the grace period of a tombstone of `files`, given its sequence number and
+    // its file, is over.
+    fn expired_tombstones(&self, files: &[u32]) -> Result<impl Fn(u64, u32) -> bool> {
+        let internal = self.internal.read().unwrap();
+        let current_seq = internal.current_seq.load(Ordering::SeqCst);
+        let seq_gap = self.options.tombstone_seq_gap;
+
+        let mut file_ages = HashMap::new();
+        if let Some(ttl) = self.options.tombstone_ttl {
+            let now = SystemTime::now();
+            for &file_id in files {
+                let modified = internal.lsm.file_modified(file_id)?;
+                // A file modified "in the future" is treated as brand new.
+                let age = now.duration_since(modified).unwrap_or_default();
+                file_ages.insert(file_id, age >= ttl);
+            }
+        }
+
+        let ttl = self.options.tombstone_ttl;
+        Ok(move |seq: u64, file_id: u32| {
+            current_seq.saturating_sub(seq) >= seq_gap &&
+                (ttl.is_none() || file_ages.get(&file_id).cloned().unwrap_or(false))
+        })
+    }
+
+    fn compact_files(&self, files: &[u32], drop_tombstones: bool) -> Result<()> {
+        {
+            let mut status = self.compaction_status.lock().unwrap();
+            *status = CompactionStatus {
+                running: true,
+                files: files.to_vec(),
+                started_at: Some(SystemTime::now()),
+                ..CompactionStatus::default()
+            };
+        }
+
+        let result = self.compact_files_batch(files, drop_tombstones);
+
+        let mut status = self.compaction_status.lock().unwrap();
+        status.running = false;
+        status.finished_at = Some(SystemTime::now());
+        result
+    }
+
+    // Disjoint groups of files are merged by parallel workers, each with its own writer.
+    // Tombstones can only be dropped by a compaction of every file at once, which is
+    // therefore never split.
+    fn compact_files_batch(&self, files: &[u32], drop_tombstones: bool) -> Result<()> {
+        let parallelism = self.options.compaction_parallelism.max(1).min(files.len());
+        if drop_tombstones || parallelism <= 1 {
+            return self.compact_group(files, drop_tombstones);
         }
 
-        Ok((compacted_files, new_files))
+        let group_size = files.len().div_ceil(parallelism);
+        info!(
+            "Compacting data files {:?} in {} parallel groups",
+            files,
+            files.len().div_ceil(group_size)
+        );
+        thread::scope(|scope| {
+            let workers: Vec<_> = files
+                .chunks(group_size)
+                .map(|group| scope.spawn(move || self.compact_group(group, false)))
+                .collect();
+
+            // Every worker is joined before the first error is reported.
+            let results: Vec<Result<()>> = workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect();
+            results.into_iter().collect()
+        })
     }
 
-    fn compact_files(&self, files: &[u32]) -> Result<()> {
+    fn compact_group(&self, files: &[u32], drop_tombstones: bool) -> Result<()> {
         info!("Compacting data files: {:?}", files);
-        let (ref compacted_files, ref new_files) = self.compact_files_util(files)?;
-        for &file_id in new_files {
+        let (ref compacted_files, ref new_files, ref dropped_tombstones) =
+            self.compact_files_util(files, drop_tombstones)?;
+        // Archived before `swap_files`, which would otherwise copy them under the write lock.
+        let archive = self.internal.read().unwrap().lsm.archive();
+        if let Some(archive) = archive {
+            archive.add_data_files(compacted_files)?;
+        }
+        for &(file_id, _) in new_files {
             let compaction_hints = {
                 self.internal.read().unwrap().lsm.compaction_hints(file_id)?
             };
@@ -296,10 +2863,12 @@ impl CrabeDB {
             if let Some(chs) = compaction_hints {
                 for ch in chs {
                     let ch = ch?;
-                    self.internal.write().unwrap().idx.update(ch, file_id);
+                    self.internal.write().unwrap().relocate(ch, file_id)?;
                 }
             };
         }
+        // Lock-free readers must see the new locations before the old files are removed.
+        self.internal.write().unwrap().publish();
         self.internal.write().unwrap().idx.compaction_analysis.remove_files(
             compacted_files,
         );
@@ -307,16 +2876,150 @@ impl CrabeDB {
             compacted_files,
             new_files,
         )?;
+        self.internal.write().unwrap().idx.forget_tombstones(dropped_tombstones);
+        // The blob files only the compacted files pointed to are garbage now.
+        self.internal.read().unwrap().lsm.remove_unreferenced_blobs()?;
         info!(
             "Finished compacting data files: {:?} into: {:?}",
             compacted_files,
-            new_files
+            new_files.iter().map(|&(file_id, _)| file_id).collect::<Vec<u32>>()
         );
         Ok(())
     }
 
-    pub fn compact(&self) -> Result<()> {
+    // Stop the background and manual compactions until `resume_compaction` is called.
+    // Returns once the compaction in progress, if any, is finished.
+    pub fn pause_compaction(&self) {
+        for partition in self.partitions.iter() {
+            partition.pause_compaction();
+        }
+        self.compaction_paused.store(true, Ordering::SeqCst);
         let _lock = self.compaction.lock().unwrap();
+        info!("Compaction paused");
+    }
+
+    pub fn resume_compaction(&self) {
+        for partition in self.partitions.iter() {
+            partition.resume_compaction();
+        }
+        self.compaction_paused.store(false, Ordering::SeqCst);
+        info!("Compaction resumed");
+    }
+
+    pub fn is_compaction_paused(&self) -> bool {
+        self.compaction_paused.load(Ordering::SeqCst)
+    }
+
+    // The partitions of a store add up their progress.
+    pub fn compaction_status(&self) -> CompactionStatus {
+        let mut status = CompactionStatus {
+            paused: self.is_compaction_paused(),
+            ..self.compaction_status.lock().unwrap().clone()
+        };
+        for partition in self.partitions.iter() {
+            let partition_status = partition.compaction_status();
+            status.running |= partition_status.running;
+            status.files.extend(partition_status.files);
+            status.records_processed += partition_status.records_processed;
+            status.bytes_written += partition_status.bytes_written;
+            status.started_at = status.started_at.into_iter().chain(partition_status.started_at).min();
+            status.finished_at = status.finished_at.max(partition_status.finished_at);
+        }
+        status
+    }
+
+    // Taken by every compaction, fails when compactions are paused.
+    fn compaction_lock(&self) -> Result<MutexGuard<'_, ()>> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let lock = self.compaction.lock().unwrap();
+        if self.is_compaction_paused() {
+            return Err(Error::CompactionPaused);
+        }
+        Ok(lock)
+    }
+
+    // Offload the data files which haven't been written to for `Tiering::min_age` to the
+    // object storage of the tiering policy, and return them. It's paused along with the
+    // compaction.
+    pub fn offload_cold_files(&self) -> Result<Vec<u32>> {
+        let min_age = match self.options.tiering {
+            Some(ref tiering) => tiering.min_age,
+            None => return Ok(Vec::new()),
+        };
+        let _lock = self.compaction_lock()?;
+        let (cold_files, reader) = {
+            let lsm = &self.internal.read().unwrap().lsm;
+            (lsm.cold_files(min_age)?, lsm.reader())
+        };
+
+        // The files are uploaded without holding the lock of the store.
+        for &file_id in &cold_files {
+            reader.offload(file_id)?;
+        }
+        Ok(cold_files)
+    }
+
+    // Compact the sealed data files which aren't encrypted with the current key of
+    // `StorageOptions::encryption` into new files encrypted with it, and return them. Their
+    // tombstones are kept. The older keys can be dropped from the keyring afterwards.
+    pub fn rewrap(&self) -> Result<Vec<u32>> {
+        let mut files = Vec::new();
+        for db in self.all_partitions() {
+            files.extend(db.rewrap_partition()?);
+        }
+        Ok(files)
+    }
+
+    fn rewrap_partition(&self) -> Result<Vec<u32>> {
+        let _lock = self.compaction_lock()?;
+        let files = {
+            self.internal.read().unwrap().lsm.unwrapped_files()?
+        };
+
+        if files.is_empty() {
+            info!("No files to rewrap");
+            return Ok(files);
+        }
+
+        info!("Rewrapping data files {:?}", files);
+        self.compact_files(&files, false)?;
+        Ok(files)
+    }
+
+    pub fn full_compaction(&self) -> Result<()> {
+        for db in self.all_partitions() {
+            db.full_compaction_partition()?;
+        }
+        Ok(())
+    }
+
+    fn full_compaction_partition(&self) -> Result<()> {
+        let _lock = self.compaction_lock()?;
+        let (files, drop_tombstones) = {
+            let lsm = &self.internal.read().unwrap().lsm;
+            (lsm.files(), lsm.active_file_id.is_none())
+        };
+
+        if files.is_empty() {
+            info!("No files eligible for compaction");
+            return Ok(());
+        }
+
+        self.compact_files(&files, drop_tombstones)
+    }
+
+    pub fn compact(&self) -> Result<()> {
+        for db in self.all_partitions() {
+            db.compact_partition()?;
+        }
+        Ok(())
+    }
+
+    // Compact a single partition, run by `compact` and by the compaction scheduler.
+    fn compact_partition(&self) -> Result<()> {
+        let _lock = self.compaction_lock()?;
         let active_file_id = {
             self.internal.read().unwrap().lsm.active_file_id
         };
@@ -324,82 +3027,119 @@ impl CrabeDB {
             self.internal.read().unwrap().idx.compaction_analysis.file_analysis()
         };
 
-        let mut files = BTreeSet::new();
-        let mut triggered = false;
-
+        let mut files = Vec::new();
         for (file_id, fragmentation, dead_bytes) in compaction_analysis {
             if active_file_id.is_some() && file_id == active_file_id.unwrap() {
                 continue;
             }
 
-            if !triggered {
-                if fragmentation >= self.options.fragmentation_trigger {
-                    info!(
-                        "File {} has fragmentation factor of {:.1}%, compaction will start",
-                        file_id,
-                        fragmentation * 100.0
-                    );
-                    triggered = true;
-                    files.insert(file_id);
-                } else if dead_bytes >= self.options.dead_bytes_trigger && !files.contains(&file_id) {
-                    info!(
-                        "File {} has {} of dead data, triggered compaction",
-                        file_id,
-                        human_readable_byte_count(dead_bytes as usize, true)
-                    );
-                    triggered = true;
-                    files.insert(file_id);
-                }
-            }
-
-            if fragmentation >= self.options.fragmentation_threshold && !files.contains(&file_id) {
-                info!(
-                    "File {} has fragmentation factor of {:.1}%, adding for compaction",
-                    file_id,
-                    fragmentation * 100.0
-                );
-                files.insert(file_id);
-            } else if dead_bytes >= self.options.dead_bytes_threshold && !files.contains(&file_id) {
-                info!(
-                    "File {} has {} of dead data, adding for compaction",
+            let size = {
+                self.internal.read().unwrap().lsm.file_size(file_id)
+            };
+            // The file may have been removed since the analysis was taken.
+            if let Ok(size) = size {
+                files.push(FileInfo {
                     file_id,
-                    human_readable_byte_count(dead_bytes as usize, true)
-                );
-                files.insert(file_id);
+                    fragmentation,
+                    dead_bytes,
+                    size,
+                });
             }
+        }
 
-            if !files.contains(&file_id) {
-                let file_size = {
-                    self.internal.read().unwrap().lsm.file_size(file_id).ok()
-                };
-
-                if let Some(file_size) = file_size {
-                    if file_size <= self.options.small_file_threshold {
-                        info!(
-                            "File {} has total size of {}, adding for compaction",
-                            file_id,
-                            human_readable_byte_count(file_size as usize, true)
-                        );
-                        files.insert(file_id);
-                    }
-                };
-            }
+        let selected = self.options.compaction_strategy.select(&files, &self.options);
+        if selected.is_empty() {
+            info!("No files eligible for compaction");
+            return Ok(());
         }
 
-        if triggered {
-            let files: Vec<_> = files.into_iter().collect();
-            self.compact_files(&files)?;
-        } else if !files.is_empty() {
-            info!(
-                "Compaction of files {:?} aborted due to missing trigger",
-                &files
-            );
-        } else {
-            info!("No files eligible for compaction")
+        self.compact_files(&selected, false)
+    }
+}
+
+// A value written a chunk at a time, e.g. from a stream, without being held in memory
+// whole: once it reaches the blob threshold, see `StorageOptions::blob_threshold`, it goes
+// to a blob file of its own as the chunks arrive. The value is only visible once committed,
+// and its blob file is removed if it's dropped before. It holds no clone of the store,
+// whose drop would stop its background threads: its calls take the store it was started
+// from.
+pub struct ValueWriter {
+    key: Vec<u8>,
+    buffer: Vec<u8>,
+    blob_threshold: usize,
+    stream: Option<BlobStream>,
+}
+
+impl ValueWriter {
+    pub fn write(&mut self, db: &CrabeDB, chunk: &[u8]) -> Result<()> {
+        if let Some(ref mut stream) = self.stream {
+            return stream.write(chunk);
+        }
+        if self.buffer.len() + chunk.len() < self.blob_threshold {
+            self.buffer.extend_from_slice(chunk);
+            return Ok(());
         }
 
+        let db = db.partition(&self.key).unwrap_or(db);
+        let mut stream = db.internal.write().unwrap().lsm.blob_stream()?;
+        stream.write(&self.buffer)?;
+        stream.write(chunk)?;
+        self.buffer = Vec::new();
+        self.stream = Some(stream);
         Ok(())
     }
+
+    // The size of the value written so far.
+    pub fn size(&self) -> u64 {
+        match self.stream {
+            Some(ref stream) => stream.size(),
+            None => self.buffer.len() as u64,
+        }
+    }
+
+    // Like `CrabeDB::set_as`, returns the sequence number of the write.
+    pub fn commit(self, db: &CrabeDB, peer: Option<&str>) -> Result<u64> {
+        let seq = match self.stream {
+            Some(stream) => {
+                let db = db.partition(&self.key).unwrap_or(db);
+                let mut internal = db.internal.write().unwrap();
+                let seq = internal.put_blob(self.key.clone(), stream, peer)?;
+                internal.publish();
+                if db.writer.is_some() && db.options.sync == SyncOptions::Always {
+                    internal.sync()?;
+                }
+                seq
+            }
+            None => db.write_value(peer, self.key.clone(), self.buffer)?,
+        };
+        db.detach(&self.key, seq);
+        Ok(seq)
+    }
+
+    // Like `CrabeDB::merge`, the value being the one of the write of sequence number `seq`
+    // of another node.
+    pub fn merge(self, db: &CrabeDB, seq: u64) -> Result<bool> {
+        let stream = match self.stream {
+            Some(stream) => stream,
+            None => return db.merge(Log::new(seq, self.key, self.buffer)?),
+        };
+
+        let merged = {
+            let db = db.partition(&self.key).unwrap_or(db);
+            let mut internal = db.internal.write().unwrap();
+            internal.check_writable()?;
+            let merged = internal.merge_blob(seq, self.key.clone(), stream)?;
+            internal.publish();
+            if db.writer.is_some() && db.options.sync == SyncOptions::Always {
+                internal.sync()?;
+            }
+            merged
+        };
+        if merged {
+            db.detach(&self.key, seq);
+        }
+        Ok(merged)
+    }
 }
 
 impl Drop for CrabeDB {
@@ -0,0 +1,145 @@
+use std::convert::TryInto;
+use std::io::{Result, Write};
+use std::result::Result::Ok;
+
+// Reversed Castagnoli polynomial, the one of the SSE 4.2 and ARMv8 CRC instructions.
+const POLYNOMIAL: u32 = 0x82f6_3b78;
+
+// Tables of the slicing-by-8 software fallback.
+static TABLES: [[u32; 256]; 8] = tables();
+
+const fn tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0u32; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+
+    let mut slice = 1;
+    while slice < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let previous = tables[slice - 1][i];
+            tables[slice][i] = (previous >> 8) ^ tables[0][(previous & 0xff) as usize];
+            i += 1;
+        }
+        slice += 1;
+    }
+    tables
+}
+
+pub struct Crc32c(u32);
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32c {
+    pub fn new() -> Crc32c {
+        Crc32c(0)
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        self.0 = append(self.0, buf);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Write for Crc32c {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn crc32c(buf: &[u8]) -> u32 {
+    append(0, buf)
+}
+
+// Extend the CRC of the bytes before `buf` with it, using the CRC instructions of the CPU
+// when it has them (the detection is cached by the standard library).
+fn append(crc: u32, buf: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.2") {
+            return unsafe { append_hardware(crc, buf) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("crc") {
+            return unsafe { append_hardware(crc, buf) };
+        }
+    }
+    append_software(crc, buf)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn append_hardware(crc: u32, buf: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut crc = u64::from(!crc);
+    let mut words = buf.chunks_exact(8);
+    for word in &mut words {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &byte in words.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+    !crc
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "crc")]
+unsafe fn append_hardware(crc: u32, buf: &[u8]) -> u32 {
+    use std::arch::aarch64::{__crc32cb, __crc32cd};
+
+    let mut crc = !crc;
+    let mut words = buf.chunks_exact(8);
+    for word in &mut words {
+        crc = __crc32cd(crc, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    for &byte in words.remainder() {
+        crc = __crc32cb(crc, byte);
+    }
+    !crc
+}
+
+fn append_software(crc: u32, buf: &[u8]) -> u32 {
+    let mut crc = !crc;
+    let mut words = buf.chunks_exact(8);
+    for word in &mut words {
+        let low = crc ^ u32::from_le_bytes(word[..4].try_into().unwrap());
+        let high = u32::from_le_bytes(word[4..].try_into().unwrap());
+        crc = TABLES[7][(low & 0xff) as usize]
+            ^ TABLES[6][((low >> 8) & 0xff) as usize]
+            ^ TABLES[5][((low >> 16) & 0xff) as usize]
+            ^ TABLES[4][(low >> 24) as usize]
+            ^ TABLES[3][(high & 0xff) as usize]
+            ^ TABLES[2][((high >> 8) & 0xff) as usize]
+            ^ TABLES[1][((high >> 16) & 0xff) as usize]
+            ^ TABLES[0][(high >> 24) as usize];
+    }
+    for &byte in words.remainder() {
+        crc = TABLES[0][((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
@@ -0,0 +1,39 @@
+use std::cell::Cell;
+use std::time::Instant;
+
+use super::error::{Error, Result};
+
+thread_local! {
+    // Deadline of the request the thread is serving, past which its client gave up.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+// Restores the deadline of the thread, even if the call panics.
+struct Restore(Option<Instant>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        DEADLINE.with(|current| current.set(self.0));
+    }
+}
+
+// Run `f` with the given deadline on the current thread: the storage calls which may
+// block or read a lot of data (the writes stalled by the compaction, the scans, the reads
+// waiting for a sequence number) fail with `Error::DeadlineExceeded` once it's passed,
+// instead of completing for a client which isn't waiting anymore.
+pub fn with_deadline<T, F: FnOnce() -> T>(deadline: Option<Instant>, f: F) -> T {
+    let _restore = Restore(DEADLINE.with(|current| current.replace(deadline)));
+    f()
+}
+
+// The deadline of the current thread, if any.
+pub fn current() -> Option<Instant> {
+    DEADLINE.with(|current| current.get())
+}
+
+pub fn check() -> Result<()> {
+    match current() {
+        Some(deadline) if Instant::now() >= deadline => Err(Error::DeadlineExceeded),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::result::Result::Ok;
+use std::slice;
+
+use super::io_engine::IoEngine;
+
+// Alignment of the offsets, lengths and buffers of direct I/O, a multiple of the logical
+// block size of the usual devices.
+pub const BLOCK_SIZE: usize = 4096;
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct Block([u8; BLOCK_SIZE]);
+
+// Bytes of a file from a block boundary to its end, in memory aligned for direct I/O.
+struct AlignedBuffer {
+    blocks: Vec<Block>,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.blocks.as_ptr() as *const u8, self.len) }
+    }
+
+    fn extend_from_slice(&mut self, buf: &[u8]) {
+        let len = self.len + buf.len();
+        let blocks = len.div_ceil(BLOCK_SIZE);
+        if blocks > self.blocks.len() {
+            self.blocks.resize(blocks, Block([0; BLOCK_SIZE]));
+        }
+        let bytes = unsafe {
+            slice::from_raw_parts_mut(self.blocks.as_mut_ptr() as *mut u8, self.blocks.len() * BLOCK_SIZE)
+        };
+        bytes[self.len..len].copy_from_slice(buf);
+        self.len = len;
+    }
+
+    // Drop the `blocks` first blocks, keeping the bytes after them.
+    fn consume(&mut self, blocks: usize) {
+        self.blocks.drain(..blocks);
+        self.len -= blocks * BLOCK_SIZE;
+    }
+}
+
+// Appends to a data file without going through the page cache, so that a large ingest
+// doesn't evict the pages the readers depend on. The complete blocks are written with a
+// second handle opened for direct I/O, and the bytes of the last, incomplete one through
+// the page cache until it's complete, so that the readers of the file, which go through
+// the page cache, see every record as soon as it's appended. The kernel keeps both views
+// of the file coherent.
+pub struct DirectAppender {
+    file: File,
+    // The bytes of the file from `block_pos` on, always less than a block once appended.
+    pending: AlignedBuffer,
+    block_pos: u64,
+}
+
+impl DirectAppender {
+    // Append to the file at `path`, whose first bytes, less than a block, are `written`.
+    // Fails if the system or the file system doesn't support direct I/O.
+    pub fn open(path: &Path, written: &[u8]) -> io::Result<DirectAppender> {
+        let mut pending = AlignedBuffer {
+            blocks: Vec::new(),
+            len: 0,
+        };
+        pending.extend_from_slice(written);
+
+        Ok(DirectAppender {
+            file: open_direct(path)?,
+            pending,
+            block_pos: 0,
+        })
+    }
+
+    // Append `buf`, the bytes which are also written to `file`, the handle of the file
+    // going through the page cache.
+    pub fn append(&mut self, io_engine: &dyn IoEngine, file: &File, buf: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(buf);
+        let blocks = self.pending.len / BLOCK_SIZE;
+        let complete = blocks * BLOCK_SIZE;
+        if blocks > 0 {
+            io_engine.write_all_at(&self.file, &self.pending.as_slice()[..complete], self.block_pos)?;
+        }
+
+        // The bytes of `buf` in the last block.
+        let tail = (self.pending.len - complete).min(buf.len());
+        let end = self.block_pos + self.pending.len as u64;
+        io_engine.write_all_at(file, &buf[buf.len() - tail..], end - tail as u64)?;
+
+        self.pending.consume(blocks);
+        self.block_pos += complete as u64;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new().write(true).custom_flags(libc::O_DIRECT).open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "direct I/O requires Linux"))
+}
@@ -0,0 +1,377 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::warn;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::error::{Error, Result};
+use super::slot::Log;
+use super::tiering::{amz_date, endpoint_host, AwsCredentials};
+
+// The size of a data-encryption key, for AES-256-GCM.
+pub const KEY_SIZE: usize = 32;
+// What encrypting a value adds to it: its nonce and its tag.
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_LEN + 16;
+const KMS_CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+
+// Where the data-encryption keys of a `Keyring` come from when they aren't added to it,
+// so that they never live in a configuration file or on the command line. A key id always
+// names the same key: the keyring fetches each key once, but asks again for the current
+// key id every `Keyring::refresh_interval`, picking up the keys rotated in the provider.
+pub trait KeyProvider: Send + Sync {
+    // The id of the key the new files are encrypted with.
+    fn current_key_id(&self) -> Result<u32>;
+    // The key `key_id`, of `KEY_SIZE` bytes, or `Error::UnknownEncryptionKey`.
+    fn fetch_key(&self, key_id: u32) -> Result<Vec<u8>>;
+}
+
+// The keys of the environment variables named after a prefix and their key id, e.g.
+// `CRABEDB_ENCRYPTION_KEY_2`, holding a hex-encoded key. The key with the highest id is the
+// current one.
+pub struct EnvKeyProvider {
+    prefix: String,
+}
+
+impl EnvKeyProvider {
+    pub fn new(prefix: &str) -> EnvKeyProvider {
+        EnvKeyProvider {
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+impl Default for EnvKeyProvider {
+    fn default() -> EnvKeyProvider {
+        EnvKeyProvider::new("CRABEDB_ENCRYPTION_KEY_")
+    }
+}
+
+impl KeyProvider for EnvKeyProvider {
+    fn current_key_id(&self) -> Result<u32> {
+        env::vars_os()
+            .filter_map(|(name, _)| name.to_str()?.strip_prefix(&self.prefix)?.parse::<u32>().ok())
+            .max()
+            .ok_or_else(|| Error::InvalidKeyring(format!("no {}<id> variable", self.prefix)))
+    }
+
+    fn fetch_key(&self, key_id: u32) -> Result<Vec<u8>> {
+        let name = format!("{}{}", self.prefix, key_id);
+        let key = env::var(&name).map_err(|_| Error::UnknownEncryptionKey(key_id))?;
+        hex::decode(key.trim()).map_err(|_| Error::InvalidKeyring(format!("{} isn't hex-encoded", name)))
+    }
+}
+
+// Data keys wrapped by AWS KMS: a key file holds a key per line as its id and the
+// base64-encoded ciphertext of the key under a KMS key, separated by a space, e.g. the
+// `CiphertextBlob` of `aws kms generate-data-key --key-spec AES_256`. The wrapped keys are
+// useless without access to the KMS key, and are unwrapped with the `Decrypt` action of
+// KMS when first used. The key with the highest id is the current one.
+pub struct KmsKeyProvider {
+    path: PathBuf,
+    endpoint: String,
+    credentials: AwsCredentials,
+    agent: ureq::Agent,
+}
+
+impl KmsKeyProvider {
+    // `endpoint` is the URL of the service, by default `https://kms.<region>.amazonaws.com`.
+    pub fn new<P: AsRef<Path>>(path: P, endpoint: Option<&str>, credentials: AwsCredentials) -> KmsKeyProvider {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://kms.{}.amazonaws.com", credentials.region),
+        };
+        KmsKeyProvider {
+            path: path.as_ref().to_path_buf(),
+            endpoint,
+            credentials,
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(10))
+                .timeout_read(Duration::from_secs(30))
+                .build(),
+        }
+    }
+
+    // With the credentials of `AwsCredentials::from_env`.
+    pub fn from_env<P: AsRef<Path>>(path: P, endpoint: Option<&str>) -> Result<KmsKeyProvider> {
+        Ok(KmsKeyProvider::new(path, endpoint, AwsCredentials::from_env()?))
+    }
+
+    // The wrapped keys of the key file, which is read again every time.
+    fn wrapped_keys(&self) -> Result<Vec<(u32, Vec<u8>)>> {
+        let mut keys = Vec::new();
+        for (n, key_id, key) in read_key_file(&self.path)? {
+            let key = BASE64_STANDARD
+                .decode(key)
+                .map_err(|_| Error::InvalidKeyring(format!("invalid key at line {}", n)))?;
+            keys.push((key_id, key));
+        }
+        Ok(keys)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let body = json!({ "CiphertextBlob": BASE64_STANDARD.encode(ciphertext) }).to_string();
+        let amz_date = amz_date();
+        let target = "TrentService.Decrypt";
+        let authorization = self.credentials.authorization(
+            "kms",
+            "POST",
+            "/",
+            &[
+                ("content-type", KMS_CONTENT_TYPE),
+                ("host", endpoint_host(&self.endpoint)),
+                ("x-amz-date", &amz_date),
+                ("x-amz-target", target),
+            ],
+            &hex::encode(Sha256::digest(body.as_bytes())),
+            &amz_date,
+        );
+
+        let response = self
+            .agent
+            .post(&format!("{}/", self.endpoint))
+            .set("Content-Type", KMS_CONTENT_TYPE)
+            .set("X-Amz-Date", &amz_date)
+            .set("X-Amz-Target", target)
+            .set("Authorization", &authorization)
+            .send_string(&body)
+            .map_err(kms_error)?;
+        let response: serde_json::Value = serde_json::from_reader(response.into_reader()).map_err(io::Error::from)?;
+        let plaintext = response["Plaintext"]
+            .as_str()
+            .and_then(|plaintext| BASE64_STANDARD.decode(plaintext).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "KMS Decrypt returned no plaintext"))?;
+        Ok(plaintext)
+    }
+}
+
+impl KeyProvider for KmsKeyProvider {
+    fn current_key_id(&self) -> Result<u32> {
+        self.wrapped_keys()?
+            .iter()
+            .map(|&(key_id, _)| key_id)
+            .max()
+            .ok_or_else(|| Error::InvalidKeyring(format!("no key in {:?}", self.path)))
+    }
+
+    fn fetch_key(&self, key_id: u32) -> Result<Vec<u8>> {
+        let (_, wrapped) = self
+            .wrapped_keys()?
+            .into_iter()
+            .find(|&(id, _)| id == key_id)
+            .ok_or(Error::UnknownEncryptionKey(key_id))?;
+        self.decrypt(&wrapped)
+    }
+}
+
+fn kms_error(err: ureq::Error) -> Error {
+    let reason = match err {
+        ureq::Error::Status(status, response) => {
+            format!("status {}: {}", status, response.into_string().unwrap_or_default())
+        }
+        err => err.to_string(),
+    };
+    Error::Io(io::Error::other(format!("KMS Decrypt failed with {}", reason)))
+}
+
+// The data-encryption keys of a store, by key id. The new data files are encrypted with
+// the current key, whose id is written in their header (see `FLAG_ENCRYPTION`), while the
+// other keys are retained to read the older files until `CrabeDB::rewrap` rewrote them.
+// The keys are either added to it or fetched from a `KeyProvider`, and its clones share
+// them.
+#[derive(Clone)]
+pub struct Keyring {
+    state: Arc<RwLock<KeyringState>>,
+    provider: Option<Arc<dyn KeyProvider>>,
+    refresh_interval: Duration,
+}
+
+#[derive(Default)]
+struct KeyringState {
+    keys: HashMap<u32, Arc<LessSafeKey>>,
+    current: Option<u32>,
+    // When the current key id was last asked to the provider.
+    refreshed: Option<Instant>,
+}
+
+impl Keyring {
+    pub fn new() -> Keyring {
+        Keyring {
+            state: Arc::new(RwLock::new(KeyringState::default())),
+            provider: None,
+            refresh_interval: Duration::from_secs(300),
+        }
+    }
+
+    pub fn with_provider<P: KeyProvider + 'static>(provider: P) -> Keyring {
+        Keyring {
+            provider: Some(Arc::new(provider)),
+            ..Keyring::new()
+        }
+    }
+
+    // How long the current key id of the provider is cached.
+    pub fn refresh_interval(&mut self, refresh_interval: Duration) -> &mut Keyring {
+        self.refresh_interval = refresh_interval;
+        self
+    }
+
+    // Forget the keys fetched from the provider and its current key id, e.g. after a key
+    // was revoked.
+    pub fn refresh(&self) {
+        if self.provider.is_some() {
+            *self.state.write().unwrap() = KeyringState::default();
+        }
+    }
+
+    // Add a key of `KEY_SIZE` bytes. The key with the highest id is the current one,
+    // unless another one is chosen with `current`.
+    pub fn add_key(&mut self, key_id: u32, key: &[u8]) -> Result<&mut Keyring> {
+        let key = new_key(key_id, key)?;
+        let mut state = self.state.write().unwrap();
+        state.keys.insert(key_id, Arc::new(key));
+        if state.current.is_none_or(|current| current < key_id) {
+            state.current = Some(key_id);
+        }
+        drop(state);
+        Ok(self)
+    }
+
+    pub fn current(&mut self, key_id: u32) -> Result<&mut Keyring> {
+        let mut state = self.state.write().unwrap();
+        if !state.keys.contains_key(&key_id) {
+            return Err(Error::UnknownEncryptionKey(key_id));
+        }
+        state.current = Some(key_id);
+        drop(state);
+        Ok(self)
+    }
+
+    // Load the keys of a key file, holding a key per line as its id and its hex-encoded
+    // bytes separated by a space. Empty lines and lines starting with `#` are skipped.
+    // A key is rotated by appending a new key with a higher id.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Keyring> {
+        let mut keyring = Keyring::new();
+        for (n, key_id, key) in read_key_file(path.as_ref())? {
+            let key = hex::decode(key).map_err(|_| Error::InvalidKeyring(format!("invalid key at line {}", n)))?;
+            keyring.add_key(key_id, &key)?;
+        }
+        Ok(keyring)
+    }
+
+    // The id of the key of the new files.
+    pub fn current_key_id(&self) -> Result<u32> {
+        if let Some(ref provider) = self.provider {
+            let stale = self
+                .state
+                .read()
+                .unwrap()
+                .refreshed
+                .is_none_or(|refreshed| refreshed.elapsed() >= self.refresh_interval);
+            if stale {
+                let mut state = self.state.write().unwrap();
+                match provider.current_key_id() {
+                    Ok(key_id) => state.current = Some(key_id),
+                    // The last known key is kept while the provider is unavailable.
+                    Err(err) if state.current.is_some() => {
+                        warn!("Failed to refresh the current encryption key: {}", err)
+                    }
+                    Err(err) => return Err(err),
+                }
+                state.refreshed = Some(Instant::now());
+            }
+        }
+        self.state.read().unwrap().current.ok_or_else(|| Error::InvalidKeyring("no key".to_string()))
+    }
+
+    fn key(&self, key_id: u32) -> Result<Arc<LessSafeKey>> {
+        if let Some(key) = self.state.read().unwrap().keys.get(&key_id) {
+            return Ok(key.clone());
+        }
+        let provider = self.provider.as_ref().ok_or(Error::UnknownEncryptionKey(key_id))?;
+        let key = Arc::new(new_key(key_id, &provider.fetch_key(key_id)?)?);
+        self.state.write().unwrap().keys.insert(key_id, key.clone());
+        Ok(key)
+    }
+
+    // The nonce followed by the ciphertext and its tag.
+    pub fn encrypt(&self, key_id: u32, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let key = self.key(key_id)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| io::Error::other("no random nonce available"))?;
+
+        let mut in_out = plaintext.to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out)
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        let mut encrypted = Vec::with_capacity(NONCE_LEN + in_out.len());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&in_out);
+        Ok(encrypted)
+    }
+
+    pub fn decrypt(&self, key_id: u32, encrypted: &[u8]) -> Result<Vec<u8>> {
+        let key = self.key(key_id)?;
+        if encrypted.len() < ENCRYPTION_OVERHEAD {
+            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+        let mut in_out = ciphertext.to_vec();
+        let len = key.open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("decryption with key {} failed", key_id)))?
+            .len();
+        in_out.truncate(len);
+        Ok(in_out)
+    }
+
+    // Replace the value of a record by its encrypted form. Tombstones and blob pointers
+    // are left alone.
+    pub fn encrypt_log(&self, key_id: u32, log: &mut Log) -> Result<()> {
+        if !log.deleted && !log.range && !log.blob {
+            log.value = Cow::Owned(self.encrypt(key_id, &log.value)?);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Keyring {
+    fn default() -> Keyring {
+        Keyring::new()
+    }
+}
+
+fn new_key(key_id: u32, key: &[u8]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&AES_256_GCM, key)
+        .map_err(|_| Error::InvalidKeyring(format!("key {} isn't {} bytes long", key_id, KEY_SIZE)))?;
+    Ok(LessSafeKey::new(key))
+}
+
+// The line number, id and encoded key of the keys of a key file. Empty lines and lines
+// starting with `#` are skipped, the others hold a key id and a key separated by a space.
+fn read_key_file(path: &Path) -> Result<Vec<(usize, u32, String)>> {
+    let content = fs::read_to_string(path)?;
+    let mut keys = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || Error::InvalidKeyring(format!("invalid key at line {}", n + 1));
+        let (key_id, key) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let key_id = key_id.parse::<u32>().map_err(|_| invalid())?;
+        keys.push((n + 1, key_id, key.trim().to_string()));
+    }
+    Ok(keys)
+}
@@ -4,6 +4,7 @@ use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::result;
+use std::time::SystemTime;
 
 use tonic::{Status, Code};
 
@@ -15,12 +16,63 @@ pub enum Error {
     InvalidFileId(u32),
     InvalidKeySize(usize),
     InvalidValueSize(usize),
-    InvalidChecksum { expected: u32, found: u32 },
+    InvalidChecksum { expected: u64, found: u64 },
     InvalidPath(String),
+    InvalidManifest(String),
+    InvalidArchive(String),
+    InvalidSstFile(String),
+    UnsupportedFormat { version: u16, flags: u16 },
+    CompactionPaused,
+    ReadOnly,
+    NotStandby,
+    SequenceNotApplied { seq: u64, applied: u64 },
+    LeaseNotFound(u64),
+    WritesFenced(BackgroundError),
+    Busy(String),
+    DeadlineExceeded,
+    InUse,
+    Closed,
+    DuplicateFileId(u32),
+    FileIdsExhausted,
+    UnknownEncryptionKey(u32),
+    InvalidKeyring(String),
+    TombstonesPurged { seq: u64 },
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+// The first I/O error of a background task, e.g. a failed sync of the data files, after
+// which the writes are refused until `CrabeDB::clear_background_error`: the records
+// written since the last successful sync may not be durable.
+#[derive(Clone, Debug)]
+pub struct BackgroundError {
+    // The task which failed: "sync" or "compaction".
+    pub task: &'static str,
+    pub kind: io::ErrorKind,
+    pub message: String,
+    pub time: SystemTime,
+}
+
+impl Display for BackgroundError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "background {} failed: {}", self.task, self.message)
+    }
+}
+
+// Notified of the first error of a background task, from the thread of the task.
+pub trait BackgroundErrorHandler: Send + Sync {
+    fn on_error(&self, error: &BackgroundError);
+}
+
+impl<F> BackgroundErrorHandler for F
+where
+    F: Fn(&BackgroundError) + Send + Sync,
+{
+    fn on_error(&self, error: &BackgroundError) {
+        self(error)
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
@@ -51,6 +103,43 @@ impl Display for Error {
                 )
             }
             Error::InvalidPath(ref path) => write!(f, "Invalid path provided: {}", path),
+            Error::InvalidManifest(ref reason) => write!(f, "Invalid manifest: {}", reason),
+            Error::InvalidArchive(ref reason) => write!(f, "Invalid archive: {}", reason),
+            Error::InvalidSstFile(ref reason) => write!(f, "Invalid SST file: {}", reason),
+            Error::UnsupportedFormat { version, flags } => {
+                write!(
+                    f,
+                    "Unsupported file format, version: {}, flags: {:#06x}",
+                    version,
+                    flags
+                )
+            }
+            Error::CompactionPaused => write!(f, "Compaction is paused"),
+            Error::ReadOnly => write!(f, "The store is opened read-only"),
+            Error::NotStandby => write!(f, "The store is not a standby"),
+            Error::SequenceNotApplied { seq, applied } => {
+                write!(
+                    f,
+                    "Sequence number not applied yet: {}, last applied: {}",
+                    seq,
+                    applied
+                )
+            }
+            Error::LeaseNotFound(id) => write!(f, "Lease not found: {}", id),
+            Error::WritesFenced(ref error) => {
+                write!(f, "Writes are fenced after a {}", error)
+            }
+            Error::Busy(ref reason) => write!(f, "The store is busy: {}", reason),
+            Error::DeadlineExceeded => write!(f, "The deadline of the request has passed"),
+            Error::InUse => write!(f, "The store is opened by another handle"),
+            Error::Closed => write!(f, "The store has been closed"),
+            Error::DuplicateFileId(file_id) => write!(f, "Several files have the id {}", file_id),
+            Error::FileIdsExhausted => write!(f, "No file id left, the store must be renumbered"),
+            Error::UnknownEncryptionKey(key_id) => write!(f, "Unknown encryption key: {}", key_id),
+            Error::InvalidKeyring(ref reason) => write!(f, "Invalid keyring: {}", reason),
+            Error::TombstonesPurged { seq } => {
+                write!(f, "Tombstones written after sequence number {} may have been purged", seq)
+            }
         }
     }
 }
@@ -62,8 +151,19 @@ impl From<io::Error> for Error {
 }
 
 impl From<Error> for Status {
-    fn from(_: Error) -> Self {
-        Status::new(Code::Internal, "CrabeDB internal error.")
+    fn from(err: Error) -> Self {
+        match err {
+            // The client is expected to back off and retry.
+            Error::Busy(_) => Status::new(Code::ResourceExhausted, err.to_string()),
+            Error::DeadlineExceeded => Status::new(Code::DeadlineExceeded, err.to_string()),
+            // The standby has to be seeded again.
+            Error::TombstonesPurged { .. } => Status::new(Code::OutOfRange, err.to_string()),
+            // e.g. a forwarded write whose sequence number is out of range.
+            Error::Io(ref io) if io.kind() == io::ErrorKind::InvalidInput => {
+                Status::new(Code::InvalidArgument, err.to_string())
+            }
+            _ => Status::new(Code::Internal, "CrabeDB internal error."),
+        }
     }
 }
 
@@ -77,6 +177,25 @@ impl error::Error for Error {
             Error::InvalidKeySize(..) => "Invalid key size",
             Error::InvalidValueSize(..) => "Invalid value size",
             Error::InvalidPath(..) => "Invalid path",
+            Error::InvalidManifest(..) => "Invalid manifest",
+            Error::InvalidArchive(..) => "Invalid archive",
+            Error::InvalidSstFile(..) => "Invalid SST file",
+            Error::UnsupportedFormat { .. } => "Unsupported file format",
+            Error::CompactionPaused => "Compaction is paused",
+            Error::ReadOnly => "The store is opened read-only",
+            Error::NotStandby => "The store is not a standby",
+            Error::SequenceNotApplied { .. } => "Sequence number not applied yet",
+            Error::LeaseNotFound(..) => "Lease not found",
+            Error::WritesFenced(..) => "Writes are fenced after a background error",
+            Error::Busy(..) => "The store is busy",
+            Error::DeadlineExceeded => "The deadline of the request has passed",
+            Error::InUse => "The store is opened by another handle",
+            Error::Closed => "The store has been closed",
+            Error::DuplicateFileId(..) => "Several files have the same id",
+            Error::FileIdsExhausted => "No file id left",
+            Error::UnknownEncryptionKey(..) => "Unknown encryption key",
+            Error::InvalidKeyring(..) => "Invalid keyring",
+            Error::TombstonesPurged { .. } => "Tombstones may have been purged",
         }
     }
 }
\ No newline at end of file
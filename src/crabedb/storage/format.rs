@@ -0,0 +1,255 @@
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::result::Result::Ok;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::error::{Error, Result};
+use super::options::ChecksumKind;
+
+pub const DATA_FILE_MAGIC: &[u8; 4] = b"CRBD";
+pub const HINT_FILE_MAGIC: &[u8; 4] = b"CRBH";
+pub const BLOB_FILE_MAGIC: &[u8; 4] = b"CRBB";
+
+// Files written before headers were introduced have no header at all: they are
+// reported as version 0 and share the record layout of version 1.
+pub const LEGACY_FORMAT_VERSION: u16 = 0;
+pub const FORMAT_VERSION_1: u16 = 1;
+// Version 2 added the creation timestamp of the records and hints.
+pub const FORMAT_VERSION: u16 = 2;
+// Version 3 widened the checksums of the records and of the hint files to 64 bits
+// (xxHash64). It's only written with `ChecksumKind::XxHash64`, so that the files of the
+// other stores remain readable by older versions.
+pub const FORMAT_VERSION_3: u16 = 3;
+const FILE_HEADER_SIZE: u64 = 8; // magic(4) + version(2) + flags(2)
+// The largest header, with the highest sequence number, but without a dictionary.
+pub const MAX_FILE_HEADER_SIZE: u64 = FILE_HEADER_SIZE + 8;
+// Offset of the highest sequence number in the header, see `FLAG_MAX_SEQ`.
+pub const MAX_SEQ_OFFSET: u64 = FILE_HEADER_SIZE;
+
+// The header of a data file ends with the size of a zstd dictionary and the dictionary,
+// possibly empty, and the values of its records are compressed with it, see
+// `compression::ValueCompressor`. Only the files written by a compaction have it.
+pub const FLAG_COMPRESSION: u16 = 1;
+// The header of a data file is followed by the id of the key of `encryption::Keyring`
+// with which the values of its records, and its dictionary, are encrypted.
+pub const FLAG_ENCRYPTION: u16 = 1 << 1;
+pub const FLAG_TIMESTAMPS: u16 = 1 << 2;
+// The high bit of the value size of a record marks a value stored in a blob file.
+pub const FLAG_BLOB_POINTERS: u16 = 1 << 3;
+// The next bit marks a range tombstone, whose value is the end of the range.
+pub const FLAG_RANGE_TOMBSTONES: u16 = 1 << 4;
+// The records are checksummed with CRC32C instead of xxHash32.
+pub const FLAG_CRC32C: u16 = 1 << 5;
+// The header of a data file is followed by the highest sequence number of its records,
+// written once the file is sealed and 0 until then, so that the sequence number of a
+// store doesn't depend on its hint files alone. The seal of the file is the hash of its
+// content with the field left to 0, as written.
+pub const FLAG_MAX_SEQ: u16 = 1 << 6;
+// The hint file of a data file ends with the range of keys and sequence numbers of its
+// records, see `summary::FileSummary`.
+pub const FLAG_FILE_SUMMARY: u16 = 1 << 7;
+// Every hint is followed by its own checksum, of the kind of the trailing one of the hint
+// file, so that the hints of a damaged hint file are salvaged up to the first bad one.
+pub const FLAG_HINT_CHECKSUMS: u16 = 1 << 8;
+const SUPPORTED_FLAGS: u16 = FLAG_COMPRESSION
+    | FLAG_ENCRYPTION
+    | FLAG_BLOB_POINTERS
+    | FLAG_RANGE_TOMBSTONES
+    | FLAG_CRC32C
+    | FLAG_MAX_SEQ
+    | FLAG_FILE_SUMMARY
+    | FLAG_HINT_CHECKSUMS;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileHeader {
+    pub version: u16,
+    pub flags: u16,
+    // The size of the dictionary of a compressed file, see `FLAG_COMPRESSION`.
+    pub dictionary_size: u32,
+    // The key of an encrypted file, see `FLAG_ENCRYPTION`.
+    pub key_id: u32,
+}
+
+impl FileHeader {
+    pub fn current() -> FileHeader {
+        FileHeader {
+            version: FORMAT_VERSION,
+            flags: FLAG_BLOB_POINTERS | FLAG_RANGE_TOMBSTONES,
+            dictionary_size: 0,
+            key_id: 0,
+        }
+    }
+
+    // The header of a new data file whose records are checksummed with `checksum`.
+    pub fn with_checksum(checksum: ChecksumKind) -> FileHeader {
+        let mut header = FileHeader::current();
+        header.flags |= FLAG_MAX_SEQ | FLAG_FILE_SUMMARY | FLAG_HINT_CHECKSUMS;
+        match checksum {
+            ChecksumKind::XxHash32 => {}
+            ChecksumKind::Crc32c => header.flags |= FLAG_CRC32C,
+            ChecksumKind::XxHash64 => header.version = FORMAT_VERSION_3,
+        }
+        header
+    }
+
+    pub fn legacy() -> FileHeader {
+        FileHeader {
+            version: LEGACY_FORMAT_VERSION,
+            flags: 0,
+            dictionary_size: 0,
+            key_id: 0,
+        }
+    }
+
+    // The header of a file whose values are compressed with `dictionary`, as stored.
+    pub fn with_dictionary(mut self, dictionary: &[u8]) -> FileHeader {
+        self.flags |= FLAG_COMPRESSION;
+        self.dictionary_size = dictionary.len() as u32;
+        self
+    }
+
+    // The header of a file whose values are encrypted with the key `key_id`.
+    pub fn with_key(mut self, key_id: u32) -> FileHeader {
+        self.flags |= FLAG_ENCRYPTION;
+        self.key_id = key_id;
+        self
+    }
+
+    // The header of the hint file of a data file: the hints are neither compressed nor
+    // encrypted.
+    pub fn hint_header(mut self) -> FileHeader {
+        self.flags &= !(FLAG_COMPRESSION | FLAG_ENCRYPTION);
+        self.dictionary_size = 0;
+        self.key_id = 0;
+        self
+    }
+
+    pub fn size(&self) -> u64 {
+        if self.version == LEGACY_FORMAT_VERSION {
+            return 0;
+        }
+        let mut size = if self.has_flag(FLAG_MAX_SEQ) { MAX_FILE_HEADER_SIZE } else { FILE_HEADER_SIZE };
+        if self.has_flag(FLAG_ENCRYPTION) {
+            size += 4;
+        }
+        if self.has_flag(FLAG_COMPRESSION) {
+            size + 4 + u64::from(self.dictionary_size)
+        } else {
+            size
+        }
+    }
+
+    // Offset of the dictionary of a compressed file, which ends the header.
+    pub fn dictionary_offset(&self) -> u64 {
+        self.size() - u64::from(self.dictionary_size)
+    }
+
+    pub fn has_timestamps(&self) -> bool {
+        self.version >= 2
+    }
+
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn has_wide_checksums(&self) -> bool {
+        self.version >= FORMAT_VERSION_3
+    }
+
+    // The checksum of the records.
+    pub fn checksum(&self) -> ChecksumKind {
+        if self.has_wide_checksums() {
+            ChecksumKind::XxHash64
+        } else if self.has_flag(FLAG_CRC32C) {
+            ChecksumKind::Crc32c
+        } else {
+            ChecksumKind::XxHash32
+        }
+    }
+
+    // The checksum covering a whole hint file, which follows its last hint.
+    pub fn hint_file_checksum(&self) -> ChecksumKind {
+        if self.has_wide_checksums() {
+            ChecksumKind::XxHash64
+        } else {
+            ChecksumKind::XxHash32
+        }
+    }
+
+    // The dictionary of a compressed file, which the caller writes next, isn't included.
+    pub fn write_bytes<W: Write>(&self, magic: &[u8; 4], writer: &mut W) -> Result<()> {
+        writer.write_all(magic)?;
+        writer.write_u16::<LittleEndian>(self.version)?;
+        writer.write_u16::<LittleEndian>(self.flags)?;
+        if self.has_flag(FLAG_MAX_SEQ) {
+            writer.write_u64::<LittleEndian>(0)?;
+        }
+        if self.has_flag(FLAG_ENCRYPTION) {
+            writer.write_u32::<LittleEndian>(self.key_id)?;
+        }
+        if self.has_flag(FLAG_COMPRESSION) {
+            writer.write_u32::<LittleEndian>(self.dictionary_size)?;
+        }
+        Ok(())
+    }
+
+    // Leaves the reader positioned on the first record, whatever the version.
+    pub fn from_read<R: Read + Seek>(magic: &[u8; 4], reader: &mut R) -> Result<FileHeader> {
+        let mut buf = [0u8; FILE_HEADER_SIZE as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            match reader.read(&mut buf[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        if read < buf.len() || &buf[..4] != magic {
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(FileHeader::legacy());
+        }
+
+        let mut cursor = &buf[4..];
+        let mut header = FileHeader {
+            version: cursor.read_u16::<LittleEndian>()?,
+            flags: cursor.read_u16::<LittleEndian>()?,
+            dictionary_size: 0,
+            key_id: 0,
+        };
+        header.check_supported()?;
+        if header.has_flag(FLAG_MAX_SEQ) {
+            reader.read_u64::<LittleEndian>()?;
+        }
+        if header.has_flag(FLAG_ENCRYPTION) {
+            header.key_id = reader.read_u32::<LittleEndian>()?;
+        }
+        if header.has_flag(FLAG_COMPRESSION) {
+            header.dictionary_size = reader.read_u32::<LittleEndian>()?;
+            reader.seek(SeekFrom::Current(i64::from(header.dictionary_size)))?;
+        }
+        Ok(header)
+    }
+
+    // The highest sequence number in the header of a data file, if it has one and was
+    // sealed. Leaves the reader positioned on the first record.
+    pub fn read_max_seq<R: Read + Seek>(reader: &mut R) -> Result<Option<u64>> {
+        let header = FileHeader::from_read(DATA_FILE_MAGIC, reader)?;
+        if !header.has_flag(FLAG_MAX_SEQ) {
+            return Ok(None);
+        }
+        reader.seek(SeekFrom::Start(MAX_SEQ_OFFSET))?;
+        let max_seq = reader.read_u64::<LittleEndian>()?;
+        Ok(if max_seq > 0 { Some(max_seq) } else { None })
+    }
+
+    pub fn check_supported(&self) -> Result<()> {
+        if self.version > FORMAT_VERSION_3 || self.flags & !SUPPORTED_FLAGS != 0 {
+            return Err(Error::UnsupportedFormat {
+                version: self.version,
+                flags: self.flags,
+            });
+        }
+        Ok(())
+    }
+}
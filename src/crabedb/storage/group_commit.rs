@@ -0,0 +1,153 @@
+use std::io;
+use std::result::Result::Ok;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+use log::{debug, info};
+
+use super::crabe_db::CrabeDBinternal;
+use super::error::{Error, Result};
+
+// Upper bound on the number of writes applied (and synced) together.
+const MAX_GROUP_COMMIT_SIZE: usize = 1024;
+
+pub enum WriteOp {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    RemoveRange(Vec<u8>, Vec<u8>),
+}
+
+struct WriteRequest {
+    op: WriteOp,
+    peer: Option<String>,
+    sync: bool,
+    done: Sender<Result<u64>>,
+}
+
+// Resolved once the write has been appended to the data files and, when the store
+// syncs every write, once the data file has been synced. It yields the sequence number of
+// the write.
+pub struct WriteHandle {
+    done: Receiver<Result<u64>>,
+}
+
+impl WriteHandle {
+    pub fn ready(result: Result<u64>) -> WriteHandle {
+        let (sender, done) = channel();
+        let _ = sender.send(result);
+        WriteHandle { done }
+    }
+
+    pub fn wait(self) -> Result<u64> {
+        self.done.recv().unwrap_or_else(|_| {
+            Err(Error::Io(io::Error::other(
+                "the writer thread exited before completing the write",
+            )))
+        })
+    }
+}
+
+// Every append goes through a single background thread. It drains all the writes queued
+// while it was busy, applies them under one acquisition of the store lock and issues a
+// single `sync_data` for the whole group.
+#[derive(Clone)]
+pub struct GroupCommitWriter {
+    // `None` stops the thread, once the writes queued before it are applied.
+    requests: Sender<Option<WriteRequest>>,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl GroupCommitWriter {
+    pub fn spawn(internal: Arc<RwLock<CrabeDBinternal>>) -> GroupCommitWriter {
+        let (requests, receiver) = channel();
+
+        let thread = thread::spawn(move || {
+            run(internal, receiver);
+            info!("CrabeDB has been dropped, writer thread is exiting");
+        });
+
+        GroupCommitWriter {
+            requests,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        }
+    }
+
+    // Apply the writes already queued and wait for the thread to exit. The later ones
+    // fail.
+    pub fn shutdown(&self) {
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = self.requests.send(None);
+            let _ = thread.join();
+        }
+    }
+
+    pub fn submit(&self, op: WriteOp, peer: Option<&str>, sync: bool) -> WriteHandle {
+        let (done, handle) = channel();
+        let peer = peer.map(String::from);
+        let request = WriteRequest { op, peer, sync, done };
+
+        match self.requests.send(Some(request)) {
+            Ok(()) => WriteHandle { done: handle },
+            Err(_) if self.thread.lock().unwrap().is_none() => WriteHandle::ready(Err(Error::Closed)),
+            Err(_) => WriteHandle::ready(Err(Error::Io(io::Error::other(
+                "the writer thread is not running",
+            )))),
+        }
+    }
+}
+
+fn run(internal: Arc<RwLock<CrabeDBinternal>>, receiver: Receiver<Option<WriteRequest>>) {
+    let mut stopped = false;
+    while let Ok(Some(request)) = receiver.recv() {
+        let mut group = vec![request];
+        while group.len() < MAX_GROUP_COMMIT_SIZE {
+            match receiver.try_recv() {
+                Ok(Some(request)) => group.push(request),
+                Ok(None) => {
+                    stopped = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        debug!("Committing a group of {} writes", group.len());
+
+        let mut waiters = Vec::with_capacity(group.len());
+        let results: Vec<Result<u64>> = {
+            let mut internal = internal.write().unwrap();
+            let results = group
+                .into_iter()
+                .map(|request| {
+                    waiters.push((request.sync, request.done));
+                    let peer = request.peer.as_deref();
+                    match request.op {
+                        WriteOp::Set(key, value) => internal.put(key, &value, peer),
+                        WriteOp::Remove(key) => internal.delete(&key, peer),
+                        WriteOp::RemoveRange(start, end) => internal.delete_range(&start, &end, peer),
+                    }
+                })
+                .collect();
+            internal.publish();
+            results
+        };
+
+        let sync_result = if waiters.iter().any(|&(sync, _)| sync) {
+            internal.read().unwrap().sync()
+        } else {
+            Ok(())
+        };
+
+        for ((sync, done), result) in waiters.into_iter().zip(results) {
+            let result = match (result, &sync_result) {
+                (Ok(_), Err(err)) if sync => Err(Error::Io(io::Error::other(err.to_string()))),
+                (result, _) => result,
+            };
+            let _ = done.send(result);
+        }
+
+        if stopped {
+            break;
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::result::Result::Ok;
+use std::sync::Arc;
+
+use super::error::Result;
+use super::options::IoEngineKind;
+
+// Positioned I/O on the data files. Offsets are always explicit so a single handle can
+// be shared by concurrent readers and the writer.
+pub trait IoEngine: Send + Sync {
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    fn write_all_at(&self, file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(file, buf, offset)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => {
+                    buf = &buf[written..];
+                    offset += written as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn new_io_engine(kind: IoEngineKind) -> Result<Arc<dyn IoEngine>> {
+    match kind {
+        IoEngineKind::Sync => Ok(Arc::new(SyncEngine)),
+        IoEngineKind::IoUring => new_uring_engine(),
+    }
+}
+
+// Plain `pread`/`pwrite` system calls.
+pub struct SyncEngine;
+
+#[cfg(unix)]
+impl IoEngine for SyncEngine {
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(file, buf, offset)
+    }
+
+    fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(file, buf, offset)
+    }
+}
+
+#[cfg(windows)]
+impl IoEngine for SyncEngine {
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+    }
+
+    fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn new_uring_engine() -> Result<Arc<dyn IoEngine>> {
+    // Fail at load time rather than on the first read if the kernel doesn't support it.
+    uring::with_ring(|_| Ok(()))?;
+    Ok(Arc::new(uring::UringEngine))
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+fn new_uring_engine() -> Result<Arc<dyn IoEngine>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the io_uring engine requires Linux and the `io-uring` feature",
+    ).into())
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring {
+    use std::cell::{Cell, RefCell};
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::Duration;
+
+    use io_uring::{opcode, squeue, types, IoUring};
+    use log::warn;
+
+    use super::IoEngine;
+
+    const RING_ENTRIES: u32 = 32;
+    // Failed waits for a completion, other than the interrupted ones, after which the
+    // entry is cancelled.
+    const MAX_WAIT_FAILURES: u32 = 5;
+    // The wait after a failed one, doubled each time up to the maximum.
+    const WAIT_BACKOFF: Duration = Duration::from_millis(1);
+    const MAX_WAIT_BACKOFF: Duration = Duration::from_millis(500);
+
+    thread_local! {
+        // One ring per thread: concurrent readers never contend on a shared ring.
+        static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+        static NEXT_USER_DATA: Cell<u64> = const { Cell::new(0) };
+    }
+
+    pub fn with_ring<T, F>(f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut IoUring) -> io::Result<T>,
+    {
+        RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            if ring.is_none() {
+                *ring = Some(IoUring::new(RING_ENTRIES)?);
+            }
+            f(ring.as_mut().unwrap())
+        })
+    }
+
+    fn next_user_data() -> u64 {
+        NEXT_USER_DATA.with(|next| {
+            let user_data = next.get();
+            next.set(user_data.wrapping_add(1));
+            user_data
+        })
+    }
+
+    // Submit `entry` and wait for its completion, recognized by its `user_data`: the
+    // completions left by a ring dropped after a failure are skipped. Once the kernel has
+    // consumed the entry, its buffer may be accessed until it completes, so this never
+    // returns in between, even when the wait is interrupted. After `MAX_WAIT_FAILURES`
+    // failed waits the entry is cancelled, so that it completes soon, and the waits go on
+    // with a growing backoff.
+    fn submit(entry: squeue::Entry) -> io::Result<usize> {
+        let user_data = next_user_data();
+        let entry = entry.user_data(user_data);
+
+        RING.with(|cell| {
+            let mut cell = cell.borrow_mut();
+            if cell.is_none() {
+                *cell = Some(IoUring::new(RING_ENTRIES)?);
+            }
+            let ring = cell.as_mut().unwrap();
+
+            // The buffer outlives the call: we wait for the completion before returning.
+            unsafe {
+                ring.submission()
+                    .push(&entry)
+                    .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            }
+            let (mut failures, mut backoff, mut cancelled) = (0, WAIT_BACKOFF, false);
+            loop {
+                if let Err(err) = ring.submit_and_wait(1) {
+                    let retry = err.kind() == io::ErrorKind::Interrupted
+                        || err.kind() == io::ErrorKind::WouldBlock
+                        || err.raw_os_error() == Some(libc::EBUSY);
+                    if !retry && !cancelled && !ring.submission().is_empty() {
+                        // The kernel never saw the entry: drop the ring so that it never
+                        // does once the buffer is gone.
+                        *cell = None;
+                        return Err(err);
+                    }
+                    if !retry {
+                        warn!("Waiting for an io_uring completion failed, retrying in {:?}: {}", backoff, err);
+                        failures += 1;
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_WAIT_BACKOFF);
+                    }
+                    if failures >= MAX_WAIT_FAILURES && !cancelled {
+                        let cancel = opcode::AsyncCancel::new(user_data).build().user_data(next_user_data());
+                        // Pushed again on the next failure when the queue is full.
+                        cancelled = unsafe { ring.submission().push(&cancel).is_ok() };
+                        if cancelled {
+                            warn!("Cancelling the io_uring entry after {} failed waits", failures);
+                        }
+                    }
+                }
+
+                for cqe in ring.completion() {
+                    if cqe.user_data() != user_data {
+                        continue;
+                    }
+                    return if cqe.result() < 0 {
+                        Err(io::Error::from_raw_os_error(-cqe.result()))
+                    } else {
+                        Ok(cqe.result() as usize)
+                    };
+                }
+            }
+        })
+    }
+
+    pub struct UringEngine;
+
+    impl IoEngine for UringEngine {
+        fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let entry = opcode::Read::new(
+                types::Fd(file.as_raw_fd()),
+                buf.as_mut_ptr(),
+                buf.len() as u32,
+            ).offset(offset)
+                .build();
+            submit(entry)
+        }
+
+        fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+            let entry = opcode::Write::new(
+                types::Fd(file.as_raw_fd()),
+                buf.as_ptr(),
+                buf.len() as u32,
+            ).offset(offset)
+                .build();
+            submit(entry)
+        }
+    }
+}
+
+// Reads a file from a given offset without touching the position of the handle.
+pub struct PositionedReader<'a> {
+    engine: &'a dyn IoEngine,
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> PositionedReader<'a> {
+    pub fn new(engine: &'a dyn IoEngine, file: &'a File, pos: u64) -> PositionedReader<'a> {
+        PositionedReader { engine, file, pos }
+    }
+}
+
+impl<'a> Read for PositionedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.engine.read_at(self.file, buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
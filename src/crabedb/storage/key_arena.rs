@@ -0,0 +1,102 @@
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
+use std::ptr::NonNull;
+use std::slice;
+
+// Size of the slabs the keys are copied to, much larger than the largest key.
+const SLAB_SIZE: usize = 1024 * 1024;
+
+// A key copied to a `KeyArena`. It's only valid as long as the arena which allocated it,
+// and is compared and hashed as the bytes it points to, so that a map of `ArenaKey`s is
+// looked up with a `&[u8]`.
+pub struct ArenaKey {
+    ptr: NonNull<u8>,
+    len: u32,
+}
+
+// The bytes of the key are never written to once copied to the arena.
+unsafe impl Send for ArenaKey {}
+unsafe impl Sync for ArenaKey {}
+
+impl ArenaKey {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len as usize) }
+    }
+}
+
+impl Borrow<[u8]> for ArenaKey {
+    fn borrow(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Hash for ArenaKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state)
+    }
+}
+
+impl PartialEq for ArenaKey {
+    fn eq(&self, other: &ArenaKey) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for ArenaKey {}
+
+// Keys of the index packed in large slabs instead of an allocation each, which would
+// roughly double the memory used by small keys. The space of a freed key is only reclaimed
+// by copying the live keys to a new arena, see `should_compact`.
+#[derive(Default)]
+pub struct KeyArena {
+    slabs: Vec<Box<[u8]>>,
+    // Bytes used in the last slab.
+    used: usize,
+    size: usize,
+    live: usize,
+}
+
+impl KeyArena {
+    pub fn new() -> KeyArena {
+        KeyArena::default()
+    }
+
+    pub fn alloc(&mut self, key: &[u8]) -> ArenaKey {
+        if key.is_empty() {
+            return ArenaKey {
+                ptr: NonNull::dangling(),
+                len: 0,
+            };
+        }
+
+        if self.slabs.is_empty() || self.used + key.len() > SLAB_SIZE {
+            self.slabs.push(vec![0u8; SLAB_SIZE].into_boxed_slice());
+            self.size += SLAB_SIZE;
+            self.used = 0;
+        }
+        let start = self.used;
+        self.used += key.len();
+        let slab = &mut self.slabs.last_mut().unwrap()[start..start + key.len()];
+        slab.copy_from_slice(key);
+        self.live += key.len();
+
+        ArenaKey {
+            ptr: NonNull::new(slab.as_mut_ptr()).unwrap(),
+            len: key.len() as u32,
+        }
+    }
+
+    pub fn free(&mut self, key: &ArenaKey) {
+        self.live -= key.len as usize;
+    }
+
+    // Memory allocated by the arena.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Whether most of the arena is made of freed keys.
+    pub fn should_compact(&self) -> bool {
+        self.size > 4 * SLAB_SIZE && self.live < self.size / 2
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::mem;
+
+use twox_hash::RandomXxHashBuilder32;
+
+use super::key_arena::{ArenaKey, KeyArena};
+use super::slot::MemIdxEntry;
+
+pub type Entries<'a> = Box<dyn Iterator<Item = (&'a [u8], MemIdxEntry)> + 'a>;
+
+// The keys of the index kept in memory and their entries, selected by
+// `StorageOptions::index_kind`.
+pub trait KeyMap: Send + Sync {
+    fn get(&self, key: &[u8]) -> Option<MemIdxEntry>;
+
+    // Returns the previous entry of the key.
+    fn insert(&mut self, key: &[u8], entry: MemIdxEntry) -> Option<MemIdxEntry>;
+
+    fn remove(&mut self, key: &[u8]) -> Option<MemIdxEntry>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Every key and its entry, in no particular order.
+    fn entries(&self) -> Entries<'_>;
+
+    // The keys from the first one at least equal to `start` on, ordered, when the map
+    // keeps its keys ordered.
+    fn entries_from(&self, _start: &[u8]) -> Option<Entries<'_>> {
+        None
+    }
+
+    // Memory used by the map, its keys included.
+    fn memory_usage(&self) -> usize;
+}
+
+// Hash map of the keys (`IndexKind::Hash`), whose keys are packed in a `KeyArena`.
+pub struct HashKeyMap {
+    // Use xxHash for lookup and insertion speed at RAM's limits
+    map: HashMap<ArenaKey, MemIdxEntry, RandomXxHashBuilder32>,
+    arena: KeyArena,
+}
+
+impl Default for HashKeyMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HashKeyMap {
+    pub fn new() -> HashKeyMap {
+        HashKeyMap {
+            map: Default::default(),
+            arena: KeyArena::new(),
+        }
+    }
+
+    // Copy the keys to a new arena, once most of the current one is made of removed keys.
+    fn compact_arena(&mut self) {
+        let mut arena = KeyArena::new();
+        let mut map: HashMap<ArenaKey, MemIdxEntry, RandomXxHashBuilder32> =
+            HashMap::with_capacity_and_hasher(self.map.len(), Default::default());
+        for (key, entry) in self.map.drain() {
+            map.insert(arena.alloc(key.as_slice()), entry);
+        }
+        self.map = map;
+        self.arena = arena;
+    }
+}
+
+impl KeyMap for HashKeyMap {
+    fn get(&self, key: &[u8]) -> Option<MemIdxEntry> {
+        self.map.get(key).copied()
+    }
+
+    fn insert(&mut self, key: &[u8], entry: MemIdxEntry) -> Option<MemIdxEntry> {
+        if let Some(current) = self.map.get_mut(key) {
+            return Some(mem::replace(current, entry));
+        }
+        self.map.insert(self.arena.alloc(key), entry);
+        None
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<MemIdxEntry> {
+        let (key, entry) = self.map.remove_entry(key)?;
+        self.arena.free(&key);
+        if self.arena.should_compact() {
+            self.compact_arena();
+        }
+        Some(entry)
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        Box::new(self.map.iter().map(|(key, entry)| (key.as_slice(), *entry)))
+    }
+
+    // The hash table and the arena of the keys.
+    fn memory_usage(&self) -> usize {
+        self.map.capacity() * (mem::size_of::<(ArenaKey, MemIdxEntry)>() + 1) + self.arena.size()
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+
+use super::error::{Error, Result};
+use super::util::{get_file_handle, sync_dir};
+use super::xxhash::xxhash32;
+
+pub(crate) const LEASES_FILE_NAME: &str = "crabe.leases";
+const LEASES_TEMP_FILE_NAME: &str = "crabe.leases.tmp";
+// Starts the log of the changes of the leases. A file without it is a snapshot of the
+// leases written by the previous versions.
+const LEASE_LOG_MAGIC: &[u8; 8] = b"CRBLEASE";
+// The log is rewritten with the current leases alone once it holds twice as many changes,
+// and this many more.
+const LEASE_LOG_SLACK: usize = 1024;
+// Changes kept in memory for the standbys, which get every lease again once further behind.
+const LEASE_FEED_SIZE: usize = 4096;
+
+// The kinds of changes of the log.
+const CHANGE_NEXT_ID: u8 = 0;
+const CHANGE_GRANT: u8 = 1;
+const CHANGE_KEEP_ALIVE: u8 = 2;
+const CHANGE_REVOKE: u8 = 3;
+const CHANGE_ATTACH: u8 = 4;
+const CHANGE_EXPIRE: u8 = 5;
+const CHANGE_DETACH: u8 = 6;
+
+// A lease expires once it isn't kept alive for `ttl`, and takes the keys attached to it
+// with it.
+struct Lease {
+    ttl: Duration,
+    deadline: Instant,
+    keys: HashSet<Vec<u8>>,
+}
+
+// What removes a key: a lease, or a time to live of its own.
+enum Holder {
+    Lease(u64),
+    Ttl { ttl: Duration, deadline: Instant },
+}
+
+// A key stays attached with the sequence number of its write: once written again, it's
+// detached, or left alone if that write didn't detach it.
+struct Attachment {
+    seq: u64,
+    holder: Holder,
+}
+
+// A change of the leases, as appended to their log: kind(1) + size(4) + payload
+// + checksum(4). The deadlines are in milliseconds since the Unix epoch.
+enum Change {
+    NextId(u64),
+    Grant { id: u64, ttl: Duration, deadline: u64, next_id: u64 },
+    KeepAlive { id: u64, deadline: u64 },
+    Revoke(u64),
+    Attach { id: u64, key: Vec<u8>, seq: u64 },
+    Expire { key: Vec<u8>, seq: u64, ttl: Duration, deadline: u64 },
+    Detach(Vec<u8>),
+}
+
+impl Change {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut payload = Vec::new();
+        // Writing to a Vec can't fail.
+        let kind = match *self {
+            Change::NextId(next_id) => {
+                payload.write_u64::<LittleEndian>(next_id).unwrap();
+                CHANGE_NEXT_ID
+            }
+            Change::Grant { id, ttl, deadline, next_id } => {
+                payload.write_u64::<LittleEndian>(id).unwrap();
+                payload.write_u64::<LittleEndian>(ttl.as_millis() as u64).unwrap();
+                payload.write_u64::<LittleEndian>(deadline).unwrap();
+                payload.write_u64::<LittleEndian>(next_id).unwrap();
+                CHANGE_GRANT
+            }
+            Change::KeepAlive { id, deadline } => {
+                payload.write_u64::<LittleEndian>(id).unwrap();
+                payload.write_u64::<LittleEndian>(deadline).unwrap();
+                CHANGE_KEEP_ALIVE
+            }
+            Change::Revoke(id) => {
+                payload.write_u64::<LittleEndian>(id).unwrap();
+                CHANGE_REVOKE
+            }
+            Change::Attach { id, ref key, seq } => {
+                payload.write_u64::<LittleEndian>(id).unwrap();
+                payload.write_u64::<LittleEndian>(seq).unwrap();
+                payload.extend_from_slice(key);
+                CHANGE_ATTACH
+            }
+            Change::Expire { ref key, seq, ttl, deadline } => {
+                payload.write_u64::<LittleEndian>(seq).unwrap();
+                payload.write_u64::<LittleEndian>(ttl.as_millis() as u64).unwrap();
+                payload.write_u64::<LittleEndian>(deadline).unwrap();
+                payload.extend_from_slice(key);
+                CHANGE_EXPIRE
+            }
+            Change::Detach(ref key) => {
+                payload.extend_from_slice(key);
+                CHANGE_DETACH
+            }
+        };
+
+        let start = buf.len();
+        buf.push(kind);
+        buf.write_u32::<LittleEndian>(payload.len() as u32).unwrap();
+        buf.extend_from_slice(&payload);
+        let checksum = xxhash32(&buf[start..]);
+        buf.write_u32::<LittleEndian>(checksum).unwrap();
+    }
+
+    // The next change of `bytes`, `None` once they were read whole.
+    fn decode(bytes: &mut &[u8]) -> Result<Option<Change>> {
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        if bytes.len() < 9 {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let size = Cursor::new(&bytes[1..5]).read_u32::<LittleEndian>()? as usize;
+        if bytes.len() < 9 + size {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let (record, rest) = bytes.split_at(9 + size);
+        let (content, checksum) = record.split_at(5 + size);
+        let checksum = Cursor::new(checksum).read_u32::<LittleEndian>()?;
+        let hash = xxhash32(content);
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: u64::from(checksum),
+                found: u64::from(hash),
+            });
+        }
+        *bytes = rest;
+
+        let mut payload = Cursor::new(&content[5..]);
+        let change = match content[0] {
+            CHANGE_NEXT_ID => Change::NextId(payload.read_u64::<LittleEndian>()?),
+            CHANGE_GRANT => Change::Grant {
+                id: payload.read_u64::<LittleEndian>()?,
+                ttl: Duration::from_millis(payload.read_u64::<LittleEndian>()?),
+                deadline: payload.read_u64::<LittleEndian>()?,
+                next_id: payload.read_u64::<LittleEndian>()?,
+            },
+            CHANGE_KEEP_ALIVE => Change::KeepAlive {
+                id: payload.read_u64::<LittleEndian>()?,
+                deadline: payload.read_u64::<LittleEndian>()?,
+            },
+            CHANGE_REVOKE => Change::Revoke(payload.read_u64::<LittleEndian>()?),
+            CHANGE_ATTACH => Change::Attach {
+                id: payload.read_u64::<LittleEndian>()?,
+                seq: payload.read_u64::<LittleEndian>()?,
+                key: content[5 + 16..].to_vec(),
+            },
+            CHANGE_EXPIRE => Change::Expire {
+                seq: payload.read_u64::<LittleEndian>()?,
+                ttl: Duration::from_millis(payload.read_u64::<LittleEndian>()?),
+                deadline: payload.read_u64::<LittleEndian>()?,
+                key: content[5 + 24..].to_vec(),
+            },
+            CHANGE_DETACH => Change::Detach(content[5..].to_vec()),
+            _ => return Err(Error::Io(io::ErrorKind::InvalidData.into())),
+        };
+        Ok(Some(change))
+    }
+}
+
+fn unix_millis(deadline: Instant) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now + deadline.saturating_duration_since(Instant::now())).as_millis() as u64
+}
+
+fn deadline_of(unix_millis: u64) -> Instant {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Instant::now() + Duration::from_millis(unix_millis).saturating_sub(now)
+}
+
+// Remaining time to live of a lease, returned by `CrabeDB::lease_info`. The lease of a key
+// with a time to live of its own has the id 0.
+#[derive(Clone, Debug)]
+pub struct LeaseInfo {
+    pub id: u64,
+    pub ttl: Duration,
+    pub remaining: Duration,
+    pub keys: Vec<Vec<u8>>,
+}
+
+// The changes of the leases a standby applies, see `Leases::changes`: every lease when
+// `reset` is set, the changes after the ones it applied otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct LeaseChanges {
+    pub reset: bool,
+    pub changes: Vec<u8>,
+    // The feed the changes come from, which starts over with every load, and the number of
+    // changes of that feed they go up to.
+    pub feed_id: u64,
+    pub seq: u64,
+}
+
+// The leases of a store, and the keys with a time to live. Their changes are appended to a
+// log, synced right away with `SyncOptions::Always` and along with the data files
+// otherwise, which is rewritten with the current leases on load and once it holds many
+// more changes than that. The deadlines are saved, a lease which wasn't kept alive while
+// the store was closed expires once it's loaded.
+pub struct Leases {
+    // `None` for the leases of an in-memory store, which aren't saved.
+    path: Option<PathBuf>,
+    // `None` until loaded, and for a read-only store.
+    log: Option<File>,
+    sync: bool,
+    log_changes: usize,
+    next_id: u64,
+    // With a node id, the low 16 bits of the ids of the leases granted here, so that they
+    // don't clash with the leases of the other nodes of a cluster mirrored here.
+    node_id: Option<u16>,
+    leases: HashMap<u64, Lease>,
+    attached: BTreeMap<Vec<u8>, Attachment>,
+    // The keys with a time to live of their own, by deadline.
+    deadlines: BTreeSet<(Instant, Vec<u8>)>,
+    // Read by the writes without the lock of the leases, to only detach their key when
+    // some keys are attached.
+    attached_count: Arc<AtomicUsize>,
+    // The last changes, sent to the standbys.
+    feed_id: u64,
+    feed_seq: u64,
+    feed: VecDeque<Vec<u8>>,
+    // On a standby, the feed of the primary and the number of its changes applied.
+    applied: Option<(u64, u64)>,
+}
+
+impl Leases {
+    pub fn load(path: &Path, sync: bool, read_only: bool) -> Result<Leases> {
+        let mut leases = Leases::in_memory();
+        leases.path = Some(path.to_path_buf());
+        leases.sync = sync;
+
+        let leases_path = path.join(LEASES_FILE_NAME);
+        if leases_path.is_file() {
+            let mut buf = Vec::new();
+            get_file_handle(&leases_path, false)?.read_to_end(&mut buf)?;
+            if buf.starts_with(LEASE_LOG_MAGIC) {
+                leases.replay(&buf[LEASE_LOG_MAGIC.len()..]);
+            } else {
+                leases.load_snapshot(&buf)?;
+            }
+        }
+
+        if !read_only {
+            leases.rewrite()?;
+        }
+        Ok(leases)
+    }
+
+    // The format of the previous versions: next_id(8) + count(4) + [id(8) + ttl(8)
+    // + key_count(4) + [key_size(2) + key + seq(8)]] + checksum(4), without the deadlines.
+    fn load_snapshot(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.len() < 16 {
+            return Err(Error::Io(io::ErrorKind::InvalidData.into()));
+        }
+
+        let (content, checksum) = buf.split_at(buf.len() - 4);
+        let checksum = Cursor::new(checksum).read_u32::<LittleEndian>()?;
+        let hash = xxhash32(content);
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: u64::from(checksum),
+                found: u64::from(hash),
+            });
+        }
+
+        let now = Instant::now();
+        let mut cursor = Cursor::new(content);
+        self.next_id = cursor.read_u64::<LittleEndian>()?;
+        for _ in 0..cursor.read_u32::<LittleEndian>()? {
+            let id = cursor.read_u64::<LittleEndian>()?;
+            let ttl = Duration::from_millis(cursor.read_u64::<LittleEndian>()?);
+            self.leases.insert(id, Lease {
+                ttl,
+                deadline: now + ttl,
+                keys: HashSet::new(),
+            });
+            for _ in 0..cursor.read_u32::<LittleEndian>()? {
+                let mut key = vec![0u8; cursor.read_u16::<LittleEndian>()? as usize];
+                cursor.read_exact(&mut key)?;
+                let seq = cursor.read_u64::<LittleEndian>()?;
+                self.apply(&Change::Attach { id, key, seq });
+            }
+        }
+        Ok(())
+    }
+
+    // A change cut short by a crash ends the log.
+    fn replay(&mut self, mut bytes: &[u8]) {
+        loop {
+            match Change::decode(&mut bytes) {
+                Ok(Some(change)) => self.apply(&change),
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Dropped the last {} bytes of the lease log: {}", bytes.len(), err);
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn in_memory() -> Leases {
+        Leases {
+            path: None,
+            log: None,
+            sync: false,
+            log_changes: 0,
+            next_id: 1,
+            node_id: None,
+            leases: HashMap::new(),
+            attached: BTreeMap::new(),
+            deadlines: BTreeSet::new(),
+            attached_count: Arc::new(AtomicUsize::new(0)),
+            feed_id: rand::random(),
+            feed_seq: 0,
+            feed: VecDeque::new(),
+            applied: None,
+        }
+    }
+
+    // The changes recreating the current leases.
+    fn snapshot(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Change::NextId(self.next_id).encode(&mut buf);
+        for (&id, lease) in &self.leases {
+            Change::Grant {
+                id,
+                ttl: lease.ttl,
+                deadline: unix_millis(lease.deadline),
+                next_id: self.next_id,
+            }.encode(&mut buf);
+        }
+        for (key, attachment) in &self.attached {
+            match attachment.holder {
+                Holder::Lease(id) => Change::Attach { id, key: key.clone(), seq: attachment.seq },
+                Holder::Ttl { ttl, deadline } => Change::Expire {
+                    key: key.clone(),
+                    seq: attachment.seq,
+                    ttl,
+                    deadline: unix_millis(deadline),
+                },
+            }.encode(&mut buf);
+        }
+        buf
+    }
+
+    // Write the leases to the store at `path`, e.g. a backup.
+    pub fn save_into(&self, path: &Path) -> Result<()> {
+        let temp_path = path.join(LEASES_TEMP_FILE_NAME);
+        let mut temp_file = get_file_handle(&temp_path, true)?;
+        temp_file.write_all(LEASE_LOG_MAGIC)?;
+        temp_file.write_all(&self.snapshot())?;
+        temp_file.sync_all()?;
+        fs::rename(&temp_path, path.join(LEASES_FILE_NAME))?;
+        sync_dir(path)?;
+        Ok(())
+    }
+
+    // Replace the log by the current leases alone.
+    fn rewrite(&mut self) -> Result<()> {
+        if let Some(path) = self.path.clone() {
+            self.save_into(&path)?;
+            self.log = Some(OpenOptions::new().append(true).open(path.join(LEASES_FILE_NAME))?);
+            self.log_changes = 0;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        if let Some(ref log) = self.log {
+            log.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Apply the change, append it to the log and keep it for the standbys.
+    fn record(&mut self, change: Change) -> Result<()> {
+        self.apply(&change);
+        let mut buf = Vec::new();
+        change.encode(&mut buf);
+
+        self.feed.push_back(buf.clone());
+        if self.feed.len() > LEASE_FEED_SIZE {
+            self.feed.pop_front();
+        }
+        self.feed_seq += 1;
+
+        let sync = self.sync;
+        let written = self.log.as_mut().map(|log| -> io::Result<()> {
+            log.write_all(&buf)?;
+            if sync {
+                log.sync_data()?;
+            }
+            Ok(())
+        });
+        match written {
+            Some(Ok(())) => self.log_changes += 1,
+            // A change cut short would end the log, which is written again whole.
+            Some(Err(err)) => {
+                warn!("Couldn't append to the lease log, rewriting it: {}", err);
+                return self.rewrite();
+            }
+            None => {}
+        }
+
+        let live_changes = 1 + self.leases.len() + self.attached.len();
+        if self.log_changes > 2 * live_changes + LEASE_LOG_SLACK {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, change: &Change) {
+        match *change {
+            Change::NextId(next_id) => self.next_id = self.next_id.max(next_id),
+            Change::Grant { id, ttl, deadline, next_id } => {
+                let lease = self.leases.entry(id).or_insert_with(|| Lease {
+                    ttl,
+                    deadline: Instant::now(),
+                    keys: HashSet::new(),
+                });
+                lease.ttl = ttl;
+                lease.deadline = deadline_of(deadline);
+                self.next_id = self.next_id.max(next_id);
+            }
+            Change::KeepAlive { id, deadline } => {
+                if let Some(lease) = self.leases.get_mut(&id) {
+                    lease.deadline = deadline_of(deadline);
+                }
+            }
+            Change::Revoke(id) => {
+                if let Some(lease) = self.leases.remove(&id) {
+                    for key in lease.keys {
+                        self.attached.remove(&key);
+                    }
+                }
+            }
+            Change::Attach { id, ref key, seq } => {
+                self.detach_key(key);
+                if let Some(lease) = self.leases.get_mut(&id) {
+                    lease.keys.insert(key.clone());
+                    self.attached.insert(key.clone(), Attachment { seq, holder: Holder::Lease(id) });
+                }
+            }
+            Change::Expire { ref key, seq, ttl, deadline } => {
+                self.detach_key(key);
+                let deadline = deadline_of(deadline);
+                self.deadlines.insert((deadline, key.clone()));
+                self.attached.insert(key.clone(), Attachment { seq, holder: Holder::Ttl { ttl, deadline } });
+            }
+            Change::Detach(ref key) => self.detach_key(key),
+        }
+        self.attached_count.store(self.attached.len(), Ordering::SeqCst);
+    }
+
+    fn detach_key(&mut self, key: &[u8]) {
+        match self.attached.remove(key).map(|attachment| attachment.holder) {
+            Some(Holder::Lease(id)) => {
+                if let Some(lease) = self.leases.get_mut(&id) {
+                    lease.keys.remove(key);
+                }
+            }
+            Some(Holder::Ttl { deadline, .. }) => {
+                self.deadlines.remove(&(deadline, key.to_vec()));
+            }
+            None => {}
+        }
+    }
+
+    pub fn node_id(&mut self, node_id: u16) {
+        self.node_id = Some(node_id);
+    }
+
+    pub fn attached_count(&self) -> Arc<AtomicUsize> {
+        self.attached_count.clone()
+    }
+
+    pub fn grant(&mut self, ttl: Duration) -> Result<u64> {
+        let id = match self.node_id {
+            Some(node_id) => (self.next_id << 16) | node_id as u64,
+            None => self.next_id,
+        };
+        self.record(Change::Grant {
+            id,
+            ttl,
+            deadline: unix_millis(Instant::now() + ttl),
+            next_id: self.next_id + 1,
+        })?;
+        Ok(id)
+    }
+
+    // The lease of another node the keys of its forwarded writes are attached to, created
+    // with the whole time to live the first time.
+    pub fn mirror(&mut self, id: u64, ttl: Duration) -> Result<()> {
+        if self.leases.contains_key(&id) {
+            return Ok(());
+        }
+        self.record(Change::Grant {
+            id,
+            ttl,
+            deadline: unix_millis(Instant::now() + ttl),
+            next_id: self.next_id,
+        })
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.leases.contains_key(&id)
+    }
+
+    // Push the deadline of the lease back by its time to live, which is returned.
+    pub fn keep_alive(&mut self, id: u64) -> Result<Duration> {
+        let ttl = self.leases.get(&id).ok_or(Error::LeaseNotFound(id))?.ttl;
+        self.record(Change::KeepAlive { id, deadline: unix_millis(Instant::now() + ttl) })?;
+        Ok(ttl)
+    }
+
+    // Whether the key is attached for a write more recent than `seq`.
+    fn attached_after(&self, key: &[u8], seq: u64) -> bool {
+        self.attached.get(key).is_some_and(|attachment| attachment.seq > seq)
+    }
+
+    // Attach the key as written with `seq`, detaching it from its previous lease or time to
+    // live.
+    pub fn attach(&mut self, id: u64, key: Vec<u8>, seq: u64) -> Result<()> {
+        if !self.leases.contains_key(&id) {
+            return Err(Error::LeaseNotFound(id));
+        }
+        if self.attached_after(&key, seq) {
+            return Ok(());
+        }
+        self.record(Change::Attach { id, key, seq })
+    }
+
+    // Remove the key as written with `seq` after `ttl`, with the deadline saved.
+    pub fn expire_after(&mut self, key: Vec<u8>, seq: u64, ttl: Duration) -> Result<()> {
+        if self.attached_after(&key, seq) {
+            return Ok(());
+        }
+        self.record(Change::Expire {
+            key,
+            seq,
+            ttl,
+            deadline: unix_millis(Instant::now() + ttl),
+        })
+    }
+
+    // The key was written again with `seq`, without a lease or a time to live.
+    pub fn detach(&mut self, key: &[u8], seq: u64) -> Result<()> {
+        if self.attached.get(key).is_some_and(|attachment| attachment.seq < seq) {
+            self.record(Change::Detach(key.to_vec()))?;
+        }
+        Ok(())
+    }
+
+    // Like `detach`, for the keys from `start` (included) to `end` (excluded, or no upper
+    // bound when empty).
+    pub fn detach_range(&mut self, start: &[u8], end: &[u8], seq: u64) -> Result<()> {
+        let end = if end.is_empty() { Bound::Unbounded } else { Bound::Excluded(end) };
+        let keys: Vec<Vec<u8>> = self.attached
+            .range::<[u8], _>((Bound::Included(start), end))
+            .filter(|(_, attachment)| attachment.seq < seq)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            self.record(Change::Detach(key))?;
+        }
+        Ok(())
+    }
+
+    // The keys of the lease along with the sequence number of their write.
+    pub fn keys(&self, id: u64) -> Result<Vec<(Vec<u8>, u64)>> {
+        let lease = self.leases.get(&id).ok_or(Error::LeaseNotFound(id))?;
+        Ok(lease.keys.iter().map(|key| (key.clone(), self.attached[key].seq)).collect())
+    }
+
+    // The lease removing the key as written with `seq`, or its own time to live.
+    pub fn holding(&self, key: &[u8], seq: u64) -> Option<LeaseInfo> {
+        let attachment = self.attached.get(key).filter(|attachment| attachment.seq == seq)?;
+        let mut info = match attachment.holder {
+            Holder::Lease(id) => self.info(id)?,
+            Holder::Ttl { ttl, deadline } => LeaseInfo {
+                id: 0,
+                ttl,
+                remaining: deadline.saturating_duration_since(Instant::now()),
+                keys: Vec::new(),
+            },
+        };
+        info.keys = vec![key.to_vec()];
+        Some(info)
+    }
+
+    pub fn revoke(&mut self, id: u64) -> Result<()> {
+        if !self.leases.contains_key(&id) {
+            return Err(Error::LeaseNotFound(id));
+        }
+        self.record(Change::Revoke(id))
+    }
+
+    pub fn expired(&self) -> Vec<u64> {
+        let now = Instant::now();
+        self.leases
+            .iter()
+            .filter(|(_, lease)| lease.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    // The keys whose time to live ran out, along with the sequence number of their write.
+    pub fn expired_keys(&self) -> Vec<(Vec<u8>, u64)> {
+        let now = Instant::now();
+        self.deadlines
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .map(|(_, key)| (key.clone(), self.attached[key].seq))
+            .collect()
+    }
+
+    // Detach the keys removed once their time to live ran out, unless written again since.
+    pub fn forget(&mut self, keys: &[(Vec<u8>, u64)]) -> Result<()> {
+        for (key, seq) in keys {
+            if self.attached.get(key).is_some_and(|attachment| attachment.seq == *seq) {
+                self.record(Change::Detach(key.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Without the keys, only the caller knows which ones weren't written again.
+    pub fn info(&self, id: u64) -> Option<LeaseInfo> {
+        self.leases.get(&id).map(|lease| LeaseInfo {
+            id,
+            ttl: lease.ttl,
+            remaining: lease.deadline.saturating_duration_since(Instant::now()),
+            keys: Vec::new(),
+        })
+    }
+
+    // The changes a standby which applied the first `seq` changes of the feed `feed_id`
+    // doesn't have yet, or every lease once they are no longer kept.
+    pub fn changes(&self, feed_id: u64, seq: u64) -> LeaseChanges {
+        let behind = self.feed_seq.checked_sub(seq).map(|behind| behind as usize);
+        let (reset, changes) = match behind {
+            Some(behind) if feed_id == self.feed_id && behind <= self.feed.len() => {
+                let start = self.feed.len() - behind;
+                (false, self.feed.iter().skip(start).flatten().copied().collect())
+            }
+            _ => (true, self.snapshot()),
+        };
+        LeaseChanges {
+            reset,
+            changes,
+            feed_id: self.feed_id,
+            seq: self.feed_seq,
+        }
+    }
+
+    // On a standby, apply the changes of the leases of its primary, see `changes`.
+    pub fn apply_changes(&mut self, changes: &LeaseChanges) -> Result<()> {
+        if changes.reset {
+            self.leases.clear();
+            self.attached.clear();
+            self.deadlines.clear();
+            self.attached_count.store(0, Ordering::SeqCst);
+            self.rewrite()?;
+            // Its own standbys, if any, get every lease again too.
+            self.feed_id = rand::random();
+            self.feed_seq = 0;
+            self.feed.clear();
+        }
+        let mut bytes = &changes.changes[..];
+        while let Some(change) = Change::decode(&mut bytes)? {
+            self.record(change)?;
+        }
+        self.applied = Some((changes.feed_id, changes.seq));
+        Ok(())
+    }
+
+    // The feed of the primary and the number of its changes applied, see `apply_changes`.
+    pub fn applied(&self) -> Option<(u64, u64)> {
+        self.applied
+    }
+}
@@ -1,65 +1,123 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
-use std::io::{Cursor, SeekFrom, Take};
+use std::io::{BufReader, Cursor, SeekFrom, Take};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::result::Result::Ok;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
 use std::vec::Vec;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
 use fs2::FileExt;
 use lazy_static::lazy_static;
-use log::{info, warn};
+use log::{debug, info, warn};
 use regex::Regex;
 
-use super::slot::{Log, CompactionHint};
+use super::backend::{Appender, MemoryStorage, StdStorage, Storage};
+use super::bootstrap::remove_snapshots;
+use super::blob::{
+    get_blob_file_path, list_blob_files, read_blob, read_blob_into, BlobPointer, BlobReader, BlobStream, BlobWriter,
+    ValueReader,
+};
+use super::checksum::{checksum_size, read_checksum, write_checksum, ChecksumHasher};
+use super::slot::{Log, CompactionHint, StoredValue};
 use super::error::{Error, Result};
 use super::chunk_queue::{ChunkQueue};
-use super::util::{human_readable_byte_count, get_file_handle};
-use super::xxhash::{XxHash32, xxhash32};
-
-const DATA_FILE_EXTENSION: &'static str = "crabe.sst";
-const COMPACTION_FILE_EXTENSION: &'static str = "crabe.cpct";
-const LOCK_FILE_NAME: &'static str = "crabe.lock";
-
-pub struct Sequence(AtomicUsize);
+use super::compression::{ValueCompressor, ValueDecompressor};
+use super::encryption::Keyring;
+use super::direct_io::DirectAppender;
+use super::format::{
+    FileHeader, DATA_FILE_MAGIC, FLAG_COMPRESSION, FLAG_ENCRYPTION, FLAG_FILE_SUMMARY, FLAG_HINT_CHECKSUMS, FLAG_MAX_SEQ, HINT_FILE_MAGIC,
+    LEGACY_FORMAT_VERSION, MAX_FILE_HEADER_SIZE, MAX_SEQ_OFFSET,
+};
+use super::io_engine::{new_io_engine, PositionedReader};
+use super::manifest::{FileSeal, Manifest};
+use super::options::{ChecksumKind, RecoveryMode, StorageOptions, SyncOptions};
+use super::pitr::FileArchive;
+use super::stats::ChunkQueueStats;
+use super::summary::{load_file_summary, read_footer, FileSummary};
+use super::tiering::{get_remote_file_path, RemoteFiles, RemoteStub};
+use super::util::{
+    copy_synced, human_readable_byte_count, get_file_handle, link_or_copy,
+};
+
+pub(crate) const DATA_FILE_EXTENSION: &str = "crabe.sst";
+pub(crate) const COMPACTION_FILE_EXTENSION: &str = "crabe.cpct";
+const TEMP_FILE_EXTENSION: &str = "tmp";
+const LOCK_FILE_NAME: &str = "crabe.lock";
+// Locked shared by the read-only openers of the store, see `Lsm::load`.
+const READERS_LOCK_FILE_NAME: &str = "crabe.readers.lock";
+// How many data file ids may be left before the load warns about their exhaustion.
+const FILE_IDS_WARNING_MARGIN: u32 = 1 << 20;
+// The data and hint files are read sequentially through a buffer of this size, rather
+// than with a system call per record, when they are loaded or compacted.
+const READ_BUFFER_SIZE: usize = 256 * 1024;
+// Bytes of a data file checked in memory at once while looking for a record after a
+// corrupted one.
+const RESYNC_WINDOW_SIZE: usize = 1024 * 1024;
+
+// Hands out the data file ids, in increasing order: the files are ordered by id, from the
+// oldest to the newest. Once the last one is handed out, the store has to be renumbered
+// offline (see `renumber`), rather than wrapping around onto the ids of live files.
+pub struct Sequence(AtomicU32);
 
 impl Sequence {
     pub fn new(id: u32) -> Sequence {
-        Sequence(AtomicUsize::new(id as usize))
+        Sequence(AtomicU32::new(id))
     }
 
-    pub fn increment(&self) -> u32 {
-        self.0.fetch_add(1, Ordering::SeqCst) as u32 + 1
+    pub fn increment(&self) -> Result<u32> {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |id| id.checked_add(1))
+            .map(|id| id + 1)
+            .map_err(|_| Error::FileIdsExhausted)
     }
 }
 
 pub struct Lsm {
     pub path: PathBuf,
     max_file_size: usize,
-    lock_file: File,
+    recovery_mode: RecoveryMode,
+    // `None` for an in-memory store.
+    lock_file: Option<File>,
+    read_only: bool,
+    manifest: Manifest,
     files: Vec<u32>,
+    // Compacted files kept on disk for the read-only openers attached to the store.
+    obsolete_files: Vec<u32>,
     file_id_seq: Arc<Sequence>,
-    file_chunk_queue: Mutex<ChunkQueue>,
+    reader: Arc<LsmReader>,
+    // The data files offloaded to the object storage, with a tiering policy.
+    remote: Option<Arc<RemoteFiles>>,
+    // Where the removed files go, with `StorageOptions::archive_dir`.
+    archive: Option<Arc<FileArchive>>,
     lsm_writer: LsmWriter,
+    blob_writer: BlobWriter,
+    // The keyring of `StorageOptions::encryption`.
+    encryption: Option<Keyring>,
+    // With `SyncOptions::EveryBytes`, 0 otherwise.
+    sync_bytes: u64,
+    unsynced_bytes: u64,
     pub active_file_id: Option<u32>,
+    // Set by `close`, after which the files are left to the next opener.
+    closed: bool,
 }
 
 impl Lsm {
-    pub fn load(
-        path: &str,
-        create: bool,
-        sync: bool,
-        max_file_size: usize,
-        file_chunk_queue_size: usize,
-    ) -> Result<Lsm> {
+    pub fn load(path: &str, options: &StorageOptions) -> Result<Lsm> {
         let path_str = path;
         let path = PathBuf::from(path);
+        if options.in_memory {
+            return Lsm::load_in_memory(path, options);
+        }
 
-        if create {
+        if options.create && !options.read_only {
             if path.exists() && !path.is_dir() {
                 return Err(Error::InvalidPath(path_str.to_string()));
             } else if !path.exists() {
@@ -71,113 +129,444 @@ impl Lsm {
             }
         }
 
-        let lock_file = File::create(path.join(LOCK_FILE_NAME))?;
-        lock_file.try_lock_exclusive()?;
+        // A single writer holds the store lock exclusively, while any number of read-only
+        // openers share the readers lock. The writer doesn't remove the files compacted
+        // away as long as a reader is attached, readers never modify the directory and
+        // only load the files which are no longer written to.
+        let storage = new_storage(options)?;
+        if options.read_only {
+            let lock_file = acquire_readers_lock(&path)?;
+            return Lsm::load_read_only(path, lock_file, storage, options);
+        }
+        let lock_file = acquire_lock(&path)?;
+
+        remove_temp_files(&*storage, &path)?;
+        remove_snapshots(&path)?;
+
+        let remote = load_remote_files(&path, options)?;
+        let data_files = find_all_data_files(&*storage, &path, &remote)?;
+        let current_file_id = data_files.last().cloned().unwrap_or(0);
+
+        let mut obsolete_files = Vec::new();
+        let manifest = match Manifest::load(&path)? {
+            Some(manifest) => {
+                let readers_attached = is_locked(&path.join(READERS_LOCK_FILE_NAME))?;
+                for &file_id in &data_files {
+                    if !manifest.contains(file_id) {
+                        if readers_attached {
+                            obsolete_files.push(file_id);
+                            continue;
+                        }
+                        warn!(
+                            "Removing data file {} which is not referenced by the manifest",
+                            file_id
+                        );
+                        match remote {
+                            Some(ref remote) if remote.stub(file_id).is_some() => remote.remove(file_id)?,
+                            _ => storage.remove(&get_data_file_path(&path, file_id))?,
+                        }
+                        let _ = storage.remove(&get_compaction_hint_file_path(&path, file_id));
+                    }
+                }
+                storage.sync_dir(&path)?;
+
+                for file_id in manifest.files() {
+                    if data_files.binary_search(&file_id).is_err() {
+                        if get_remote_file_path(&path, file_id).is_file() {
+                            return Err(Error::InvalidManifest(format!(
+                                "data file {} is offloaded, but the store has no tiering policy",
+                                file_id
+                            )));
+                        }
+                        return Err(Error::InvalidManifest(format!(
+                            "data file {} referenced by the manifest is missing",
+                            file_id
+                        )));
+                    }
+                }
+                manifest
+            }
+            None => Manifest::create(&path, &data_files)?,
+        };
+        let files = manifest.files();
+
+        // Any unsealed file may have been the active one when the store crashed.
+        for file_id in manifest.unsealed_files() {
+            if remote_stub(&remote, file_id).is_some() {
+                continue;
+            }
+            truncate_torn_tail(&*storage, &path, file_id)?;
+        }
 
-        let files = find_data_files(&path)?;
-        let current_file_id = if files.is_empty() {
-            0
-        } else {
-            files[files.len() - 1]
+        let mut lsm = Lsm::open(path, Some(lock_file), manifest, files, current_file_id, remote, storage, options)?;
+        lsm.obsolete_files = obsolete_files;
+        Ok(lsm)
+    }
+
+    // The files are those of the manifest when the store is opened, except the active one
+    // while a writer may still be appending to it.
+    fn load_read_only(
+        path: PathBuf,
+        lock_file: File,
+        storage: Arc<dyn Storage>,
+        options: &StorageOptions,
+    ) -> Result<Lsm> {
+        let remote = load_remote_files(&path, options)?;
+        let data_files = find_all_data_files(&*storage, &path, &remote)?;
+        let current_file_id = data_files.last().cloned().unwrap_or(0);
+        let manifest = match Manifest::load(&path)? {
+            Some(manifest) => manifest,
+            None => Manifest::in_memory(&path, &data_files),
+        };
+
+        let mut files = manifest.files();
+        if is_locked(&path.join(LOCK_FILE_NAME))? {
+            if let Some(&file_id) = manifest.unsealed_files().last() {
+                info!("Skipping data file {} which may still be written to", file_id);
+                files.retain(|&id| id != file_id);
+            }
+        }
+
+        Lsm::open(path, Some(lock_file), manifest, files, current_file_id, remote, storage, options)
+    }
+
+    // An in-memory store starts empty, without a directory, a lock or a manifest file:
+    // `path` only names its files.
+    fn load_in_memory(path: PathBuf, options: &StorageOptions) -> Result<Lsm> {
+        let storage = new_storage(options)?;
+        let manifest = Manifest::in_memory(&path, &[]);
+        Lsm::open(path, None, manifest, Vec::new(), 0, None, storage, options)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        path: PathBuf,
+        lock_file: Option<File>,
+        manifest: Manifest,
+        files: Vec<u32>,
+        current_file_id: u32,
+        remote: Option<Arc<RemoteFiles>>,
+        storage: Arc<dyn Storage>,
+        options: &StorageOptions,
+    ) -> Result<Lsm> {
+        // Refuse to open a store holding files written by a newer version rather than
+        // misreading them.
+        let mut file_headers = HashMap::new();
+        for &file_id in &files {
+            let file_header = match remote_stub(&remote, file_id) {
+                Some(stub) => stub.header,
+                None => read_file_header(&*storage, &path, file_id)?,
+            };
+            file_headers.insert(file_id, file_header);
+        }
+
+        let archive = match options.archive_dir {
+            Some(ref dir) if !options.read_only => Some(Arc::new(FileArchive::new(&path, dir, remote.clone())?)),
+            _ => None,
+        };
+        // The ids of the archived files aren't reused, even once the store is cleared.
+        let (last_archived_file_id, last_archived_blob_id) = match archive {
+            Some(ref archive) => archive.last_file_ids()?,
+            None => (0, 0),
         };
+        let current_file_id = current_file_id.max(last_archived_file_id);
 
         let file_id_seq = Arc::new(Sequence::new(current_file_id));
         info!("Current file id : {}", current_file_id);
-        let lsm_writer = LsmWriter::new(&path, sync, max_file_size, file_id_seq.clone());
+        if current_file_id > u32::MAX - FILE_IDS_WARNING_MARGIN {
+            warn!(
+                "Only {} data file ids left, the store should be renumbered with `crabedb-admin renumber`",
+                u32::MAX - current_file_id
+            );
+        }
+        let encryption = options.encryption.clone();
+        // With group commit, the writer thread syncs once per group instead.
+        let sync = options.sync == SyncOptions::Always && !options.group_commit;
+        let mut lsm_writer = LsmWriter::new(
+            &path,
+            sync,
+            options.max_file_size,
+            options.checksum,
+            options.direct_io,
+            file_id_seq.clone(),
+            storage.clone(),
+        );
+        // The writes keep the key current at the load, the compactions take the current one.
+        if let Some(ref keyring) = encryption {
+            lsm_writer.encrypt(keyring.clone(), keyring.current_key_id()?);
+        }
+        let blob_writer = BlobWriter::new(
+            &path,
+            sync,
+            options.max_file_size,
+            storage.clone(),
+            last_archived_blob_id,
+        )?;
+
+        let reader = Arc::new(LsmReader {
+            path: path.clone(),
+            storage,
+            remote: remote.clone(),
+            file_headers: RwLock::new(file_headers),
+            keyring: options.encryption.clone(),
+            codecs: RwLock::new(HashMap::new()),
+            file_chunk_queue: Mutex::new(ChunkQueue::new(
+                options.file_chunk_queue_size,
+                options.handles_per_file,
+                options.file_chunk_queue_policy,
+                options.file_chunk_queue_unit,
+            )),
+        });
 
         Ok(Lsm {
-            path: path,
-            max_file_size: max_file_size,
-            lock_file: lock_file,
-            files: files,
-            file_id_seq: file_id_seq,
-            file_chunk_queue: Mutex::new(ChunkQueue::new(file_chunk_queue_size)),
-            lsm_writer: lsm_writer,
+            path,
+            max_file_size: options.max_file_size,
+            recovery_mode: options.recovery_mode,
+            lock_file,
+            read_only: options.read_only,
+            manifest,
+            files,
+            obsolete_files: Vec::new(),
+            file_id_seq,
+            reader,
+            remote,
+            archive,
+            lsm_writer,
+            blob_writer,
+            encryption,
+            sync_bytes: match options.sync {
+                SyncOptions::EveryBytes(bytes) => bytes as u64,
+                _ => 0,
+            },
+            unsynced_bytes: 0,
             active_file_id: None,
+            closed: false,
         })
     }
 
     pub fn file_size(&self, file_id: u32) -> Result<u64> {
-        let data_file = self.file_chunk_queue
-            .lock()
-            .unwrap()
-            .get(file_id)
-            .map(Ok)
-            .unwrap_or_else(|| {
-                get_file_handle(&get_data_file_path(&self.path, file_id), false)
-            })?;
-        let res = Ok(data_file.metadata()?.len());
-        self.file_chunk_queue.lock().unwrap().put(file_id, data_file);
-        res
+        self.reader.file_size(file_id)
+    }
+
+    pub fn file_header(&self, file_id: u32) -> Result<FileHeader> {
+        self.reader.file_header(file_id)
+    }
+
+    // The sealed files not encrypted with the current key, which `CrabeDB::rewrap` rewrites.
+    pub fn unwrapped_files(&self) -> Result<Vec<u32>> {
+        let key_id = match self.encryption {
+            Some(ref keyring) => keyring.current_key_id()?,
+            None => return Ok(Vec::new()),
+        };
+        let mut files = Vec::new();
+        for file_id in self.files() {
+            if Some(file_id) == self.active_file_id {
+                continue;
+            }
+            let file_header = self.file_header(file_id)?;
+            if !file_header.has_flag(FLAG_ENCRYPTION) || file_header.key_id != key_id {
+                files.push(file_id);
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn chunk_queue_stats(&self) -> ChunkQueueStats {
+        self.reader.chunk_queue_stats()
+    }
+
+    pub fn file_modified(&self, file_id: u32) -> Result<SystemTime> {
+        if let Some(stub) = remote_stub(&self.remote, file_id) {
+            return Ok(stub.modified);
+        }
+        Ok(self.reader.data_file(file_id)?.metadata()?.modified()?)
+    }
+
+    pub fn is_remote(&self, file_id: u32) -> bool {
+        remote_stub(&self.remote, file_id).is_some()
+    }
+
+    // The data files which haven't been written to for `min_age` and can be offloaded:
+    // those which were closed, with a complete hint file.
+    pub fn cold_files(&self, min_age: Duration) -> Result<Vec<u32>> {
+        if self.remote.is_none() || self.read_only {
+            return Ok(Vec::new());
+        }
+
+        let now = SystemTime::now();
+        let mut cold_files = Vec::new();
+        for &file_id in &self.files {
+            if self.is_remote(file_id)
+                || !is_valid_compaction_hint_file(
+                    &*self.reader.storage,
+                    &get_compaction_hint_file_path(&self.path, file_id),
+                )?
+            {
+                continue;
+            }
+            let modified = fs::metadata(get_data_file_path(&self.path, file_id))?.modified()?;
+            if now.duration_since(modified).unwrap_or_default() >= min_age {
+                cold_files.push(file_id);
+            }
+        }
+        Ok(cold_files)
+    }
+
+    // Open a data file ahead of its first read, caching its handle.
+    pub fn warm(&self, file_id: u32) -> Result<()> {
+        self.reader.data_file(file_id)?;
+        Ok(())
+    }
+
+    pub fn reader(&self) -> Arc<LsmReader> {
+        self.reader.clone()
     }
 
     pub fn files(&self) -> Vec<u32> {
         self.files.clone()
     }
 
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
     pub fn entries<'a>(&self, file_id: u32) -> Result<Entries<'a>> {
+        self.reader.fetch(file_id)?;
+        open_entries(&*self.reader.storage, &self.path, file_id, self.recovery_mode)
+    }
+
+    // The highest sequence number of a sealed data file, from its header. The offloaded
+    // files aren't downloaded for it.
+    pub fn max_seq(&self, file_id: u32) -> Result<Option<u64>> {
         let data_file_path = get_data_file_path(&self.path, file_id);
-        info!("Loading data file: {:?}", data_file_path);
-        let data_file = get_file_handle(&data_file_path, false)?;
-        let data_file_size = data_file.metadata()?.len();
+        if !self.reader.storage.exists(&data_file_path) {
+            return Ok(None);
+        }
+        FileHeader::read_max_seq(&mut self.reader.storage.open(&data_file_path)?)
+    }
 
-        Ok(Entries {
-            data_file: data_file.take(data_file_size),
-            data_file_pos: 0,
-            phantom: PhantomData,
-        })
+    // The summary of a data file, from the footer of its hint file, or of the hints written
+    // so far to the active one. `None` for a file without one, which may hold any record.
+    pub fn file_summary(&self, file_id: u32) -> Result<Option<FileSummary>> {
+        if self.active_file_id == Some(file_id) {
+            if let Some(ref log_writer) = self.lsm_writer.log_writer {
+                return Ok(log_writer.compaction_writer.summary().cloned());
+            }
+        }
+        load_file_summary(&*self.reader.storage, &get_compaction_hint_file_path(&self.path, file_id))
     }
 
     pub fn compaction_hints<'a>(&self, file_id: u32) -> Result<Option<CompactionHints<'a>>> {
         let compaction_file_path = get_compaction_hint_file_path(&self.path, file_id);
-        Ok(if is_valid_compaction_hint_file(&compaction_file_path)? {
-            info!("Loading compaction file: {:?}", compaction_file_path);
-            let compaction_file = get_file_handle(&compaction_file_path, false)?;
-            let compaction_file_size = compaction_file.metadata()?.len();
-
-            Some(CompactionHints {
-                compaction_file: compaction_file.take(compaction_file_size - 4),
-                phantom: PhantomData,
-            })
+        Ok(if is_valid_compaction_hint_file(&*self.reader.storage, &compaction_file_path)? {
+            Some(open_compaction_hints(&*self.reader.storage, &self.path, file_id)?)
         } else {
             None
         })
     }
 
-    pub fn update_compaction_hints<'a>(&mut self, file_id: u32) -> Result<RecreateHints<'a>> {
+    // The hints of a data file whose hint file is missing or damaged. Those of a damaged
+    // file are salvaged up to the first bad one, and only the records after them are read
+    // from the data file. The hint file is left as is, see `RecoveredHints::rebuild`.
+    pub fn recover_compaction_hints<'a>(&self, file_id: u32) -> Result<RecoveredHints<'a>> {
         let compaction_file_path = get_compaction_hint_file_path(&self.path, file_id);
-        warn!("Re-creating compaction file: {:?}", compaction_file_path);
-
-        let compaction_writer = CompactionHintWriter::new(&self.path, file_id)?;
-        let entries = self.entries(file_id)?;
+        let mut entries = self.entries(file_id)?;
+        let salvaged = salvage_compaction_hints(&*self.reader.storage, &compaction_file_path, entries.data_file_size)?;
+        if let Some(last) = salvaged.last() {
+            warn!(
+                "Salvaged {} hints of compaction file {:?}, reading data file {} from offset {}",
+                salvaged.len(),
+                compaction_file_path,
+                file_id,
+                last.log_pos + last.log_size()
+            );
+            entries.skip_to(last.log_pos + last.log_size())?;
+        }
 
-        Ok(RecreateHints {
-            hint_writer: compaction_writer,
-            entries: entries,
+        Ok(RecoveredHints {
+            path: self.path.clone(),
+            storage: self.reader.storage.clone(),
+            file_id,
+            salvaged: salvaged.into_iter(),
+            entries,
         })
     }
 
     pub fn read_log<'a>(&self, file_id: u32, log_pos: u64) -> Result<Log<'a>> {
-        let mut data_file = self.file_chunk_queue
-            .lock()
-            .unwrap()
-            .get(file_id)
-            .map(Ok)
-            .unwrap_or_else(|| {
-                get_file_handle(&get_data_file_path(&self.path, file_id), false)
-            })?;
+        self.reader.read_log(file_id, log_pos)
+    }
+
+    pub fn resolve<'a>(&self, log: Log<'a>) -> Result<Log<'a>> {
+        self.reader.resolve(log)
+    }
+
+    pub fn read_value(&self, file_id: u32, log_pos: u64, log_size: u64) -> Result<Option<Bytes>> {
+        self.reader.read_value(file_id, log_pos, log_size)
+    }
+
+    pub fn open_value(&self, file_id: u32, log_pos: u64, log_size: u64) -> Result<Option<ValueReader>> {
+        self.reader.open_value(file_id, log_pos, log_size)
+    }
+
+    // Position right after the last record written, to tail the store from now on.
+    pub fn end_position(&self) -> Result<LogPosition> {
+        if let Some(ref log_writer) = self.lsm_writer.log_writer {
+            return Ok(LogPosition {
+                file_id: self.active_file_id.unwrap(),
+                pos: log_writer.data_file_pos,
+            });
+        }
+
+        Ok(match self.files.last() {
+            Some(&file_id) => LogPosition {
+                file_id,
+                pos: self.file_size(file_id)?,
+            },
+            None => LogPosition { file_id: 0, pos: 0 },
+        })
+    }
 
-        data_file.seek(SeekFrom::Start(log_pos))?;
-        let res = Log::from_read(&mut data_file);
+    // Follow the records appended from the given position on, see `Tail`.
+    pub fn tail(&self, from_file_id: u32, from_pos: u64) -> Tail {
+        Tail {
+            path: self.path.clone(),
+            storage: self.reader.storage.clone(),
+            remote: self.remote.clone(),
+            keyring: self.reader.keyring.clone(),
+            file: None,
+            position: LogPosition {
+                file_id: from_file_id,
+                pos: from_pos,
+            },
+            last_seq: None,
+        }
+    }
 
-        self.file_chunk_queue.lock().unwrap().put(file_id, data_file);
+    pub fn read_blob(&self, pointer: &BlobPointer) -> Result<Bytes> {
+        read_blob(&*self.reader.storage, &self.path, pointer)
+    }
 
-        res
+    pub fn read_value_into(
+        &self,
+        file_id: u32,
+        log_pos: u64,
+        log_size: u64,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<usize>> {
+        self.reader.read_value_into(file_id, log_pos, log_size, buf)
     }
 
-    pub fn append_log<'a>(&mut self, log: &Log<'a>) -> Result<(u32, u64)> {
-        Ok(match self.lsm_writer.write(log)? {
-            LsmWrite::NewFile(file_id) => {
+    // Returns the file and the position of the record, and its size in the file.
+    pub fn append_log<'a>(&mut self, log: &Log<'a>) -> Result<(u32, u64, u64)> {
+        // A new file is in the manifest before it holds any record, or the next load would
+        // remove it along with the records.
+        let manifest = &mut self.manifest;
+        let write = self.lsm_writer.write_with(log, |lsm_writer, file_id| {
+            manifest.add_file(file_id, &lsm_writer.take_sealed_files())
+        })?;
+        let appended = match write {
+            LsmWrite::NewFile(file_id, log_pos, size) => {
+                self.reader.add_file_header(file_id, self.lsm_writer.file_header());
                 if let Some(active_file_id) = self.active_file_id {
                     self.add_file(active_file_id);
                 }
@@ -186,43 +575,248 @@ impl Lsm {
                     "New active data file {:?}",
                     self.lsm_writer.log_writer()?.data_file_path
                 );
-                (file_id, 0)
+                (file_id, log_pos, size)
             }
-            LsmWrite::Ok(log_pos) => (self.active_file_id.unwrap(), log_pos),
-        })
+            LsmWrite::Ok(log_pos, size) => (self.active_file_id.unwrap(), log_pos, size),
+        };
+        self.appended(appended.2)?;
+        Ok(appended)
+    }
+
+    // The value must be written before the record pointing to it.
+    pub fn append_blob(&mut self, value: &[u8]) -> Result<BlobPointer> {
+        let pointer = self.blob_writer.append(value)?;
+        // The record pointing to the blob is appended next, which syncs both if needed.
+        self.unsynced_bytes += pointer.size;
+        Ok(pointer)
     }
 
-    pub fn writer(&self) -> LsmWriter {
-        LsmWriter::new(
+    // A value too large to be held in memory, written to a blob file as it arrives. It's
+    // synced once complete, before the record pointing to it is appended.
+    pub fn blob_stream(&mut self) -> Result<BlobStream> {
+        self.blob_writer.stream()
+    }
+
+    // Count the bytes appended since the last sync, and sync once they reach the threshold
+    // of `SyncOptions::EveryBytes`.
+    fn appended(&mut self, size: u64) -> Result<()> {
+        if self.sync_bytes == 0 {
+            return Ok(());
+        }
+        self.unsynced_bytes += size;
+        if self.unsynced_bytes >= self.sync_bytes {
+            self.sync()?;
+            self.unsynced_bytes = 0;
+        }
+        Ok(())
+    }
+
+    // Remove the blob files no data file points to anymore, as told by their summaries,
+    // along with those of the values given up while streamed. They are only known once
+    // every data file tells, i.e. once those written by older versions or left open by a
+    // crash have been compacted.
+    pub fn remove_unreferenced_blobs(&self) -> Result<()> {
+        if is_locked(&self.path.join(READERS_LOCK_FILE_NAME))? {
+            info!("Keeping the unreferenced blob files for the attached readers");
+            return Ok(());
+        }
+
+        let mut referenced = HashSet::new();
+        for file_id in self.files.iter().cloned().chain(self.active_file_id) {
+            match self.file_summary(file_id)?.and_then(|summary| summary.blob_files) {
+                Some(blob_files) => referenced.extend(blob_files),
+                None => {
+                    debug!("Keeping the blob files, data file {} doesn't tell which ones it uses", file_id);
+                    return Ok(());
+                }
+            }
+        }
+
+        let unreferenced: Vec<u32> = list_blob_files(&*self.reader.storage, &self.path)?
+            .into_iter()
+            .filter(|&file_id| !referenced.contains(&file_id) && !self.blob_writer.is_writing(file_id))
+            .collect();
+        if unreferenced.is_empty() {
+            return Ok(());
+        }
+        if let Some(ref archive) = self.archive {
+            archive.add_blob_files(&unreferenced)?;
+        }
+        for file_id in unreferenced {
+            info!("Removing unreferenced blob file {}", file_id);
+            self.reader.storage.remove(&get_blob_file_path(&self.path, file_id))?;
+        }
+        self.reader.storage.sync_dir(&self.path)?;
+        Ok(())
+    }
+
+    pub fn writer(&self) -> Result<LsmWriter> {
+        let mut lsm_writer = LsmWriter::temp(
             &self.path,
-            false,
             self.max_file_size,
+            self.lsm_writer.checksum,
+            self.lsm_writer.direct_io,
             self.file_id_seq.clone(),
-        )
+            self.reader.storage.clone(),
+        );
+        if let Some(ref keyring) = self.encryption {
+            lsm_writer.encrypt(keyring.clone(), keyring.current_key_id()?);
+        }
+        Ok(lsm_writer)
+    }
+
+    pub fn ingester(&self) -> Ingester {
+        Ingester {
+            path: self.path.clone(),
+            storage: self.reader.storage.clone(),
+            file_id_seq: self.file_id_seq.clone(),
+            temp_files: Vec::new(),
+            sealed_files: Vec::new(),
+        }
     }
 
     pub fn sync(&self) -> Result<()> {
+        self.blob_writer.sync()?;
         self.lsm_writer.sync()
     }
 
-    pub fn swap_files(&mut self, old_files: &[u32], new_files: &[u32]) -> Result<()> {
-        for &file_id in old_files {
-            let idx = self.files.binary_search(&file_id).map_err(|_| {
-                Error::InvalidFileId(file_id)
-            })?;
+    // Like `sync`, the hint file of the active data file included.
+    pub fn flush(&self) -> Result<()> {
+        self.blob_writer.sync()?;
+        self.lsm_writer.flush()
+    }
+
+    // Seal the active data file, whose hint file gets its trailing checksum, and release
+    // the lock of the store. Nothing can be written afterwards.
+    pub fn close(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.lsm_writer.seal_active()?;
+        let sealed_files = self.lsm_writer.take_sealed_files();
+        if !sealed_files.is_empty() {
+            self.manifest.seal_files(&sealed_files)?;
+        }
+        self.blob_writer.sync()?;
+        self.closed = true;
+        if let Some(ref lock_file) = self.lock_file {
+            lock_file.unlock()?;
+        }
+        Ok(())
+    }
 
-            self.files.remove(idx);
+    pub fn archive(&self) -> Option<Arc<FileArchive>> {
+        self.archive.clone()
+    }
 
-            let data_file_path = get_data_file_path(&self.path, file_id);
-            let compaction_file_path = get_compaction_hint_file_path(&self.path, file_id);
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
 
-            fs::remove_file(data_file_path)?;
-            let _ = fs::remove_file(compaction_file_path);
+    // Seal the active data and blob files, the next writes going to new ones, so that
+    // none of the files of the snapshot is written to anymore.
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        self.lsm_writer.seal_active()?;
+        let sealed_files = self.lsm_writer.take_sealed_files();
+        if !sealed_files.is_empty() {
+            self.manifest.seal_files(&sealed_files)?;
         }
+        if let Some(active_file_id) = self.active_file_id.take() {
+            self.add_file(active_file_id);
+        }
+        self.blob_writer.seal()?;
+
+        Ok(Snapshot {
+            path: self.path.clone(),
+            files: self
+                .files
+                .iter()
+                .map(|&file_id| (file_id, self.manifest.seal(file_id)))
+                .collect(),
+            blob_files: list_blob_files(&*self.reader.storage, &self.path)?,
+            remote: self.remote.clone(),
+        })
+    }
 
-        self.files.extend(new_files);
+    pub fn swap_files(&mut self, old_files: &[u32], new_files: &[(u32, FileSeal)]) -> Result<()> {
+        // The files may already belong to another opener.
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        for &file_id in old_files {
+            if self.files.binary_search(&file_id).is_err() {
+                return Err(Error::InvalidFileId(file_id));
+            }
+        }
+
+        // Nothing leaves the manifest before it's archived.
+        if let Some(ref archive) = self.archive {
+            archive.add_data_files(old_files)?;
+        }
+
+        let added_files: Vec<(u32, Option<FileSeal>)> =
+            new_files.iter().map(|&(file_id, seal)| (file_id, Some(seal))).collect();
+        self.manifest.apply(old_files, &added_files, &[])?;
+        self.files.retain(|file_id| !old_files.contains(file_id));
+        self.obsolete_files.extend(old_files);
+
+        self.files.extend(new_files.iter().map(|&(file_id, _)| file_id));
+        self.reader.remove_files(old_files);
         self.files.sort();
 
+        self.remove_obsolete_files()
+    }
+
+    // Drop every data file, the next write starting a new one. The files are removed
+    // like compacted ones, once no reader is attached anymore. Returns the dropped files.
+    pub fn clear(&mut self) -> Result<Vec<u32>> {
+        self.lsm_writer.seal_active()?;
+        // Their seals are useless, the files are no longer part of the store.
+        self.lsm_writer.take_sealed_files();
+        if let Some(active_file_id) = self.active_file_id.take() {
+            self.add_file(active_file_id);
+        }
+
+        let files = self.files.clone();
+        self.swap_files(&files, &[])?;
+        self.remove_unreferenced_blobs()?;
+        Ok(files)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Readers attach before loading the manifest, so once none is attached, none can use
+    // a file which is no longer in the manifest. Files left behind are removed on the
+    // next compaction, or at the next load.
+    fn remove_obsolete_files(&mut self) -> Result<()> {
+        if self.obsolete_files.is_empty() {
+            return Ok(());
+        }
+        if is_locked(&self.path.join(READERS_LOCK_FILE_NAME))? {
+            info!(
+                "Keeping {} compacted data files for the attached readers",
+                self.obsolete_files.len()
+            );
+            return Ok(());
+        }
+
+        for file_id in self.obsolete_files.drain(..) {
+            let data_file_path = get_data_file_path(&self.path, file_id);
+            let compaction_file_path = get_compaction_hint_file_path(&self.path, file_id);
+
+            match self.remote {
+                Some(ref remote) if remote.stub(file_id).is_some() => remote.remove(file_id)?,
+                _ => self.reader.storage.remove(&data_file_path)?,
+            }
+            let _ = self.reader.storage.remove(&compaction_file_path);
+        }
+        // Otherwise the removed files could be back after a power failure.
+        self.reader.storage.sync_dir(&self.path)?;
         Ok(())
     }
 
@@ -234,21 +828,320 @@ impl Lsm {
 
 impl Drop for Lsm {
     fn drop(&mut self) {
-        let _ = self.lock_file.unlock();
+        if self.closed {
+            return;
+        }
+        // Nothing is appended to the active file after a clean shutdown, the next write
+        // goes to a new one.
+        if self.lsm_writer.seal_active().is_ok() {
+            let sealed_files = self.lsm_writer.take_sealed_files();
+            if !sealed_files.is_empty() {
+                let _ = self.manifest.seal_files(&sealed_files);
+            }
+        }
+        if let Some(ref lock_file) = self.lock_file {
+            let _ = lock_file.unlock();
+        }
+    }
+}
+
+// The files of the store when `Lsm::snapshot` was taken. They must not be removed, i.e.
+// compacted away, until they are linked.
+pub struct Snapshot {
+    path: PathBuf,
+    files: Vec<(u32, Option<FileSeal>)>,
+    blob_files: Vec<u32>,
+    remote: Option<Arc<RemoteFiles>>,
+}
+
+impl Snapshot {
+    pub fn files(&self) -> usize {
+        self.files.len() + self.blob_files.len()
+    }
+
+    // Hard link the data and blob files into `dest`, an empty directory, and write a
+    // manifest of these files only. The files are copied when `dest` is on another file
+    // system, the offloaded ones are downloaded, and the hint files, which a load of the
+    // store may rewrite in place, are always copied.
+    pub fn link_into(&self, dest: &Path) -> Result<()> {
+        for &(file_id, _) in &self.files {
+            let data_file_path = get_data_file_path(&self.path, file_id);
+            let dest_file_path = get_data_file_path(dest, file_id);
+            match self.remote {
+                Some(ref remote) if remote.stub(file_id).is_some() => {
+                    // The local copy may be evicted meanwhile.
+                    if link_or_copy(&data_file_path, &dest_file_path).is_err() {
+                        remote.download(file_id, &dest_file_path)?;
+                    }
+                }
+                _ => link_or_copy(&data_file_path, &dest_file_path)?,
+            }
+
+            let compaction_file_path = get_compaction_hint_file_path(&self.path, file_id);
+            if compaction_file_path.is_file() {
+                copy_synced(&compaction_file_path, &get_compaction_hint_file_path(dest, file_id))?;
+            }
+        }
+        for &file_id in &self.blob_files {
+            link_or_copy(&get_blob_file_path(&self.path, file_id), &get_blob_file_path(dest, file_id))?;
+        }
+
+        Manifest::create_sealed(dest, &self.files)?;
+        Ok(())
+    }
+}
+
+// The read side of the data files. It is shared with the lock-free read path, which
+// reads logs without going through the lock protecting the `Lsm`.
+pub struct LsmReader {
+    path: PathBuf,
+    storage: Arc<dyn Storage>,
+    remote: Option<Arc<RemoteFiles>>,
+    file_headers: RwLock<HashMap<u32, FileHeader>>,
+    keyring: Option<Keyring>,
+    // The codecs of the compressed or encrypted files read so far.
+    codecs: RwLock<HashMap<u32, Arc<ValueCodec>>>,
+    file_chunk_queue: Mutex<ChunkQueue>,
+}
+
+impl LsmReader {
+    fn data_file(&self, file_id: u32) -> Result<Arc<File>> {
+        if let Some(data_file) = self.file_chunk_queue.lock().unwrap().get(file_id) {
+            return Ok(data_file);
+        }
+
+        let data_file = Arc::new(self.open_data_file(file_id)?);
+        let size = data_file.metadata()?.len();
+        self.file_chunk_queue.lock().unwrap().put(file_id, data_file.clone(), size);
+        Ok(data_file)
+    }
+
+    // An offloaded file is downloaded first. It may also be offloaded between the check
+    // and the open, it's then downloaded right away.
+    fn open_data_file(&self, file_id: u32) -> Result<File> {
+        self.fetch(file_id)?;
+        let data_file_path = get_data_file_path(&self.path, file_id);
+        match self.storage.open(&data_file_path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound && self.remote.is_some() => {
+                self.fetch(file_id)?;
+                Ok(self.storage.open(&data_file_path)?)
+            }
+            data_file => Ok(data_file?),
+        }
+    }
+
+    // Make sure an offloaded data file has a local copy, closing the handles of the local
+    // copies removed to make room for it.
+    pub fn fetch(&self, file_id: u32) -> Result<()> {
+        if let Some(ref remote) = self.remote {
+            let evicted = remote.fetch(file_id)?;
+            if !evicted.is_empty() {
+                let mut file_chunk_queue = self.file_chunk_queue.lock().unwrap();
+                for file_id in evicted {
+                    file_chunk_queue.remove(file_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Upload a data file which is no longer written to and remove it locally, see
+    // `Tiering`.
+    pub fn offload(&self, file_id: u32) -> Result<()> {
+        if let Some(ref remote) = self.remote {
+            remote.offload(file_id, self.file_header(file_id)?)?;
+            self.file_chunk_queue.lock().unwrap().remove(file_id);
+        }
+        Ok(())
+    }
+
+    pub fn chunk_queue_stats(&self) -> ChunkQueueStats {
+        self.file_chunk_queue.lock().unwrap().stats()
+    }
+
+    pub fn file_size(&self, file_id: u32) -> Result<u64> {
+        if let Some(stub) = remote_stub(&self.remote, file_id) {
+            return Ok(stub.size);
+        }
+        Ok(self.data_file(file_id)?.metadata()?.len())
+    }
+
+    // Files published by a compaction are indexed before `Lsm::swap_files` runs, so their
+    // header may not be known yet.
+    fn file_header(&self, file_id: u32) -> Result<FileHeader> {
+        let file_header = self.file_headers.read().unwrap().get(&file_id).cloned();
+        match file_header {
+            Some(file_header) => Ok(file_header),
+            None => {
+                let file_header = match remote_stub(&self.remote, file_id) {
+                    Some(stub) => stub.header,
+                    None => read_file_header(&*self.storage, &self.path, file_id)?,
+                };
+                self.add_file_header(file_id, file_header);
+                Ok(file_header)
+            }
+        }
+    }
+
+    // The codec of a compressed or encrypted file, see `ValueCodec`.
+    fn codec(&self, file_id: u32, file_header: &FileHeader) -> Result<Option<Arc<ValueCodec>>> {
+        if !file_header.has_flag(FLAG_COMPRESSION | FLAG_ENCRYPTION) {
+            return Ok(None);
+        }
+        if let Some(codec) = self.codecs.read().unwrap().get(&file_id) {
+            return Ok(Some(codec.clone()));
+        }
+
+        let data_file = self.data_file(file_id)?;
+        let mut dictionary = vec![0u8; file_header.dictionary_size as usize];
+        PositionedReader::new(&*self.storage, &data_file, file_header.dictionary_offset())
+            .read_exact(&mut dictionary)?;
+        let codec = match ValueCodec::new(file_header, &dictionary, self.keyring.as_ref())? {
+            Some(codec) => Arc::new(codec),
+            None => return Ok(None),
+        };
+        self.codecs.write().unwrap().insert(file_id, codec.clone());
+        Ok(Some(codec))
+    }
+
+    pub fn read_log<'a>(&self, file_id: u32, log_pos: u64) -> Result<Log<'a>> {
+        let mut log = self.read_record(file_id, log_pos)?;
+        if let Some(codec) = self.codec(file_id, &self.file_header(file_id)?)? {
+            codec.decode_log(&mut log)?;
+        }
+        Ok(log)
+    }
+
+    // The record as stored, whose value may be compressed.
+    pub fn read_record<'a>(&self, file_id: u32, log_pos: u64) -> Result<Log<'a>> {
+        let file_header = self.file_header(file_id)?;
+        let data_file = self.data_file(file_id)?;
+
+        Log::decode(
+            &mut PositionedReader::new(&*self.storage, &data_file, log_pos),
+            &file_header,
+        )
+    }
+
+    // Read a whole record of `log_size` bytes with a single allocation and return its
+    // value as a slice of that buffer.
+    pub fn read_value(&self, file_id: u32, log_pos: u64, log_size: u64) -> Result<Option<Bytes>> {
+        let file_header = self.file_header(file_id)?;
+        let data_file = self.data_file(file_id)?;
+
+        let mut record = vec![0u8; log_size as usize];
+        PositionedReader::new(&*self.storage, &data_file, log_pos).read_exact(&mut record)?;
+
+        match Log::decode_value(Bytes::from(record), &file_header)? {
+            Some(StoredValue::Inline(value)) => match self.codec(file_id, &file_header)? {
+                Some(codec) => Ok(Some(codec.decode_bytes(value)?)),
+                None => Ok(Some(value)),
+            },
+            Some(StoredValue::Blob(pointer)) => {
+                Ok(Some(read_blob(&*self.storage, &self.path, &pointer)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Like `read_value`, but a value of a blob file is only read as the chunks are asked
+    // for, see `ValueReader`.
+    pub fn open_value(&self, file_id: u32, log_pos: u64, log_size: u64) -> Result<Option<ValueReader>> {
+        let file_header = self.file_header(file_id)?;
+        let data_file = self.data_file(file_id)?;
+
+        let mut record = vec![0u8; log_size as usize];
+        PositionedReader::new(&*self.storage, &data_file, log_pos).read_exact(&mut record)?;
+
+        match Log::decode_value(Bytes::from(record), &file_header)? {
+            Some(StoredValue::Inline(value)) => match self.codec(file_id, &file_header)? {
+                Some(codec) => Ok(Some(ValueReader::Inline(codec.decode_bytes(value)?))),
+                None => Ok(Some(ValueReader::Inline(value))),
+            },
+            Some(StoredValue::Blob(pointer)) => Ok(Some(ValueReader::Blob(BlobReader::open(
+                self.storage.clone(),
+                &self.path,
+                &pointer,
+            )?))),
+            None => Ok(None),
+        }
+    }
+
+    // Replace the blob pointer of a record by the value it points to.
+    pub fn resolve<'a>(&self, log: Log<'a>) -> Result<Log<'a>> {
+        resolve_blob(&*self.storage, &self.path, log)
+    }
+
+    pub fn read_value_into(
+        &self,
+        file_id: u32,
+        log_pos: u64,
+        log_size: u64,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<usize>> {
+        let file_header = self.file_header(file_id)?;
+        let data_file = self.data_file(file_id)?;
+
+        buf.clear();
+        buf.resize(log_size as usize, 0);
+        PositionedReader::new(&*self.storage, &data_file, log_pos).read_exact(buf)?;
+
+        match Log::decode_value_into(buf, &file_header)? {
+            Some(StoredValue::Inline(len)) => match self.codec(file_id, &file_header)? {
+                Some(codec) => {
+                    *buf = codec.decode(buf)?;
+                    Ok(Some(buf.len()))
+                }
+                None => Ok(Some(len)),
+            },
+            Some(StoredValue::Blob(pointer)) => {
+                Ok(Some(read_blob_into(&*self.storage, &self.path, &pointer, buf)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn add_file_header(&self, file_id: u32, file_header: FileHeader) {
+        self.file_headers.write().unwrap().insert(file_id, file_header);
+    }
+
+    fn remove_files(&self, file_ids: &[u32]) {
+        let mut file_headers = self.file_headers.write().unwrap();
+        let mut codecs = self.codecs.write().unwrap();
+        let mut file_chunk_queue = self.file_chunk_queue.lock().unwrap();
+        for &file_id in file_ids {
+            file_headers.remove(&file_id);
+            codecs.remove(&file_id);
+            file_chunk_queue.remove(file_id);
+        }
     }
 }
 
 pub struct LsmWriter {
     path: PathBuf,
     sync: bool,
+    temp: bool,
     max_file_size: usize,
+    checksum: ChecksumKind,
+    direct_io: bool,
     file_id_seq: Arc<Sequence>,
+    storage: Arc<dyn Storage>,
     log_writer: Option<LogWriter>,
+    // Set for the files of a compaction, see `compress`.
+    compressor: Option<ValueCompressor>,
+    // The dictionary of the compressor, as stored in the header of the files.
+    dictionary: Vec<u8>,
+    // The keyring and the key encrypting the files, see `encrypt`.
+    keyring: Option<(Keyring, u32)>,
+    temp_files: Vec<u32>,
+    // The files closed since the last `take_sealed_files`, with their seal.
+    sealed_files: Vec<(u32, FileSeal)>,
 }
 
+// The position of a record and its size as stored, in a new file for `NewFile`.
 pub enum LsmWrite {
-    Ok(u64),
-    NewFile(u32),
+    Ok(u64, u64),
+    NewFile(u32, u64, u64),
 }
 
 impl LsmWriter {
@@ -256,16 +1149,74 @@ impl LsmWriter {
         path: &Path,
         sync: bool,
         max_file_size: usize,
+        checksum: ChecksumKind,
+        direct_io: bool,
         file_id_seq: Arc<Sequence>,
+        storage: Arc<dyn Storage>,
     ) -> LsmWriter {
 
         LsmWriter {
             path: path.to_path_buf(),
-            sync: sync,
-            max_file_size: max_file_size,
-            file_id_seq: file_id_seq,
+            sync,
+            temp: false,
+            max_file_size,
+            checksum,
+            direct_io,
+            file_id_seq,
+            storage,
             log_writer: None,
+            compressor: None,
+            dictionary: Vec::new(),
+            keyring: None,
+            temp_files: Vec::new(),
+            sealed_files: Vec::new(),
+        }
+    }
+
+    // A temporary writer only produces `*.tmp` files which are invisible to the store
+    // until `publish` renames them. Unpublished files are removed when it is dropped.
+    pub fn temp(
+        path: &Path,
+        max_file_size: usize,
+        checksum: ChecksumKind,
+        direct_io: bool,
+        file_id_seq: Arc<Sequence>,
+        storage: Arc<dyn Storage>,
+    ) -> LsmWriter {
+        let mut lsm_writer =
+            LsmWriter::new(path, false, max_file_size, checksum, direct_io, file_id_seq, storage);
+        lsm_writer.temp = true;
+        lsm_writer
+    }
+
+    // Encrypt the values of the files it writes with the key `key_id` of `keyring`,
+    // before the first write.
+    pub fn encrypt(&mut self, keyring: Keyring, key_id: u32) {
+        self.keyring = Some((keyring, key_id));
+    }
+
+    // Compress the values of the files written from now on, whose header holds the
+    // dictionary of the compressor, encrypted like the values.
+    pub fn compress(&mut self, compressor: ValueCompressor) -> Result<()> {
+        self.seal_active()?;
+        self.dictionary = match self.keyring {
+            Some((ref keyring, key_id)) => keyring.encrypt(key_id, compressor.dictionary())?,
+            None => compressor.dictionary().to_vec(),
+        };
+        self.compressor = Some(compressor);
+        Ok(())
+    }
+
+    // The header of the data files it writes.
+    fn file_header(&self) -> FileHeader {
+        let mut file_header = FileHeader::with_checksum(self.checksum);
+        if let Some((_, key_id)) = self.keyring {
+            file_header = file_header.with_key(key_id);
         }
+        if self.compressor.is_some() {
+            file_header = file_header.with_dictionary(&self.dictionary);
+        }
+        file_header
     }
 
     fn log_writer(&mut self) -> Result<&LogWriter> {
@@ -276,138 +1227,606 @@ impl LsmWriter {
     }
 
     fn new_log_writer(&mut self) -> Result<u32> {
-        let file_id = self.file_id_seq.increment();
+        self.seal_active()?;
+        let file_id = self.file_id_seq.increment()?;
 
-        if self.log_writer.is_some() {
-            info!(
-                "Closed data file {:?}",
-                self.log_writer.as_ref().unwrap().data_file_path
-            );
+        if self.temp {
+            self.temp_files.push(file_id);
         }
 
-        self.log_writer = Some(LogWriter::new(&self.path, self.sync, file_id)?);
+        self.log_writer = Some(LogWriter::new(
+            &self.path,
+            self.sync,
+            self.temp,
+            file_id,
+            self.file_header(),
+            &self.dictionary,
+            self.direct_io,
+            self.storage.clone(),
+        )?);
         Ok(file_id)
     }
 
+    // Close the file being written, if any, which gets sealed.
+    fn seal_active(&mut self) -> Result<()> {
+        if let Some(log_writer) = self.log_writer.take() {
+            info!("Closed data file {:?}", log_writer.data_file_path);
+            let file_id = log_writer.file_id;
+            self.sealed_files.push((file_id, log_writer.close()?));
+        }
+        Ok(())
+    }
+
+    pub fn take_sealed_files(&mut self) -> Vec<(u32, FileSeal)> {
+        std::mem::take(&mut self.sealed_files)
+    }
+
     pub fn write(&mut self, log: &Log) -> Result<LsmWrite> {
-        Ok(if self.log_writer.is_none() ||
-            self.log_writer.as_ref().unwrap().data_file_pos + log.size() >
-            self.max_file_size as u64
-        {
-            if self.log_writer.is_some() {
-                info!(
-                    "Data file {:?} reached file limit of {}",
-                    self.log_writer.as_ref().unwrap().data_file_path,
-                    human_readable_byte_count(self.max_file_size, true)
-                );
+        self.write_with(log, |_, _| Ok(()))
+    }
+
+    // Like `write`, `on_new_file` being called with the id of a new file before the record
+    // is written to it, e.g. to register the file in the manifest first. The file is left
+    // behind, unused, when it fails.
+    pub fn write_with<F>(&mut self, log: &Log, on_new_file: F) -> Result<LsmWrite>
+    where
+        F: FnOnce(&mut LsmWriter, u32) -> Result<()>,
+    {
+        // Records copied from the files of older versions get an unknown timestamp.
+        let file_header = self.file_header();
+        let mut log = log.in_format(&file_header);
+        if let Some(ref mut compressor) = self.compressor {
+            compressor.compress(&mut log)?;
+        }
+        if let Some((ref keyring, key_id)) = self.keyring {
+            keyring.encrypt_log(key_id, &mut log)?;
+        }
+        let log = &log;
+        if let Some(ref mut log_writer) = self.log_writer {
+            if log_writer.data_file_pos + log.size() <= self.max_file_size as u64 {
+                let log_pos = log_writer.write(log)?;
+                return Ok(LsmWrite::Ok(log_pos, log.size()));
+            }
+
+            info!(
+                "Data file {:?} reached file limit of {}",
+                log_writer.data_file_path,
+                human_readable_byte_count(self.max_file_size, true)
+            );
+        }
+
+        let file_id = self.new_log_writer()?;
+        if let Err(err) = on_new_file(self, file_id) {
+            self.log_writer = None;
+            return Err(err);
+        }
+        let log_pos = self.log_writer.as_mut().unwrap().write(log)?;
+
+        assert_eq!(log_pos, file_header.size());
+
+        Ok(LsmWrite::NewFile(file_id, log_pos, log.size()))
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        if let Some(ref writer) = self.log_writer {
+            self.storage.sync(&writer.data_file)?
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        if let Some(ref writer) = self.log_writer {
+            self.storage.sync(&writer.data_file)?;
+            writer.compaction_writer.sync()?;
+        }
+        Ok(())
+    }
+
+    // Returns the published files, which are all sealed.
+    pub fn publish(mut self) -> Result<Vec<(u32, FileSeal)>> {
+        self.seal_active()?;
+
+        let temp_files = std::mem::take(&mut self.temp_files);
+        for &file_id in &temp_files {
+            self.storage.rename(
+                &get_temp_data_file_path(&self.path, file_id),
+                &get_data_file_path(&self.path, file_id),
+            )?;
+            self.storage.rename(
+                &get_temp_compaction_hint_file_path(&self.path, file_id),
+                &get_compaction_hint_file_path(&self.path, file_id),
+            )?;
+        }
+        self.storage.sync_dir(&self.path)?;
+
+        Ok(self.take_sealed_files())
+    }
+}
+
+impl Drop for LsmWriter {
+    fn drop(&mut self) {
+        if self.temp_files.is_empty() {
+            return;
+        }
+
+        self.log_writer = None;
+        for &file_id in &self.temp_files {
+            warn!("Discarding unpublished data file {}", file_id);
+            let _ = self.storage.remove(&get_temp_data_file_path(&self.path, file_id));
+            let _ = self.storage.remove(&get_temp_compaction_hint_file_path(&self.path, file_id));
+        }
+    }
+}
+
+// Brings data files written outside of the store, e.g. by an `SstBuilder`, in under new
+// file ids. Like the files of a compaction, they are temporary files until `publish`
+// renames them, and only part of the store once added to the manifest.
+pub struct Ingester {
+    path: PathBuf,
+    storage: Arc<dyn Storage>,
+    file_id_seq: Arc<Sequence>,
+    temp_files: Vec<u32>,
+    sealed_files: Vec<(u32, FileSeal)>,
+}
+
+// A data file brought in by an `Ingester`.
+pub struct IngestedFile {
+    pub file_id: u32,
+    pub records: u64,
+    pub last_seq: u64,
+}
+
+impl Ingester {
+    // The file is hard linked into the store, or copied when it's on another file system,
+    // then every record is checked while its hint file is written. A file without a
+    // header, with an unreadable record or pointing to blob files is refused.
+    pub fn add(&mut self, source: &Path) -> Result<IngestedFile> {
+        let file_id = self.file_id_seq.increment()?;
+        let data_file_path = get_temp_data_file_path(&self.path, file_id);
+        self.temp_files.push(file_id);
+        if fs::hard_link(source, &data_file_path).is_err() {
+            fs::copy(source, &data_file_path)?;
+        }
+
+        let invalid = |reason: String| Error::InvalidSstFile(format!("{:?}: {}", source, reason));
+        let mut data_file = self.storage.open(&data_file_path)?;
+        let length = data_file.metadata()?.len();
+        let file_header = FileHeader::from_read(DATA_FILE_MAGIC, &mut data_file)?;
+        if file_header.version == LEGACY_FORMAT_VERSION {
+            return Err(invalid("not a CrabeDB data file".to_string()));
+        }
+
+        let mut reader = HashingReader {
+            reader: BufReader::new(data_file),
+            hasher: ChecksumHasher::new(ChecksumKind::XxHash64),
+        };
+        file_header.write_bytes(DATA_FILE_MAGIC, &mut reader.hasher)?;
+        if file_header.has_flag(FLAG_COMPRESSION) {
+            let mut dictionary = vec![0u8; file_header.dictionary_size as usize];
+            reader.reader.seek(SeekFrom::Start(file_header.dictionary_offset()))?;
+            reader.reader.read_exact(&mut dictionary)?;
+            reader.hasher.update(&dictionary);
+        }
+        let mut hint_writer = CompactionHintWriter::new(
+            self.storage.clone(),
+            &get_temp_compaction_hint_file_path(&self.path, file_id),
+            file_header,
+        )?;
+        // None of its records points to a blob file, which is checked below.
+        hint_writer.track_blobs();
+
+        let (mut records, mut last_seq) = (0, 0);
+        let mut log_pos = file_header.size();
+        while log_pos < length {
+            let log = Log::decode(&mut reader, &file_header)
+                .map_err(|err| invalid(format!("unreadable record at offset {}: {}", log_pos, err)))?;
+            if log.blob {
+                return Err(invalid(format!("the record at offset {} points to a blob file", log_pos)));
             }
+            hint_writer.write(&CompactionHint::new(&log, log_pos))?;
+            records += 1;
+            last_seq = last_seq.max(log.seq);
+            log_pos += log.size();
+        }
+        hint_writer.finish()?;
+        // A copied file isn't durable yet.
+        self.storage.sync(reader.reader.get_ref())?;
 
-            let file_id = self.new_log_writer()?;
-            let log_pos = self.log_writer.as_mut().unwrap().write(log)?;
+        let seal = FileSeal {
+            length,
+            hash: reader.hasher.get(),
+        };
+        self.sealed_files.push((file_id, seal));
+        Ok(IngestedFile {
+            file_id,
+            records,
+            last_seq,
+        })
+    }
 
-            assert_eq!(log_pos, 0);
+    // Returns the published files, which are all sealed.
+    pub fn publish(mut self) -> Result<Vec<(u32, FileSeal)>> {
+        let temp_files = std::mem::take(&mut self.temp_files);
+        for &file_id in &temp_files {
+            self.storage.rename(
+                &get_temp_data_file_path(&self.path, file_id),
+                &get_data_file_path(&self.path, file_id),
+            )?;
+            self.storage.rename(
+                &get_temp_compaction_hint_file_path(&self.path, file_id),
+                &get_compaction_hint_file_path(&self.path, file_id),
+            )?;
+        }
+        self.storage.sync_dir(&self.path)?;
 
-            LsmWrite::NewFile(file_id)
-        } else {
-            let log_pos = self.log_writer.as_mut().unwrap().write(log)?;
-            LsmWrite::Ok(log_pos)
-        })
+        Ok(std::mem::take(&mut self.sealed_files))
     }
+}
 
-    pub fn sync(&self) -> Result<()> {
-        if let Some(ref writer) = self.log_writer {
-            writer.data_file.sync_data()?
+impl Drop for Ingester {
+    fn drop(&mut self) {
+        for &file_id in &self.temp_files {
+            warn!("Discarding unpublished ingested file {}", file_id);
+            let _ = self.storage.remove(&get_temp_data_file_path(&self.path, file_id));
+            let _ = self.storage.remove(&get_temp_compaction_hint_file_path(&self.path, file_id));
         }
-        Ok(())
+    }
+}
+
+// Hashes the bytes read through it, for the seal of a file read once.
+struct HashingReader<R> {
+    reader: R,
+    hasher: ChecksumHasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
     }
 }
 
 pub struct LogWriter {
+    file_id: u32,
     sync: bool,
+    storage: Arc<dyn Storage>,
     data_file_path: PathBuf,
     data_file: File,
     data_file_pos: u64,
+    checksum: ChecksumKind,
+    buffer: Vec<u8>,
+    // Set with `StorageOptions::direct_io`, when the file system supports it.
+    direct: Option<DirectAppender>,
+    // Hash of the whole file, for its seal.
+    file_hasher: ChecksumHasher,
     compaction_writer: CompactionHintWriter,
+    // Written in the header once sealed, see `FLAG_MAX_SEQ`.
+    max_seq: Option<u64>,
 }
 
 impl LogWriter {
-    pub fn new(path: &Path, sync: bool, file_id: u32) -> Result<LogWriter> {
-        let data_file_path = get_data_file_path(path, file_id);
-        let data_file = get_file_handle(&data_file_path, true)?;
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        path: &Path,
+        sync: bool,
+        temp: bool,
+        file_id: u32,
+        file_header: FileHeader,
+        dictionary: &[u8],
+        direct_io: bool,
+        storage: Arc<dyn Storage>,
+    ) -> Result<LogWriter> {
+        let (data_file_path, compaction_file_path) = if temp {
+            (
+                get_temp_data_file_path(path, file_id),
+                get_temp_compaction_hint_file_path(path, file_id),
+            )
+        } else {
+            (
+                get_data_file_path(path, file_id),
+                get_compaction_hint_file_path(path, file_id),
+            )
+        };
+        // A file with the same id can't be overwritten.
+        let data_file = storage.create(&data_file_path, true)?;
+        let mut file_hasher = ChecksumHasher::new(ChecksumKind::XxHash64);
+        let mut header = Vec::new();
+        file_header.write_bytes(DATA_FILE_MAGIC, &mut header)?;
+        header.extend_from_slice(dictionary);
+        storage.append(&data_file, &header)?;
+        file_hasher.update(&header);
 
         info!("Created new data file {:?}", data_file_path);
 
-        let compaction_writer = CompactionHintWriter::new(path, file_id)?;
+        let direct = if direct_io {
+            match DirectAppender::open(&data_file_path, &header) {
+                Ok(direct) => Some(direct),
+                Err(err) => {
+                    warn!("Writing data file {:?} without direct I/O: {}", data_file_path, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut compaction_writer = CompactionHintWriter::new(storage.clone(), &compaction_file_path, file_header)?;
+        compaction_writer.track_blobs();
+
+        // Syncing the file itself doesn't make its directory entry durable: a rotated file
+        // could vanish after a power failure, along with the records synced to it. The
+        // temporary files are only made durable when they are published.
+        if !temp {
+            storage.sync_dir(path)?;
+        }
 
         Ok(LogWriter {
-            sync: sync,
-            data_file_path: data_file_path,
-            data_file: data_file,
-            data_file_pos: 0,
-            compaction_writer: compaction_writer,
+            file_id,
+            sync,
+            storage,
+            data_file_path,
+            data_file,
+            data_file_pos: file_header.size(),
+            checksum: file_header.checksum(),
+            buffer: Vec::new(),
+            direct,
+            file_hasher,
+            compaction_writer,
+            max_seq: if file_header.has_flag(FLAG_MAX_SEQ) { Some(0) } else { None },
         })
     }
 
     pub fn write<'a>(&mut self, log: &Log<'a>) -> Result<u64> {
         let log_pos = self.data_file_pos;
 
+        // Encode the whole record first so it is appended with a single write.
         let ch = CompactionHint::new(log, log_pos);
-        log.write_bytes(&mut self.data_file)?;
+        self.buffer.clear();
+        log.write_bytes(&mut self.buffer, self.checksum)?;
+        match self.direct {
+            Some(ref mut direct) => direct.append(&*self.storage, &self.data_file, &self.buffer)?,
+            None => self.storage.write_all_at(&self.data_file, &self.buffer, log_pos)?,
+        }
+        self.file_hasher.update(&self.buffer);
 
         self.compaction_writer.write(&ch)?;
+        if let Some(pointer) = log.blob_pointer()? {
+            self.compaction_writer.add_blob(pointer.file_id);
+        }
+        if let Some(ref mut max_seq) = self.max_seq {
+            *max_seq = (*max_seq).max(log.seq);
+        }
 
         if self.sync {
-            self.data_file.sync_data()?;
+            self.storage.sync(&self.data_file)?;
         }
 
         self.data_file_pos += log.size();
 
         Ok(log_pos)
     }
+
+    pub fn close(mut self) -> Result<FileSeal> {
+        if let Some(max_seq) = self.max_seq.filter(|&max_seq| max_seq > 0) {
+            self.storage.write_all_at(&self.data_file, &max_seq.to_le_bytes(), MAX_SEQ_OFFSET)?;
+        }
+        self.storage.sync(&self.data_file)?;
+        self.compaction_writer.finish()?;
+        Ok(FileSeal {
+            length: self.data_file_pos,
+            hash: self.file_hasher.get(),
+        })
+    }
 }
 
 impl Drop for LogWriter {
     fn drop(&mut self) {
-        let _ = self.data_file.sync_data();
+        let _ = self.storage.sync(&self.data_file);
     }
 }
 
-struct CompactionHintWriter {
+pub(crate) struct CompactionHintWriter {
+    storage: Arc<dyn Storage>,
     compaction_file: File,
-    compaction_file_hasher: XxHash32,
+    compaction_file_hasher: ChecksumHasher,
+    file_header: FileHeader,
+    // Written in the footer, see `FLAG_FILE_SUMMARY`.
+    summary: Option<FileSummary>,
+    buffer: Vec<u8>,
+    finished: bool,
 }
 
 impl CompactionHintWriter {
-    pub fn new(path: &Path, file_id: u32) -> Result<CompactionHintWriter> {
-        let compaction_hint_file = get_file_handle(&get_compaction_hint_file_path(path, file_id), true)?;
+    // A hint file shares the format of its data file, whose records its hints describe,
+    // but not its dictionary.
+    pub fn new(storage: Arc<dyn Storage>, path: &Path, file_header: FileHeader) -> Result<CompactionHintWriter> {
+        let file_header = file_header.hint_header();
+        let compaction_hint_file = storage.create(path, false)?;
+        let mut compaction_file_hasher = ChecksumHasher::new(file_header.hint_file_checksum());
+
+        // The header is covered by the trailing checksum like the hints themselves.
+        file_header.write_bytes(HINT_FILE_MAGIC, &mut Appender::new(&*storage, &compaction_hint_file))?;
+        file_header.write_bytes(HINT_FILE_MAGIC, &mut compaction_file_hasher)?;
 
         Ok(CompactionHintWriter {
+            storage,
             compaction_file: compaction_hint_file,
-            compaction_file_hasher: XxHash32::new(),
+            compaction_file_hasher,
+            file_header,
+            summary: if file_header.has_flag(FLAG_FILE_SUMMARY) { Some(FileSummary::default()) } else { None },
+            buffer: Vec::new(),
+            finished: false,
         })
     }
 
     pub fn write<'a>(&mut self, ch: &CompactionHint<'a>) -> Result<()> {
-        ch.write_bytes(&mut self.compaction_file)?;
-        ch.write_bytes(&mut self.compaction_file_hasher)?;
+        self.buffer.clear();
+        ch.write_bytes(&mut self.buffer)?;
+        if self.file_header.has_flag(FLAG_HINT_CHECKSUMS) {
+            let checksum = self.file_header.hint_file_checksum();
+            let mut hasher = ChecksumHasher::new(checksum);
+            hasher.update(&self.buffer);
+            write_checksum(&mut self.buffer, checksum, hasher.get())?;
+        }
+        self.storage.append(&self.compaction_file, &self.buffer)?;
+        self.compaction_file_hasher.update(&self.buffer);
+        if let Some(ref mut summary) = self.summary {
+            summary.add(ch);
+        }
+        Ok(())
+    }
+
+    // Record the blob files the records of the data file point to in its summary, for a
+    // writer which sees every one of them, see `add_blob`.
+    pub fn track_blobs(&mut self) {
+        if let Some(ref mut summary) = self.summary {
+            summary.blob_files.get_or_insert_with(BTreeSet::new);
+        }
+    }
+
+    pub fn add_blob(&mut self, file_id: u32) {
+        if let Some(ref mut summary) = self.summary {
+            summary.add_blob(file_id);
+        }
+    }
+
+    // The hints written so far, without the trailing checksum.
+    pub fn sync(&self) -> Result<()> {
+        self.storage.sync(&self.compaction_file)?;
+        Ok(())
+    }
+
+    // The summary of the hints written so far.
+    pub fn summary(&self) -> Option<&FileSummary> {
+        self.summary.as_ref()
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        if !self.finished {
+            self.finished = true;
+            self.write_trailer()?;
+            self.storage.sync(&self.compaction_file)?;
+        }
+        Ok(())
+    }
+
+    // The footer, if any, then the checksum of the whole file.
+    fn write_trailer(&mut self) -> Result<()> {
+        let mut compaction_file = Appender::new(&*self.storage, &self.compaction_file);
+        if let Some(ref summary) = self.summary {
+            summary.write_footer(&mut compaction_file, &self.file_header)?;
+            summary.write_footer(&mut self.compaction_file_hasher, &self.file_header)?;
+        }
+        write_checksum(
+            &mut compaction_file,
+            self.file_header.hint_file_checksum(),
+            self.compaction_file_hasher.get(),
+        )?;
         Ok(())
     }
 }
 
 impl Drop for CompactionHintWriter {
     fn drop(&mut self) {
-        let _ = self.compaction_file.write_u32::<LittleEndian>(
-            self.compaction_file_hasher.get(),
-        );
+        if !self.finished {
+            let _ = self.write_trailer();
+        }
     }
 }
 
 pub struct Entries<'a> {
-    data_file: Take<File>,
+    data_file: Take<BufReader<File>>,
+    data_file_header: FileHeader,
     data_file_pos: u64,
+    data_file_size: u64,
+    // The dictionary of a compressed file, as stored.
+    dictionary: Vec<u8>,
+    recovery_mode: RecoveryMode,
     phantom: PhantomData<&'a ()>,
 }
 
+impl<'a> Entries<'a> {
+    fn new(mut data_file: File, recovery_mode: RecoveryMode) -> Result<Entries<'a>> {
+        let data_file_size = data_file.metadata()?.len();
+        let data_file_header = FileHeader::from_read(DATA_FILE_MAGIC, &mut data_file)?;
+        let dictionary = read_dictionary(&mut data_file, &data_file_header)?;
+        let data_file_pos = data_file_header.size();
+
+        Ok(Entries {
+            data_file: BufReader::with_capacity(READ_BUFFER_SIZE, data_file)
+                .take(data_file_size - data_file_pos),
+            data_file_header,
+            data_file_pos,
+            data_file_size,
+            dictionary,
+            recovery_mode,
+            phantom: PhantomData,
+        })
+    }
+
+    pub fn header(&self) -> FileHeader {
+        self.data_file_header
+    }
+
+    // The records are iterated as stored: the values of a compressed or encrypted file
+    // have to go through its codec.
+    pub fn codec(&self, keyring: Option<&Keyring>) -> Result<Option<ValueCodec>> {
+        ValueCodec::new(&self.data_file_header, &self.dictionary, keyring)
+    }
+
+    // Go on from the record at `pos`.
+    fn skip_to(&mut self, pos: u64) -> Result<()> {
+        self.data_file.get_mut().seek(SeekFrom::Start(pos))?;
+        self.data_file.set_limit(self.data_file_size - pos);
+        self.data_file_pos = pos;
+        Ok(())
+    }
+
+    // Look for the next offset at which a record can be decoded with a valid checksum. The
+    // offsets are checked in a window of the file read in memory: only those whose header
+    // claims a record fitting in the file, with a matching checksum when it fits in the
+    // window, are decoded from the file.
+    fn resync(&mut self, from_pos: u64) -> Option<(u64, Log<'a>)> {
+        let (mut window, mut window_pos) = (Vec::new(), from_pos);
+        let mut pos = from_pos;
+        while pos < self.data_file_size {
+            let mut offset = (pos - window_pos) as usize;
+            let mut claimed = Log::claimed_size(&window[offset..], &self.data_file_header);
+            if claimed.is_none() && window_pos + (window.len() as u64) < self.data_file_size {
+                // The header runs past the window, which starts from `pos` again.
+                window = self.read_window(pos)?;
+                window_pos = pos;
+                offset = 0;
+                claimed = Log::claimed_size(&window, &self.data_file_header);
+            }
+            let size = claimed?;
+
+            let candidate = pos + size <= self.data_file_size
+                && window.get(offset..offset + size as usize).is_none_or(|record| {
+                    Log::has_valid_checksum(record, &self.data_file_header)
+                });
+            if candidate {
+                self.skip_to(pos).ok()?;
+                if let Ok(log) = Log::decode(&mut self.data_file, &self.data_file_header) {
+                    return Some((pos, log));
+                }
+            }
+            pos += 1;
+        }
+        None
+    }
+
+    // Up to `RESYNC_WINDOW_SIZE` bytes of the file from `pos`.
+    fn read_window(&mut self, pos: u64) -> Option<Vec<u8>> {
+        let reader = self.data_file.get_mut();
+        reader.seek(SeekFrom::Start(pos)).ok()?;
+        let mut window = Vec::with_capacity(RESYNC_WINDOW_SIZE);
+        reader.by_ref().take(RESYNC_WINDOW_SIZE as u64).read_to_end(&mut window).ok()?;
+        Some(window)
+    }
+}
+
 impl<'a> Iterator for Entries<'a> {
     type Item = (u64, Result<Log<'a>>);
 
@@ -416,7 +1835,7 @@ impl<'a> Iterator for Entries<'a> {
         if limit == 0 {
             None
         } else {
-            let log = Log::from_read(&mut self.data_file);
+            let log = Log::decode(&mut self.data_file, &self.data_file_header);
             let log_pos = self.data_file_pos;
 
             let read = limit - self.data_file.limit();
@@ -428,6 +1847,25 @@ impl<'a> Iterator for Entries<'a> {
                     assert_eq!(log.size(), read);
                     Ok(log)
                 }
+                Err(err) if self.recovery_mode == RecoveryMode::SkipCorrupt => {
+                    warn!("Skipping unreadable record at offset {}: {}", log_pos, err);
+                    return match self.resync(log_pos + 1) {
+                        Some((pos, log)) => {
+                            warn!(
+                                "Resynchronized on record at offset {}, skipped {} bytes",
+                                pos,
+                                pos - log_pos
+                            );
+                            self.data_file_pos = pos + log.size();
+                            Some((pos, Ok(log)))
+                        }
+                        None => {
+                            warn!("No readable record after offset {}", log_pos);
+                            self.data_file.set_limit(0);
+                            None
+                        }
+                    };
+                }
                 e => e,
             };
 
@@ -436,8 +1874,153 @@ impl<'a> Iterator for Entries<'a> {
     }
 }
 
+// Position of a record in the data files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogPosition {
+    pub file_id: u32,
+    pub pos: u64,
+}
+
+// Iterates over the records appended to the data files from a position, moving on to the
+// next data file once one has been read entirely. The iteration stops at the end of the
+// last file, and `next` returns the records written since when called again. Values
+// stored in blob files are resolved.
+//
+// Records copied by a compaction into newer files are skipped, as their sequence number
+// was already passed. A position at the start of a file which doesn't exist (e.g. file 0)
+// starts from the next file, while the files a tail is positioned on or hasn't reached
+// yet shouldn't be compacted away, by pausing the compaction if the tail lags behind. A
+// tail follows the new writes: it doesn't replace a copy of the store, as the records
+// compacted into newer files are skipped as well.
+pub struct Tail {
+    path: PathBuf,
+    storage: Arc<dyn Storage>,
+    remote: Option<Arc<RemoteFiles>>,
+    keyring: Option<Keyring>,
+    file: Option<(File, FileHeader, Option<ValueCodec>)>,
+    position: LogPosition,
+    last_seq: Option<u64>,
+}
+
+impl Tail {
+    // Position of the next record, from which another tail can resume.
+    pub fn position(&self) -> LogPosition {
+        self.position
+    }
+
+    fn next_file_id(&self) -> Result<Option<u32>> {
+        Ok(find_all_data_files(&*self.storage, &self.path, &self.remote)?
+            .into_iter()
+            .find(|&file_id| file_id > self.position.file_id))
+    }
+
+    fn move_to(&mut self, file_id: u32) {
+        self.file = None;
+        self.position = LogPosition { file_id, pos: 0 };
+    }
+
+    // Open the file of the current position, or return false when the writer hasn't
+    // created it yet.
+    fn open(&mut self) -> Result<bool> {
+        loop {
+            if let Some(ref remote) = self.remote {
+                remote.fetch(self.position.file_id)?;
+            }
+            let data_file_path = get_data_file_path(&self.path, self.position.file_id);
+            if !self.storage.exists(&data_file_path) {
+                if self.position.pos != 0 {
+                    return Err(Error::InvalidFileId(self.position.file_id));
+                }
+                match self.next_file_id()? {
+                    Some(file_id) => {
+                        self.move_to(file_id);
+                        continue;
+                    }
+                    None => return Ok(false),
+                }
+            }
+
+            let mut data_file = self.storage.open(&data_file_path)?;
+            // The header of a new file may still be partially written.
+            if data_file.metadata()?.len() < MAX_FILE_HEADER_SIZE
+                && self.next_file_id()?.is_none()
+            {
+                return Ok(false);
+            }
+
+            let file_header = FileHeader::from_read(DATA_FILE_MAGIC, &mut data_file)?;
+            let dictionary = read_dictionary(&mut data_file, &file_header)?;
+            let codec = ValueCodec::new(&file_header, &dictionary, self.keyring.as_ref())?;
+            self.position.pos = self.position.pos.max(file_header.size());
+            self.file = Some((data_file, file_header, codec));
+            return Ok(true);
+        }
+    }
+
+    fn read_log(&self) -> Result<Log<'static>> {
+        let (ref data_file, ref file_header, _) = *self.file.as_ref().unwrap();
+        Log::decode(
+            &mut PositionedReader::new(&*self.storage, data_file, self.position.pos),
+            file_header,
+        )
+    }
+
+    fn next_log(&mut self) -> Result<Option<Log<'static>>> {
+        loop {
+            if self.file.is_none() && !self.open()? {
+                return Ok(None);
+            }
+
+            let size = self.file.as_ref().unwrap().0.metadata()?.len();
+            if self.position.pos >= size {
+                let next_file_id = self.next_file_id()?;
+                // Records may have been appended before the writer moved to the next file.
+                if self.position.pos < self.file.as_ref().unwrap().0.metadata()?.len() {
+                    continue;
+                }
+                match next_file_id {
+                    Some(file_id) => {
+                        self.move_to(file_id);
+                        continue;
+                    }
+                    None => return Ok(None),
+                }
+            }
+
+            let mut log = match self.read_log() {
+                Ok(log) => log,
+                // The last record of the last file may still be partially written, while
+                // the writer only moves to another file once it is complete.
+                Err(err) => match self.next_file_id()? {
+                    Some(_) => self.read_log().map_err(|_| err)?,
+                    None => return Ok(None),
+                },
+            };
+            self.position.pos += log.size();
+
+            if self.last_seq.is_some_and(|last_seq| log.seq <= last_seq) {
+                continue;
+            }
+            self.last_seq = Some(log.seq);
+            if let Some(ref codec) = self.file.as_ref().unwrap().2 {
+                codec.decode_log(&mut log)?;
+            }
+            return resolve_blob(&*self.storage, &self.path, log).map(Some);
+        }
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<Log<'static>>;
+
+    fn next(&mut self) -> Option<Result<Log<'static>>> {
+        self.next_log().transpose()
+    }
+}
+
 pub struct CompactionHints<'a> {
-    compaction_file: Take<File>,
+    compaction_file: Take<BufReader<File>>,
+    compaction_file_header: FileHeader,
     phantom: PhantomData<&'a ()>,
 }
 
@@ -448,48 +2031,430 @@ impl<'a> Iterator for CompactionHints<'a> {
         if self.compaction_file.limit() == 0 {
             None
         } else {
-            Some(CompactionHint::from_read(&mut self.compaction_file))
+            Some(decode_hint(&mut self.compaction_file, &self.compaction_file_header))
         }
     }
 }
 
-pub struct RecreateHints<'a> {
-    hint_writer: CompactionHintWriter,
+// Decode a hint, checking its own checksum when it has one, see `FLAG_HINT_CHECKSUMS`.
+fn decode_hint<'a, R: Read>(reader: &mut R, header: &FileHeader) -> Result<CompactionHint<'a>> {
+    if !header.has_flag(FLAG_HINT_CHECKSUMS) {
+        return CompactionHint::decode(reader, header);
+    }
+    let checksum = header.hint_file_checksum();
+    let mut hashing_reader = HashingReader {
+        reader: &mut *reader,
+        hasher: ChecksumHasher::new(checksum),
+    };
+    let ch = CompactionHint::decode(&mut hashing_reader, header)?;
+    let found = hashing_reader.hasher.get();
+    let expected = read_checksum(reader, checksum)?;
+    if found != expected {
+        return Err(Error::InvalidChecksum { expected, found });
+    }
+    Ok(ch)
+}
+
+// The salvaged hints of a data file, then those of the records after them.
+pub struct RecoveredHints<'a> {
+    path: PathBuf,
+    storage: Arc<dyn Storage>,
+    file_id: u32,
+    salvaged: std::vec::IntoIter<CompactionHint<'a>>,
     entries: Entries<'a>,
 }
 
-impl<'a> Iterator for RecreateHints<'a> {
+impl<'a> RecoveredHints<'a> {
+    // Write the hints to a new hint file, which replaces the damaged one once complete so
+    // that it's never seen half written.
+    pub fn rebuild(self) -> Result<()> {
+        let compaction_file_path = get_compaction_hint_file_path(&self.path, self.file_id);
+        let temp_compaction_file_path = get_temp_compaction_hint_file_path(&self.path, self.file_id);
+        warn!("Re-creating compaction file: {:?}", compaction_file_path);
+
+        let path = self.path.clone();
+        let storage = self.storage.clone();
+        let mut hint_writer =
+            CompactionHintWriter::new(storage.clone(), &temp_compaction_file_path, self.entries.header())?;
+        for hint in self {
+            hint_writer.write(&hint?)?;
+        }
+        hint_writer.finish()?;
+        storage.rename(&temp_compaction_file_path, &compaction_file_path)?;
+        storage.sync_dir(&path)?;
+        info!("Re-created compaction file: {:?}", compaction_file_path);
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for RecoveredHints<'a> {
     type Item = Result<CompactionHint<'a>>;
 
     fn next(&mut self) -> Option<Result<CompactionHint<'a>>> {
-        self.entries.next().map(|e| {
-            let (log_pos, log) = e;
-            let hint = CompactionHint::from(log?, log_pos);
-            self.hint_writer.write(&hint)?;
-            Ok(hint)
-        })
+        if let Some(hint) = self.salvaged.next() {
+            return Some(Ok(hint));
+        }
+        self.entries.next().map(|(log_pos, log)| log.map(|log| CompactionHint::from(log, log_pos)))
     }
 }
 
-impl<'a> Drop for RecreateHints<'a> {
-    fn drop(&mut self) {
-        while self.next().is_some() {}
+fn resolve_blob<'a>(storage: &dyn Storage, path: &Path, mut log: Log<'a>) -> Result<Log<'a>> {
+    if let Some(pointer) = log.blob_pointer()? {
+        let mut value = Vec::new();
+        read_blob_into(storage, path, &pointer, &mut value)?;
+        log.value = Cow::from(value);
+        log.blob = false;
+    }
+    Ok(log)
+}
+
+pub(crate) fn acquire_lock(path: &Path) -> Result<File> {
+    let lock_file = File::create(path.join(LOCK_FILE_NAME))?;
+    lock_file.try_lock_exclusive()?;
+    Ok(lock_file)
+}
+
+pub(crate) fn acquire_readers_lock(path: &Path) -> Result<File> {
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(READERS_LOCK_FILE_NAME))?;
+    FileExt::try_lock_shared(&lock_file)?;
+    Ok(lock_file)
+}
+
+// Remove the files of the store at `path`, then the directory once nothing else is left
+// in it. Fails when the directory doesn't hold a store, or with `Error::InUse` when the
+// store is opened, even read-only, before anything is removed. The files offloaded to the
+// object storage by a tiering policy are left there.
+pub(crate) fn destroy(path: &Path) -> Result<()> {
+    if !path.is_dir() || (!path.join(LOCK_FILE_NAME).is_file() && find_data_files(path)?.is_empty()) {
+        return Err(Error::InvalidPath(path.to_string_lossy().into_owned()));
+    }
+
+    let in_use = |err: Error| match err {
+        Error::Io(ref err) if err.kind() == io::ErrorKind::WouldBlock => Error::InUse,
+        err => err,
+    };
+    // Holding both locks keeps any writer or reader from opening the store meanwhile.
+    let _lock_file = acquire_lock(path).map_err(in_use)?;
+    let readers_lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(READERS_LOCK_FILE_NAME))?;
+    readers_lock_file.try_lock_exclusive().map_err(|err| in_use(err.into()))?;
+
+    remove_snapshots(path)?;
+    lazy_static! {
+        static ref RE: Regex = Regex::new("^(\\d{10}\\.)?crabe\\.").unwrap();
+    }
+    for file in fs::read_dir(path)? {
+        let file = file?;
+        if file.metadata()?.is_file() && RE.is_match(&file.file_name().to_string_lossy()) {
+            fs::remove_file(file.path())?;
+        }
+    }
+
+    if fs::read_dir(path)?.next().is_none() {
+        fs::remove_dir(path)?;
+    } else {
+        warn!("Keeping {:?}, which holds other files than those of the store", path);
+    }
+    info!("Destroyed key/value store: {:?}", path);
+    Ok(())
+}
+
+// Whether another handle holds a lock on the file.
+fn is_locked(lock_file_path: &Path) -> Result<bool> {
+    if !lock_file_path.exists() {
+        return Ok(false);
+    }
+
+    let lock_file = get_file_handle(lock_file_path, false)?;
+    match lock_file.try_lock_exclusive() {
+        Ok(()) => {
+            lock_file.unlock()?;
+            Ok(false)
+        }
+        Err(_) => Ok(true),
+    }
+}
+
+pub(crate) fn open_entries<'a>(
+    storage: &dyn Storage,
+    path: &Path,
+    file_id: u32,
+    recovery_mode: RecoveryMode,
+) -> Result<Entries<'a>> {
+    let data_file_path = get_data_file_path(path, file_id);
+    info!("Loading data file: {:?}", data_file_path);
+    Entries::new(storage.open(&data_file_path)?, recovery_mode)
+}
+
+// The caller is responsible for checking the hint file with `is_valid_compaction_hint_file`.
+pub(crate) fn open_compaction_hints<'a>(storage: &dyn Storage, path: &Path, file_id: u32) -> Result<CompactionHints<'a>> {
+    let compaction_file_path = get_compaction_hint_file_path(path, file_id);
+    info!("Loading compaction file: {:?}", compaction_file_path);
+    let mut compaction_file = storage.open(&compaction_file_path)?;
+    let compaction_file_size = compaction_file.metadata()?.len();
+    let compaction_file_header = FileHeader::from_read(HINT_FILE_MAGIC, &mut compaction_file)?;
+    // The hints stop at the footer, which a valid file with the flag has.
+    let footer_size = if compaction_file_header.has_flag(FLAG_FILE_SUMMARY) {
+        match read_footer(&mut compaction_file, &compaction_file_header, compaction_file_size)? {
+            Some((_, footer_size)) => footer_size,
+            None => return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("hint file {:?} has no valid footer", compaction_file_path),
+            ).into()),
+        }
+    } else {
+        0
+    };
+    compaction_file.seek(SeekFrom::Start(compaction_file_header.size()))?;
+
+    Ok(CompactionHints {
+        compaction_file: BufReader::with_capacity(READ_BUFFER_SIZE, compaction_file)
+            .take(
+                (compaction_file_size - compaction_file_header.size())
+                    .saturating_sub(checksum_size(compaction_file_header.hint_file_checksum()) as u64)
+                    .saturating_sub(footer_size),
+            ),
+        compaction_file_header,
+        phantom: PhantomData,
+    })
+}
+
+// The hints of a hint file, valid or not, up to the first one which is unreadable, has a
+// bad checksum or points past the end of its data file, of `data_file_size` bytes. Only
+// the files whose hints have their own checksum are salvaged.
+fn salvage_compaction_hints<'a>(
+    storage: &dyn Storage,
+    path: &Path,
+    data_file_size: u64,
+) -> Result<Vec<CompactionHint<'a>>> {
+    if !storage.exists(path) {
+        return Ok(Vec::new());
+    }
+    let mut compaction_file = storage.open(path)?;
+    let compaction_file_size = compaction_file.metadata()?.len();
+    let header = FileHeader::from_read(HINT_FILE_MAGIC, &mut compaction_file)?;
+    if !header.has_flag(FLAG_HINT_CHECKSUMS) {
+        return Ok(Vec::new());
+    }
+    // A valid footer bounds the hints, the file may otherwise end anywhere.
+    let end = match read_footer(&mut compaction_file, &header, compaction_file_size)? {
+        Some((_, footer_size)) => {
+            compaction_file_size - footer_size - checksum_size(header.hint_file_checksum()) as u64
+        }
+        None => compaction_file_size,
+    };
+    compaction_file.seek(SeekFrom::Start(header.size()))?;
+
+    let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, compaction_file)
+        .take(end.saturating_sub(header.size()));
+    let mut hints = Vec::new();
+    while reader.limit() > 0 {
+        match decode_hint(&mut reader, &header) {
+            Ok(ch) if ch.log_pos + ch.log_size() <= data_file_size => hints.push(ch),
+            _ => break,
+        }
+    }
+    Ok(hints)
+}
+
+// How the values of a data file are stored: compressed, encrypted, or both, in which case
+// they are compressed first. See `FLAG_COMPRESSION` and `FLAG_ENCRYPTION`.
+pub struct ValueCodec {
+    keyring: Option<(Keyring, u32)>,
+    decompressor: Option<ValueDecompressor>,
+}
+
+impl ValueCodec {
+    // The codec of a file given its dictionary as stored, or `None` when its values are
+    // stored as they are. An encrypted file can't be read without a keyring holding its key.
+    pub fn new(file_header: &FileHeader, dictionary: &[u8], keyring: Option<&Keyring>) -> Result<Option<ValueCodec>> {
+        let keyring = match keyring {
+            _ if !file_header.has_flag(FLAG_ENCRYPTION) => None,
+            Some(keyring) => Some((keyring.clone(), file_header.key_id)),
+            None => return Err(Error::UnknownEncryptionKey(file_header.key_id)),
+        };
+        let decompressor = if file_header.has_flag(FLAG_COMPRESSION) {
+            match keyring {
+                Some((ref keyring, key_id)) => Some(ValueDecompressor::new(&keyring.decrypt(key_id, dictionary)?)),
+                None => Some(ValueDecompressor::new(dictionary)),
+            }
+        } else {
+            None
+        };
+
+        if keyring.is_none() && decompressor.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(ValueCodec {
+            keyring,
+            decompressor,
+        }))
+    }
+
+    // The value of a record from its stored form.
+    pub fn decode(&self, stored: &[u8]) -> Result<Vec<u8>> {
+        match (&self.keyring, &self.decompressor) {
+            (Some((keyring, key_id)), Some(decompressor)) => {
+                decompressor.decompress(&keyring.decrypt(*key_id, stored)?)
+            }
+            (Some((keyring, key_id)), None) => keyring.decrypt(*key_id, stored),
+            (None, Some(decompressor)) => decompressor.decompress(stored),
+            (None, None) => Ok(stored.to_vec()),
+        }
+    }
+
+    // Same as `decode`, but a value kept as is by the compression isn't copied.
+    pub fn decode_bytes(&self, stored: Bytes) -> Result<Bytes> {
+        match (&self.keyring, &self.decompressor) {
+            (None, Some(decompressor)) => decompressor.decompress_bytes(stored),
+            _ => Ok(self.decode(&stored)?.into()),
+        }
+    }
+
+    // Replace the stored value of a record by the value itself. Tombstones and blob
+    // pointers are stored as they are.
+    pub fn decode_log(&self, log: &mut Log) -> Result<()> {
+        if !log.deleted && !log.range && !log.blob {
+            log.value = Cow::Owned(self.decode(&log.value)?);
+        }
+        Ok(())
+    }
+}
+
+// The dictionary of a compressed data file as stored, which ends its header, or nothing.
+// Leaves the reader positioned on the first record.
+fn read_dictionary<R: Read + Seek>(reader: &mut R, file_header: &FileHeader) -> Result<Vec<u8>> {
+    if !file_header.has_flag(FLAG_COMPRESSION) {
+        return Ok(Vec::new());
     }
+    let mut dictionary = vec![0u8; file_header.dictionary_size as usize];
+    reader.seek(SeekFrom::Start(file_header.dictionary_offset()))?;
+    reader.read_exact(&mut dictionary)?;
+    Ok(dictionary)
 }
 
-fn get_data_file_path(path: &Path, file_id: u32) -> PathBuf {
+pub(crate) fn read_file_header(storage: &dyn Storage, path: &Path, file_id: u32) -> Result<FileHeader> {
+    let mut data_file = storage.open(&get_data_file_path(path, file_id))?;
+    FileHeader::from_read(DATA_FILE_MAGIC, &mut data_file)
+}
+
+pub(crate) fn get_data_file_path(path: &Path, file_id: u32) -> PathBuf {
     let file_id = format!("{:010}", file_id);
     path.join(file_id).with_extension(DATA_FILE_EXTENSION)
 }
 
-fn get_compaction_hint_file_path(path: &Path, file_id: u32) -> PathBuf {
+pub(crate) fn get_compaction_hint_file_path(path: &Path, file_id: u32) -> PathBuf {
     let file_id = format!("{:010}", file_id);
     path.join(file_id).with_extension(COMPACTION_FILE_EXTENSION)
 }
 
-fn find_data_files(path: &Path) -> Result<Vec<u32>> {
-    let files = fs::read_dir(path)?;
+fn get_temp_data_file_path(path: &Path, file_id: u32) -> PathBuf {
+    let file_id = format!("{:010}", file_id);
+    path.join(file_id)
+        .with_extension(format!("{}.{}", DATA_FILE_EXTENSION, TEMP_FILE_EXTENSION))
+}
+
+fn get_temp_compaction_hint_file_path(path: &Path, file_id: u32) -> PathBuf {
+    let file_id = format!("{:010}", file_id);
+    path.join(file_id)
+        .with_extension(format!("{}.{}", COMPACTION_FILE_EXTENSION, TEMP_FILE_EXTENSION))
+}
+
+fn remove_temp_files(storage: &dyn Storage, path: &Path) -> Result<()> {
+    for file_path in storage.list(path)? {
+        if file_path.extension().is_some_and(|ext| ext == TEMP_FILE_EXTENSION) {
+            warn!("Removing leftover temporary file: {:?}", file_path);
+            storage.remove(&file_path)?;
+        }
+    }
+    storage.sync_dir(path)?;
+    Ok(())
+}
+
+// A crash in the middle of an append leaves a partially written record at the end of the
+// active data file. Such a file was never closed, so its hint file has no valid
+// trailing checksum: in that case, cut the file right after the last decodable record.
+// Corrupted records followed by valid ones are not a torn write and are left untouched.
+fn truncate_torn_tail(storage: &dyn Storage, path: &Path, file_id: u32) -> Result<()> {
+    if is_valid_compaction_hint_file(storage, &get_compaction_hint_file_path(path, file_id))? {
+        return Ok(());
+    }
+
+    let data_file_path = get_data_file_path(path, file_id);
+    let data_file = storage.open(&data_file_path)?;
+    let data_file_size = data_file.metadata()?.len();
+
+    let entries = Entries::new(data_file, RecoveryMode::SkipCorrupt)?;
+
+    let mut valid_pos = entries.header().size();
+    for (log_pos, log) in entries {
+        valid_pos = log_pos + log?.size();
+    }
+
+    if valid_pos < data_file_size {
+        warn!(
+            "Data file {:?} has a torn tail, truncating it from {} to {} bytes",
+            data_file_path,
+            data_file_size,
+            valid_pos
+        );
+        storage.truncate(&data_file_path, valid_pos)?;
+    }
+
+    Ok(())
+}
+
+fn load_remote_files(path: &Path, options: &StorageOptions) -> Result<Option<Arc<RemoteFiles>>> {
+    Ok(match options.tiering {
+        Some(ref tiering) => Some(Arc::new(RemoteFiles::load(path, tiering)?)),
+        None => None,
+    })
+}
+
+fn remote_stub(remote: &Option<Arc<RemoteFiles>>, file_id: u32) -> Option<RemoteStub> {
+    remote.as_ref().and_then(|remote| remote.stub(file_id))
+}
+
+// The backend of `StorageOptions::storage`, or the file system through the I/O engine.
+fn new_storage(options: &StorageOptions) -> Result<Arc<dyn Storage>> {
+    if let Some(ref storage) = options.storage {
+        return Ok(storage.clone());
+    }
+    if options.in_memory {
+        return Ok(Arc::new(MemoryStorage::default()));
+    }
+    let io_engine = new_io_engine(options.io_engine)?;
+    info!("Using the {:?} I/O engine", options.io_engine);
+    Ok(Arc::new(StdStorage::new(io_engine)))
+}
+
+// The local data files along with the offloaded ones.
+fn find_all_data_files(
+    storage: &dyn Storage,
+    path: &Path,
+    remote: &Option<Arc<RemoteFiles>>,
+) -> Result<Vec<u32>> {
+    let mut data_files = list_data_files(storage, path)?;
+    if let Some(ref remote) = *remote {
+        data_files.extend(remote.files());
+        data_files.sort();
+        data_files.dedup();
+    }
+    Ok(data_files)
+}
+
+pub(crate) fn find_data_files(path: &Path) -> Result<Vec<u32>> {
+    list_data_files(&StdStorage::default(), path)
+}
 
+fn list_data_files(storage: &dyn Storage, path: &Path) -> Result<Vec<u32>> {
     lazy_static! {
         static ref RE: Regex =
             Regex::new(&format!("(\\d+).{}$", DATA_FILE_EXTENSION)).unwrap();
@@ -497,39 +2462,52 @@ fn find_data_files(path: &Path) -> Result<Vec<u32>> {
 
     let mut data_files = Vec::new();
 
-    for file in files {
-        let file = file?;
-        if file.metadata()?.is_file() {
-            let file_name = file.file_name();
-            let captures = RE.captures(file_name.to_str().unwrap());
-            if let Some(n) = captures.and_then(|c| {
-                c.get(1).and_then(|n| n.as_str().parse::<u32>().ok())
-            })
-            {
-                data_files.push(n)
-            }
+    for file_path in storage.list(path)? {
+        let file_name = file_path.file_name().unwrap_or_default();
+        let captures = RE.captures(file_name.to_str().unwrap());
+        if let Some(n) = captures.and_then(|c| c.get(1)) {
+            // Not a file id, which a store never wraps around.
+            let file_id = n.as_str().parse::<u32>().map_err(|_| {
+                Error::InvalidPath(file_path.to_string_lossy().into_owned())
+            })?;
+            data_files.push(file_id)
         }
     }
 
-    data_files.sort();
+    sort_file_ids(data_files)
+}
 
-    Ok(data_files)
+// Sort the ids of the files found in a directory. Two files with the same id, e.g. named
+// with a different number of leading zeros, would shadow each other: the load fails
+// instead of silently ignoring one of them.
+pub(crate) fn sort_file_ids(mut file_ids: Vec<u32>) -> Result<Vec<u32>> {
+    file_ids.sort();
+    match file_ids.windows(2).find(|ids| ids[0] == ids[1]) {
+        Some(ids) => Err(Error::DuplicateFileId(ids[0])),
+        None => Ok(file_ids),
+    }
 }
 
-fn is_valid_compaction_hint_file(path: &Path) -> Result<bool> {
+pub(crate) fn is_valid_compaction_hint_file(storage: &dyn Storage, path: &Path) -> Result<bool> {
     Ok(
-        path.is_file() &&
+        storage.exists(path) &&
             {
-                let mut compaction_hint_file = get_file_handle(path, false)?;
+                let mut compaction_hint_file = storage.open(path)?;
                 let mut buf = Vec::new();
                 compaction_hint_file.read_to_end(&mut buf)?;
 
-                buf.len() >= 4 &&
+                let checksum = FileHeader::from_read(HINT_FILE_MAGIC, &mut Cursor::new(&buf))?.hint_file_checksum();
+                let checksum_size = checksum_size(checksum);
+                buf.len() >= checksum_size &&
                     {
-                        let hash = xxhash32(&buf[..buf.len() - 4]);
-
-                        let mut cursor = Cursor::new(&buf[buf.len() - 4..]);
-                        let checksum = cursor.read_u32::<LittleEndian>()?;
+                        let hash = {
+                            let mut hasher = ChecksumHasher::new(checksum);
+                            hasher.update(&buf[..buf.len() - checksum_size]);
+                            hasher.get()
+                        };
+
+                        let mut cursor = Cursor::new(&buf[buf.len() - checksum_size..]);
+                        let checksum = read_checksum(&mut cursor, checksum)?;
 
                         let valid = hash == checksum;
 
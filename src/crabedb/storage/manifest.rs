@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::info;
+
+use super::error::{Error, Result};
+use super::util::{get_file_handle, sync_dir};
+use super::xxhash::xxhash32;
+
+const MANIFEST_FILE_NAME: &str = "crabe.manifest";
+const MANIFEST_TEMP_FILE_NAME: &str = "crabe.manifest.tmp";
+const MANIFEST_VERSION_1: u16 = 1;
+// Version 2 added the seal of the data files.
+const MANIFEST_VERSION: u16 = 2;
+
+// Length and xxHash64 of the whole content of a data file, recorded once nothing is
+// appended to it anymore, so that it can be checked without decoding its records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileSeal {
+    pub length: u64,
+    pub hash: u64,
+}
+
+// The manifest is the authoritative list of the data files composing the store.
+// It is always rewritten as a whole: the new content goes to a temporary file which
+// is synced and atomically renamed over the previous manifest, so a crash leaves
+// either the old or the new file set, never a mix of both.
+pub struct Manifest {
+    path: PathBuf,
+    // The active data file, and those sealed before version 2, have no seal.
+    files: BTreeMap<u32, Option<FileSeal>>,
+    // Unset for the manifests kept in memory only.
+    persistent: bool,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Option<Manifest>> {
+        let manifest_path = path.join(MANIFEST_FILE_NAME);
+        if !manifest_path.is_file() {
+            return Ok(None);
+        }
+
+        let mut buf = Vec::new();
+        get_file_handle(&manifest_path, false)?.read_to_end(&mut buf)?;
+
+        if buf.len() < 4 {
+            return Err(Error::InvalidManifest(format!(
+                "{:?} is truncated",
+                manifest_path
+            )));
+        }
+
+        let (content, checksum) = buf.split_at(buf.len() - 4);
+        let checksum = Cursor::new(checksum).read_u32::<LittleEndian>()?;
+        let hash = xxhash32(content);
+        if hash != checksum {
+            return Err(Error::InvalidManifest(format!(
+                "{:?} has an invalid checksum, expected: {}, found: {}",
+                manifest_path,
+                checksum,
+                hash
+            )));
+        }
+
+        let mut cursor = Cursor::new(content);
+        let version = cursor.read_u16::<LittleEndian>()?;
+        if version != MANIFEST_VERSION_1 && version != MANIFEST_VERSION {
+            return Err(Error::InvalidManifest(format!(
+                "{:?} has an unsupported version: {}",
+                manifest_path,
+                version
+            )));
+        }
+
+        let count = cursor.read_u32::<LittleEndian>()?;
+        let mut files = BTreeMap::new();
+        for _ in 0..count {
+            let file_id = cursor.read_u32::<LittleEndian>()?;
+            let seal = if version == MANIFEST_VERSION && cursor.read_u8()? != 0 {
+                Some(FileSeal {
+                    length: cursor.read_u64::<LittleEndian>()?,
+                    hash: cursor.read_u64::<LittleEndian>()?,
+                })
+            } else {
+                None
+            };
+            files.insert(file_id, seal);
+        }
+
+        info!("Loaded manifest {:?} with {} data files", manifest_path, files.len());
+
+        Ok(Some(Manifest {
+            path: path.to_path_buf(),
+            files,
+            persistent: true,
+        }))
+    }
+
+    pub fn create(path: &Path, files: &[u32]) -> Result<Manifest> {
+        let manifest = Manifest {
+            path: path.to_path_buf(),
+            files: files.iter().map(|&file_id| (file_id, None)).collect(),
+            persistent: true,
+        };
+        manifest.persist()?;
+        info!("Created manifest {:?}", path.join(MANIFEST_FILE_NAME));
+        Ok(manifest)
+    }
+
+    // A manifest listing the given files, with their seal.
+    pub fn create_sealed(path: &Path, files: &[(u32, Option<FileSeal>)]) -> Result<Manifest> {
+        let manifest = Manifest {
+            path: path.to_path_buf(),
+            files: files.iter().cloned().collect(),
+            persistent: true,
+        };
+        manifest.persist()?;
+        info!("Created manifest {:?}", path.join(MANIFEST_FILE_NAME));
+        Ok(manifest)
+    }
+
+    // A manifest which is never persisted, for read-only openers of a store without one
+    // and in-memory stores.
+    pub fn in_memory(path: &Path, files: &[u32]) -> Manifest {
+        Manifest {
+            path: path.to_path_buf(),
+            files: files.iter().map(|&file_id| (file_id, None)).collect(),
+            persistent: false,
+        }
+    }
+
+    pub fn files(&self) -> Vec<u32> {
+        self.files.keys().cloned().collect()
+    }
+
+    pub fn contains(&self, file_id: u32) -> bool {
+        self.files.contains_key(&file_id)
+    }
+
+    pub fn seal(&self, file_id: u32) -> Option<FileSeal> {
+        self.files.get(&file_id).cloned().flatten()
+    }
+
+    // The files without a seal: the one being written to, those a crash left open and
+    // those sealed before version 2. Compactions and imports add sealed files only, so
+    // the active file is the last of them, not necessarily the last of the store.
+    pub fn unsealed_files(&self) -> Vec<u32> {
+        self.files
+            .iter()
+            .filter(|&(_, seal)| seal.is_none())
+            .map(|(&file_id, _)| file_id)
+            .collect()
+    }
+
+    // Add a new active data file, along with the seals of the files it follows.
+    pub fn add_file(&mut self, file_id: u32, sealed_files: &[(u32, FileSeal)]) -> Result<()> {
+        self.apply(&[], &[(file_id, None)], sealed_files)
+    }
+
+    pub fn seal_files(&mut self, sealed_files: &[(u32, FileSeal)]) -> Result<()> {
+        self.apply(&[], &[], sealed_files)
+    }
+
+    // Only the seals of files of the manifest, or added with them, are recorded.
+    pub fn apply(
+        &mut self,
+        removed_files: &[u32],
+        added_files: &[(u32, Option<FileSeal>)],
+        sealed_files: &[(u32, FileSeal)],
+    ) -> Result<()> {
+        let mut files = self.files.clone();
+        for file_id in removed_files {
+            files.remove(file_id);
+        }
+        files.extend(added_files.iter().cloned());
+        for &(file_id, seal) in sealed_files {
+            if let Some(current) = files.get_mut(&file_id) {
+                *current = Some(seal);
+            }
+        }
+
+        let previous = std::mem::replace(&mut self.files, files);
+        if let Err(err) = self.persist() {
+            self.files = previous;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn persist(&self) -> Result<()> {
+        if !self.persistent {
+            return Ok(());
+        }
+        let mut buf = Vec::with_capacity(10 + self.files.len() * 21);
+        buf.write_u16::<LittleEndian>(MANIFEST_VERSION)?;
+        buf.write_u32::<LittleEndian>(self.files.len() as u32)?;
+        for (&file_id, seal) in &self.files {
+            buf.write_u32::<LittleEndian>(file_id)?;
+            match seal {
+                Some(seal) => {
+                    buf.write_u8(1)?;
+                    buf.write_u64::<LittleEndian>(seal.length)?;
+                    buf.write_u64::<LittleEndian>(seal.hash)?;
+                }
+                None => buf.write_u8(0)?,
+            }
+        }
+        let checksum = xxhash32(&buf);
+        buf.write_u32::<LittleEndian>(checksum)?;
+
+        let temp_path = self.path.join(MANIFEST_TEMP_FILE_NAME);
+        let mut temp_file = get_file_handle(&temp_path, true)?;
+        temp_file.write_all(&buf)?;
+        temp_file.sync_all()?;
+
+        fs::rename(&temp_path, self.path.join(MANIFEST_FILE_NAME))?;
+        sync_dir(&self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+use std::result::Result::Ok;
+use std::time::UNIX_EPOCH;
+
+use log::info;
+
+use super::error::{Error, Result};
+use super::options::{StorageOptions, SyncOptions};
+use super::slot::{in_range, Log};
+use super::util::is_new_store_path;
+
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    // Records written to the new store, one per key.
+    pub records: u64,
+    // Keys found in both stores, of which only the latest version was kept.
+    pub duplicates: u64,
+    // Keys whose latest version is a tombstone.
+    pub removed: u64,
+    // Point and range tombstones, none of which is carried over.
+    pub tombstones: u64,
+}
+
+// The versions of a store deciding the value of its keys, see `CrabeDB::versions`.
+#[derive(Default)]
+pub(crate) struct StoreVersions {
+    // The sequence number of each live key.
+    pub live: Vec<(Vec<u8>, u64)>,
+    // The latest point tombstone of each key.
+    pub deletes: HashMap<Vec<u8>, u64>,
+    pub range_deletes: Vec<(Vec<u8>, Vec<u8>, u64)>,
+}
+
+// The latest version of a key among those of the merged stores.
+struct Version {
+    seq: u64,
+    store: usize,
+    deleted: bool,
+    // Bit `i` is set when store `i` has a version of the key.
+    stores: u8,
+}
+
+// Merge the stores at `a` and `b`, which are not in use, into a new store at `out`. The
+// latest version of each key wins, by sequence number, the one of `b` on a tie, and the
+// keys whose latest version is a tombstone are dropped. No tombstone is carried over, as
+// the new store has no older record for them to hide, so it's as compact as it gets.
+// The sequence numbers of two stores overlap: the records are written in the order of
+// their sequence numbers, and renumbered from 1.
+pub fn merge_stores(a: &str, b: &str, out: &str) -> Result<MergeReport> {
+    if !is_new_store_path(Path::new(out))? {
+        return Err(Error::InvalidPath(out.to_string()));
+    }
+
+    let mut options = StorageOptions::default();
+    options.create(false).compaction(false).read_only(true);
+    let sources = [options.load(a)?, options.load(b)?];
+
+    let mut report = MergeReport::default();
+    let mut versions: HashMap<Vec<u8>, Version> = HashMap::new();
+    let mut range_deletes = Vec::new();
+    for (store, db) in sources.iter().enumerate() {
+        let store_versions = db.versions()?;
+        report.tombstones += (store_versions.deletes.len() + store_versions.range_deletes.len()) as u64;
+
+        let live = store_versions.live.into_iter().map(|(key, seq)| (key, seq, false));
+        let deletes = store_versions.deletes.into_iter().map(|(key, seq)| (key, seq, true));
+        for (key, seq, deleted) in live.chain(deletes) {
+            match versions.entry(key) {
+                Entry::Occupied(mut occupied) => {
+                    let version = occupied.get_mut();
+                    version.stores |= 1 << store;
+                    if version.seq <= seq {
+                        version.seq = seq;
+                        version.store = store;
+                        version.deleted = deleted;
+                    }
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(Version {
+                        seq,
+                        store,
+                        deleted,
+                        stores: 1 << store,
+                    });
+                }
+            }
+        }
+        range_deletes.extend(store_versions.range_deletes);
+    }
+
+    // A range tombstone of a store also hides the older versions of the other one.
+    let mut survivors = Vec::new();
+    for (key, version) in versions {
+        if version.stores == 0b11 {
+            report.duplicates += 1;
+        }
+        let hidden = version.deleted || range_deletes
+            .iter()
+            .any(|(start, end, seq)| *seq > version.seq && in_range(&key, start, end));
+        if hidden {
+            report.removed += 1;
+        } else {
+            survivors.push((version.seq, version.store, key));
+        }
+    }
+    survivors.sort();
+    info!("Merging {} keys into {:?}", survivors.len(), out);
+
+    let db = StorageOptions::default()
+        .sync(SyncOptions::Never)
+        .compaction(false)
+        .load(out)?;
+    for (seq, (_, store, key)) in (1..).zip(survivors) {
+        // The sources aren't in use, the key can't have been removed since.
+        let (value, metadata) = match sources[store].get_with_metadata(&key)? {
+            Some(found) => found,
+            None => continue,
+        };
+        let mut log = Log::new(seq, key, value)?;
+        // The record keeps its creation time.
+        log.timestamp = metadata
+            .timestamp
+            .and_then(|timestamp| timestamp.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_millis() as u64);
+        db.merge(log)?;
+        report.records += 1;
+    }
+
+    Ok(report)
+}
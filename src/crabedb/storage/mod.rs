@@ -1,9 +1,45 @@
+pub mod archive;
+pub mod art;
+pub mod audit;
+pub mod backend;
+pub mod bitcask;
+pub mod blob;
+pub mod bootstrap;
+pub mod checksum;
 pub mod chunk_queue;
+pub mod compaction;
+pub mod compression;
 pub mod crabe_db;
+pub mod crc32c;
+pub mod deadline;
+pub mod direct_io;
+pub mod encryption;
 pub mod error;
+pub mod format;
+pub mod group_commit;
+pub mod io_engine;
+pub mod key_arena;
+pub mod key_map;
+pub mod lease;
 pub mod lsm;
+pub mod manifest;
+pub mod merge;
 pub mod options;
+pub mod partition;
+pub mod pitr;
+pub mod rate_limiter;
+pub mod renumber;
 pub mod slot;
+pub mod spill;
+pub mod split;
+pub mod sst;
+pub mod standby;
+pub mod stats;
+pub mod summary;
+pub mod tiering;
 pub mod util;
+pub mod value_cache;
+pub mod verify;
+pub mod write_batch;
 pub mod xxhash;
 
@@ -1,19 +1,70 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::audit::AuditSink;
+use super::backend::Storage;
+use super::compaction::{CompactionFilter, CompactionStrategy, FragmentationStrategy};
 use super::crabe_db::CrabeDB;
-use super::error::Result;
+use super::encryption::Keyring;
+use super::error::{BackgroundErrorHandler, Result};
+use super::tiering::Tiering;
 
 #[derive(Clone, PartialEq)]
 pub enum SyncOptions {
     Frequency(usize),
+    // Sync once this many bytes have been appended since the last sync.
+    EveryBytes(usize),
     Never,
     Always,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryMode {
+    Strict,
+    SkipCorrupt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoEngineKind {
+    Sync,
+    IoUring,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumKind {
+    XxHash32,
+    Crc32c,
+    XxHash64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexKind {
+    Hash,
+    Art,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    Lru,
+    Fifo,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheUnit {
+    Handles,
+    Bytes,
+}
+
 #[derive(Clone)]
 pub struct StorageOptions {
     pub create: bool,
     pub sync: SyncOptions,
     pub max_file_size: usize,
     pub file_chunk_queue_size: usize,
+    pub handles_per_file: usize,
+    pub file_chunk_queue_policy: EvictionPolicy,
+    pub file_chunk_queue_unit: CacheUnit,
     pub compaction: bool,
     pub compaction_check_frequency: u64,
     pub compaction_window: (usize, usize),
@@ -22,6 +73,41 @@ pub struct StorageOptions {
     pub fragmentation_threshold: f64,
     pub dead_bytes_threshold: u64,
     pub small_file_threshold: u64,
+    pub compaction_rate_limit: u64,
+    pub write_slowdown: (f64, usize),
+    pub write_stop: (f64, usize),
+    pub write_slowdown_delay: Duration,
+    pub write_stop_timeout: Duration,
+    pub compaction_parallelism: usize,
+    pub compaction_concurrency: usize,
+    pub compaction_strategy: Arc<dyn CompactionStrategy>,
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    pub tombstone_ttl: Option<Duration>,
+    pub tombstone_seq_gap: u64,
+    pub node_id: Option<u16>,
+    pub recovery_mode: RecoveryMode,
+    pub read_optimized: bool,
+    pub index_kind: IndexKind,
+    pub index_batch_size: usize,
+    pub index_memory_budget: usize,
+    pub group_commit: bool,
+    pub partitions: usize,
+    pub io_engine: IoEngineKind,
+    pub storage: Option<Arc<dyn Storage>>,
+    pub in_memory: bool,
+    pub direct_io: bool,
+    pub checksum: ChecksumKind,
+    pub value_cache_size: usize,
+    pub warm_files: usize,
+    pub blob_threshold: usize,
+    pub compression: Option<i32>,
+    pub encryption: Option<Keyring>,
+    pub audit: Option<Arc<dyn AuditSink>>,
+    pub background_error_handler: Option<Arc<dyn BackgroundErrorHandler>>,
+    pub tiering: Option<Tiering>,
+    pub archive_dir: Option<PathBuf>,
+    pub read_only: bool,
+    pub standby: bool,
 }
 
 impl Default for StorageOptions {
@@ -31,6 +117,9 @@ impl Default for StorageOptions {
             sync: SyncOptions::Frequency(2000),
             max_file_size: 1024 * 1024 * 1024, // 1GB
             file_chunk_queue_size: 2048,
+            handles_per_file: 1,
+            file_chunk_queue_policy: EvictionPolicy::Lru,
+            file_chunk_queue_unit: CacheUnit::Handles,
             compaction: true,
             compaction_check_frequency: 3600,
             compaction_window: (0, 23),
@@ -39,6 +128,41 @@ impl Default for StorageOptions {
             fragmentation_threshold: 0.4,
             dead_bytes_threshold: 128 * 1024 * 1024,
             small_file_threshold: 10 * 1024 * 1024,
+            compaction_rate_limit: 0, // unlimited
+            write_slowdown: (0.0, 0), // disabled
+            write_stop: (0.0, 0), // disabled
+            write_slowdown_delay: Duration::from_millis(1),
+            write_stop_timeout: Duration::from_secs(10),
+            compaction_parallelism: 1,
+            compaction_concurrency: 1,
+            compaction_strategy: Arc::new(FragmentationStrategy),
+            compaction_filter: None,
+            tombstone_ttl: None,
+            tombstone_seq_gap: 0,
+            node_id: None,
+            recovery_mode: RecoveryMode::Strict,
+            read_optimized: false,
+            index_kind: IndexKind::Hash,
+            index_batch_size: 1024,
+            index_memory_budget: 0, // unlimited
+            group_commit: false,
+            partitions: 1,
+            io_engine: IoEngineKind::Sync,
+            storage: None, // the file system
+            in_memory: false,
+            direct_io: false,
+            checksum: ChecksumKind::XxHash32,
+            value_cache_size: 0, // disabled
+            warm_files: 0, // disabled
+            blob_threshold: 0, // disabled
+            compression: None, // disabled
+            encryption: None, // disabled
+            audit: None,
+            background_error_handler: None,
+            tiering: None, // disabled
+            archive_dir: None, // disabled
+            read_only: false,
+            standby: false,
         }
     }
 }
@@ -48,6 +172,9 @@ impl StorageOptions {
         StorageOptions::default()
     }
 
+    // When the appended records are synced: after every write, every `Frequency`
+    // milliseconds from a background thread, once `EveryBytes` bytes were appended since
+    // the last sync, which suits bursty ingestion better, or never, leaving it to the OS.
     pub fn sync(&mut self, sync: SyncOptions) -> &mut StorageOptions {
         self.sync = sync;
         self
@@ -63,6 +190,21 @@ impl StorageOptions {
         self
     }
 
+    pub fn handles_per_file(&mut self, handles_per_file: usize) -> &mut StorageOptions {
+        self.handles_per_file = handles_per_file;
+        self
+    }
+
+    pub fn file_chunk_queue_policy(&mut self, policy: EvictionPolicy) -> &mut StorageOptions {
+        self.file_chunk_queue_policy = policy;
+        self
+    }
+
+    pub fn file_chunk_queue_unit(&mut self, unit: CacheUnit) -> &mut StorageOptions {
+        self.file_chunk_queue_unit = unit;
+        self
+    }
+
     pub fn compaction(&mut self, compaction: bool) -> &mut StorageOptions {
         self.compaction = compaction;
         self
@@ -108,6 +250,250 @@ impl StorageOptions {
         self
     }
 
+    pub fn compaction_rate_limit(&mut self, bytes_per_sec: u64) -> &mut StorageOptions {
+        self.compaction_rate_limit = bytes_per_sec;
+        self
+    }
+
+    // Delay every write by `write_slowdown_delay` once the dead bytes make up `dead_ratio`
+    // of the data files, or once there are `file_count` data files, so that the compaction
+    // catches up. 0 disables a limit.
+    pub fn write_slowdown(&mut self, dead_ratio: f64, file_count: usize) -> &mut StorageOptions {
+        self.write_slowdown = (dead_ratio, file_count);
+        self
+    }
+
+    // Block the writes beyond these limits, until the compaction brings the store back
+    // under them or `write_stop_timeout` elapses, the write failing with `Error::Busy`.
+    // 0 disables a limit.
+    pub fn write_stop(&mut self, dead_ratio: f64, file_count: usize) -> &mut StorageOptions {
+        self.write_stop = (dead_ratio, file_count);
+        self
+    }
+
+    pub fn write_slowdown_delay(&mut self, delay: Duration) -> &mut StorageOptions {
+        self.write_slowdown_delay = delay;
+        self
+    }
+
+    pub fn write_stop_timeout(&mut self, timeout: Duration) -> &mut StorageOptions {
+        self.write_stop_timeout = timeout;
+        self
+    }
+
+    pub fn compaction_parallelism(&mut self, compaction_parallelism: usize) -> &mut StorageOptions {
+        self.compaction_parallelism = compaction_parallelism;
+        self
+    }
+
+    // The number of partitions compacted at once by the background compaction, see
+    // `partitions`. The partitions whose writes are stalled go first, then the most
+    // fragmented ones.
+    pub fn compaction_concurrency(&mut self, compaction_concurrency: usize) -> &mut StorageOptions {
+        self.compaction_concurrency = compaction_concurrency;
+        self
+    }
+
+    pub fn compaction_strategy<S: CompactionStrategy + 'static>(&mut self, strategy: S) -> &mut StorageOptions {
+        self.compaction_strategy = Arc::new(strategy);
+        self
+    }
+
+    pub fn compaction_filter<F: CompactionFilter + 'static>(&mut self, filter: F) -> &mut StorageOptions {
+        self.compaction_filter = Some(Arc::new(filter));
+        self
+    }
+
+    // Minimum age of a tombstone before a compaction may drop it. Records aren't
+    // timestamped, so the age is measured from the last write to the file holding it.
+    pub fn tombstone_ttl(&mut self, tombstone_ttl: Duration) -> &mut StorageOptions {
+        self.tombstone_ttl = Some(tombstone_ttl);
+        self
+    }
+
+    // Minimum number of writes since a tombstone before a compaction may drop it.
+    pub fn tombstone_seq_gap(&mut self, tombstone_seq_gap: u64) -> &mut StorageOptions {
+        self.tombstone_seq_gap = tombstone_seq_gap;
+        self
+    }
+
+    // The id of the node in a cluster whose nodes write the same keys, see `CrabeDB::merge`.
+    // The low 16 bits of the sequence numbers of its writes are the id, so that the writes
+    // of two nodes never share one and the last one wins on every node, and the sequence
+    // numbers of the removed keys are kept in memory until their tombstones are dropped,
+    // so that an older write arriving late doesn't bring them back. A write then counts
+    // for 65536 in `tombstone_seq_gap`.
+    pub fn node_id(&mut self, node_id: u16) -> &mut StorageOptions {
+        self.node_id = Some(node_id);
+        self
+    }
+
+    pub fn recovery_mode(&mut self, recovery_mode: RecoveryMode) -> &mut StorageOptions {
+        self.recovery_mode = recovery_mode;
+        self
+    }
+
+    pub fn read_optimized(&mut self, read_optimized: bool) -> &mut StorageOptions {
+        self.read_optimized = read_optimized;
+        self
+    }
+
+    // The structure holding the keys of the index in memory: a hash map, or an adaptive
+    // radix tree keeping them ordered for the scans and the prefix queries.
+    pub fn index_kind(&mut self, index_kind: IndexKind) -> &mut StorageOptions {
+        self.index_kind = index_kind;
+        self
+    }
+
+    pub fn index_batch_size(&mut self, index_batch_size: usize) -> &mut StorageOptions {
+        self.index_batch_size = index_batch_size;
+        self
+    }
+
+    // Keep about `index_memory_budget` bytes of the index in memory, the most recently
+    // written keys, and spill the other ones to disk. It can't be used along with
+    // `read_optimized`, whose lock-free readers hold a copy of the whole index.
+    pub fn index_memory_budget(&mut self, index_memory_budget: usize) -> &mut StorageOptions {
+        self.index_memory_budget = index_memory_budget;
+        self
+    }
+
+    pub fn group_commit(&mut self, group_commit: bool) -> &mut StorageOptions {
+        self.group_commit = group_commit;
+        self
+    }
+
+    // Split the store into `partitions` stores, each with its own index, data files, writer
+    // and compaction, the keys being routed by their hash, so that the writes to different
+    // partitions don't contend for a single lock and active file. The first partition is
+    // the store directory itself, the others are sub-directories of it, and the sequence
+    // numbers are shared. The count is fixed when the store is created. The batches are only
+    // atomic within a partition, and a partitioned store can't be a standby, tiered,
+    // archived, tailed, backed up or ingest files.
+    pub fn partitions(&mut self, partitions: usize) -> &mut StorageOptions {
+        self.partitions = partitions;
+        self
+    }
+
+    pub fn io_engine(&mut self, io_engine: IoEngineKind) -> &mut StorageOptions {
+        self.io_engine = io_engine;
+        self
+    }
+
+    // The backend the data, hint and blob files are created, read, written and removed
+    // through, in place of the file system and `io_engine`. The manifest and the locks stay
+    // on the file system.
+    pub fn storage<S: Storage + 'static>(&mut self, storage: S) -> &mut StorageOptions {
+        self.storage = Some(Arc::new(storage));
+        self
+    }
+
+    // Keep the files in RAM (Linux only), with no directory, lock or manifest on the disk:
+    // the store starts empty on every load and is lost once closed, with the same API and
+    // compactions as any other, e.g. for tests or a cache. It can't be read-only, a
+    // standby, tiered, archived, nor spill its index or use another `storage`, and it can't
+    // be backed up or ingest files.
+    pub fn in_memory(&mut self, in_memory: bool) -> &mut StorageOptions {
+        self.in_memory = in_memory;
+        self
+    }
+
+    // Append to the data files, compaction outputs included, with direct I/O rather than
+    // through the page cache, so that a large sequential ingest doesn't evict the pages of
+    // the read path. Linux only, on a file system supporting O_DIRECT: elsewhere the data
+    // files are written through the page cache as usual.
+    pub fn direct_io(&mut self, direct_io: bool) -> &mut StorageOptions {
+        self.direct_io = direct_io;
+        self
+    }
+
+    // Checksum of the records of the new data files. CRC32C is computed with the CRC
+    // instructions of SSE 4.2 or ARMv8 when the CPU has them, and xxHash64 widens the
+    // checksums of the records and hint files to 64 bits, in version 3 files. xxHash32 is
+    // the default.
+    pub fn checksum(&mut self, checksum: ChecksumKind) -> &mut StorageOptions {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn value_cache_size(&mut self, value_cache_size: usize) -> &mut StorageOptions {
+        self.value_cache_size = value_cache_size;
+        self
+    }
+
+    // Open the `warm_files` most recently written data files at load time, and read their
+    // live values into the value cache while it has room, so that the first requests after
+    // a restart aren't all served from cold.
+    pub fn warm_files(&mut self, warm_files: usize) -> &mut StorageOptions {
+        self.warm_files = warm_files;
+        self
+    }
+
+    pub fn blob_threshold(&mut self, blob_threshold: usize) -> &mut StorageOptions {
+        self.blob_threshold = blob_threshold;
+        self
+    }
+
+    // Compress the values of the files written by the compaction with zstd at `level`,
+    // using a dictionary trained from values sampled from the compacted files and stored
+    // in the header of each output file, which suits many small, similar values. The
+    // writes land uncompressed in the active file until they are compacted.
+    pub fn compression(&mut self, level: i32) -> &mut StorageOptions {
+        self.compression = Some(level);
+        self
+    }
+
+    // Encrypt the values of the new data files, and the dictionaries of the compressed
+    // ones, with the current key of `keyring`. The files encrypted with its other keys stay
+    // readable, and `CrabeDB::rewrap` rewrites them with the current key.
+    pub fn encryption(&mut self, keyring: Keyring) -> &mut StorageOptions {
+        self.encryption = Some(keyring);
+        self
+    }
+
+    // Record every set and remove, e.g. to an `AuditLog` file or through a closure.
+    pub fn audit<S: AuditSink + 'static>(&mut self, sink: S) -> &mut StorageOptions {
+        self.audit = Some(Arc::new(sink));
+        self
+    }
+
+    // Called with the first error of a background task, which fences the writes, see
+    // `CrabeDB::background_error`.
+    pub fn background_error_handler<H: BackgroundErrorHandler + 'static>(
+        &mut self,
+        handler: H,
+    ) -> &mut StorageOptions {
+        self.background_error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    // Offload the cold data files to an object storage, see `Tiering`.
+    pub fn tiering(&mut self, tiering: Tiering) -> &mut StorageOptions {
+        self.tiering = Some(tiering);
+        self
+    }
+
+    // Keep the data and blob files compacted away in `dir` instead of removing them, to
+    // restore the store as it was at any point in time, see `pitr::restore`.
+    pub fn archive_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut StorageOptions {
+        self.archive_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    // Open the store for reading while another process may be writing to it: see
+    // `Lsm::load` for what a read-only opener sees.
+    pub fn read_only(&mut self, read_only: bool) -> &mut StorageOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    // Refuse the writes of clients, the store being fed by `CrabeDB::apply` with the records
+    // of a primary until it is promoted.
+    pub fn standby(&mut self, standby: bool) -> &mut StorageOptions {
+        self.standby = standby;
+        self
+    }
+
     pub fn load(&self, path: &str) -> Result<CrabeDB> {
         CrabeDB::load(path, self.clone())
     }
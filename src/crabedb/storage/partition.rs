@@ -0,0 +1,94 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::error::{Error, Result};
+use super::util::{get_file_handle, is_new_store_path, sync_dir};
+use super::xxhash::xxhash32;
+
+pub(crate) const PARTITIONS_FILE_NAME: &str = "crabe.partitions";
+const PARTITIONS_TEMP_FILE_NAME: &str = "crabe.partitions.tmp";
+
+// The directory of a partition of the store at `path`: the first partition is the store
+// directory itself, the others are sub-directories of it.
+pub fn partition_path(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        path.to_path_buf()
+    } else {
+        path.join(format!("partition-{}", index))
+    }
+}
+
+// The partition holding `key`, out of `partitions`.
+pub fn partition_of(key: &[u8], partitions: usize) -> usize {
+    xxhash32(key) as usize % partitions
+}
+
+// The number of partitions of a store: count(4) + checksum(4), replaced atomically like
+// the position of a standby. Stores without the file have a single partition.
+pub fn load_count(path: &Path) -> Result<Option<usize>> {
+    let partitions_path = path.join(PARTITIONS_FILE_NAME);
+    if !partitions_path.is_file() {
+        return Ok(None);
+    }
+
+    let mut buf = Vec::new();
+    get_file_handle(&partitions_path, false)?.read_to_end(&mut buf)?;
+    if buf.len() != 8 {
+        return Err(Error::Io(io::ErrorKind::InvalidData.into()));
+    }
+
+    let (content, checksum) = buf.split_at(4);
+    let checksum = Cursor::new(checksum).read_u32::<LittleEndian>()?;
+    let hash = xxhash32(content);
+    if hash != checksum {
+        return Err(Error::InvalidChecksum {
+            expected: u64::from(checksum),
+            found: u64::from(hash),
+        });
+    }
+    Ok(Some(Cursor::new(content).read_u32::<LittleEndian>()? as usize))
+}
+
+fn save_count(path: &Path, count: usize) -> Result<()> {
+    let mut buf = Vec::with_capacity(8);
+    buf.write_u32::<LittleEndian>(count as u32)?;
+    let checksum = xxhash32(&buf);
+    buf.write_u32::<LittleEndian>(checksum)?;
+
+    let temp_path = path.join(PARTITIONS_TEMP_FILE_NAME);
+    let mut temp_file = get_file_handle(&temp_path, true)?;
+    temp_file.write_all(&buf)?;
+    temp_file.sync_all()?;
+    fs::rename(&temp_path, path.join(PARTITIONS_FILE_NAME))?;
+    sync_dir(path)?;
+    Ok(())
+}
+
+// Check that the store at `path` is split into `count` partitions, the keys of a store
+// being routed by their hash. A new store records its count, the existing ones can't be
+// split or merged.
+pub fn check_count(path: &Path, count: usize, create: bool) -> Result<()> {
+    let mismatch = |found: usize| -> Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the store has {} partitions, not {}", found, count),
+        ).into())
+    };
+    match load_count(path)? {
+        Some(found) if found == count => Ok(()),
+        Some(found) => mismatch(found),
+        None if count == 1 => Ok(()),
+        None if !is_new_store_path(path)? => mismatch(1),
+        None if !create => Err(Error::InvalidPath(path.to_string_lossy().into_owned())),
+        None => {
+            fs::create_dir_all(path)?;
+            save_count(path, count)
+        }
+    }
+}
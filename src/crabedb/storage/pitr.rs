@@ -0,0 +1,300 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::info;
+
+use super::backend::StdStorage;
+use super::blob::{find_blob_files, get_blob_file_path, read_blob, BlobPointer};
+use super::encryption::Keyring;
+use super::error::{Error, Result};
+use super::lsm::{
+    acquire_readers_lock, find_data_files, get_compaction_hint_file_path, get_data_file_path,
+    is_valid_compaction_hint_file, open_entries,
+};
+use super::manifest::Manifest;
+use super::options::{RecoveryMode, StorageOptions, SyncOptions};
+use super::slot::{in_range, Log};
+use super::summary::{read_file_summary, FileSummary};
+use super::tiering::RemoteFiles;
+use super::util::{is_new_store_path, link_or_copy, sync_dir};
+
+// The data and blob files removed from a store, kept in the directory set with
+// `StorageOptions::archive_dir` for `restore`, along with the hint files of the data files. They are hard linked there, or copied
+// when it's on another file system, and never written to again, so the archive of a
+// file is just the file itself.
+pub struct FileArchive {
+    path: PathBuf,
+    dir: PathBuf,
+    remote: Option<Arc<RemoteFiles>>,
+}
+
+impl FileArchive {
+    pub fn new(path: &Path, dir: &Path, remote: Option<Arc<RemoteFiles>>) -> Result<FileArchive> {
+        fs::create_dir_all(dir)?;
+        Ok(FileArchive {
+            path: path.to_path_buf(),
+            dir: dir.to_path_buf(),
+            remote,
+        })
+    }
+
+    // The last data and blob file ids of the archive, which the store must not reuse.
+    pub fn last_file_ids(&self) -> Result<(u32, u32)> {
+        Ok((
+            find_data_files(&self.dir)?.last().cloned().unwrap_or(0),
+            find_blob_files(&self.dir)?.last().cloned().unwrap_or(0),
+        ))
+    }
+
+    // Archive sealed data files, the offloaded ones being downloaded. A file already in
+    // the archive is skipped.
+    pub fn add_data_files(&self, files: &[u32]) -> Result<()> {
+        for &file_id in files {
+            let archived_path = get_data_file_path(&self.dir, file_id);
+            if archived_path.is_file() {
+                continue;
+            }
+            // Its hint file, with its summary, lets `restore` skip it.
+            let compaction_file_path = get_compaction_hint_file_path(&self.path, file_id);
+            if is_valid_compaction_hint_file(&StdStorage::default(), &compaction_file_path)? {
+                let archived_compaction_file_path = get_compaction_hint_file_path(&self.dir, file_id);
+                let temp_compaction_file_path = temp_path(&archived_compaction_file_path);
+                link_or_copy(&compaction_file_path, &temp_compaction_file_path)?;
+                fs::rename(&temp_compaction_file_path, &archived_compaction_file_path)?;
+            }
+            let temp_path = temp_path(&archived_path);
+            let data_file_path = get_data_file_path(&self.path, file_id);
+            match self.remote {
+                Some(ref remote) if remote.stub(file_id).is_some() => {
+                    if link_or_copy(&data_file_path, &temp_path).is_err() {
+                        remote.download(file_id, &temp_path)?;
+                    }
+                }
+                _ => link_or_copy(&data_file_path, &temp_path)?,
+            }
+            fs::rename(&temp_path, &archived_path)?;
+            info!("Archived data file {} into {:?}", file_id, self.dir);
+        }
+        sync_dir(&self.dir)?;
+        Ok(())
+    }
+
+    pub fn add_blob_files(&self, files: &[u32]) -> Result<()> {
+        for &file_id in files {
+            let archived_path = get_blob_file_path(&self.dir, file_id);
+            if archived_path.is_file() {
+                continue;
+            }
+            let temp_path = temp_path(&archived_path);
+            link_or_copy(&get_blob_file_path(&self.path, file_id), &temp_path)?;
+            fs::rename(&temp_path, &archived_path)?;
+            info!("Archived blob file {} into {:?}", file_id, self.dir);
+        }
+        sync_dir(&self.dir)?;
+        Ok(())
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+// The point in time a store is restored to: every write up to a sequence number, or
+// every write made until a time, by the creation time of the records.
+#[derive(Clone, Copy, Debug)]
+pub enum RestoreTarget {
+    Seq(u64),
+    Time(SystemTime),
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    // Data files read, from the archive and the store.
+    pub files: usize,
+    // Live keys at the target, written to the new store.
+    pub records: u64,
+    // Sequence number of the last write restored.
+    pub last_seq: u64,
+}
+
+// A data file to read the records of, in the archive or in the store.
+struct Source {
+    dir: usize,
+    file_id: u32,
+    recovery_mode: RecoveryMode,
+}
+
+// The latest version of a key at the target.
+struct Version {
+    seq: u64,
+    source: usize,
+    pos: u64,
+    deleted: bool,
+}
+
+// Rebuild, in a new store at `out`, the state of a store at `target` from its archive
+// and, if given, its directory: the archive holds the files compacted away, and the
+// store those which weren't yet. The store may be in use, it's attached to like a
+// read-only opener so that no file is removed while it's read. The restored records keep
+// their sequence number and creation time. The encrypted files are read, and the new store
+// encrypted, with `keyring`.
+pub fn restore(
+    archive: &str,
+    store: Option<&str>,
+    out: &str,
+    target: RestoreTarget,
+    keyring: Option<Keyring>,
+) -> Result<RestoreReport> {
+    if !is_new_store_path(Path::new(out))? {
+        return Err(Error::InvalidPath(out.to_string()));
+    }
+
+    let mut dirs = vec![PathBuf::from(archive)];
+    let mut sources = Vec::new();
+    let archived_files = find_data_files(&dirs[0])?;
+    for &file_id in &archived_files {
+        sources.push(Source { dir: 0, file_id, recovery_mode: RecoveryMode::Strict });
+    }
+    let _readers_lock = match store {
+        Some(store) => {
+            let path = PathBuf::from(store);
+            let readers_lock = acquire_readers_lock(&path)?;
+            let files = match Manifest::load(&path)? {
+                Some(manifest) => manifest.files(),
+                None => find_data_files(&path)?,
+            };
+            for file_id in files {
+                // The active data file may end with a record being written.
+                if archived_files.binary_search(&file_id).is_err() {
+                    sources.push(Source { dir: 1, file_id, recovery_mode: RecoveryMode::SkipCorrupt });
+                }
+            }
+            dirs.push(path);
+            Some(readers_lock)
+        }
+        None => None,
+    };
+
+    let target_seq = match target {
+        RestoreTarget::Seq(seq) => seq,
+        RestoreTarget::Time(time) => seq_at(&dirs, &sources, time)?,
+    };
+    info!("Restoring {:?} up to sequence number {} into {:?}", archive, target_seq, out);
+    // The files whose records are all newer than the target aren't read.
+    let mut files = Vec::with_capacity(sources.len());
+    for file in sources {
+        if summary(&dirs, &file)?.is_none_or(|summary| summary.min_seq <= target_seq) {
+            files.push(file);
+        }
+    }
+    let sources = files;
+
+    let mut report = RestoreReport {
+        files: sources.len(),
+        ..RestoreReport::default()
+    };
+    let mut versions: HashMap<Vec<u8>, Version> = HashMap::new();
+    let mut range_deletes = Vec::new();
+    for (source, file) in sources.iter().enumerate() {
+        for (pos, log) in open_entries(&StdStorage::default(), &dirs[file.dir], file.file_id, file.recovery_mode)? {
+            let log = log?;
+            if log.seq > target_seq {
+                continue;
+            }
+            report.last_seq = report.last_seq.max(log.seq);
+            if log.range {
+                range_deletes.push((log.key.into_owned(), log.value.into_owned(), log.seq));
+                continue;
+            }
+            let newer = versions.get(log.key.as_ref()).is_none_or(|version| version.seq < log.seq);
+            if newer {
+                versions.insert(log.key.into_owned(), Version {
+                    seq: log.seq,
+                    source,
+                    pos,
+                    deleted: log.deleted,
+                });
+            }
+        }
+    }
+
+    let survivors: HashSet<(usize, u64)> = versions
+        .iter()
+        .filter(|(key, version)| {
+            !version.deleted && !range_deletes
+                .iter()
+                .any(|(start, end, seq)| *seq > version.seq && in_range(key, start, end))
+        })
+        .map(|(_, version)| (version.source, version.pos))
+        .collect();
+    drop(versions);
+
+    let mut options = StorageOptions::default();
+    options.sync(SyncOptions::Never).compaction(false);
+    if let Some(ref keyring) = keyring {
+        options.encryption(keyring.clone());
+    }
+    let db = options.load(out)?;
+    for (source, file) in sources.iter().enumerate() {
+        let entries = open_entries(&StdStorage::default(), &dirs[file.dir], file.file_id, file.recovery_mode)?;
+        let codec = entries.codec(keyring.as_ref())?;
+        for (pos, log) in entries {
+            if !survivors.contains(&(source, pos)) {
+                continue;
+            }
+            let mut log = log?;
+            if let Some(ref codec) = codec {
+                codec.decode_log(&mut log)?;
+            }
+            if log.blob {
+                let pointer = BlobPointer::decode(&log.value)?;
+                let dir = dirs
+                    .iter()
+                    .find(|dir| get_blob_file_path(dir, pointer.file_id).is_file())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, format!("blob file {} not found", pointer.file_id))
+                    })?;
+                log.value = read_blob(&StdStorage::default(), dir, &pointer)?.to_vec().into();
+                log.blob = false;
+            }
+            db.merge(log)?;
+            report.records += 1;
+        }
+    }
+    db.close()?;
+
+    Ok(report)
+}
+
+// The sequence number of the last record created until `time`. The records without a
+// creation time, from the files of old versions, don't count.
+fn seq_at(dirs: &[PathBuf], sources: &[Source], time: SystemTime) -> Result<u64> {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let mut seq = 0;
+    for file in sources {
+        // A file can't raise the sequence number past its last record.
+        if summary(dirs, file)?.is_some_and(|summary| summary.max_seq <= seq) {
+            continue;
+        }
+        for (_, log) in open_entries(&StdStorage::default(), &dirs[file.dir], file.file_id, file.recovery_mode)? {
+            let log: Log = log?;
+            match log.timestamp {
+                Some(timestamp) if timestamp > 0 && timestamp <= time => seq = seq.max(log.seq),
+                _ => {}
+            }
+        }
+    }
+    Ok(seq)
+}
+
+// The summary of the data file of a source, if its hint file has one.
+fn summary(dirs: &[PathBuf], file: &Source) -> Result<Option<FileSummary>> {
+    read_file_summary(&get_compaction_hint_file_path(&dirs[file.dir], file.file_id))
+}
@@ -0,0 +1,73 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    available: f64,
+    last_refill: Instant,
+}
+
+// Token bucket throttling an I/O stream to `bytes_per_sec`, with bursts of up to one
+// second worth of bytes. Requests larger than the bucket are let through and paid back by
+// the next ones. It also counts requests rather than bytes, e.g. to limit the writes of
+// the clients of the server.
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec as f64,
+            bucket: Mutex::new(Bucket {
+                available: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.bytes_per_sec;
+        bucket.available = (bucket.available + refill).min(self.bytes_per_sec);
+        bucket.last_refill = now;
+    }
+
+    // Block until `bytes` can be read or written without exceeding the rate.
+    pub fn request(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            self.refill(&mut bucket);
+
+            bucket.available -= bytes as f64;
+            if bucket.available < 0.0 {
+                Duration::from_secs_f64(-bucket.available / self.bytes_per_sec)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    // Take `bytes` from the bucket if it holds them, rather than waiting for them.
+    pub fn try_request(&self, bytes: u64) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        if bucket.available < bytes as f64 {
+            return false;
+        }
+        bucket.available -= bytes as f64;
+        true
+    }
+
+    // Whether the bucket is full again, i.e. it wasn't used for a second.
+    pub fn is_idle(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        bucket.available >= self.bytes_per_sec
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+
+use log::info;
+
+use super::blob::{find_blob_files, get_blob_file_path};
+use super::error::{Error, Result};
+use super::lease::LEASES_FILE_NAME;
+use super::lsm::{acquire_lock, find_data_files, get_compaction_hint_file_path, get_data_file_path};
+use super::manifest::Manifest;
+use super::standby::{ELECTION_FILE_NAME, SEED_FILE_NAME, STANDBY_FILE_NAME};
+use super::tiering::find_remote_files;
+use super::util::{copy_synced, is_new_store_path, link_or_copy, sync_dir};
+
+#[derive(Debug, Default)]
+pub struct RenumberReport {
+    // Data files of the new store, numbered from 1.
+    pub files: usize,
+    // The highest data file id of the store, which its next file would have followed.
+    pub last_file_id: u32,
+}
+
+// Copy the store at `path`, which is not in use, into a new store at `out` whose data
+// files are numbered from 1 in the same order, giving its ids back to a long-lived store
+// once `Error::FileIdsExhausted` is near. The data files are hard linked with their seal,
+// or copied when `out` is on another file system, and their hint files copied. The blob
+// files keep their ids, which the records point to.
+//
+// The offloaded data files must be fetched first. The positions of the standbys of the
+// store name its former ids, they have to be bootstrapped again, and its archive, whose
+// ids the new store would reuse, isn't carried over.
+pub fn renumber(path: &str, out: &str) -> Result<RenumberReport> {
+    let path = PathBuf::from(path);
+    let out_path = Path::new(out);
+    if !is_new_store_path(out_path)? {
+        return Err(Error::InvalidPath(out.to_string()));
+    }
+    if !path.is_dir() {
+        return Err(Error::InvalidPath(path.to_string_lossy().into_owned()));
+    }
+    let _lock_file = acquire_lock(&path).map_err(|err| match err {
+        Error::Io(ref err) if err.kind() == io::ErrorKind::WouldBlock => Error::InUse,
+        err => err,
+    })?;
+    if let Some(&file_id) = find_remote_files(&path)?.first() {
+        return Err(io::Error::other(format!("data file {} is offloaded, it must be fetched first", file_id)).into());
+    }
+
+    // The files compacted away but not removed yet aren't carried over.
+    let manifest = Manifest::load(&path)?;
+    let files = match manifest {
+        Some(ref manifest) => manifest.files(),
+        None => find_data_files(&path)?,
+    };
+    fs::create_dir_all(out_path)?;
+
+    let mut renumbered = Vec::with_capacity(files.len());
+    for (new_file_id, &file_id) in (1..).zip(&files) {
+        link_or_copy(&get_data_file_path(&path, file_id), &get_data_file_path(out_path, new_file_id))?;
+        let compaction_file_path = get_compaction_hint_file_path(&path, file_id);
+        if compaction_file_path.is_file() {
+            copy_synced(&compaction_file_path, &get_compaction_hint_file_path(out_path, new_file_id))?;
+        }
+        let seal = manifest.as_ref().and_then(|manifest| manifest.seal(file_id));
+        renumbered.push((new_file_id, seal));
+    }
+    for file_id in find_blob_files(&path)? {
+        link_or_copy(&get_blob_file_path(&path, file_id), &get_blob_file_path(out_path, file_id))?;
+    }
+    for name in &[LEASES_FILE_NAME, STANDBY_FILE_NAME, SEED_FILE_NAME, ELECTION_FILE_NAME] {
+        if path.join(name).is_file() {
+            copy_synced(&path.join(name), &out_path.join(name))?;
+        }
+    }
+
+    Manifest::create_sealed(out_path, &renumbered)?;
+    sync_dir(out_path)?;
+
+    let report = RenumberReport {
+        files: files.len(),
+        last_file_id: files.last().cloned().unwrap_or(0),
+    };
+    info!(
+        "Renumbered the {} data files of {:?} into {:?}, the last one was {}",
+        report.files,
+        path,
+        out,
+        report.last_file_id
+    );
+    Ok(report)
+}
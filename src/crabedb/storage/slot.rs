@@ -1,23 +1,51 @@
 use std::borrow::Cow;
+use std::cmp;
+use std::io;
 use std::io::prelude::*;
 use std::io::Cursor;
+use std::ops::Range;
+use std::path::Path;
 use std::result::Result::{Err, Ok};
-use std::collections::HashMap;
-use std::collections::hash_map::{Entry as HashMapEntry, Keys};
+use std::collections::{BinaryHeap, HashMap};
+use std::collections::hash_map::Entry as HashMapEntry;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use arc_swap::ArcSwap;
+use bytes::Bytes;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use log::warn;
 use twox_hash::RandomXxHashBuilder32;
 
+use super::art::ArtKeyMap;
+use super::blob::BlobPointer;
+use super::checksum::{checksum_size, read_checksum, write_checksum, ChecksumHasher};
 use super::error::{Error, Result};
-use super::xxhash::XxHash32;
+use super::format::{
+    FileHeader, FLAG_BLOB_POINTERS, FLAG_RANGE_TOMBSTONES, FORMAT_VERSION, FORMAT_VERSION_1,
+    FORMAT_VERSION_3, LEGACY_FORMAT_VERSION,
+};
+use super::key_map::{HashKeyMap, KeyMap};
+use super::options::{ChecksumKind, IndexKind};
+use super::spill::SpilledIdx;
+use super::xxhash::xxhash32;
 
 const LOG_STATIC_SIZE: usize = 18; // checksum(4) + seq(8) + key_size(2) + value_size(4)
+// Follows the sequence number in the records and hints of version 2 files.
+const LOG_TIMESTAMP_SIZE: usize = 8;
+// The checksum of the records of version 3 files takes 8 bytes instead of 4.
+const LOG_WIDE_CHECKSUM_SIZE: usize = 4;
 const LOG_TOMBSTONE: u32 = !0;
-pub const MAX_VALUE_SIZE: u32 = !0 - 1;
+// Set in the value size of a record whose value is a `BlobPointer`, in files written with
+// `FLAG_BLOB_POINTERS`.
+const LOG_BLOB_POINTER: u32 = 1 << 31;
+// Set in the value size of a range tombstone, in files written with `FLAG_RANGE_TOMBSTONES`.
+const LOG_RANGE_TOMBSTONE: u32 = 1 << 30;
+const MAX_PREALLOCATED_VALUE_SIZE: usize = 1024 * 1024;
+pub const MAX_VALUE_SIZE: u32 = LOG_RANGE_TOMBSTONE - 1;
 pub const MAX_KEY_SIZE: u16 = !0;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct MemIdxEntry {
     pub pos: u64,
     pub seq: u64,
@@ -27,29 +55,44 @@ pub struct MemIdxEntry {
 
 struct CompactionAnalysisEntry {
     entries: u64,
+    bytes: u64,
     dead_entries: u64,
     dead_bytes: u64,
 }
 
 pub struct CompactionAnalysis {
     map: HashMap<u32, CompactionAnalysisEntry>,
+    // Totals of every file.
+    bytes: u64,
+    dead_bytes: u64,
+}
+
+impl Default for CompactionAnalysis {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CompactionAnalysis {
     pub fn new() -> CompactionAnalysis {
         CompactionAnalysis {
-            map: HashMap::new()
+            map: HashMap::new(),
+            bytes: 0,
+            dead_bytes: 0,
         }
     }
 
     pub fn add(&mut self, entry: &MemIdxEntry) {
+        self.bytes += entry.size;
         match self.map.entry(entry.file_id) {
             HashMapEntry::Occupied(mut occupied) => {
                 occupied.get_mut().entries += 1;
+                occupied.get_mut().bytes += entry.size;
             }
             HashMapEntry::Vacant(e) => {
                 e.insert(CompactionAnalysisEntry {
                     entries: 1,
+                    bytes: entry.size,
                     dead_entries: 0,
                     dead_bytes: 0,
                 });
@@ -62,6 +105,7 @@ impl CompactionAnalysis {
             HashMapEntry::Occupied(mut occupied) => {
                 occupied.get_mut().dead_entries += 1;
                 occupied.get_mut().dead_bytes += entry.size;
+                self.dead_bytes += entry.size;
             }
             HashMapEntry::Vacant(_) => {
                 warn!("Tried to reclaim non-existant entry {:?}", entry);
@@ -71,8 +115,31 @@ impl CompactionAnalysis {
 
     pub fn remove_files(&mut self, files: &[u32]) {
         for file_id in files {
-            self.map.remove(file_id);
+            if let Some(e) = self.map.remove(file_id) {
+                self.bytes -= e.bytes;
+                self.dead_bytes -= e.dead_bytes;
+            }
+        }
+    }
+
+    // Size of the records the index points to, in every file.
+    pub fn live_bytes(&self) -> u64 {
+        self.map.values().map(|e| e.bytes - e.dead_bytes).sum()
+    }
+
+    // Share of the bytes of every file which the index no longer points to.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.bytes == 0 {
+            return 0.0;
         }
+        self.dead_bytes as f64 / self.bytes as f64
+    }
+
+    // Entries, dead entries and dead bytes of a file.
+    pub fn file_entries(&self, file_id: u32) -> (u64, u64, u64) {
+        self.map
+            .get(&file_id)
+            .map_or((0, 0, 0), |e| (e.entries, e.dead_entries, e.dead_bytes))
     }
 
     pub fn file_analysis(&self) -> Vec<(u32, f64, u64)> {
@@ -89,75 +156,580 @@ impl CompactionAnalysis {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct LogPointer {
+    pub file_id: u32,
+    pub pos: u64,
+    pub size: u64,
+}
+
+impl<'a> From<&'a MemIdxEntry> for LogPointer {
+    fn from(entry: &'a MemIdxEntry) -> LogPointer {
+        LogPointer {
+            file_id: entry.file_id,
+            pos: entry.pos,
+            size: entry.size,
+        }
+    }
+}
+
+// Shards of a view of the index, so that a publication only copies the shards it updates.
+const IDX_SHARDS: usize = 64;
+
+type IdxBase = HashMap<Vec<u8>, LogPointer, RandomXxHashBuilder32>;
+
+// A shard of a view of the index: a large base shared by successive views and a small
+// overlay holding the updates published since the base was built (`None` for removals).
+#[derive(Default)]
+struct IdxShard {
+    base: Arc<IdxBase>,
+    overlay: HashMap<Vec<u8>, Option<LogPointer>, RandomXxHashBuilder32>,
+}
+
+impl IdxShard {
+    fn get(&self, key: &[u8]) -> Option<LogPointer> {
+        match self.overlay.get(key) {
+            Some(pointer) => *pointer,
+            None => self.base.get(key).cloned(),
+        }
+    }
+}
+
+fn idx_shard_of(key: &[u8]) -> usize {
+    xxhash32(key) as usize % IDX_SHARDS
+}
+
+// An immutable view of the index. Successive views share the shards which weren't updated
+// in between.
+pub struct IdxView {
+    shards: Vec<Arc<IdxShard>>,
+}
+
+impl Default for IdxView {
+    fn default() -> Self {
+        IdxView {
+            shards: (0..IDX_SHARDS).map(|_| Arc::default()).collect(),
+        }
+    }
+}
+
+impl IdxView {
+    pub fn get(&self, key: &[u8]) -> Option<LogPointer> {
+        self.shards[idx_shard_of(key)].get(key)
+    }
+}
+
+// Index views published for the lock-free read path. Readers load the current view
+// without taking any lock; writers publish their updates in batches by swapping in a
+// new view, and fold the overlay of a shard into a new base once it holds `batch_size`
+// keys. A publication copies the overlays of the shards it updates, and a fold only the
+// base of its shard.
+pub struct SharedIdx {
+    view: ArcSwap<IdxView>,
+    batch_size: usize,
+}
+
+impl SharedIdx {
+    pub fn load(&self) -> Arc<IdxView> {
+        self.view.load_full()
+    }
+
+    pub fn is_current(&self, view: &Arc<IdxView>) -> bool {
+        Arc::ptr_eq(&self.view.load(), view)
+    }
+
+    // Publications are serialized by the `MemIdx` owning this index.
+    fn publish(&self, updates: Vec<(Vec<u8>, Option<LogPointer>)>) {
+        let mut shard_updates: Vec<Vec<(Vec<u8>, Option<LogPointer>)>> = vec![Vec::new(); IDX_SHARDS];
+        for (key, pointer) in updates {
+            shard_updates[idx_shard_of(&key)].push((key, pointer));
+        }
+
+        let current = self.view.load_full();
+        let mut shards = current.shards.clone();
+        for (shard, updates) in shards.iter_mut().zip(shard_updates) {
+            if updates.is_empty() {
+                continue;
+            }
+            let mut overlay = shard.overlay.clone();
+            overlay.extend(updates);
+
+            *shard = Arc::new(if overlay.len() >= self.batch_size {
+                let mut base = (*shard.base).clone();
+                for (key, pointer) in overlay {
+                    match pointer {
+                        Some(pointer) => base.insert(key, pointer),
+                        None => base.remove(&key),
+                    };
+                }
+                IdxShard {
+                    base: Arc::new(base),
+                    overlay: Default::default(),
+                }
+            } else {
+                IdxShard {
+                    base: shard.base.clone(),
+                    overlay,
+                }
+            });
+        }
+
+        self.view.store(Arc::new(IdxView { shards }));
+    }
+}
+
+// Whether `key` is in the range of a range tombstone, an empty `end` meaning that the range
+// has no upper bound.
+pub fn in_range(key: &[u8], start: &[u8], end: &[u8]) -> bool {
+    key >= start && (end.is_empty() || key < end)
+}
+
+// Estimated memory used by an entry of the index besides the bytes of its key: its slot in
+// the hash table, and its share of the spare slots.
+const MEM_IDX_ENTRY_OVERHEAD: usize = 64;
+
+// The keys which don't fit in the memory budget of the index, see `MemIdx::with_memory_budget`.
+struct Spill {
+    idx: SpilledIdx,
+    budget: usize,
+    usage: usize,
+}
+
+// A key of the index and its entry, read from the spill file when it isn't in memory.
+type IdxEntry<'a> = Result<(Cow<'a, [u8]>, MemIdxEntry)>;
+
+// The key, or the start and end of the range, and the sequence number of a removal.
+pub type Tombstone = (Vec<u8>, Option<Vec<u8>>, u64);
+
 pub struct MemIdx {
-    mem: HashMap<Vec<u8>, MemIdxEntry, RandomXxHashBuilder32>,
+    mem: Box<dyn KeyMap>,
+    spill: Option<Spill>,
+    shared: Option<Arc<SharedIdx>>,
+    pending: Vec<(Vec<u8>, Option<LogPointer>)>,
+    // Range tombstones met while loading: the files aren't read in sequence order, so they
+    // also hide the older records of their range read afterwards.
+    range_tombstones: Vec<(Vec<u8>, Vec<u8>, u64)>,
+    // With `keep_tombstones`, the sequence numbers of the removed keys, and the range
+    // tombstones after the load too, until compaction drops their tombstones.
+    tombstones: HashMap<Vec<u8>, u64>,
+    keep_tombstones: bool,
     pub compaction_analysis: CompactionAnalysis,
 }
 
+impl Default for MemIdx {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemIdx {
     pub fn new() -> MemIdx {
-        // Use xxHash for lookup and insertion speed at RAM's limits
-        let hash : HashMap<Vec<u8>, MemIdxEntry, RandomXxHashBuilder32> = Default::default();
+        MemIdx::with_kind(IndexKind::Hash)
+    }
+
+    pub fn with_kind(kind: IndexKind) -> MemIdx {
+        let mem: Box<dyn KeyMap> = match kind {
+            IndexKind::Hash => Box::new(HashKeyMap::new()),
+            IndexKind::Art => Box::new(ArtKeyMap::new()),
+        };
         MemIdx {
-            mem: hash,
+            mem,
+            spill: None,
+            shared: None,
+            pending: Vec::new(),
+            range_tombstones: Vec::new(),
+            tombstones: HashMap::new(),
+            keep_tombstones: false,
             compaction_analysis: CompactionAnalysis::new(),
         }
     }
 
-    pub fn set(&mut self, key: Vec<u8>, entry: MemIdxEntry) -> Option<MemIdxEntry> {
+    // Remember the sequence number of every removal, see `tombstone_seq`, for the writes
+    // of other nodes arriving out of order. It's set before the index is loaded.
+    pub fn keep_tombstones(&mut self) {
+        self.keep_tombstones = true;
+    }
+
+    // Keep about `budget` bytes of the index in memory, the most recently written keys,
+    // and the other ones in a spill file of `dir`. Their lookups then read the disk. It's
+    // set before the index is loaded.
+    pub fn spill_to(&mut self, dir: &Path, budget: usize) -> Result<()> {
+        self.spill = Some(Spill {
+            idx: SpilledIdx::create(dir)?,
+            budget,
+            usage: 0,
+        });
+        Ok(())
+    }
+
+    // Start publishing the index for lock-free readers. Updates are buffered until the
+    // next call to `publish`.
+    pub fn share(&mut self, batch_size: usize) -> Arc<SharedIdx> {
+        let mut bases: Vec<IdxBase> = (0..IDX_SHARDS).map(|_| IdxBase::default()).collect();
+        for (key, entry) in self.mem.entries() {
+            bases[idx_shard_of(key)].insert(key.to_vec(), LogPointer::from(&entry));
+        }
+        let shards = bases
+            .into_iter()
+            .map(|base| {
+                Arc::new(IdxShard {
+                    base: Arc::new(base),
+                    overlay: Default::default(),
+                })
+            })
+            .collect();
+        let shared = Arc::new(SharedIdx {
+            view: ArcSwap::from_pointee(IdxView { shards }),
+            batch_size,
+        });
+        self.shared = Some(shared.clone());
+        shared
+    }
+
+    pub fn publish(&mut self) {
+        if let Some(ref shared) = self.shared {
+            if !self.pending.is_empty() {
+                shared.publish(std::mem::take(&mut self.pending));
+            }
+        }
+    }
+
+    fn stage(&mut self, key: &[u8], pointer: Option<LogPointer>) {
+        if self.shared.is_some() {
+            self.pending.push((key.to_vec(), pointer));
+        }
+    }
+
+    // Insert the entry in memory, moving the key out of the spill file if it was there.
+    // The operations on the spill file are disk accesses, which fail with `Error::Io`.
+    fn insert(&mut self, key: &[u8], entry: MemIdxEntry) -> Result<Option<MemIdxEntry>> {
+        if self.spill.is_none() {
+            return Ok(self.mem.insert(key, entry));
+        }
+
+        let previous = match self.mem.insert(key, entry) {
+            Some(previous) => Some(previous),
+            None => {
+                let spill = self.spill.as_mut().unwrap();
+                spill.usage += key.len() + MEM_IDX_ENTRY_OVERHEAD;
+                spill.idx.remove(key)?
+            }
+        };
+        self.evict()?;
+        Ok(previous)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<Option<MemIdxEntry>> {
+        match self.mem.remove(key) {
+            Some(entry) => {
+                if let Some(ref mut spill) = self.spill {
+                    spill.usage -= key.len() + MEM_IDX_ENTRY_OVERHEAD;
+                }
+                Ok(Some(entry))
+            }
+            None => match self.spill {
+                Some(ref mut spill) => Ok(spill.idx.remove(key)?),
+                None => Ok(None),
+            },
+        }
+    }
+
+    // Move the least recently written quarter of the keys to the spill file once the
+    // memory budget is exceeded, so that it isn't done at every insertion. A key only
+    // leaves the memory once it was written to the spill file.
+    fn evict(&mut self) -> Result<()> {
+        match self.spill {
+            Some(ref spill) if spill.usage > spill.budget => {}
+            _ => return Ok(()),
+        }
+
+        let mut seqs: Vec<u64> = self.mem.entries().map(|(_, entry)| entry.seq).collect();
+        let evicted = (seqs.len() / 4).max(1);
+        let max_seq = *seqs.select_nth_unstable(evicted - 1).1;
+        let keys: Vec<Vec<u8>> = self.mem
+            .entries()
+            .filter(|(_, entry)| entry.seq <= max_seq)
+            .map(|(key, _)| key.to_vec())
+            .collect();
+
+        for key in keys {
+            if let Some(entry) = self.mem.get(&key) {
+                let spill = self.spill.as_mut().unwrap();
+                spill.idx.insert(&key, &entry)?;
+                spill.usage -= key.len() + MEM_IDX_ENTRY_OVERHEAD;
+                self.mem.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, entry: MemIdxEntry) -> Result<Option<MemIdxEntry>> {
+        if self.keep_tombstones {
+            self.tombstones.remove(&key);
+        }
+        self.stage(&key, Some(LogPointer::from(&entry)));
         self.compaction_analysis.add(&entry);
-        self.mem.insert(key, entry).map(|entry| {
-            self.compaction_analysis.remove(&entry);
-            entry
-        })
+        Ok(self.insert(&key, entry)?.inspect(|entry| {
+            self.compaction_analysis.remove(entry);
+        }))
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&MemIdxEntry> {
-        self.mem.get(key)
+    pub fn get(&self, key: &[u8]) -> Result<Option<MemIdxEntry>> {
+        match (self.mem.get(key), &self.spill) {
+            (Some(entry), _) => Ok(Some(entry)),
+            (None, Some(spill)) => Ok(spill.idx.get(key)?),
+            (None, None) => Ok(None),
+        }
     }
 
-    pub fn remove(&mut self, key: &[u8]) -> Option<MemIdxEntry> {
-        self.mem.remove(key).map(|entry| {
-            self.compaction_analysis.remove(&entry);
-            entry
-        })
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<MemIdxEntry>> {
+        let entry = self.delete(key)?.inspect(|entry| {
+            self.compaction_analysis.remove(entry);
+        });
+        if entry.is_some() {
+            self.stage(key, None);
+        }
+        Ok(entry)
+    }
+
+    // Remove the keys of the range older than `seq`, and return them.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8], seq: u64) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for entry in self.range_entries(start, end) {
+            let (key, entry) = entry?;
+            if entry.seq < seq {
+                keys.push(key.into_owned());
+            }
+        }
+        for key in &keys {
+            self.remove(key)?;
+        }
+        Ok(keys)
+    }
+
+    // Record the removal of `key`, or of the range from `key` to `end`, at `seq`.
+    pub fn tombstone(&mut self, key: &[u8], end: Option<&[u8]>, seq: u64) {
+        if !self.keep_tombstones {
+            return;
+        }
+        match end {
+            Some(end) => self.range_tombstones.push((key.to_vec(), end.to_vec(), seq)),
+            None => {
+                let tombstone = self.tombstones.entry(key.to_vec()).or_insert(seq);
+                *tombstone = (*tombstone).max(seq);
+            }
+        }
     }
 
-    pub fn update(&mut self, ch: CompactionHint, file_id: u32) {
+    // The sequence number of the last removal of `key` whose tombstone wasn't dropped yet,
+    // a write older than it is already overwritten.
+    pub fn tombstone_seq(&self, key: &[u8]) -> Option<u64> {
+        self.range_tombstones
+            .iter()
+            .filter(|(start, end, _)| in_range(key, start, end))
+            .map(|&(_, _, seq)| seq)
+            .chain(self.tombstones.get(key).cloned())
+            .max()
+    }
+
+    // Forget the tombstones dropped by a compaction, unless the key was removed again.
+    pub fn forget_tombstones(&mut self, dropped: &[Tombstone]) {
+        for (key, end, seq) in dropped {
+            match end {
+                Some(end) => self.range_tombstones.retain(|tombstone| tombstone != &(key.clone(), end.clone(), *seq)),
+                None => {
+                    if self.tombstones.get(key) == Some(seq) {
+                        self.tombstones.remove(key);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn clear_tombstones(&mut self) {
+        self.tombstones = HashMap::new();
+        self.range_tombstones = Vec::new();
+    }
+
+    // The range tombstones are only needed until every file is loaded, unless the
+    // tombstones are kept.
+    pub fn finish_load(&mut self) {
+        if !self.keep_tombstones {
+            self.range_tombstones = Vec::new();
+        }
+    }
+
+    pub fn update(&mut self, ch: CompactionHint, file_id: u32) -> Result<()> {
+        if let Some(ref end) = ch.range_end {
+            self.delete_range(&ch.key, end, ch.seq)?;
+            self.range_tombstones.push((ch.key.to_vec(), end.to_vec(), ch.seq));
+            return Ok(());
+        }
+
         let mem_idx_entry = MemIdxEntry {
             pos: ch.log_pos,
             seq: ch.seq,
             size: ch.log_size(),
-            file_id: file_id,
+            file_id,
         };
 
-        match self.mem.entry(ch.key.to_vec()) {
-            HashMapEntry::Occupied(mut occupied) => {
-                if occupied.get().seq <= ch.seq {
-                    self.compaction_analysis.remove(occupied.get());
+        let covered = self.range_tombstones
+            .iter()
+            .any(|(start, end, seq)| *seq > ch.seq && in_range(&ch.key, start, end));
+        let removed = self.tombstones.get(ch.key.as_ref()).is_some_and(|&seq| seq > ch.seq);
+        if covered || removed {
+            self.compaction_analysis.add(&mem_idx_entry);
+            self.compaction_analysis.remove(&mem_idx_entry);
+            return Ok(());
+        }
+
+        match self.get(&ch.key)? {
+            Some(current) => {
+                if current.seq <= ch.seq {
+                    self.compaction_analysis.remove(&current);
                     if ch.deleted {
-                        occupied.remove();
+                        self.delete(&ch.key)?;
+                        self.stage(&ch.key, None);
+                        self.tombstone(&ch.key, None, ch.seq);
                     } else {
+                        if self.keep_tombstones {
+                            self.tombstones.remove(ch.key.as_ref());
+                        }
                         self.compaction_analysis.add(&mem_idx_entry);
-                        occupied.insert(mem_idx_entry);
+                        self.stage(&ch.key, Some(LogPointer::from(&mem_idx_entry)));
+                        self.insert(&ch.key, mem_idx_entry)?;
                     }
                 } else {
                     self.compaction_analysis.add(&mem_idx_entry);
                     self.compaction_analysis.remove(&mem_idx_entry);
                 }
             }
-            HashMapEntry::Vacant(e) => {
-                if !ch.deleted {
+            None => {
+                if ch.deleted {
+                    self.tombstone(&ch.key, None, ch.seq);
+                } else {
+                    if self.keep_tombstones {
+                        self.tombstones.remove(ch.key.as_ref());
+                    }
                     self.compaction_analysis.add(&mem_idx_entry);
-                    e.insert(mem_idx_entry);
+                    self.stage(&ch.key, Some(LogPointer::from(&mem_idx_entry)));
+                    self.insert(&ch.key, mem_idx_entry)?;
                 }
             }
         }
+        Ok(())
+    }
+
+    // The keys and their entries, those in memory first. Only the reads of the spilled
+    // ones can fail.
+    pub fn entries(&self) -> impl Iterator<Item = IdxEntry<'_>> + '_ {
+        self.mem
+            .entries()
+            .map(|(key, entry)| Ok((Cow::Borrowed(key), entry)))
+            .chain(self.spilled_entries())
+    }
+
+    fn spilled_entries(&self) -> impl Iterator<Item = IdxEntry<'_>> + '_ {
+        self.spill
+            .iter()
+            .flat_map(|spill| spill.idx.entries())
+            .map(|live| {
+                let (key, entry) = live?;
+                Ok((Cow::Owned(key), entry))
+            })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Result<Cow<'_, [u8]>>> + '_ {
+        self.entries().map(|entry| entry.map(|(key, _)| key))
+    }
+
+    // Memory used by the keys kept in memory: the hash table and the arena of the keys.
+    pub fn memory_usage(&self) -> usize {
+        self.mem.memory_usage()
     }
 
-    pub fn keys(&self) -> Keys<Vec<u8>, MemIdxEntry> {
-        self.mem.keys()
+    pub fn len(&self) -> usize {
+        self.mem.len() + self.spill.as_ref().map_or(0, |spill| spill.idx.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Size of the records of the keys from `start` to `end`, an empty `end` meaning no
+    // upper bound.
+    pub fn range_size(&self, start: &[u8], end: &[u8]) -> Result<u64> {
+        self.range_entries(start, end).map(|entry| entry.map(|(_, entry)| entry.size)).sum()
+    }
+
+    // The keys from `start` to `end`, an empty `end` meaning no upper bound. Only those
+    // keys are visited when the keys in memory are ordered.
+    fn range_entries<'a>(
+        &'a self,
+        start: &'a [u8],
+        end: &'a [u8],
+    ) -> Box<dyn Iterator<Item = IdxEntry<'a>> + 'a> {
+        let in_range = move |entry: &IdxEntry<'_>| match entry {
+            Ok((key, _)) => in_range(key, start, end),
+            Err(_) => true,
+        };
+        let spilled_entries = self.spilled_entries().filter(in_range);
+        match self.mem.entries_from(start) {
+            Some(entries) => Box::new(
+                entries
+                    .take_while(move |(key, _)| end.is_empty() || *key < end)
+                    .map(|(key, entry)| Ok((Cow::Borrowed(key), entry)))
+                    .chain(spilled_entries),
+            ),
+            None => Box::new(self.entries().filter(in_range)),
+        }
+    }
+
+    // A page of at most `limit` keys starting with `prefix`, ordered, after `cursor` (from
+    // the first one if it's empty). An ordered index is walked from the cursor, otherwise
+    // only the `limit` smallest matching keys are kept while the keys are visited.
+    pub fn list_keys(&self, prefix: &[u8], cursor: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        let matches = |key: &Result<Cow<'_, [u8]>>| match key {
+            Ok(key) => key.starts_with(prefix) && (cursor.is_empty() || key.as_ref() > cursor),
+            Err(_) => true,
+        };
+        match self.mem.entries_from(prefix.max(cursor)) {
+            Some(entries) => {
+                let page = entries
+                    .map(|(key, _)| key)
+                    .skip_while(|&key| !cursor.is_empty() && key == cursor)
+                    .take_while(|key| key.starts_with(prefix))
+                    .take(limit);
+                if self.spill.is_none() {
+                    return Ok(page.map(|key| key.to_vec()).collect());
+                }
+                // The spilled keys aren't ordered.
+                let spilled = self.spilled_entries().map(|entry| entry.map(|(key, _)| key)).filter(matches);
+                smallest_keys(page.map(|key| Ok(Cow::Borrowed(key))).chain(spilled), limit)
+            }
+            None => smallest_keys(self.keys().filter(matches), limit),
+        }
+    }
+}
+
+// The `limit` smallest keys, ordered, only the ones smaller than the largest kept so far
+// being copied.
+fn smallest_keys<'a>(keys: impl Iterator<Item = Result<Cow<'a, [u8]>>>, limit: usize) -> Result<Vec<Vec<u8>>> {
+    if limit == 0 {
+        return Ok(Vec::new());
     }
+    let mut kept: BinaryHeap<Vec<u8>> = BinaryHeap::with_capacity(limit + 1);
+    for key in keys {
+        let key = key?;
+        if kept.len() == limit && kept.peek().is_some_and(|largest| key.as_ref() >= largest.as_slice()) {
+            continue;
+        }
+        kept.push(key.into_owned());
+        if kept.len() > limit {
+            kept.pop();
+        }
+    }
+    Ok(kept.into_sorted_vec())
 }
 
 #[derive(Eq, PartialEq)]
@@ -166,6 +738,21 @@ pub struct Log<'a> {
     pub value: Cow<'a, [u8]>,
     pub seq: u64,
     pub deleted: bool,
+    // The value is an encoded `BlobPointer`.
+    pub blob: bool,
+    // A tombstone of the keys from `key` (included) to `value` (excluded).
+    pub range: bool,
+    // Milliseconds since the Unix epoch at which the record was created, `None` in the
+    // files of versions before 2, and 0 when such a record was copied to a newer file.
+    pub timestamp: Option<u64>,
+    // The record has a 64-bit checksum, in the files of version 3.
+    pub wide_checksum: bool,
+}
+
+// Value of a record, either stored in the record itself or in a blob file.
+pub enum StoredValue<T> {
+    Inline(T),
+    Blob(BlobPointer),
 }
 
 impl<'a> Log<'a> {
@@ -188,11 +775,32 @@ impl<'a> Log<'a> {
         Ok(Log {
             key: k,
             value: v,
-            seq: seq,
+            seq,
             deleted: false,
+            blob: false,
+            range: false,
+            timestamp: Some(now_millis()),
+            wide_checksum: false,
         })
     }
 
+    pub fn blob<K>(seq: u64, key: K, pointer: &BlobPointer) -> Result<Log<'a>>
+    where
+        Cow<'a, [u8]>: From<K>,
+    {
+        let mut log = Log::new::<K, Vec<u8>>(seq, key, pointer.encode())?;
+        log.blob = true;
+        Ok(log)
+    }
+
+    pub fn blob_pointer(&self) -> Result<Option<BlobPointer>> {
+        if self.blob {
+            BlobPointer::decode(&self.value).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn deleted<K>(seq: u64, key: K) -> Log<'a>
     where
         Cow<'a, [u8]>: From<K>,
@@ -200,75 +808,280 @@ impl<'a> Log<'a> {
         Log {
             key: Cow::from(key),
             value: Cow::Borrowed(&[]),
-            seq: seq,
+            seq,
             deleted: true,
+            blob: false,
+            range: false,
+            timestamp: Some(now_millis()),
+            wide_checksum: false,
+        }
+    }
+
+    pub fn deleted_range<K, E>(seq: u64, start: K, end: E) -> Result<Log<'a>>
+    where
+        Cow<'a, [u8]>: From<K>,
+        Cow<'a, [u8]>: From<E>,
+    {
+        let start = Cow::from(start);
+        let end = Cow::from(end);
+
+        for bound in &[&start, &end] {
+            if bound.len() > MAX_KEY_SIZE as usize {
+                return Err(Error::InvalidKeySize(bound.len()));
+            }
+        }
+
+        Ok(Log {
+            key: start,
+            value: end,
+            seq,
+            deleted: true,
+            blob: false,
+            range: true,
+            timestamp: Some(now_millis()),
+            wide_checksum: false,
+        })
+    }
+
+    // The same record in the format of a new file: with a timestamp, and the checksum of
+    // the file.
+    pub fn in_format(&self, file_header: &FileHeader) -> Log<'_> {
+        Log {
+            key: Cow::from(&*self.key),
+            value: Cow::from(&*self.value),
+            seq: self.seq,
+            deleted: self.deleted,
+            blob: self.blob,
+            range: self.range,
+            timestamp: Some(self.timestamp.unwrap_or(0)),
+            wide_checksum: file_header.has_wide_checksums(),
         }
     }
 
     pub fn size(&self) -> u64 {
-        LOG_STATIC_SIZE as u64 + self.key.len() as u64 + self.value.len() as u64
+        static_size(self.timestamp.is_some(), self.wide_checksum) as u64
+            + self.key.len() as u64
+            + self.value.len() as u64
     }
 
-    pub fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let mut cursor = Cursor::new(Vec::with_capacity(LOG_STATIC_SIZE));
-        cursor.set_position(4);
+    // The checksum must be 64-bit for a record with a wide checksum, and 32-bit otherwise.
+    pub fn write_bytes<W: Write>(&self, writer: &mut W, checksum: ChecksumKind) -> Result<()> {
+        let checksum_size = checksum_size(checksum);
+        debug_assert_eq!(self.wide_checksum, checksum_size == 8);
+        let mut cursor = Cursor::new(Vec::with_capacity(static_size(self.timestamp.is_some(), self.wide_checksum)));
+        cursor.set_position(checksum_size as u64);
         cursor.write_u64::<LittleEndian>(self.seq)?;
+        if let Some(timestamp) = self.timestamp {
+            cursor.write_u64::<LittleEndian>(timestamp)?;
+        }
         cursor.write_u16::<LittleEndian>(self.key.len() as u16)?;
 
-        if self.deleted {
+        if self.range {
+            cursor.write_u32::<LittleEndian>(self.value.len() as u32 | LOG_RANGE_TOMBSTONE)?;
+        } else if self.deleted {
             cursor.write_u32::<LittleEndian>(LOG_TOMBSTONE)?;
+        } else if self.blob {
+            cursor.write_u32::<LittleEndian>(self.value.len() as u32 | LOG_BLOB_POINTER)?;
         } else {
             cursor.write_u32::<LittleEndian>(self.value.len() as u32)?;
         }
 
-        let checksum = {
-            let mut hasher = XxHash32::new();
-            hasher.update(&cursor.get_ref()[4..]);
+        let hash = {
+            let mut hasher = ChecksumHasher::new(checksum);
+            hasher.update(&cursor.get_ref()[checksum_size..]);
             hasher.update(&self.key);
             hasher.update(&self.value);
             hasher.get()
         };
 
         cursor.set_position(0);
-        cursor.write_u32::<LittleEndian>(checksum)?;
+        write_checksum(&mut cursor, checksum, hash)?;
 
         writer.write_all(&cursor.into_inner())?;
         writer.write_all(&self.key)?;
 
-        if !self.deleted {
+        if !self.deleted || self.range {
             writer.write_all(&self.value)?;
         }
 
         Ok(())
     }
 
-    pub fn from_read<R: Read>(reader: &mut R) -> Result<Log<'a>> {
-        let mut header = vec![0u8; LOG_STATIC_SIZE as usize];
+    // Decode a record of a file written with the given format.
+    pub fn decode<R: Read>(reader: &mut R, file_header: &FileHeader) -> Result<Log<'a>> {
+        match file_header.version {
+            LEGACY_FORMAT_VERSION | FORMAT_VERSION_1 | FORMAT_VERSION | FORMAT_VERSION_3 => {
+                Log::from_read(reader, file_header)
+            }
+            version => Err(Error::UnsupportedFormat {
+                version,
+                flags: file_header.flags,
+            }),
+        }
+    }
+
+    // Decode the value of a whole record already read in memory, without copying it: the
+    // value is a slice of `record`. Returns `None` for a (range) tombstone.
+    pub fn decode_value(record: Bytes, file_header: &FileHeader) -> Result<Option<StoredValue<Bytes>>> {
+        Ok(match Log::value_range(&record, file_header)? {
+            Some((range, false)) => Some(StoredValue::Inline(record.slice(range))),
+            Some((range, true)) => Some(StoredValue::Blob(BlobPointer::decode(&record[range])?)),
+            None => None,
+        })
+    }
+
+    // Same as `decode_value`, but the record is decoded in place: on success, `record`
+    // only holds the value and its length is returned.
+    pub fn decode_value_into(
+        record: &mut Vec<u8>,
+        file_header: &FileHeader,
+    ) -> Result<Option<StoredValue<usize>>> {
+        match Log::value_range(record, file_header)? {
+            Some((range, false)) => {
+                let len = range.len();
+                record.copy_within(range, 0);
+                record.truncate(len);
+                Ok(Some(StoredValue::Inline(len)))
+            }
+            Some((range, true)) => Ok(Some(StoredValue::Blob(BlobPointer::decode(&record[range])?))),
+            None => {
+                record.clear();
+                Ok(None)
+            }
+        }
+    }
+
+    // The range of the value in the record and whether it is a blob pointer.
+    fn value_range(record: &[u8], file_header: &FileHeader) -> Result<Option<(Range<usize>, bool)>> {
+        match file_header.version {
+            LEGACY_FORMAT_VERSION | FORMAT_VERSION_1 | FORMAT_VERSION | FORMAT_VERSION_3 => {
+                Log::record_value_range(record, file_header)
+            }
+            version => Err(Error::UnsupportedFormat {
+                version,
+                flags: file_header.flags,
+            }),
+        }
+    }
+
+    fn record_value_range(record: &[u8], file_header: &FileHeader) -> Result<Option<(Range<usize>, bool)>> {
+        let static_size = static_size(file_header.has_timestamps(), file_header.has_wide_checksums());
+        if record.len() < static_size {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let mut cursor = Cursor::new(&record[..static_size]);
+        let checksum = read_checksum(&mut cursor, file_header.checksum())?;
+        let _seq = cursor.read_u64::<LittleEndian>()?;
+        if file_header.has_timestamps() {
+            let _timestamp = cursor.read_u64::<LittleEndian>()?;
+        }
+        let key_size = cursor.read_u16::<LittleEndian>()? as usize;
+        let (value_size, deleted, blob, range) = split_value_size(
+            cursor.read_u32::<LittleEndian>()?,
+            file_header.flags,
+        );
+
+        let value_start = static_size + key_size;
+        let value_end = value_start + value_size as usize;
+        if record.len() != value_end {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let hash = {
+            let mut hasher = ChecksumHasher::new(file_header.checksum());
+            hasher.update(&record[checksum_size(file_header.checksum())..]);
+            hasher.get()
+        };
+
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: checksum,
+                found: hash,
+            });
+        }
+
+        Ok(if deleted || range {
+            None
+        } else {
+            Some((value_start..value_end, blob))
+        })
+    }
+
+    // The size of the record whose header starts `bytes`, as claimed by the header, which
+    // may be anything when it's corrupted. `None` when the header isn't whole.
+    pub fn claimed_size(bytes: &[u8], file_header: &FileHeader) -> Option<u64> {
+        let static_size = static_size(file_header.has_timestamps(), file_header.has_wide_checksums());
+        let mut cursor = Cursor::new(bytes.get(..static_size)?);
+        read_checksum(&mut cursor, file_header.checksum()).ok()?;
+        cursor.read_u64::<LittleEndian>().ok()?;
+        if file_header.has_timestamps() {
+            cursor.read_u64::<LittleEndian>().ok()?;
+        }
+        let key_size = cursor.read_u16::<LittleEndian>().ok()?;
+        let (value_size, deleted, _, _) = split_value_size(cursor.read_u32::<LittleEndian>().ok()?, file_header.flags);
+        let value_size = if deleted { 0 } else { value_size };
+        Some(static_size as u64 + key_size as u64 + value_size as u64)
+    }
+
+    // Whether a whole record read in memory, of the size claimed by its header, has a valid
+    // checksum.
+    pub fn has_valid_checksum(record: &[u8], file_header: &FileHeader) -> bool {
+        let checksum_size = checksum_size(file_header.checksum());
+        if record.len() < checksum_size {
+            return false;
+        }
+        let checksum = match read_checksum(&mut Cursor::new(record), file_header.checksum()) {
+            Ok(checksum) => checksum,
+            Err(_) => return false,
+        };
+        let mut hasher = ChecksumHasher::new(file_header.checksum());
+        hasher.update(&record[checksum_size..]);
+        hasher.get() == checksum
+    }
+
+    pub fn from_read<R: Read>(reader: &mut R, file_header: &FileHeader) -> Result<Log<'a>> {
+        let wide_checksum = file_header.has_wide_checksums();
+        let mut header = vec![0u8; static_size(file_header.has_timestamps(), wide_checksum)];
         reader.read_exact(&mut header)?;
 
         let mut cursor = Cursor::new(header);
-        let checksum = cursor.read_u32::<LittleEndian>()?;
+        let checksum = read_checksum(&mut cursor, file_header.checksum())?;
         let seq = cursor.read_u64::<LittleEndian>()?;
+        let timestamp = if file_header.has_timestamps() {
+            Some(cursor.read_u64::<LittleEndian>()?)
+        } else {
+            None
+        };
         let key_size = cursor.read_u16::<LittleEndian>()?;
-        let value_size = cursor.read_u32::<LittleEndian>()?;
+        let (value_size, deleted, blob, range) = split_value_size(
+            cursor.read_u32::<LittleEndian>()?,
+            file_header.flags,
+        );
 
         let mut key = vec![0u8; key_size as usize];
         reader.read_exact(&mut key)?;
 
-        let deleted = value_size == LOG_TOMBSTONE;
-
         let value = if deleted {
             let empty: &[u8] = &[];
             Cow::from(empty)
         } else {
-            let mut value = vec![0u8; value_size as usize];
-            reader.read_exact(&mut value)?;
+            // The size comes straight from the disk, don't trust it for the allocation:
+            // a torn or corrupted header could otherwise request gigabytes.
+            let mut value = Vec::with_capacity(cmp::min(value_size as usize, MAX_PREALLOCATED_VALUE_SIZE));
+            reader.by_ref().take(value_size as u64).read_to_end(&mut value)?;
+            if value.len() != value_size as usize {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                )));
+            }
             Cow::from(value)
         };
 
         let hash = {
-            let mut hasher = XxHash32::new();
-            hasher.update(&cursor.get_ref()[4..]);
+            let mut hasher = ChecksumHasher::new(file_header.checksum());
+            hasher.update(&cursor.get_ref()[checksum_size(file_header.checksum())..]);
             hasher.update(&key);
             hasher.update(&value);
             hasher.get()
@@ -283,51 +1096,111 @@ impl<'a> Log<'a> {
 
         Ok(Log {
             key: Cow::from(key),
-            value: value,
-            seq: seq,
-            deleted: deleted,
+            value,
+            seq,
+            deleted: deleted || range,
+            blob,
+            range,
+            timestamp,
+            wide_checksum,
         })
     }
 }
 
+fn static_size(timestamped: bool, wide_checksum: bool) -> usize {
+    let mut size = LOG_STATIC_SIZE;
+    if timestamped {
+        size += LOG_TIMESTAMP_SIZE;
+    }
+    if wide_checksum {
+        size += LOG_WIDE_CHECKSUM_SIZE;
+    }
+    size
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+// Split the value size field of a record (or a hint) of a file written with `flags` into
+// the size of its value and whether it is a tombstone, a blob pointer or a range tombstone.
+fn split_value_size(value_size: u32, flags: u16) -> (u32, bool, bool, bool) {
+    if value_size == LOG_TOMBSTONE {
+        return (0, true, false, false);
+    }
+
+    let blob = flags & FLAG_BLOB_POINTERS != 0 && value_size & LOG_BLOB_POINTER != 0;
+    let range = flags & FLAG_RANGE_TOMBSTONES != 0 && value_size & LOG_RANGE_TOMBSTONE != 0;
+    let mut size = value_size;
+    if blob {
+        size &= !LOG_BLOB_POINTER;
+    }
+    if range {
+        size &= !LOG_RANGE_TOMBSTONE;
+    }
+    (size, false, blob, range)
+}
+
 pub struct CompactionHint<'a> {
     pub key: Cow<'a, [u8]>,
     pub log_pos: u64,
     pub value_size: u32,
     pub seq: u64,
     pub deleted: bool,
+    // The end of the range of a range tombstone.
+    pub range_end: Option<Cow<'a, [u8]>>,
+    // The timestamp of the record, see `Log::timestamp`.
+    pub timestamp: Option<u64>,
+    // The record has a 64-bit checksum, see `Log::wide_checksum`.
+    pub wide_checksum: bool,
 }
 
 impl<'a> CompactionHint<'a> {
     pub fn new(e: &'a Log, log_pos: u64) -> CompactionHint<'a> {
         CompactionHint {
             key: Cow::from(&*e.key),
-            log_pos: log_pos,
+            log_pos,
             value_size: e.value.len() as u32,
             seq: e.seq,
             deleted: e.deleted,
+            range_end: if e.range { Some(Cow::from(&*e.value)) } else { None },
+            timestamp: e.timestamp,
+            wide_checksum: e.wide_checksum,
         }
     }
 
     pub fn from(e: Log<'a>, log_pos: u64) -> CompactionHint<'a> {
         CompactionHint {
             key: e.key,
-            log_pos: log_pos,
+            log_pos,
             value_size: e.value.len() as u32,
             seq: e.seq,
             deleted: e.deleted,
+            range_end: if e.range { Some(e.value) } else { None },
+            timestamp: e.timestamp,
+            wide_checksum: e.wide_checksum,
         }
     }
 
     pub fn log_size(&self) -> u64 {
-        LOG_STATIC_SIZE as u64 + self.key.len() as u64 + self.value_size as u64
+        static_size(self.timestamp.is_some(), self.wide_checksum) as u64
+            + self.key.len() as u64
+            + self.value_size as u64
     }
 
+    // A hint has a timestamp when its record has one, and so when its file does.
     pub fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_u64::<LittleEndian>(self.seq)?;
+        if let Some(timestamp) = self.timestamp {
+            writer.write_u64::<LittleEndian>(timestamp)?;
+        }
         writer.write_u16::<LittleEndian>(self.key.len() as u16)?;
 
-        if self.deleted {
+        if self.range_end.is_some() {
+            writer.write_u32::<LittleEndian>(self.value_size | LOG_RANGE_TOMBSTONE)?;
+        } else if self.deleted {
             writer.write_u32::<LittleEndian>(LOG_TOMBSTONE)?;
         } else {
             writer.write_u32::<LittleEndian>(self.value_size)?;
@@ -336,26 +1209,63 @@ impl<'a> CompactionHint<'a> {
         writer.write_u64::<LittleEndian>(self.log_pos)?;
         writer.write_all(&self.key)?;
 
+        // The end of a range is needed to load the index, it follows the key.
+        if let Some(ref end) = self.range_end {
+            writer.write_all(end)?;
+        }
+
         Ok(())
     }
 
-    pub fn from_read<R: Read>(reader: &mut R) -> Result<CompactionHint<'a>> {
+    pub fn decode<R: Read>(
+        reader: &mut R,
+        file_header: &FileHeader,
+    ) -> Result<CompactionHint<'a>> {
+        match file_header.version {
+            LEGACY_FORMAT_VERSION | FORMAT_VERSION_1 | FORMAT_VERSION | FORMAT_VERSION_3 => {
+                CompactionHint::from_read(reader, file_header)
+            }
+            version => Err(Error::UnsupportedFormat {
+                version,
+                flags: file_header.flags,
+            }),
+        }
+    }
+
+    pub fn from_read<R: Read>(reader: &mut R, file_header: &FileHeader) -> Result<CompactionHint<'a>> {
         let seq = reader.read_u64::<LittleEndian>()?;
+        let timestamp = if file_header.has_timestamps() {
+            Some(reader.read_u64::<LittleEndian>()?)
+        } else {
+            None
+        };
         let key_size = reader.read_u16::<LittleEndian>()?;
-        let value_size = reader.read_u32::<LittleEndian>()?;
+        let (value_size, deleted, _, range) = split_value_size(
+            reader.read_u32::<LittleEndian>()?,
+            file_header.flags,
+        );
         let log_pos = reader.read_u64::<LittleEndian>()?;
 
         let mut key = vec![0u8; key_size as usize];
         reader.read_exact(&mut key)?;
 
-        let deleted = value_size == LOG_TOMBSTONE;
+        let range_end = if range {
+            let mut end = vec![0u8; value_size as usize];
+            reader.read_exact(&mut end)?;
+            Some(Cow::from(end))
+        } else {
+            None
+        };
 
         Ok(CompactionHint {
             key: Cow::from(key),
-            log_pos: log_pos,
-            value_size: if deleted { 0 } else { value_size },
-            seq: seq,
-            deleted: value_size == LOG_TOMBSTONE,
+            log_pos,
+            value_size,
+            seq,
+            deleted: deleted || range,
+            range_end,
+            timestamp,
+            wide_checksum: file_header.has_wide_checksums(),
         })
     }
 }
@@ -0,0 +1,249 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use twox_hash::XxHash64;
+
+use super::io_engine::{IoEngine, PositionedReader, SyncEngine};
+use super::slot::MemIdxEntry;
+
+// next(8) + removed(1) + key_size(2) + file_id(4) + pos(8) + seq(8) + size(8)
+const RECORD_HEADER_SIZE: usize = 39;
+// Bytes read at once when walking a chain, enough for the record of most keys.
+const RECORD_READ_SIZE: usize = 128;
+const INITIAL_BUCKETS: u64 = 1 << 16;
+// Average length of the chains beyond which the table gets twice as many buckets.
+const MAX_LOAD_FACTOR: u64 = 4;
+
+// Tells apart the spill files of the stores opened by the same process.
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+struct Record {
+    next: u64,
+    key: Vec<u8>,
+    // `None` for a removal.
+    entry: Option<MemIdxEntry>,
+}
+
+// The part of the index which doesn't fit in its memory budget, kept on disk: a hash table
+// of `buckets` offsets at the start of the file, each pointing to a chain of records
+// appended to the file, the most recent first. An update or a removal appends a record
+// shadowing the previous one of the key, and the table is rebuilt once the shadowed
+// records outnumber the live ones or the chains get too long.
+//
+// It's rebuilt from the hint files at every load, so the file is unlinked as soon as it's
+// created and goes away with its handle, even after a crash.
+pub struct SpilledIdx {
+    dir: PathBuf,
+    file: File,
+    buckets: u64,
+    end: u64,
+    len: usize,
+    garbage: usize,
+}
+
+impl SpilledIdx {
+    pub fn create(dir: &Path) -> io::Result<SpilledIdx> {
+        SpilledIdx::with_buckets(dir, INITIAL_BUCKETS)
+    }
+
+    fn with_buckets(dir: &Path, buckets: u64) -> io::Result<SpilledIdx> {
+        let path = dir.join(format!(
+            "crabe.index.{}.{}.spill",
+            process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        fs::remove_file(&path)?;
+        file.set_len(buckets * 8)?;
+
+        Ok(SpilledIdx {
+            dir: dir.to_path_buf(),
+            file,
+            buckets,
+            end: buckets * 8,
+            len: 0,
+            garbage: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<MemIdxEntry>> {
+        let mut offset = self.head(self.bucket(key))?;
+        while offset != 0 {
+            let record = self.read_record(offset)?;
+            if record.key == key {
+                return Ok(record.entry);
+            }
+            offset = record.next;
+        }
+        Ok(None)
+    }
+
+    // Add a key which isn't in the file.
+    pub fn insert(&mut self, key: &[u8], entry: &MemIdxEntry) -> io::Result<()> {
+        self.append(key, Some(entry))?;
+        self.len += 1;
+        self.maybe_rebuild()
+    }
+
+    // Returns the removed entry.
+    pub fn remove(&mut self, key: &[u8]) -> io::Result<Option<MemIdxEntry>> {
+        let previous = self.get(key)?;
+        if previous.is_some() {
+            self.append(key, None)?;
+            self.len -= 1;
+            // The removed record and the removal itself.
+            self.garbage += 2;
+            self.maybe_rebuild()?;
+        }
+        Ok(previous)
+    }
+
+    // The live keys and their entries, in no particular order.
+    pub fn entries(&self) -> SpilledEntries<'_> {
+        SpilledEntries {
+            idx: self,
+            bucket: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    fn bucket(&self, key: &[u8]) -> u64 {
+        let mut hasher = XxHash64::with_seed(0);
+        hasher.write(key);
+        hasher.finish() % self.buckets
+    }
+
+    fn head(&self, bucket: u64) -> io::Result<u64> {
+        PositionedReader::new(&SyncEngine, &self.file, bucket * 8).read_u64::<LittleEndian>()
+    }
+
+    fn read_record(&self, offset: u64) -> io::Result<Record> {
+        let mut reader = BufReader::with_capacity(
+            RECORD_READ_SIZE,
+            PositionedReader::new(&SyncEngine, &self.file, offset),
+        );
+        let next = reader.read_u64::<LittleEndian>()?;
+        let removed = reader.read_u8()? != 0;
+        let key_size = reader.read_u16::<LittleEndian>()?;
+        let entry = MemIdxEntry {
+            file_id: reader.read_u32::<LittleEndian>()?,
+            pos: reader.read_u64::<LittleEndian>()?,
+            seq: reader.read_u64::<LittleEndian>()?,
+            size: reader.read_u64::<LittleEndian>()?,
+        };
+        let mut key = vec![0u8; key_size as usize];
+        reader.read_exact(&mut key)?;
+
+        Ok(Record {
+            next,
+            key,
+            entry: if removed { None } else { Some(entry) },
+        })
+    }
+
+    // The live records of a bucket: the first one of each key in its chain.
+    fn chain(&self, bucket: u64) -> io::Result<Vec<(Vec<u8>, MemIdxEntry)>> {
+        let mut seen = HashSet::new();
+        let mut live = Vec::new();
+        let mut offset = self.head(bucket)?;
+        while offset != 0 {
+            let record = self.read_record(offset)?;
+            offset = record.next;
+            if !seen.insert(record.key.clone()) {
+                continue;
+            }
+            if let Some(entry) = record.entry {
+                live.push((record.key, entry));
+            }
+        }
+        Ok(live)
+    }
+
+    fn append(&mut self, key: &[u8], entry: Option<&MemIdxEntry>) -> io::Result<()> {
+        let bucket = self.bucket(key);
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + key.len());
+        record.write_u64::<LittleEndian>(self.head(bucket)?)?;
+        record.write_u8(entry.is_none() as u8)?;
+        record.write_u16::<LittleEndian>(key.len() as u16)?;
+        let &MemIdxEntry { file_id, pos, seq, size } = entry.unwrap_or(&MemIdxEntry {
+            file_id: 0,
+            pos: 0,
+            seq: 0,
+            size: 0,
+        });
+        record.write_u32::<LittleEndian>(file_id)?;
+        record.write_u64::<LittleEndian>(pos)?;
+        record.write_u64::<LittleEndian>(seq)?;
+        record.write_u64::<LittleEndian>(size)?;
+        record.extend_from_slice(key);
+
+        SyncEngine.write_all_at(&self.file, &record, self.end)?;
+        SyncEngine.write_all_at(&self.file, &self.end.to_le_bytes(), bucket * 8)?;
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    fn maybe_rebuild(&mut self) -> io::Result<()> {
+        let too_long = self.len as u64 > self.buckets * MAX_LOAD_FACTOR;
+        let too_sparse = self.garbage > self.len.max(self.buckets as usize);
+        if !too_long && !too_sparse {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets;
+        while self.len as u64 > buckets * MAX_LOAD_FACTOR / 2 {
+            buckets *= 2;
+        }
+        let mut rebuilt = SpilledIdx::with_buckets(&self.dir, buckets)?;
+        for live in self.entries() {
+            let (key, entry) = live?;
+            rebuilt.append(&key, Some(&entry))?;
+            rebuilt.len += 1;
+        }
+        *self = rebuilt;
+        Ok(())
+    }
+}
+
+pub struct SpilledEntries<'a> {
+    idx: &'a SpilledIdx,
+    bucket: u64,
+    pending: Vec<(Vec<u8>, MemIdxEntry)>,
+}
+
+impl<'a> Iterator for SpilledEntries<'a> {
+    type Item = io::Result<(Vec<u8>, MemIdxEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(live) = self.pending.pop() {
+                return Some(Ok(live));
+            }
+            if self.bucket == self.idx.buckets {
+                return None;
+            }
+            match self.idx.chain(self.bucket) {
+                Ok(live) => self.pending = live,
+                Err(err) => {
+                    self.bucket = self.idx.buckets;
+                    return Some(Err(err));
+                }
+            }
+            self.bucket += 1;
+        }
+    }
+}
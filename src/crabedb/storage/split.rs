@@ -0,0 +1,58 @@
+use std::path::Path;
+use std::result::Result::Ok;
+
+use log::info;
+
+use super::error::{Error, Result};
+use super::options::{StorageOptions, SyncOptions};
+use super::util::is_new_store_path;
+
+// Keys read from the store at a time.
+const SPLIT_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Default)]
+pub struct SplitReport {
+    // Records written to each new store.
+    pub records: Vec<u64>,
+}
+
+// Copy the live records of the store at `path`, which is not in use, into new stores at
+// `outs`, each key going to the one of index `shard(key)`, e.g. the node a
+// `client::ring::HashRing` maps it to. The records keep their sequence number and
+// creation time, so the writes made to a new store afterwards are newer.
+pub fn split_store<F: Fn(&[u8]) -> usize>(path: &str, outs: &[String], shard: F) -> Result<SplitReport> {
+    for out in outs {
+        if !is_new_store_path(Path::new(out))? {
+            return Err(Error::InvalidPath(out.to_string()));
+        }
+    }
+
+    let mut options = StorageOptions::default();
+    options.create(false).compaction(false).read_only(true);
+    let source = options.load(path)?;
+
+    let mut options = StorageOptions::default();
+    options.sync(SyncOptions::Never).compaction(false);
+    let shards = outs.iter().map(|out| options.load(out)).collect::<Result<Vec<_>>>()?;
+
+    let mut report = SplitReport {
+        records: vec![0; outs.len()],
+    };
+    let mut cursor = Vec::new();
+    loop {
+        let (logs, next_cursor) = source.live_records(&cursor, SPLIT_PAGE_SIZE)?;
+        for log in logs {
+            let index = shard(&log.key);
+            shards[index].merge(log)?;
+            report.records[index] += 1;
+        }
+
+        match next_cursor {
+            Some(next_cursor) => cursor = next_cursor,
+            None => break,
+        }
+    }
+
+    info!("Split {:?} into {} stores: {:?} records", path, outs.len(), report.records);
+    Ok(report)
+}
@@ -0,0 +1,89 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use super::backend::StdStorage;
+use super::error::Result;
+use super::format::{FileHeader, DATA_FILE_MAGIC, MAX_SEQ_OFFSET};
+use super::lsm::{CompactionHintWriter, COMPACTION_FILE_EXTENSION, DATA_FILE_EXTENSION};
+use super::options::ChecksumKind;
+use super::slot::{CompactionHint, Log};
+use super::util::get_file_handle;
+
+// Writes a data file outside of any store, e.g. from an offline ETL job or a tool which
+// pre-shards a dataset, to be loaded with `CrabeDB::ingest_files` without going through
+// the write path. The pairs can come in any order. Its hint file, complete with its
+// trailing checksum, is written next to it (see `hint_file_path`).
+//
+// The records are numbered from `first_seq` on: an ingested record doesn't replace a
+// newer version of its key, so starting after the `CrabeDB::last_seq` of the store
+// makes every one win.
+pub struct SstBuilder {
+    writer: BufWriter<File>,
+    hint_writer: CompactionHintWriter,
+    file_header: FileHeader,
+    data_file_pos: u64,
+    seq: u64,
+    count: u64,
+}
+
+impl SstBuilder {
+    // A file with the default checksum of the stores, numbered from 1.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<SstBuilder> {
+        SstBuilder::create(path, ChecksumKind::XxHash32, 1)
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P, checksum: ChecksumKind, first_seq: u64) -> Result<SstBuilder> {
+        let path = path.as_ref();
+        let file_header = FileHeader::with_checksum(checksum);
+        let mut writer = BufWriter::new(get_file_handle(path, true)?);
+        file_header.write_bytes(DATA_FILE_MAGIC, &mut writer)?;
+
+        Ok(SstBuilder {
+            writer,
+            hint_writer: CompactionHintWriter::new(Arc::new(StdStorage::default()), &hint_file_path(path), file_header)?,
+            file_header,
+            data_file_pos: file_header.size(),
+            seq: first_seq,
+            count: 0,
+        })
+    }
+
+    // Returns the sequence number of the record.
+    pub fn set<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<u64> {
+        let log = Log::new(self.seq, key.into(), value.as_ref())?;
+        let log = log.in_format(&self.file_header);
+        log.write_bytes(&mut self.writer, self.file_header.checksum())?;
+        self.hint_writer.write(&CompactionHint::new(&log, self.data_file_pos))?;
+
+        self.data_file_pos += log.size();
+        self.seq += 1;
+        self.count += 1;
+        Ok(log.seq)
+    }
+
+    // Flush and sync both files, returns the number of records.
+    pub fn finish(mut self) -> Result<u64> {
+        let mut file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        if self.count > 0 {
+            file.seek(SeekFrom::Start(MAX_SEQ_OFFSET))?;
+            file.write_u64::<LittleEndian>(self.seq - 1)?;
+        }
+        file.sync_all()?;
+        self.hint_writer.finish()?;
+        Ok(self.count)
+    }
+}
+
+// The hint file of the data file at `path`: `<name>.crabe.cpct` for `<name>.crabe.sst`,
+// or the name of the file followed by `.crabe.cpct` otherwise.
+pub fn hint_file_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let suffix = format!(".{}", DATA_FILE_EXTENSION);
+    let stem = name.strip_suffix(suffix.as_str()).unwrap_or(&name);
+    path.with_file_name(format!("{}.{}", stem, COMPACTION_FILE_EXTENSION))
+}
@@ -0,0 +1,222 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::Path;
+use std::result::Result::Ok;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::error::{Error, Result};
+use super::lsm::LogPosition;
+use super::util::{get_file_handle, sync_dir};
+use super::xxhash::xxhash32;
+
+pub(crate) const STANDBY_FILE_NAME: &str = "crabe.standby";
+const STANDBY_TEMP_FILE_NAME: &str = "crabe.standby.tmp";
+pub(crate) const SEED_FILE_NAME: &str = "crabe.seed";
+pub(crate) const ELECTION_FILE_NAME: &str = "crabe.election";
+const ELECTION_TEMP_FILE_NAME: &str = "crabe.election.tmp";
+
+// Position in the log of the primary up to which a standby applied the records:
+// file_id(4) + pos(8) + checksum(4). It is replaced atomically, like the manifest, and only
+// written once the records before it are durable, so a standby never skips records.
+pub fn load_position(path: &Path) -> Result<Option<LogPosition>> {
+    let standby_path = path.join(STANDBY_FILE_NAME);
+    if !standby_path.is_file() {
+        return Ok(None);
+    }
+
+    let mut buf = Vec::new();
+    get_file_handle(&standby_path, false)?.read_to_end(&mut buf)?;
+    if buf.len() != 16 {
+        return Err(Error::Io(io::ErrorKind::InvalidData.into()));
+    }
+
+    let (content, checksum) = buf.split_at(12);
+    let checksum = Cursor::new(checksum).read_u32::<LittleEndian>()?;
+    let hash = xxhash32(content);
+    if hash != checksum {
+        return Err(Error::InvalidChecksum {
+            expected: u64::from(checksum),
+            found: u64::from(hash),
+        });
+    }
+
+    let mut cursor = Cursor::new(content);
+    Ok(Some(LogPosition {
+        file_id: cursor.read_u32::<LittleEndian>()?,
+        pos: cursor.read_u64::<LittleEndian>()?,
+    }))
+}
+
+pub fn save_position(path: &Path, position: LogPosition) -> Result<()> {
+    let mut buf = Vec::with_capacity(16);
+    buf.write_u32::<LittleEndian>(position.file_id)?;
+    buf.write_u64::<LittleEndian>(position.pos)?;
+    let checksum = xxhash32(&buf);
+    buf.write_u32::<LittleEndian>(checksum)?;
+
+    let temp_path = path.join(STANDBY_TEMP_FILE_NAME);
+    let mut temp_file = get_file_handle(&temp_path, true)?;
+    temp_file.write_all(&buf)?;
+    temp_file.sync_all()?;
+    fs::rename(&temp_path, path.join(STANDBY_FILE_NAME))?;
+    sync_dir(path)?;
+    Ok(())
+}
+
+// Forget the position once the standby is promoted.
+pub fn remove_position(path: &Path) -> Result<()> {
+    let standby_path = path.join(STANDBY_FILE_NAME);
+    if standby_path.is_file() {
+        fs::remove_file(standby_path)?;
+        sync_dir(path)?;
+    }
+    Ok(())
+}
+
+// Mark a standby as being seeded with a copy of the live records of its primary, until its
+// first position is saved: the records of a seed which was interrupted are dropped before it
+// starts over.
+pub fn start_seed(path: &Path) -> Result<()> {
+    get_file_handle(&path.join(SEED_FILE_NAME), true)?.sync_all()?;
+    sync_dir(path)?;
+    Ok(())
+}
+
+pub fn is_seeding(path: &Path) -> bool {
+    path.join(SEED_FILE_NAME).is_file()
+}
+
+pub fn finish_seed(path: &Path) -> Result<()> {
+    let seed_path = path.join(SEED_FILE_NAME);
+    if seed_path.is_file() {
+        fs::remove_file(seed_path)?;
+        sync_dir(path)?;
+    }
+    Ok(())
+}
+
+// What a node of a failover group knows of the elections of its primary, like the
+// persistent state of Raft: the last term it has seen, the node it voted for in that term
+// and the primary elected in it, empty when unknown. The position saved with
+// `save_position` is in the log of that primary.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ElectionState {
+    pub term: u64,
+    pub voted_for: String,
+    pub leader: String,
+    // The nodes of the group as changed at runtime by its primary, empty until it is.
+    pub members: Vec<String>,
+    // The term of each record of the log: the first sequence number written by the primary
+    // of each term, as (term, seq), oldest first. Empty for the records of term 0.
+    pub history: Vec<(u64, u64)>,
+}
+
+impl ElectionState {
+    // Start the log of a new primary at `next_seq`, the terms of the records it never got
+    // being forgotten.
+    pub fn start_term(&mut self, next_seq: u64) {
+        self.history.retain(|&(_, seq)| seq < next_seq);
+        self.history.push((self.term, next_seq));
+    }
+}
+
+fn term_of(history: &[(u64, u64)], seq: u64) -> u64 {
+    history.iter().rev().find(|&&(_, start)| start <= seq).map_or(0, |&(term, _)| term)
+}
+
+// The last sequence number up to which two logs hold the same records, given their history
+// and last sequence number: the records of a term are the same on every node which has them.
+pub fn common_seq(history: &[(u64, u64)], last_seq: u64, other_history: &[(u64, u64)], other_last_seq: u64) -> u64 {
+    let last_seq = last_seq.min(other_last_seq);
+    let mut starts: Vec<u64> = history.iter().chain(other_history).map(|&(_, seq)| seq).collect();
+    starts.push(1);
+    starts.sort_unstable();
+    starts
+        .into_iter()
+        .filter(|&seq| seq > 0 && seq <= last_seq)
+        .find(|&seq| term_of(history, seq) != term_of(other_history, seq))
+        .map_or(last_seq, |seq| seq - 1)
+}
+
+// term(8) + voted_for_size(2) + voted_for + leader_size(2) + leader + member_count(2) +
+// (member_size(2) + member)* + term_count(2) + (term(8) + seq(8))* + checksum(4), replaced
+// atomically like the position. The files written before the members, or the history, were
+// kept end before them.
+pub fn load_election(path: &Path) -> Result<ElectionState> {
+    let election_path = path.join(ELECTION_FILE_NAME);
+    if !election_path.is_file() {
+        return Ok(ElectionState::default());
+    }
+
+    let mut buf = Vec::new();
+    get_file_handle(&election_path, false)?.read_to_end(&mut buf)?;
+    if buf.len() < 16 {
+        return Err(Error::Io(io::ErrorKind::InvalidData.into()));
+    }
+
+    let (content, checksum) = buf.split_at(buf.len() - 4);
+    let checksum = Cursor::new(checksum).read_u32::<LittleEndian>()?;
+    let hash = xxhash32(content);
+    if hash != checksum {
+        return Err(Error::InvalidChecksum {
+            expected: u64::from(checksum),
+            found: u64::from(hash),
+        });
+    }
+
+    let mut cursor = Cursor::new(content);
+    let term = cursor.read_u64::<LittleEndian>()?;
+    let voted_for = read_address(&mut cursor)?;
+    let leader = read_address(&mut cursor)?;
+    let mut members = Vec::new();
+    if cursor.position() < content.len() as u64 {
+        for _ in 0..cursor.read_u16::<LittleEndian>()? {
+            members.push(read_address(&mut cursor)?);
+        }
+    }
+    let mut history = Vec::new();
+    if cursor.position() < content.len() as u64 {
+        for _ in 0..cursor.read_u16::<LittleEndian>()? {
+            history.push((cursor.read_u64::<LittleEndian>()?, cursor.read_u64::<LittleEndian>()?));
+        }
+    }
+    Ok(ElectionState { term, voted_for, leader, members, history })
+}
+
+fn read_address(cursor: &mut Cursor<&[u8]>) -> Result<String> {
+    let mut address = vec![0u8; cursor.read_u16::<LittleEndian>()? as usize];
+    cursor.read_exact(&mut address)?;
+    String::from_utf8(address).map_err(|_| Error::Io(io::ErrorKind::InvalidData.into()))
+}
+
+pub fn save_election(path: &Path, state: &ElectionState) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.write_u64::<LittleEndian>(state.term)?;
+    for address in &[&state.voted_for, &state.leader] {
+        buf.write_u16::<LittleEndian>(address.len() as u16)?;
+        buf.write_all(address.as_bytes())?;
+    }
+    buf.write_u16::<LittleEndian>(state.members.len() as u16)?;
+    for member in &state.members {
+        buf.write_u16::<LittleEndian>(member.len() as u16)?;
+        buf.write_all(member.as_bytes())?;
+    }
+    buf.write_u16::<LittleEndian>(state.history.len() as u16)?;
+    for &(term, seq) in &state.history {
+        buf.write_u64::<LittleEndian>(term)?;
+        buf.write_u64::<LittleEndian>(seq)?;
+    }
+    let checksum = xxhash32(&buf);
+    buf.write_u32::<LittleEndian>(checksum)?;
+
+    let temp_path = path.join(ELECTION_TEMP_FILE_NAME);
+    let mut temp_file = get_file_handle(&temp_path, true)?;
+    temp_file.write_all(&buf)?;
+    temp_file.sync_all()?;
+    fs::rename(&temp_path, path.join(ELECTION_FILE_NAME))?;
+    sync_dir(path)?;
+    Ok(())
+}
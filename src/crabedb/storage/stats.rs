@@ -0,0 +1,78 @@
+use std::time::SystemTime;
+
+// Point-in-time statistics of a store, returned by `CrabeDB::stats`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub chunk_queue: ChunkQueueStats,
+    pub keys: usize,
+    // Size of the live records in the data files.
+    pub size: u64,
+    // Memory used by the in-memory part of the index, its keys included.
+    pub index_memory: usize,
+}
+
+impl Stats {
+    // Add up the statistics of the partitions of a store.
+    pub(crate) fn add(&mut self, other: &Stats) {
+        self.chunk_queue.hits += other.chunk_queue.hits;
+        self.chunk_queue.misses += other.chunk_queue.misses;
+        self.chunk_queue.evictions += other.chunk_queue.evictions;
+        self.chunk_queue.files += other.chunk_queue.files;
+        self.chunk_queue.handles += other.chunk_queue.handles;
+        self.chunk_queue.usage += other.chunk_queue.usage;
+        self.keys += other.keys;
+        self.size += other.size;
+        self.index_memory += other.index_memory;
+    }
+}
+
+// Counters of the data file handle cache, `usage` is expressed in the unit of its capacity.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChunkQueueStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub files: usize,
+    pub handles: usize,
+    pub usage: u64,
+}
+
+// Live and dead records of a data file, returned by `CrabeDB::file_stats`. Tombstones
+// aren't entries, and a record is dead once the index points to a newer one. A remote file
+// is offloaded to the object storage of the tiering policy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileStats {
+    pub file_id: u32,
+    // The partition of the file, see `StorageOptions::partitions`.
+    pub partition: usize,
+    pub active: bool,
+    pub remote: bool,
+    pub entries: u64,
+    pub dead_entries: u64,
+    pub dead_bytes: u64,
+    pub size: u64,
+    pub fragmentation: f64,
+}
+
+// Where and when the current value of a key was written, returned by
+// `CrabeDB::get_with_metadata`. `timestamp` is unknown for the records written before the
+// format recorded it, and `size` is the one of the record in its data file.
+#[derive(Clone, Copy, Debug)]
+pub struct ValueMetadata {
+    pub seq: u64,
+    pub timestamp: Option<SystemTime>,
+    pub file_id: u32,
+    pub size: u64,
+}
+
+// Progress of the compaction in progress, or of the last one when `running` is false.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionStatus {
+    pub paused: bool,
+    pub running: bool,
+    pub files: Vec<u32>,
+    pub records_processed: u64,
+    pub bytes_written: u64,
+    pub started_at: Option<SystemTime>,
+    pub finished_at: Option<SystemTime>,
+}
@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::collections::BTreeSet;
+use std::io;
+use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
+use std::path::Path;
+use std::result::Result::Ok;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::backend::{StdStorage, Storage};
+use super::checksum::{checksum_size, read_checksum, write_checksum, ChecksumHasher};
+use super::error::Result;
+use super::format::{FileHeader, FLAG_FILE_SUMMARY, HINT_FILE_MAGIC};
+use super::slot::CompactionHint;
+
+// The range of keys and sequence numbers of the records of a data file, written in the
+// footer of its hint file once it's sealed, see `FLAG_FILE_SUMMARY`. It tells which files
+// may hold a version of a key without reading their hints.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileSummary {
+    pub records: u64,
+    // Point and range tombstones.
+    pub tombstones: u64,
+    pub min_seq: u64,
+    pub max_seq: u64,
+    pub min_key: Vec<u8>,
+    // Inclusive, or the end of a range tombstone. Empty when a range has no end, like the
+    // end of `slot::in_range`.
+    pub max_key: Vec<u8>,
+    // The blob files its records point to, `None` when unknown, e.g. for the summaries
+    // written before they were recorded or the hint files rebuilt from salvaged hints.
+    pub blob_files: Option<BTreeSet<u32>>,
+}
+
+impl FileSummary {
+    pub fn add(&mut self, ch: &CompactionHint) {
+        let max_key = match ch.range_end {
+            Some(ref end) if end.is_empty() => None,
+            Some(ref end) => Some(end.as_ref()),
+            None => Some(ch.key.as_ref()),
+        };
+        if self.records == 0 {
+            self.min_seq = ch.seq;
+            self.max_seq = ch.seq;
+            self.min_key = ch.key.to_vec();
+            self.max_key = max_key.unwrap_or_default().to_vec();
+        } else {
+            self.min_seq = self.min_seq.min(ch.seq);
+            self.max_seq = self.max_seq.max(ch.seq);
+            if ch.key.as_ref() < self.min_key.as_slice() {
+                self.min_key = ch.key.to_vec();
+            }
+            match max_key {
+                None => self.max_key.clear(),
+                Some(key) if !self.max_key.is_empty() && key > self.max_key.as_slice() => {
+                    self.max_key = key.to_vec()
+                }
+                Some(_) => {}
+            }
+        }
+        self.records += 1;
+        if ch.deleted || ch.range_end.is_some() {
+            self.tombstones += 1;
+        }
+    }
+
+    pub fn add_blob(&mut self, file_id: u32) {
+        if let Some(ref mut blob_files) = self.blob_files {
+            blob_files.insert(file_id);
+        }
+    }
+
+    // Whether the file may hold a record older than `seq` of a key in `[start, end)`.
+    pub fn may_hold_older(&self, start: &[u8], end: &[u8], seq: u64) -> bool {
+        self.records > 0
+            && self.min_seq < seq
+            && (end.is_empty() || self.min_key.as_slice() < end)
+            && (self.max_key.is_empty() || start <= self.max_key.as_slice())
+    }
+
+    // Whether the file may hold a record older than `seq` of `key`.
+    pub fn may_hold_older_key(&self, key: &[u8], seq: u64) -> bool {
+        self.records > 0
+            && self.min_seq < seq
+            && self.min_key.as_slice() <= key
+            && (self.max_key.is_empty() || key <= self.max_key.as_slice())
+    }
+
+    fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u64::<LittleEndian>(self.records)?;
+        writer.write_u64::<LittleEndian>(self.tombstones)?;
+        writer.write_u64::<LittleEndian>(self.min_seq)?;
+        writer.write_u64::<LittleEndian>(self.max_seq)?;
+        writer.write_u32::<LittleEndian>(self.min_key.len() as u32)?;
+        writer.write_all(&self.min_key)?;
+        writer.write_u32::<LittleEndian>(self.max_key.len() as u32)?;
+        writer.write_all(&self.max_key)?;
+        if let Some(ref blob_files) = self.blob_files {
+            writer.write_u32::<LittleEndian>(blob_files.len() as u32)?;
+            for &file_id in blob_files {
+                writer.write_u32::<LittleEndian>(file_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn from_read<R: Read>(reader: &mut R) -> Result<FileSummary> {
+        let records = reader.read_u64::<LittleEndian>()?;
+        let tombstones = reader.read_u64::<LittleEndian>()?;
+        let min_seq = reader.read_u64::<LittleEndian>()?;
+        let max_seq = reader.read_u64::<LittleEndian>()?;
+        let mut min_key = vec![0u8; reader.read_u32::<LittleEndian>()? as usize];
+        reader.read_exact(&mut min_key)?;
+        let mut max_key = vec![0u8; reader.read_u32::<LittleEndian>()? as usize];
+        reader.read_exact(&mut max_key)?;
+        // The older footers end with the keys.
+        let blob_files = match reader.read_u32::<LittleEndian>() {
+            Ok(count) => {
+                let mut blob_files = BTreeSet::new();
+                for _ in 0..count {
+                    blob_files.insert(reader.read_u32::<LittleEndian>()?);
+                }
+                Some(blob_files)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(FileSummary {
+            records,
+            tombstones,
+            min_seq,
+            max_seq,
+            min_key,
+            max_key,
+            blob_files,
+        })
+    }
+
+    // The footer of a hint file, ahead of its trailing checksum: the summary, its size and
+    // its own checksum, so that it's read without the hints.
+    pub(crate) fn write_footer<W: Write>(&self, writer: &mut W, header: &FileHeader) -> Result<()> {
+        let mut footer = Vec::new();
+        self.write_bytes(&mut footer)?;
+        let checksum = header.hint_file_checksum();
+        let mut hasher = ChecksumHasher::new(checksum);
+        hasher.update(&footer);
+
+        writer.write_all(&footer)?;
+        writer.write_u32::<LittleEndian>(footer.len() as u32)?;
+        write_checksum(writer, checksum, hasher.get())?;
+        Ok(())
+    }
+}
+
+// Read the footer of a hint file of `size` bytes with the header `header`. Returns the
+// summary and the size of the footer, or `None` when the file has none, e.g. while its data
+// file is still written to.
+pub(crate) fn read_footer(file: &mut File, header: &FileHeader, size: u64) -> Result<Option<(FileSummary, u64)>> {
+    if !header.has_flag(FLAG_FILE_SUMMARY) {
+        return Ok(None);
+    }
+    let checksum = header.hint_file_checksum();
+    // The trailing checksum of the file, then the one of the footer and its size.
+    let tail = 2 * checksum_size(checksum) as u64 + 4;
+    if size < header.size() + tail {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(size - tail))?;
+    let footer_size = file.read_u32::<LittleEndian>()? as u64;
+    let expected = read_checksum(file, checksum)?;
+    if size < header.size() + tail + footer_size {
+        return Ok(None);
+    }
+
+    let mut footer = vec![0u8; footer_size as usize];
+    file.seek(SeekFrom::Start(size - tail - footer_size))?;
+    file.read_exact(&mut footer)?;
+    let mut hasher = ChecksumHasher::new(checksum);
+    hasher.update(&footer);
+    if hasher.get() != expected {
+        return Ok(None);
+    }
+    let summary = FileSummary::from_read(&mut Cursor::new(&footer))?;
+    Ok(Some((summary, footer_size + tail - checksum_size(checksum) as u64)))
+}
+
+// The summary in the hint file at `path`, if it has a complete one.
+pub(crate) fn read_file_summary(path: &Path) -> Result<Option<FileSummary>> {
+    load_file_summary(&StdStorage::default(), path)
+}
+
+pub(crate) fn load_file_summary(storage: &dyn Storage, path: &Path) -> Result<Option<FileSummary>> {
+    if !storage.exists(path) {
+        return Ok(None);
+    }
+    let mut file = storage.open(path)?;
+    let size = file.metadata()?.len();
+    let header = FileHeader::from_read(HINT_FILE_MAGIC, &mut file)?;
+    Ok(read_footer(&mut file, &header, size)?.map(|(summary, _)| summary))
+}
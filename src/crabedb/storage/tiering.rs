@@ -0,0 +1,583 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use super::error::{Error, Result};
+use super::format::{FileHeader, FLAG_COMPRESSION, FLAG_ENCRYPTION};
+use super::lsm::{get_data_file_path, sort_file_ids};
+use super::util::{get_file_handle, sync_dir};
+use super::xxhash::xxhash32;
+
+const REMOTE_FILE_EXTENSION: &str = "crabe.remote";
+const REMOTE_TEMP_FILE_EXTENSION: &str = "crabe.remote.tmp";
+const FETCH_TEMP_FILE_EXTENSION: &str = "crabe.sst.fetch.tmp";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+// Storage the cold data files are offloaded to, see `Tiering`. Objects are whole files,
+// only uploaded once, and named after the data file they hold.
+pub trait ObjectStore: Send + Sync {
+    // Upload the file at `path` as the object `name`.
+    fn put(&self, name: &str, path: &Path) -> Result<()>;
+    // Download the object `name` to the file at `path`.
+    fn get(&self, name: &str, path: &Path) -> Result<()>;
+    // Removing an object which doesn't exist isn't an error.
+    fn delete(&self, name: &str) -> Result<()>;
+}
+
+// A directory standing for the object storage, e.g. a network file system.
+pub struct DirObjectStore {
+    path: PathBuf,
+}
+
+impl DirObjectStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<DirObjectStore> {
+        fs::create_dir_all(path.as_ref())?;
+        Ok(DirObjectStore {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+}
+
+impl ObjectStore for DirObjectStore {
+    fn put(&self, name: &str, path: &Path) -> Result<()> {
+        let temp_path = self.path.join(format!("{}.tmp", name));
+        fs::copy(path, &temp_path)?;
+        get_file_handle(&temp_path, false)?.sync_all()?;
+        fs::rename(&temp_path, self.path.join(name))?;
+        sync_dir(&self.path)?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str, path: &Path) -> Result<()> {
+        fs::copy(self.path.join(name), path)?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match fs::remove_file(self.path.join(name)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+// The credentials of the AWS services, whose requests are signed with AWS signature V4.
+#[derive(Clone)]
+pub struct AwsCredentials {
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl AwsCredentials {
+    // `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, in the region of `AWS_REGION`
+    // (us-east-1 by default).
+    pub fn from_env() -> Result<AwsCredentials> {
+        let var = |name: &str| {
+            env::var(name).map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("{} is not set", name)))
+        };
+        Ok(AwsCredentials {
+            region: env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+        })
+    }
+
+    // The Authorization header of a request to `service` made at `amz_date`. The signed
+    // headers are given in lowercase and sorted by name, along with the hash of the payload.
+    pub(crate) fn authorization(
+        &self,
+        service: &str,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        payload_hash: &str,
+        amz_date: &str,
+    ) -> String {
+        let date = &amz_date[..8];
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method,
+            path,
+            canonical_headers,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let mut key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in &[self.region.as_str(), service, "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            scope,
+            signed_headers,
+            signature
+        )
+    }
+}
+
+// The date of a request signed with AWS signature V4.
+pub(crate) fn amz_date() -> String {
+    time::now_utc().strftime("%Y%m%dT%H%M%SZ").unwrap().to_string()
+}
+
+// The Host header sent along with the requests to `endpoint`, without the default port.
+pub(crate) fn endpoint_host(endpoint: &str) -> &str {
+    let (scheme, rest) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+    let host = rest.split('/').next().unwrap_or(rest);
+    match (scheme, host.rsplit_once(':')) {
+        ("https", Some((name, "443"))) | ("http", Some((name, "80"))) => name,
+        _ => host,
+    }
+}
+
+// S3-compatible object storage, addressed with path-style URLs
+// (`<endpoint>/<bucket>/<prefix><name>`) and requests signed with AWS signature V4.
+pub struct S3ObjectStore {
+    endpoint: String,
+    bucket: String,
+    prefix: String,
+    credentials: AwsCredentials,
+    agent: ureq::Agent,
+}
+
+impl S3ObjectStore {
+    // `endpoint` is the URL of the service, e.g. `https://s3.eu-west-1.amazonaws.com`.
+    pub fn new(endpoint: &str, bucket: &str, region: &str, access_key: &str, secret_key: &str) -> S3ObjectStore {
+        S3ObjectStore::with_credentials(endpoint, bucket, AwsCredentials {
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+        })
+    }
+
+    pub fn with_credentials(endpoint: &str, bucket: &str, credentials: AwsCredentials) -> S3ObjectStore {
+        S3ObjectStore {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            prefix: String::new(),
+            credentials,
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(10))
+                .timeout_read(Duration::from_secs(60))
+                .build(),
+        }
+    }
+
+    // With the credentials of `AwsCredentials::from_env`.
+    pub fn from_env(endpoint: &str, bucket: &str) -> Result<S3ObjectStore> {
+        Ok(S3ObjectStore::with_credentials(endpoint, bucket, AwsCredentials::from_env()?))
+    }
+
+    // Prepended to the names of the objects, e.g. `stores/users/`.
+    pub fn prefix(&mut self, prefix: &str) -> &mut S3ObjectStore {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    fn request(&self, method: &str, name: &str) -> ureq::Request {
+        let path = uri_encode(&format!("/{}/{}{}", self.bucket, self.prefix, name));
+        let amz_date = amz_date();
+        let authorization = self.credentials.authorization(
+            "s3",
+            method,
+            &path,
+            &[
+                ("host", endpoint_host(&self.endpoint)),
+                ("x-amz-content-sha256", UNSIGNED_PAYLOAD),
+                ("x-amz-date", &amz_date),
+            ],
+            UNSIGNED_PAYLOAD,
+            &amz_date,
+        );
+
+        self.agent
+            .request(method, &format!("{}{}", self.endpoint, path))
+            .set("x-amz-date", &amz_date)
+            .set("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .set("Authorization", &authorization)
+    }
+}
+
+impl ObjectStore for S3ObjectStore {
+    fn put(&self, name: &str, path: &Path) -> Result<()> {
+        let file = get_file_handle(path, false)?;
+        let size = file.metadata()?.len();
+        self.request("PUT", name)
+            .set("Content-Length", &size.to_string())
+            .send(file)
+            .map_err(|err| s3_error("PUT", name, err))?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str, path: &Path) -> Result<()> {
+        let response = self.request("GET", name).call().map_err(|err| s3_error("GET", name, err))?;
+        let mut file = get_file_handle(path, true)?;
+        io::copy(&mut response.into_reader(), &mut file)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match self.request("DELETE", name).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(err) => Err(s3_error("DELETE", name, err)),
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Percent-encodes everything but the unreserved characters and the slashes.
+fn uri_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn s3_error(method: &str, name: &str, err: ureq::Error) -> Error {
+    let reason = match err {
+        ureq::Error::Status(status, response) => {
+            format!("status {}: {}", status, response.into_string().unwrap_or_default())
+        }
+        err => err.to_string(),
+    };
+    Error::Io(io::Error::other(format!("S3 {} of {} failed with {}", method, name, reason)))
+}
+
+// Tiering policy of a store (`StorageOptions::tiering`): the data files which haven't
+// been written to for `min_age` are uploaded to `store` and removed from the local disk,
+// their compaction hints staying local. An offloaded file is downloaded again when it's
+// read, and kept locally until the local copies of offloaded files exceed `cache_size`
+// bytes, the least recently opened ones being removed first.
+#[derive(Clone)]
+pub struct Tiering {
+    pub store: Arc<dyn ObjectStore>,
+    pub min_age: Duration,
+    pub cache_size: u64,
+    // How often the files to offload are looked for.
+    pub check_interval: Duration,
+}
+
+impl Tiering {
+    pub fn new<S: ObjectStore + 'static>(store: S) -> Tiering {
+        Tiering {
+            store: Arc::new(store),
+            min_age: Duration::from_secs(7 * 24 * 3600),
+            cache_size: 1024 * 1024 * 1024, // 1GB
+            check_interval: Duration::from_secs(600),
+        }
+    }
+
+    pub fn min_age(&mut self, min_age: Duration) -> &mut Tiering {
+        self.min_age = min_age;
+        self
+    }
+
+    pub fn cache_size(&mut self, cache_size: u64) -> &mut Tiering {
+        self.cache_size = cache_size;
+        self
+    }
+
+    pub fn check_interval(&mut self, check_interval: Duration) -> &mut Tiering {
+        self.check_interval = check_interval;
+        self
+    }
+}
+
+// What is kept locally of an offloaded data file, in `<file_id>.crabe.remote`:
+// size(8) + modified(8, milliseconds since the epoch) + version(2) + flags(2) +
+// [key id(4), with `FLAG_ENCRYPTION`] + [dictionary size(4), with `FLAG_COMPRESSION`] +
+// checksum(4).
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteStub {
+    pub size: u64,
+    pub modified: SystemTime,
+    pub header: FileHeader,
+}
+
+impl RemoteStub {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // Writing to a Vec can't fail.
+        buf.write_u64::<LittleEndian>(self.size).unwrap();
+        buf.write_u64::<LittleEndian>(
+            self.modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        ).unwrap();
+        buf.write_u16::<LittleEndian>(self.header.version).unwrap();
+        buf.write_u16::<LittleEndian>(self.header.flags).unwrap();
+        if self.header.has_flag(FLAG_ENCRYPTION) {
+            buf.write_u32::<LittleEndian>(self.header.key_id).unwrap();
+        }
+        if self.header.has_flag(FLAG_COMPRESSION) {
+            buf.write_u32::<LittleEndian>(self.header.dictionary_size).unwrap();
+        }
+        let checksum = xxhash32(&buf);
+        buf.write_u32::<LittleEndian>(checksum).unwrap();
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<RemoteStub> {
+        if buf.len() < 24 {
+            return Err(Error::Io(io::ErrorKind::InvalidData.into()));
+        }
+        let (content, checksum) = buf.split_at(buf.len() - 4);
+        let checksum = Cursor::new(checksum).read_u32::<LittleEndian>()?;
+        let hash = xxhash32(content);
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: u64::from(checksum),
+                found: u64::from(hash),
+            });
+        }
+
+        let mut cursor = Cursor::new(content);
+        let size = cursor.read_u64::<LittleEndian>()?;
+        let modified = UNIX_EPOCH + Duration::from_millis(cursor.read_u64::<LittleEndian>()?);
+        let mut header = FileHeader {
+            version: cursor.read_u16::<LittleEndian>()?,
+            flags: cursor.read_u16::<LittleEndian>()?,
+            dictionary_size: 0,
+            key_id: 0,
+        };
+        if header.has_flag(FLAG_ENCRYPTION) {
+            header.key_id = cursor.read_u32::<LittleEndian>()?;
+        }
+        if header.has_flag(FLAG_COMPRESSION) {
+            header.dictionary_size = cursor.read_u32::<LittleEndian>()?;
+        }
+        if cursor.position() != content.len() as u64 {
+            return Err(Error::Io(io::ErrorKind::InvalidData.into()));
+        }
+        Ok(RemoteStub {
+            size,
+            modified,
+            header,
+        })
+    }
+}
+
+struct LocalCopies {
+    // Least recently opened first.
+    files: VecDeque<u32>,
+    size: u64,
+}
+
+// The offloaded data files of a store, and their local copies.
+pub struct RemoteFiles {
+    path: PathBuf,
+    store: Arc<dyn ObjectStore>,
+    cache_size: u64,
+    stubs: Mutex<HashMap<u32, RemoteStub>>,
+    local_copies: Mutex<LocalCopies>,
+    // Held while a file is downloaded, so that it's only downloaded once.
+    fetching: Mutex<()>,
+}
+
+impl RemoteFiles {
+    pub fn load(path: &Path, tiering: &Tiering) -> Result<RemoteFiles> {
+        let mut stubs = HashMap::new();
+        let mut local_copies = LocalCopies {
+            files: VecDeque::new(),
+            size: 0,
+        };
+        for file_id in find_remote_files(path)? {
+            let stub = read_stub(path, file_id)?;
+            if get_data_file_path(path, file_id).is_file() {
+                local_copies.files.push_back(file_id);
+                local_copies.size += stub.size;
+            }
+            stubs.insert(file_id, stub);
+        }
+
+        Ok(RemoteFiles {
+            path: path.to_path_buf(),
+            store: tiering.store.clone(),
+            cache_size: tiering.cache_size,
+            stubs: Mutex::new(stubs),
+            local_copies: Mutex::new(local_copies),
+            fetching: Mutex::new(()),
+        })
+    }
+
+    pub fn files(&self) -> Vec<u32> {
+        let mut files: Vec<u32> = self.stubs.lock().unwrap().keys().cloned().collect();
+        files.sort();
+        files
+    }
+
+    pub fn stub(&self, file_id: u32) -> Option<RemoteStub> {
+        self.stubs.lock().unwrap().get(&file_id).cloned()
+    }
+
+    // Upload a data file, which must no longer be written to, then remove it locally. The
+    // stub is written first: a reader which doesn't find the data file anymore then
+    // downloads it.
+    pub fn offload(&self, file_id: u32, header: FileHeader) -> Result<()> {
+        let data_file_path = get_data_file_path(&self.path, file_id);
+        let metadata = fs::metadata(&data_file_path)?;
+        let stub = RemoteStub {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            header,
+        };
+
+        self.store.put(&object_name(file_id), &data_file_path)?;
+
+        let temp_path = get_file_path(&self.path, file_id, REMOTE_TEMP_FILE_EXTENSION);
+        let mut temp_file = get_file_handle(&temp_path, true)?;
+        temp_file.write_all(&stub.encode())?;
+        temp_file.sync_all()?;
+        fs::rename(&temp_path, get_remote_file_path(&self.path, file_id))?;
+        sync_dir(&self.path)?;
+        self.stubs.lock().unwrap().insert(file_id, stub);
+
+        fs::remove_file(&data_file_path)?;
+        info!("Offloaded data file {} ({} bytes)", file_id, stub.size);
+        Ok(())
+    }
+
+    // Make sure an offloaded file has a local copy, downloading it if needed. Returns the
+    // local copies removed to make room for it, whose handles should be closed.
+    pub fn fetch(&self, file_id: u32) -> Result<Vec<u32>> {
+        let stub = match self.stub(file_id) {
+            Some(stub) => stub,
+            None => return Ok(Vec::new()),
+        };
+
+        let data_file_path = get_data_file_path(&self.path, file_id);
+        {
+            let _fetching = self.fetching.lock().unwrap();
+            if data_file_path.is_file() {
+                let mut local_copies = self.local_copies.lock().unwrap();
+                if let Some(index) = local_copies.files.iter().position(|&f| f == file_id) {
+                    local_copies.files.remove(index);
+                    local_copies.files.push_back(file_id);
+                }
+                return Ok(Vec::new());
+            }
+
+            info!("Fetching offloaded data file {}", file_id);
+            let temp_path = get_file_path(&self.path, file_id, FETCH_TEMP_FILE_EXTENSION);
+            if let Err(err) = self.store.get(&object_name(file_id), &temp_path) {
+                let _ = fs::remove_file(&temp_path);
+                return Err(err);
+            }
+            fs::rename(&temp_path, &data_file_path)?;
+        }
+
+        let mut local_copies = self.local_copies.lock().unwrap();
+        local_copies.files.push_back(file_id);
+        local_copies.size += stub.size;
+
+        let mut evicted = Vec::new();
+        while local_copies.size > self.cache_size && local_copies.files.len() > 1 {
+            let evicted_id = local_copies.files.pop_front().unwrap();
+            local_copies.size -= self.stub(evicted_id).map(|stub| stub.size).unwrap_or(0);
+            if let Err(err) = fs::remove_file(get_data_file_path(&self.path, evicted_id)) {
+                warn!("Couldn't remove the local copy of data file {}: {}", evicted_id, err);
+            }
+            evicted.push(evicted_id);
+        }
+        Ok(evicted)
+    }
+
+    // Download an offloaded file to `path`, outside of the local copies.
+    pub fn download(&self, file_id: u32, path: &Path) -> Result<()> {
+        self.store.get(&object_name(file_id), path)
+    }
+
+    // Forget a file compacted away, removing its object, stub and local copy.
+    pub fn remove(&self, file_id: u32) -> Result<()> {
+        let stub = match self.stubs.lock().unwrap().remove(&file_id) {
+            Some(stub) => stub,
+            None => return Ok(()),
+        };
+        self.store.delete(&object_name(file_id))?;
+        fs::remove_file(get_remote_file_path(&self.path, file_id))?;
+
+        let mut local_copies = self.local_copies.lock().unwrap();
+        if let Some(index) = local_copies.files.iter().position(|&f| f == file_id) {
+            local_copies.files.remove(index);
+            local_copies.size -= stub.size;
+        }
+        let _ = fs::remove_file(get_data_file_path(&self.path, file_id));
+        Ok(())
+    }
+}
+
+pub(crate) fn object_name(file_id: u32) -> String {
+    get_data_file_path(Path::new(""), file_id).to_string_lossy().into_owned()
+}
+
+pub(crate) fn get_remote_file_path(path: &Path, file_id: u32) -> PathBuf {
+    get_file_path(path, file_id, REMOTE_FILE_EXTENSION)
+}
+
+fn get_file_path(path: &Path, file_id: u32, extension: &str) -> PathBuf {
+    let file_id = format!("{:010}", file_id);
+    path.join(file_id).with_extension(extension)
+}
+
+pub(crate) fn read_stub(path: &Path, file_id: u32) -> Result<RemoteStub> {
+    let mut buf = Vec::new();
+    get_file_handle(&get_remote_file_path(path, file_id), false)?.read_to_end(&mut buf)?;
+    RemoteStub::decode(&buf)
+}
+
+pub(crate) fn find_remote_files(path: &Path) -> Result<Vec<u32>> {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(&format!("^(\\d+).{}$", REMOTE_FILE_EXTENSION)).unwrap();
+    }
+
+    let mut remote_files = Vec::new();
+    for file in fs::read_dir(path)? {
+        let file = file?;
+        if let Some(file_id) = RE
+            .captures(&file.file_name().to_string_lossy())
+            .and_then(|c| c.get(1))
+            .and_then(|n| n.as_str().parse::<u32>().ok())
+        {
+            remote_files.push(file_id);
+        }
+    }
+    sort_file_ids(remote_files)
+}
@@ -1,3 +1,4 @@
+use std::fs;
 use std::fs::{File, OpenOptions};
 use std::path::Path;
 use std::io::Result;
@@ -29,4 +30,42 @@ pub fn get_file_handle(path: &Path, write: bool) -> Result<File> {
     } else {
         OpenOptions::new().read(true).open(path)
     }
-}
\ No newline at end of file
+}
+
+// Create a file for writing, failing if it already exists.
+pub fn create_new_file(path: &Path) -> Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+// Hard link `source` to `dest`, or copy it when they are on different file systems, in
+// which case the copy is synced.
+pub fn link_or_copy(source: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(source, dest).is_err() {
+        copy_synced(source, dest)?;
+    }
+    Ok(())
+}
+
+pub fn copy_synced(source: &Path, dest: &Path) -> Result<()> {
+    fs::copy(source, dest)?;
+    File::open(dest)?.sync_all()
+}
+
+// Whether a new store can be created at `path`: nothing is there, or an empty directory.
+pub fn is_new_store_path(path: &Path) -> Result<bool> {
+    if !path.exists() {
+        return Ok(true);
+    }
+    Ok(path.is_dir() && fs::read_dir(path)?.next().is_none())
+}
+
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
@@ -0,0 +1,73 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+
+// Least recently used cache of values, bounded by the total size of its keys and values.
+pub struct ValueCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<Vec<u8>, (Bytes, u64)>,
+    lru: BTreeMap<u64, Vec<u8>>,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<Bytes> {
+        let tick = self.tick + 1;
+        let (value, last_used) = self.entries.get_mut(key)?;
+
+        self.lru.remove(last_used);
+        *last_used = tick;
+        self.lru.insert(tick, key.to_vec());
+        self.tick = tick;
+
+        Some(value.clone())
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: Bytes) {
+        let entry_size = key.len() + value.len();
+        if entry_size > self.capacity {
+            return;
+        }
+
+        self.remove(key);
+
+        self.tick += 1;
+        self.entries.insert(key.to_vec(), (value, self.tick));
+        self.lru.insert(self.tick, key.to_vec());
+        self.size += entry_size;
+
+        while self.size > self.capacity {
+            match self.lru.pop_first() {
+                Some((_, key)) => {
+                    if let Some((value, _)) = self.entries.remove(&key) {
+                        self.size -= key.len() + value.len();
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    // The size of the entries which can still be inserted without evicting any.
+    pub fn available(&self) -> usize {
+        self.capacity - self.size
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        if let Some((value, last_used)) = self.entries.remove(key) {
+            self.lru.remove(&last_used);
+            self.size -= key.len() + value.len();
+        }
+    }
+}
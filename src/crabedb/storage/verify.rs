@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok;
+
+use super::backend::StdStorage;
+use super::checksum::ChecksumHasher;
+use super::error::{Error, Result};
+use super::format::{FileHeader, DATA_FILE_MAGIC, FLAG_MAX_SEQ};
+use super::lsm::{
+    acquire_lock, find_data_files, get_compaction_hint_file_path, get_data_file_path,
+    is_valid_compaction_hint_file, open_compaction_hints, open_entries,
+};
+use super::manifest::Manifest;
+use super::options::{ChecksumKind, RecoveryMode};
+use super::slot::Log;
+use super::tiering::get_remote_file_path;
+use super::util::get_file_handle;
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum Corruption {
+    InvalidManifest(String),
+    MissingDataFile(u32),
+    UnreferencedDataFile(u32),
+    CorruptedRecords { file_id: u32, offset: u64, length: u64 },
+    UnreadableDataFile { file_id: u32, reason: String },
+    MissingHintFile(u32),
+    InvalidHintFile(u32),
+    HintMismatch { file_id: u32, offset: u64, reason: String },
+    RecordMissingFromHints { file_id: u32, offset: u64 },
+    SealMismatch { file_id: u32, reason: String },
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Corruption::InvalidManifest(ref reason) => write!(f, "invalid manifest: {}", reason),
+            Corruption::MissingDataFile(file_id) => {
+                write!(f, "data file {} is referenced by the manifest but missing", file_id)
+            }
+            Corruption::UnreferencedDataFile(file_id) => {
+                write!(f, "data file {} is not referenced by the manifest", file_id)
+            }
+            Corruption::CorruptedRecords { file_id, offset, length } => {
+                write!(
+                    f,
+                    "data file {}: {} unreadable bytes at offset {}",
+                    file_id,
+                    length,
+                    offset
+                )
+            }
+            Corruption::UnreadableDataFile { file_id, ref reason } => {
+                write!(f, "data file {} can't be read: {}", file_id, reason)
+            }
+            Corruption::MissingHintFile(file_id) => {
+                write!(f, "data file {} has no compaction file", file_id)
+            }
+            Corruption::InvalidHintFile(file_id) => {
+                write!(f, "data file {} has a compaction file with an invalid checksum", file_id)
+            }
+            Corruption::HintMismatch { file_id, offset, ref reason } => {
+                write!(
+                    f,
+                    "data file {}: compaction hint for offset {} doesn't match: {}",
+                    file_id,
+                    offset,
+                    reason
+                )
+            }
+            Corruption::RecordMissingFromHints { file_id, offset } => {
+                write!(
+                    f,
+                    "data file {}: record at offset {} is missing from the compaction file",
+                    file_id,
+                    offset
+                )
+            }
+            Corruption::SealMismatch { file_id, ref reason } => {
+                write!(f, "data file {} doesn't match its seal in the manifest: {}", file_id, reason)
+            }
+        }
+    }
+}
+
+// A discrepancy between an entry of the index of a live store and the record it points to,
+// see `CrabeDB::check_consistency`.
+#[derive(Debug)]
+pub enum Inconsistency {
+    MissingFile { key: Vec<u8>, file_id: u32 },
+    UnreadableRecord { key: Vec<u8>, file_id: u32, offset: u64, reason: String },
+    RecordMismatch { key: Vec<u8>, file_id: u32, offset: u64, reason: String },
+}
+
+impl Inconsistency {
+    pub fn key(&self) -> &[u8] {
+        match *self {
+            Inconsistency::MissingFile { ref key, .. }
+            | Inconsistency::UnreadableRecord { ref key, .. }
+            | Inconsistency::RecordMismatch { ref key, .. } => key,
+        }
+    }
+}
+
+impl Display for Inconsistency {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Inconsistency::MissingFile { ref key, file_id } => write!(
+                f,
+                "key {:?}: data file {} is missing",
+                String::from_utf8_lossy(key),
+                file_id
+            ),
+            Inconsistency::UnreadableRecord { ref key, file_id, offset, ref reason } => write!(
+                f,
+                "key {:?}: record at offset {} of data file {} can't be read: {}",
+                String::from_utf8_lossy(key),
+                offset,
+                file_id,
+                reason
+            ),
+            Inconsistency::RecordMismatch { ref key, file_id, offset, ref reason } => write!(
+                f,
+                "key {:?}: record at offset {} of data file {} doesn't match the index: {}",
+                String::from_utf8_lossy(key),
+                offset,
+                file_id,
+                reason
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConsistencyReport {
+    pub entries: u64,
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl ConsistencyReport {
+    pub fn is_ok(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub files: usize,
+    // Data files skipped by `verify_files` because they have no seal.
+    pub unsealed_files: usize,
+    pub records: u64,
+    pub corruptions: Vec<Corruption>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+// Walk every data file of a store which is not opened by anyone else, check each record
+// checksum and cross-check the compaction (hint) files against the data files.
+pub fn verify(path: &str) -> Result<VerifyReport> {
+    let path = PathBuf::from(path);
+    if !path.is_dir() {
+        return Err(Error::InvalidPath(path.to_string_lossy().into_owned()));
+    }
+    let _lock_file = acquire_lock(&path)?;
+
+    let mut report = VerifyReport::default();
+    let data_files = find_data_files(&path)?;
+
+    match Manifest::load(&path) {
+        Ok(Some(manifest)) => {
+            for &file_id in &data_files {
+                if !manifest.contains(file_id) {
+                    report.corruptions.push(Corruption::UnreferencedDataFile(file_id));
+                }
+            }
+            // Offloaded data files are only checked when they have a local copy.
+            for file_id in manifest.files() {
+                if data_files.binary_search(&file_id).is_err()
+                    && !get_remote_file_path(&path, file_id).is_file()
+                {
+                    report.corruptions.push(Corruption::MissingDataFile(file_id));
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(err) => report.corruptions.push(Corruption::InvalidManifest(err.to_string())),
+    }
+
+    for file_id in data_files {
+        report.files += 1;
+        if let Err(err) = verify_file(&path, file_id, &mut report) {
+            report.corruptions.push(Corruption::UnreadableDataFile {
+                file_id,
+                reason: err.to_string(),
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+// Check the sealed data files of a store which is not opened by anyone else against the
+// length and hash recorded in the manifest, without decoding their records. The active
+// data file and those sealed by older versions are skipped.
+pub fn verify_files(path: &str) -> Result<VerifyReport> {
+    let path = PathBuf::from(path);
+    if !path.is_dir() {
+        return Err(Error::InvalidPath(path.to_string_lossy().into_owned()));
+    }
+    let _lock_file = acquire_lock(&path)?;
+
+    let mut report = VerifyReport::default();
+    let manifest = match Manifest::load(&path) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => return Ok(report),
+        Err(err) => {
+            report.corruptions.push(Corruption::InvalidManifest(err.to_string()));
+            return Ok(report);
+        }
+    };
+
+    for file_id in manifest.files() {
+        let seal = match manifest.seal(file_id) {
+            Some(seal) => seal,
+            None => {
+                report.unsealed_files += 1;
+                continue;
+            }
+        };
+
+        // Offloaded data files are only checked when they have a local copy.
+        let data_file_path = get_data_file_path(&path, file_id);
+        if !data_file_path.is_file() {
+            if !get_remote_file_path(&path, file_id).is_file() {
+                report.corruptions.push(Corruption::MissingDataFile(file_id));
+            }
+            continue;
+        }
+
+        report.files += 1;
+        match hash_file(&data_file_path) {
+            Ok((length, _)) if length != seal.length => report.corruptions.push(Corruption::SealMismatch {
+                file_id,
+                reason: format!("{} bytes in the seal, {} in the file", seal.length, length),
+            }),
+            Ok((_, hash)) if hash != seal.hash => report.corruptions.push(Corruption::SealMismatch {
+                file_id,
+                reason: format!("hash {:x} in the seal, {:x} of the file", seal.hash, hash),
+            }),
+            Ok(_) => {}
+            Err(err) => report.corruptions.push(Corruption::UnreadableDataFile {
+                file_id,
+                reason: err.to_string(),
+            }),
+        }
+    }
+
+    Ok(report)
+}
+
+// The length and the hash of the whole file, see `FileSeal`. The highest sequence number
+// of the header is hashed as 0, see `FLAG_MAX_SEQ`.
+fn hash_file(path: &Path) -> Result<(u64, u64)> {
+    let mut file = get_file_handle(path, false)?;
+    let mut hasher = ChecksumHasher::new(ChecksumKind::XxHash64);
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    let mut length = 0;
+    let file_header = FileHeader::from_read(DATA_FILE_MAGIC, &mut file)?;
+    if file_header.has_flag(FLAG_MAX_SEQ) {
+        // The dictionary of a compressed file is hashed as it is read.
+        file_header.write_bytes(DATA_FILE_MAGIC, &mut hasher)?;
+        length = file_header.dictionary_offset();
+        file.seek(SeekFrom::Start(length))?;
+    } else {
+        file.seek(SeekFrom::Start(0))?;
+    }
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok((length, hasher.get()));
+        }
+        hasher.update(&buf[..read]);
+        length += read as u64;
+    }
+}
+
+fn verify_file(path: &Path, file_id: u32, report: &mut VerifyReport) -> Result<()> {
+    let data_file_size = get_file_handle(&get_data_file_path(path, file_id), false)?
+        .metadata()?
+        .len();
+
+    // Offsets of the readable records, along with their sequence number.
+    let mut records = HashMap::new();
+    let entries = open_entries(&StdStorage::default(), path, file_id, RecoveryMode::SkipCorrupt)?;
+    let file_header = entries.header();
+    let mut expected_pos = file_header.size();
+
+    for (log_pos, log) in entries {
+        let log = log?;
+        if log_pos > expected_pos {
+            report.corruptions.push(Corruption::CorruptedRecords {
+                file_id,
+                offset: expected_pos,
+                length: log_pos - expected_pos,
+            });
+        }
+        expected_pos = log_pos + log.size();
+        records.insert(log_pos, log.seq);
+        report.records += 1;
+    }
+
+    if expected_pos < data_file_size {
+        report.corruptions.push(Corruption::CorruptedRecords {
+            file_id,
+            offset: expected_pos,
+            length: data_file_size - expected_pos,
+        });
+    }
+
+    let compaction_file_path = get_compaction_hint_file_path(path, file_id);
+    if !compaction_file_path.is_file() {
+        report.corruptions.push(Corruption::MissingHintFile(file_id));
+        return Ok(());
+    }
+    if !is_valid_compaction_hint_file(&StdStorage::default(), &compaction_file_path)? {
+        report.corruptions.push(Corruption::InvalidHintFile(file_id));
+        return Ok(());
+    }
+
+    let mut data_file = get_file_handle(&get_data_file_path(path, file_id), false)?;
+
+    for ch in open_compaction_hints(&StdStorage::default(), path, file_id)? {
+        let ch = ch?;
+        let mismatch = |reason: String| Corruption::HintMismatch {
+            file_id,
+            offset: ch.log_pos,
+            reason,
+        };
+
+        if records.remove(&ch.log_pos).is_none() {
+            report.corruptions.push(mismatch("no readable record at this offset".to_string()));
+            continue;
+        }
+
+        data_file.seek(SeekFrom::Start(ch.log_pos))?;
+        let log = Log::decode(&mut data_file, &file_header)?;
+
+        if log.key != ch.key {
+            report.corruptions.push(mismatch(format!(
+                "key {:?} in the hint, {:?} in the data file",
+                ch.key,
+                log.key
+            )));
+        } else if log.seq != ch.seq {
+            report.corruptions.push(mismatch(format!(
+                "sequence {} in the hint, {} in the data file",
+                ch.seq,
+                log.seq
+            )));
+        } else if log.deleted != ch.deleted || log.value.len() as u32 != ch.value_size {
+            report.corruptions.push(mismatch(format!(
+                "value size {} (deleted: {}) in the hint, {} (deleted: {}) in the data file",
+                ch.value_size,
+                ch.deleted,
+                log.value.len(),
+                log.deleted
+            )));
+        }
+    }
+
+    let mut missing: Vec<_> = records.keys().cloned().collect();
+    missing.sort_unstable();
+    for offset in missing {
+        report.corruptions.push(Corruption::RecordMissingFromHints { file_id, offset });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use super::group_commit::WriteOp;
+
+// Writes applied together by `CrabeDB::write`, under a single acquisition of the store
+// lock, in the order they were added. Each write still gets its own record and sequence
+// number, the batch isn't atomic: a write may fail (e.g. an invalid key) while the others
+// are applied.
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<WriteOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) -> &mut WriteBatch {
+        self.ops.push(WriteOp::Set(key.into(), value.into()));
+        self
+    }
+
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) -> &mut WriteBatch {
+        self.ops.push(WriteOp::Remove(key.into()));
+        self
+    }
+
+    // Remove every key from `start` (included) to `end` (excluded, empty for no end).
+    pub fn delete_range<S: Into<Vec<u8>>, E: Into<Vec<u8>>>(&mut self, start: S, end: E) -> &mut WriteBatch {
+        self.ops.push(WriteOp::RemoveRange(start.into(), end.into()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
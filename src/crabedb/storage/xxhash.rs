@@ -6,6 +6,12 @@ use twox_hash::XxHash32 as TwoXhash32;
 
 pub struct XxHash32(TwoXhash32);
 
+impl Default for XxHash32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl XxHash32 {
     pub fn new() -> XxHash32 {
         XxHash32(TwoXhash32::with_seed(0))